@@ -0,0 +1,5 @@
+ALTER TABLE known_packages ADD COLUMN sources TEXT NOT NULL DEFAULT '[]';
+UPDATE known_packages SET sources = json_array(source) WHERE source IS NOT NULL;
+ALTER TABLE known_packages DROP COLUMN source;
+
+UPDATE meta SET value = '2' WHERE key = 'schema_version';
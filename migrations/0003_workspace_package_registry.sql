@@ -0,0 +1,3 @@
+ALTER TABLE workspace_packages ADD COLUMN registry TEXT;
+
+UPDATE meta SET value = '3' WHERE key = 'schema_version';
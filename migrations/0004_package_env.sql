@@ -0,0 +1,3 @@
+ALTER TABLE known_packages ADD COLUMN env TEXT NOT NULL DEFAULT '{}';
+
+UPDATE meta SET value = '4' WHERE key = 'schema_version';
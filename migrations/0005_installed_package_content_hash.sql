@@ -0,0 +1,4 @@
+ALTER TABLE installed_packages ADD COLUMN content_hash TEXT;
+CREATE INDEX IF NOT EXISTS installed_packages_content_hash ON installed_packages (content_hash);
+
+UPDATE meta SET value = '5' WHERE key = 'schema_version';
@@ -0,0 +1,3 @@
+ALTER TABLE known_packages ADD COLUMN provides TEXT NOT NULL DEFAULT '[]';
+
+UPDATE meta SET value = '6' WHERE key = 'schema_version';
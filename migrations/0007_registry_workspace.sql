@@ -0,0 +1,3 @@
+ALTER TABLE registries ADD COLUMN workspace TEXT REFERENCES workspaces (name) ON DELETE RESTRICT;
+
+UPDATE meta SET value = '7' WHERE key = 'schema_version';
@@ -0,0 +1,4 @@
+ALTER TABLE known_packages ADD COLUMN post_install TEXT;
+ALTER TABLE known_packages ADD COLUMN pre_remove TEXT;
+
+UPDATE meta SET value = '8' WHERE key = 'schema_version';
@@ -0,0 +1,3 @@
+ALTER TABLE installed_packages ADD COLUMN registry TEXT;
+
+UPDATE meta SET value = '9' WHERE key = 'schema_version';
@@ -0,0 +1,3 @@
+ALTER TABLE known_packages ADD COLUMN artifacts TEXT NOT NULL DEFAULT '[]';
+
+UPDATE meta SET value = '10' WHERE key = 'schema_version';
@@ -0,0 +1,3 @@
+ALTER TABLE known_packages ADD COLUMN platforms TEXT NOT NULL DEFAULT '[]';
+
+UPDATE meta SET value = '11' WHERE key = 'schema_version';
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS package_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    workspace TEXT NOT NULL,
+    name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    action TEXT NOT NULL,
+    timestamp DATETIME NOT NULL,
+
+    FOREIGN KEY (workspace) REFERENCES workspaces (name) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS package_events_workspace ON package_events (workspace);
+
+UPDATE meta SET value = '12' WHERE key = 'schema_version';
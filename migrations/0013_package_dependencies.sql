@@ -0,0 +1,3 @@
+ALTER TABLE known_packages ADD COLUMN dependencies TEXT NOT NULL DEFAULT '[]';
+
+UPDATE meta SET value = '13' WHERE key = 'schema_version';
@@ -0,0 +1,4 @@
+ALTER TABLE registries ADD COLUMN etag TEXT;
+ALTER TABLE registries ADD COLUMN last_modified TEXT;
+
+UPDATE meta SET value = '14' WHERE key = 'schema_version';
@@ -0,0 +1,3 @@
+ALTER TABLE workspaces ADD COLUMN parent TEXT REFERENCES workspaces (name) ON DELETE SET NULL;
+
+UPDATE meta SET value = '15' WHERE key = 'schema_version';
@@ -0,0 +1,3 @@
+ALTER TABLE registries ADD COLUMN public_key TEXT;
+
+UPDATE meta SET value = '16' WHERE key = 'schema_version';
@@ -0,0 +1,3 @@
+ALTER TABLE known_packages ADD COLUMN build_dependencies TEXT NOT NULL DEFAULT '[]';
+
+UPDATE meta SET value = '17' WHERE key = 'schema_version';
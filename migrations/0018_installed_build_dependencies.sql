@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS installed_build_dependencies (
+    name TEXT NOT NULL,
+    version TEXT NOT NULL,
+    dependency_name TEXT NOT NULL,
+    dependency_version TEXT NOT NULL,
+
+    PRIMARY KEY (name, version, dependency_name, dependency_version),
+    FOREIGN KEY (name, version) REFERENCES installed_packages (name, version) ON DELETE CASCADE,
+    FOREIGN KEY (dependency_name, dependency_version)
+        REFERENCES installed_packages (name, version) ON DELETE CASCADE
+);
+
+UPDATE meta SET value = '18' WHERE key = 'schema_version';
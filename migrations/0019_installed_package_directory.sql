@@ -0,0 +1,3 @@
+ALTER TABLE installed_packages ADD COLUMN directory TEXT;
+
+UPDATE meta SET value = '19' WHERE key = 'schema_version';
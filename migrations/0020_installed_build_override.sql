@@ -0,0 +1,3 @@
+ALTER TABLE installed_packages ADD COLUMN build_override TEXT;
+
+UPDATE meta SET value = '20' WHERE key = 'schema_version';
@@ -0,0 +1,3 @@
+ALTER TABLE workspace_packages ADD COLUMN link_mode TEXT NOT NULL DEFAULT 'symlink';
+
+UPDATE meta SET value = '21' WHERE key = 'schema_version';
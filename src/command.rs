@@ -2,104 +2,686 @@
 //!
 //! Anything public in this module is exposed as a command-line subcommand.
 
-use std::env::var;
+use std::{cmp::Ordering, collections::HashSet, env::var, path::Path, str::FromStr, sync::Arc};
 
 use color_eyre::eyre::{anyhow, Context, Result};
+use futures_util::future::{FutureExt, LocalBoxFuture};
 use indicatif::MultiProgress;
-use tokio::task::JoinSet;
+use serde::Serialize;
+use time::OffsetDateTime;
+use tokio::{
+    fs::{read_to_string, remove_file},
+    pin, select,
+    signal::ctrl_c,
+    sync::Semaphore,
+    task::JoinSet,
+};
 use tracing::instrument;
 
 use crate::{
-    manifest::InstallLog,
-    package::{KnownPackage, PackageChangeSet, PackageRequest, WorkspacePackage},
-    registry::{Fetcher, Registry},
+    config::{Config, ConfigRegistry},
+    download::{CacheValidators, DefaultDownloader},
+    error::{BuildFailed, FetchFailed},
+    manifest::{package_binaries, InstallLog, InstallOptions, Manifest, Package},
+    package::{
+        content_store_directory, InstalledPackage, KnownPackage, PackageAction, PackageChangeSet,
+        PackageRequest, PackageRootLock, VersionCache, VersionSpec, WorkspacePackage,
+    },
+    registry::{parse_public_key, Fetcher, Registry, Uri},
     state::State,
-    util::{create_spinner, is_file_system_safe},
+    util::{
+        create_spinner, glob_matches, is_glob, normalize_package_name, remove_read_only_dir,
+        validate_workspace_name, LinkMode, OutputFormat, ShellKind, SortKey,
+    },
+    version::compare_versions,
     workspace::Workspace,
 };
 
+/// Workspace name that selects all workspaces, for commands that support it.
+pub const ALL_WORKSPACES: &str = "all";
+
+/// Reads package requests from a file, one per line.
+///
+/// Blank lines and everything after a `#` are ignored. Every request is validated, so that a
+/// typo is reported with its line number rather than once installation is under way.
+#[instrument]
+pub async fn read_package_list(path: &Path) -> Result<Vec<String>> {
+    let contents = read_to_string(path)
+        .await
+        .wrap_err_with(|| format!("failed to read package list {}", path.display()))?;
+
+    let mut pkgs = vec![];
+    for (idx, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        line.parse::<PackageRequest>().wrap_err_with(|| {
+            format!(
+                "invalid package request on line {} of {}",
+                idx + 1,
+                path.display()
+            )
+        })?;
+        pkgs.push(line.to_string());
+    }
+
+    Ok(pkgs)
+}
+
 /// Installs a package.
+///
+/// If a registry is given, packages are only resolved from that registry, unless a request names
+/// a registry itself.
+///
+/// Requests for packages already in the workspace replace them with the requested version, but
+/// replacing a package with an older version requires `allow_downgrade`.
 #[instrument(skip(state))]
-pub async fn install_packages(state: &State, pkgs: &[String], workspace_name: &str) -> Result<()> {
+pub async fn install_packages(
+    state: &State,
+    pkgs: &[String],
+    workspace_name: &str,
+    registry: Option<&str>,
+    options: &InstallOptions,
+) -> Result<()> {
+    if options.build_override.is_some() {
+        if pkgs.len() != 1 {
+            return Err(anyhow!(
+                "a build override can only be used when installing a single package"
+            ));
+        }
+        status!(
+            "Warning: building {} with an overridden build command, the result won't match the registry",
+            pkgs[0]
+        );
+    }
+
+    let registry = configured_registry_uri(state, registry).await?;
     let pkg_reqs: Vec<PackageRequest> = pkgs
         .iter()
         .map(|pkg| pkg.parse::<PackageRequest>())
+        .map(|req| {
+            req.map(|mut req| {
+                req.registry = req.registry.or_else(|| registry.clone());
+                req
+            })
+        })
         .collect::<Result<Vec<_>>>()?;
 
     let workspace = get_create_workspace(state, workspace_name).await?;
+    let _lock = PackageRootLock::shared().await?;
+
+    if options.no_build {
+        return prefetch_packages(state, &pkg_reqs, &workspace).await;
+    }
 
     let workspace_packages = state.workspace_packages(&workspace).await?;
     let changeset = PackageChangeSet::add_packages(&pkg_reqs, &workspace_packages)?;
+    let skipped = skipped_requests(&pkg_reqs, &changeset);
+
+    // Requests resolved for the downgrade check are resolved again when installing.
+    let versions = VersionCache::default();
+
+    // Check version changes up front, so that nothing is touched if a downgrade is refused.
+    let mut changes = vec![];
+    for request in changeset.changed_packages() {
+        let existing = workspace_packages
+            .iter()
+            .find(|p| p.name == request.name)
+            .expect("changed package is not in the workspace")
+            .clone();
+        let pkg_spec = request
+            .resolve_known_version_cached(state, Some(&workspace), &versions)
+            .await
+            .wrap_err("failed to resolve package version")?;
+        if compare_versions(&pkg_spec.version, &existing.version) == Ordering::Less {
+            let message = format!(
+                "downgrading {} from {} to {}",
+                request.name, existing.version, pkg_spec.version
+            );
+            if !options.allow_downgrade {
+                return Err(anyhow!("{message}, pass --allow-downgrade to proceed"));
+            }
+            status!("Warning: {message}");
+        }
+        changes.push((request, existing));
+    }
+
+    if options.serial {
+        let mut requests = changes
+            .into_iter()
+            .map(|(pkg, existing)| (pkg, Some(existing)))
+            .chain(changeset.added_packages().map(|pkg| (pkg, None)))
+            .collect::<Vec<_>>();
+        requests.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name));
+        let mpb = MultiProgress::new();
+        // Boxed, as the install futures are too deeply nested to be inlined here.
+        let installs = requests
+            .iter()
+            .map(|(request, existing)| match existing {
+                Some(existing) => change_package(
+                    state, request, existing, &workspace, &mpb, options, &versions,
+                )
+                .boxed_local(),
+                None => install_package(state, request, &workspace, &mpb, options, &versions)
+                    .boxed_local(),
+            })
+            .collect();
+        let logs = install_serially(installs).await?;
+        let installed = logs.iter().filter(|log| log.is_success()).count();
+        print_install_summary(installed, skipped, &workspace);
+        check_path_for_workspace(&workspace);
+        return check_install_logs(&logs);
+    }
 
     let mut set = JoinSet::new();
     let mpb = MultiProgress::new();
 
-    for pkg in changeset.added_packages() {
+    for (pkg, existing) in changes {
         let state = state.clone();
         let workspace = workspace.clone();
         let mpb = mpb.clone();
-        set.spawn(async move { install_package(&state, &pkg, &workspace, &mpb).await });
+        let options = options.clone();
+        let versions = versions.clone();
+        set.spawn(async move {
+            change_package(
+                &state, &pkg, &existing, &workspace, &mpb, &options, &versions,
+            )
+            .await
+        });
     }
 
-    // TODO: Also apply changed packages.
-
-    let mut results = vec![];
-    while let Some(result) = set.join_next().await {
-        results.push(result?);
+    for pkg in changeset.added_packages() {
+        let state = state.clone();
+        let workspace = workspace.clone();
+        let mpb = mpb.clone();
+        let options = options.clone();
+        let versions = versions.clone();
+        set.spawn(async move {
+            install_package(&state, &pkg, &workspace, &mpb, &options, &versions).await
+        });
     }
+
+    let results = join_all_or_interrupt(&mut set).await?;
     let logs = results.into_iter().collect::<Result<Vec<InstallLog>>>()?;
-    for log in logs {
-        if log.is_success() {
-            // println!("Installed {}", log.package_name);
-        } else {
-            println!(
-                "Failed to install {}, build exited with code {}\nSTDOUT:\n{}STDERR:\n{}",
-                log.package_name, log.exit_code, log.stdout, log.stderr
-            );
+    let installed = logs.iter().filter(|log| log.is_success()).count();
+    let result = report_install_logs(logs);
+    print_install_summary(installed, skipped, &workspace);
+
+    check_path_for_workspace(&workspace);
+
+    result
+}
+
+/// Runs installs one after the other, in the given order, printing the full build output of each
+/// before moving on to the next.
+///
+/// If interrupted with Ctrl-C, drops the running install, which cleans up after it, and returns an
+/// error, like [`join_all_or_interrupt`].
+async fn install_serially(
+    installs: Vec<LocalBoxFuture<'_, Result<InstallLog>>>,
+) -> Result<Vec<InstallLog>> {
+    let run = async {
+        let mut logs = vec![];
+        for install in installs {
+            let log = install.await?;
+            if log.is_success() {
+                print_build_output(&log);
+            }
+            print_install_log(&log);
+            logs.push(log);
         }
+        Ok(logs)
+    };
+    select! {
+        logs = run => logs,
+        _ = ctrl_c() => Err(anyhow!("interrupted, partial installs have been cleaned up")),
     }
+}
 
-    check_path_for_workspace(&workspace);
+/// Downloads the sources of the requested packages, so that installing them later doesn't need to.
+///
+/// Packages that are already installed are skipped, as they won't be built again.
+#[instrument(skip(state))]
+async fn prefetch_packages(
+    state: &State,
+    requests: &[PackageRequest],
+    workspace: &Workspace,
+) -> Result<()> {
+    let mut set = JoinSet::new();
+    let mpb = MultiProgress::new();
+    let versions = VersionCache::default();
+
+    for request in requests {
+        let pkg_spec = request
+            .resolve_known_version_cached(state, Some(workspace), &versions)
+            .await
+            .wrap_err("failed to resolve package version")?;
+        if state.get_installed_package(&pkg_spec).await?.is_some() {
+            status!("{pkg_spec} is already installed");
+            continue;
+        }
+        let pkg = state
+            .get_known_package(&pkg_spec)
+            .await?
+            .expect("package not found");
+        let mpb = mpb.clone();
+        set.spawn(async move {
+            let spinner = create_spinner(&format!("{pkg}: Downloading..."), Some(&mpb));
+            let result = pkg
+                .prefetch_source(&DefaultDownloader::default(), &spinner)
+                .await;
+            spinner.finish_and_clear();
+            result.map(|source| (pkg, source))
+        });
+    }
 
+    let results = join_all_or_interrupt(&mut set).await?;
+    for (pkg, source) in results.into_iter().collect::<Result<Vec<_>>>()? {
+        match source {
+            Some(source) => status!("Fetched source of {pkg} to {}", source.display()),
+            None => status!("{pkg} has no source to fetch"),
+        }
+    }
     Ok(())
 }
 
+/// Returns how many requests are already satisfied by the workspace, and so are not installed.
+fn skipped_requests(requests: &[PackageRequest], changeset: &PackageChangeSet) -> usize {
+    requests
+        .iter()
+        .filter(|request| {
+            !changeset
+                .added_packages()
+                .chain(changeset.changed_packages())
+                .any(|pkg| pkg.name == request.name)
+        })
+        .count()
+}
+
+/// Prints how many packages were installed into a workspace to stderr, keeping stdout for logs.
+fn print_install_summary(installed: usize, skipped: usize, workspace: &Workspace) {
+    let mut summary = format!("Installed {installed} package(s) into workspace '{workspace}'");
+    if skipped > 0 {
+        summary.push_str(&format!(" ({skipped} skipped, already present)"));
+    }
+    status!("{summary}");
+}
+
+/// Prints the outcome of installs, including the build output of failed ones.
+///
+/// The logs are printed ordered by package, regardless of the order the installs finished in.
+///
+/// Returns an error if any of the builds failed.
+fn report_install_logs(logs: impl IntoIterator<Item = InstallLog>) -> Result<()> {
+    let mut logs = logs.into_iter().collect::<Vec<_>>();
+    logs.sort_by(|a, b| a.package_name.cmp(&b.package_name));
+
+    for log in &logs {
+        print_install_log(log);
+    }
+    check_install_logs(&logs)
+}
+
+/// Returns an error listing the failed builds, if any of the installs failed.
+fn check_install_logs(logs: &[InstallLog]) -> Result<()> {
+    let failed = logs
+        .iter()
+        .filter(|log| !log.is_success())
+        .map(|log| log.package_name.clone())
+        .collect::<Vec<_>>();
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(BuildFailed(failed).into())
+    }
+}
+
+/// Prints the outcome of an install, including the build output if it failed.
+fn print_install_log(log: &InstallLog) {
+    if log.is_success() {
+        println!(
+            "Installed {} in {:.1}s",
+            log.package_name,
+            log.duration.as_secs_f64()
+        );
+    } else {
+        println!(
+            "Failed to install {}, build exited with code {} after {:.1}s",
+            log.package_name,
+            log.exit_code,
+            log.duration.as_secs_f64(),
+        );
+        print_build_output(log);
+        if let Some(build_dir) = &log.kept_build_dir {
+            println!("Kept build directory at {}", build_dir.display());
+        }
+    }
+}
+
+/// Prints the captured output of a build.
+fn print_build_output(log: &InstallLog) {
+    if log.merged_output {
+        println!("OUTPUT:\n{}", log.stdout);
+    } else {
+        println!("STDOUT:\n{}STDERR:\n{}", log.stdout, log.stderr);
+    }
+}
+
 /// Installs a package in the given workspace.
-#[instrument(skip(state))]
+///
+/// If a build override is given, it replaces the package's build command.
+#[instrument(skip(state, versions))]
 async fn install_package(
     state: &State,
     request: &PackageRequest,
     workspace: &Workspace,
     mpb: &MultiProgress,
+    options: &InstallOptions,
+    versions: &VersionCache,
 ) -> Result<InstallLog> {
+    let pkg = resolve_package(state, request, workspace, mpb, options, versions).await?;
+    install_resolved_package(state, request, &pkg, workspace, mpb, options).await
+}
+
+/// Resolves the package a request refers to, applying any build override.
+async fn resolve_package(
+    state: &State,
+    request: &PackageRequest,
+    workspace: &Workspace,
+    mpb: &MultiProgress,
+    options: &InstallOptions,
+    versions: &VersionCache,
+) -> Result<Package> {
     let pkg_spec: KnownPackage = request
-        .resolve_known_version(state)
+        .resolve_known_version_cached(state, Some(workspace), versions)
         .await
         .wrap_err("failed to resolve package version")?;
+    mpb.suspend(|| status!("Resolving {request} -> {pkg_spec}"));
 
-    let pkg = state
+    let mut pkg = state
         .get_known_package(&pkg_spec)
         .await?
         .expect("package not found");
-    let log = pkg.install(state, workspace, mpb).await?;
+    if let Some(build) = &options.build_override {
+        // Installed packages are reused as is, so the override would silently do nothing.
+        if state.get_installed_package(&pkg_spec).await?.is_some() {
+            return Err(anyhow!(
+                "package {} is already installed, remove it and garbage collect to rebuild it",
+                pkg_spec
+            ));
+        }
+        pkg.build = Some(build.to_string());
+    }
+
+    Ok(pkg)
+}
+
+/// Replaces a package in the given workspace with the version a request resolves to.
+///
+/// The new version is installed first, replacing the binaries of the old one, which is only
+/// removed once that succeeded. If the new version fails to build, the old one stays in place.
+#[instrument(skip(state, versions))]
+async fn change_package(
+    state: &State,
+    request: &PackageRequest,
+    existing: &WorkspacePackage,
+    workspace: &Workspace,
+    mpb: &MultiProgress,
+    options: &InstallOptions,
+    versions: &VersionCache,
+) -> Result<InstallLog> {
+    let pkg = resolve_package(state, request, workspace, mpb, options, versions).await?;
+    change_resolved_package(state, request, &pkg, existing, workspace, mpb, options).await
+}
+
+/// Installs a package resolved from a request in the given workspace, in place of the existing
+/// version of it.
+async fn change_resolved_package(
+    state: &State,
+    request: &PackageRequest,
+    pkg: &Package,
+    existing: &WorkspacePackage,
+    workspace: &Workspace,
+    mpb: &MultiProgress,
+    options: &InstallOptions,
+) -> Result<InstallLog> {
+    let log = pkg.install(state, workspace, mpb, options).await?;
+    if !log.is_success() {
+        return Ok(log);
+    }
+
+    let pre_remove = pre_remove_hook(state, existing).await?;
+    workspace
+        .remove_package(
+            &existing.installed_package(state).await?,
+            pre_remove.as_deref(),
+        )
+        .await
+        .wrap_err("failed to remove package from workspace")?;
+    state
+        .remove_workspace_package(existing, workspace)
+        .await
+        .wrap_err("failed to deregister installed package")?;
+    state
+        .add_package_event(existing, workspace, PackageAction::Remove)
+        .await?;
+    register_workspace_package(state, request, pkg, workspace, options.link_mode).await?;
+
+    Ok(log)
+}
+
+/// Installs a package resolved from a request in the given workspace, and registers it there.
+async fn install_resolved_package(
+    state: &State,
+    request: &PackageRequest,
+    pkg: &Package,
+    workspace: &Workspace,
+    mpb: &MultiProgress,
+    options: &InstallOptions,
+) -> Result<InstallLog> {
+    let log = pkg.install(state, workspace, mpb, options).await?;
 
     if log.is_success() {
-        if log.new_install {
-            state.add_installed_package(&pkg_spec).await?;
-        }
-        let workspace_package = WorkspacePackage::from_request(request, &pkg.version);
-        state
-            .add_workspace_package(&workspace_package, workspace)
-            .await
-            .wrap_err("failed to register installed package")?;
+        register_workspace_package(state, request, pkg, workspace, options.link_mode).await?;
     }
 
     Ok(log)
 }
 
+/// Registers a package installed for a request in the given workspace, along with how its
+/// binaries were placed, so that later installs of it place them the same way.
+async fn register_workspace_package(
+    state: &State,
+    request: &PackageRequest,
+    pkg: &Package,
+    workspace: &Workspace,
+    link_mode: LinkMode,
+) -> Result<()> {
+    let mut workspace_package = WorkspacePackage::from_request(request, &pkg.version);
+    // The request might have been for a virtual package that this one provides.
+    workspace_package.name = pkg.name.clone();
+    workspace_package.registry = pkg.registry.clone();
+    workspace_package.link_mode = link_mode;
+    state
+        .add_workspace_package(&workspace_package, workspace)
+        .await
+        .wrap_err("failed to register installed package")?;
+    state
+        .add_package_event(&workspace_package, workspace, PackageAction::Install)
+        .await
+}
+
+/// Installs packages from a local manifest file, without adding it as a registry.
+///
+/// The packages are recorded as coming from the manifest file, so their package directories are
+/// namespaced by it, and they never stand in for a registry's package of the same name and version.
+///
+/// Like with [`install_packages`], requests for packages already in the workspace replace them,
+/// but replacing a package with an older version requires `allow_downgrade`.
+#[instrument(skip(state))]
+pub async fn install_packages_from_manifest(
+    state: &State,
+    manifest_path: &Path,
+    pkgs: &[String],
+    workspace_name: &str,
+    options: &InstallOptions,
+) -> Result<()> {
+    let mut manifest: Manifest = read_to_string(manifest_path)
+        .await
+        .wrap_err(format!(
+            "failed to read manifest at {}",
+            manifest_path.display()
+        ))?
+        .parse()
+        .wrap_err(format!(
+            "failed to parse manifest at {}",
+            manifest_path.display()
+        ))?;
+    manifest.validate()?;
+    let uri = Uri::from(manifest_path.to_string_lossy().as_ref()).to_string();
+    manifest.set_registry_uri(&uri);
+
+    let pkg_reqs: Vec<PackageRequest> = pkgs
+        .iter()
+        .map(|pkg| pkg.parse::<PackageRequest>())
+        .collect::<Result<Vec<_>>>()?;
+
+    let workspace = get_create_workspace(state, workspace_name).await?;
+    let _lock = PackageRootLock::shared().await?;
+
+    let workspace_packages = state.workspace_packages(&workspace).await?;
+    let changeset = PackageChangeSet::add_packages(&pkg_reqs, &workspace_packages)?;
+    let skipped = skipped_requests(&pkg_reqs, &changeset);
+
+    // Resolve everything up front, so that nothing gets installed if a package is missing, and
+    // nothing is touched if a downgrade is refused.
+    let mut resolved = changeset
+        .added_packages()
+        .map(|request| {
+            let pkg = manifest.find_package(&request)?.clone();
+            Ok((request, pkg, None))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for request in changeset.changed_packages() {
+        let existing = workspace_packages
+            .iter()
+            .find(|p| p.name == request.name)
+            .expect("changed package is not in the workspace")
+            .clone();
+        let pkg = manifest.find_package(&request)?.clone();
+        if compare_versions(&pkg.version, &existing.version) == Ordering::Less {
+            let message = format!(
+                "downgrading {} from {} to {}",
+                request.name, existing.version, pkg.version
+            );
+            if !options.allow_downgrade {
+                return Err(anyhow!("{message}, pass --allow-downgrade to proceed"));
+            }
+            status!("Warning: {message}");
+        }
+        resolved.push((request, pkg, Some(existing)));
+    }
+
+    let mpb = MultiProgress::new();
+    if options.serial {
+        resolved.sort_by(|(a, _, _), (b, _, _)| a.name.cmp(&b.name));
+        let installs = resolved
+            .iter()
+            .map(|(request, pkg, existing)| match existing {
+                Some(existing) => change_resolved_package(
+                    state, request, pkg, existing, &workspace, &mpb, options,
+                )
+                .boxed_local(),
+                None => install_resolved_package(state, request, pkg, &workspace, &mpb, options)
+                    .boxed_local(),
+            })
+            .collect();
+        let logs = install_serially(installs).await?;
+        let installed = logs.iter().filter(|log| log.is_success()).count();
+        print_install_summary(installed, skipped, &workspace);
+        check_path_for_workspace(&workspace);
+        return check_install_logs(&logs);
+    }
+
+    let mut set = JoinSet::new();
+
+    for (request, pkg, existing) in resolved {
+        let state = state.clone();
+        let workspace = workspace.clone();
+        let mpb = mpb.clone();
+        let options = options.clone();
+        set.spawn(async move {
+            match existing {
+                Some(existing) => {
+                    change_resolved_package(
+                        &state, &request, &pkg, &existing, &workspace, &mpb, &options,
+                    )
+                    .await
+                }
+                None => {
+                    install_resolved_package(&state, &request, &pkg, &workspace, &mpb, &options)
+                        .await
+                }
+            }
+        });
+    }
+
+    let results = join_all_or_interrupt(&mut set).await?;
+    let logs = results.into_iter().collect::<Result<Vec<InstallLog>>>()?;
+    let installed = logs.iter().filter(|log| log.is_success()).count();
+    let result = report_install_logs(logs);
+    print_install_summary(installed, skipped, &workspace);
+
+    check_path_for_workspace(&workspace);
+
+    result
+}
+
 /// Updates the given packages.
+///
+/// If the workspace is [`ALL_WORKSPACES`], updates every workspace independently, and reports
+/// failures only after all workspaces have been attempted.
+///
+/// With `changelog`, also prints the version transition of every package that was updated.
 #[instrument(skip(state))]
-pub async fn update_packages(state: &State, pkgs: &[String], workspace_name: &str) -> Result<()> {
+pub async fn update_packages(
+    state: &State,
+    pkgs: &[String],
+    workspace_name: &str,
+    changelog: bool,
+) -> Result<()> {
+    if workspace_name != ALL_WORKSPACES {
+        return update_workspace_packages(state, pkgs, workspace_name, changelog).await;
+    }
+
+    let mut failures = vec![];
+    for workspace in state.workspaces().await? {
+        println!("{}:", workspace);
+        if let Err(e) = update_workspace_packages(state, pkgs, &workspace.name, changelog).await {
+            failures.push(format!("{workspace}: {e:#}"));
+        }
+    }
+
+    if !failures.is_empty() {
+        return Err(anyhow!(
+            "failed to update some workspaces:\n{}",
+            failures.join("\n")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Updates the given packages in a single workspace.
+#[instrument(skip(state))]
+async fn update_workspace_packages(
+    state: &State,
+    pkgs: &[String],
+    workspace_name: &str,
+    changelog: bool,
+) -> Result<()> {
     let workspace = get_create_workspace(state, workspace_name).await?;
 
     let pkgs = if pkgs.is_empty() {
@@ -120,6 +702,7 @@ pub async fn update_packages(state: &State, pkgs: &[String], workspace_name: &st
 
     let workspace_packages = state.workspace_packages(&workspace).await?;
     let changeset = PackageChangeSet::update_packages(&pkg_reqs, &workspace_packages)?;
+    let _lock = PackageRootLock::shared().await?;
 
     let mut set = JoinSet::new();
     let mpb = MultiProgress::new();
@@ -131,76 +714,238 @@ pub async fn update_packages(state: &State, pkgs: &[String], workspace_name: &st
         set.spawn(async move { update_package(&state, &pkg, &workspace, &mpb).await });
     }
 
-    let mut results = vec![];
-    while let Some(result) = set.join_next().await {
-        results.push(result?);
-    }
-    let logs = results
+    let results = join_all_or_interrupt(&mut set).await?;
+    let mut updates = results
         .into_iter()
-        .collect::<Result<Vec<Option<InstallLog>>>>()?;
-    for log in logs {
-        let Some(log) = log else {
-            continue;
-        };
-        if log.is_success() {
-            println!("Installed {}", log.package_name);
-        } else {
-            println!(
-                "Failed to install {}, build exited with code {}\nSTDOUT:\n{}STDERR:\n{}",
-                log.package_name, log.exit_code, log.stdout, log.stderr
-            );
-        }
+        .collect::<Result<Vec<Option<PackageUpdate>>>>()?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    updates.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let succeeded = updates
+        .iter()
+        .filter(|update| update.log.is_success())
+        .map(|update| (update.name.clone(), update.changelog_entry()))
+        .collect::<Vec<_>>();
+    let result = report_install_logs(updates.into_iter().map(|update| update.log));
+    if changelog {
+        print_changelog(&succeeded);
     }
+    result
+}
 
-    Ok(())
+/// The outcome of updating a single package from one version to another.
+struct PackageUpdate {
+    name: String,
+    old_version: String,
+    new_version: String,
+    log: InstallLog,
+}
+
+impl PackageUpdate {
+    /// Returns the version transition, such as `1.0.0 -> 1.1.0`.
+    fn changelog_entry(&self) -> String {
+        format!("{} -> {}", self.old_version, self.new_version)
+    }
+}
+
+/// Prints a table of package names and their version transitions, aligned on the versions.
+fn print_changelog(entries: &[(String, String)]) {
+    if entries.is_empty() {
+        println!("No packages changed");
+        return;
+    }
+    let width = entries
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0)
+        + 1;
+    for (name, entry) in entries {
+        println!("{:width$} {entry}", format!("{name}:"));
+    }
 }
 
 /// Updates a package.
+///
+/// Without a version in the request, updates to the latest version matching the recorded request.
+/// With one, the package is resolved against it instead, which also replaces the recorded request.
 #[instrument(skip(state))]
 async fn update_package(
     state: &State,
     pkg: &PackageRequest,
     workspace: &Workspace,
     mpb: &MultiProgress,
-) -> Result<Option<InstallLog>> {
-    let existing_pkg = pkg
-        .resolve_workspace_version(state, workspace)
-        .await
-        .wrap_err("failed to resolve package version")?;
+) -> Result<Option<PackageUpdate>> {
+    let Some(existing_pkg) = state.get_workspace_package(&pkg.name, workspace).await? else {
+        return Err(anyhow!("package {} is not installed", pkg));
+    };
+    let spec_changed =
+        pkg.version != VersionSpec::Any && pkg.version != existing_pkg.requested_version;
 
-    if let Some(new_pkg) = existing_pkg.available_update(state).await? {
-        // Install the new version
-        let log = state
-            .get_known_package(&new_pkg)
-            .await?
-            .expect("package not found")
-            .install(state, workspace, mpb)
-            .await?;
-        // Remove the old one
-        existing_pkg.remove(workspace).await?;
-        state
-            .remove_workspace_package(&existing_pkg, workspace)
-            .await
-            .wrap_err("failed to deregister installed package")?;
-        Ok(Some(log))
+    let new_pkg = if spec_changed {
+        Some(
+            pkg.resolve_known_version(state, Some(workspace))
+                .await
+                .wrap_err("failed to resolve package version")?,
+        )
+    } else {
+        existing_pkg.available_update(state, workspace).await?
+    };
+    let requested_version = if spec_changed {
+        pkg.version.clone()
     } else {
-        Ok(None)
+        existing_pkg.requested_version.clone()
+    };
+
+    let Some(new_pkg) = new_pkg.filter(|new_pkg| new_pkg.version != existing_pkg.version) else {
+        if spec_changed {
+            state
+                .set_requested_version(&existing_pkg, workspace, &requested_version)
+                .await?;
+        }
+        return Ok(None);
+    };
+
+    // Install the new version
+    let new_pkg = state
+        .get_known_package(&new_pkg)
+        .await?
+        .expect("package not found");
+    let options = InstallOptions {
+        link_mode: existing_pkg.link_mode,
+        ..Default::default()
+    };
+    let log = new_pkg.install(state, workspace, mpb, &options).await?;
+    let update = PackageUpdate {
+        name: new_pkg.name.clone(),
+        old_version: existing_pkg.version.clone(),
+        new_version: new_pkg.version.clone(),
+        log,
+    };
+    if !update.log.is_success() {
+        // Keep the old version around if the new one failed to build.
+        return Ok(Some(update));
+    }
+    // Remove the old one
+    let pre_remove = pre_remove_hook(state, &existing_pkg).await?;
+    workspace
+        .remove_package(
+            &existing_pkg.installed_package(state).await?,
+            pre_remove.as_deref(),
+        )
+        .await
+        .wrap_err("failed to remove package from workspace")?;
+    state
+        .remove_workspace_package(&existing_pkg, workspace)
+        .await
+        .wrap_err("failed to deregister installed package")?;
+    state
+        .add_workspace_package(
+            &WorkspacePackage {
+                name: new_pkg.name.clone(),
+                version: new_pkg.version.clone(),
+                requested_version,
+                registry: new_pkg.registry.clone(),
+                link_mode: existing_pkg.link_mode,
+            },
+            workspace,
+        )
+        .await
+        .wrap_err("failed to register updated package")?;
+    state
+        .add_package_event(&new_pkg, workspace, PackageAction::Update)
+        .await?;
+    Ok(Some(update))
+}
+
+/// Returns the pre-remove hook of a workspace package, if its registry still knows it.
+async fn pre_remove_hook(state: &State, pkg: &WorkspacePackage) -> Result<Option<String>> {
+    Ok(state
+        .get_known_package(pkg)
+        .await?
+        .and_then(|pkg| pkg.pre_remove))
+}
+
+/// Waits for all tasks in the set to finish, returning their results.
+///
+/// If interrupted with Ctrl-C, aborts all tasks instead, waits for them to unwind, which cleans up
+/// any partial installs, and returns an error.
+async fn join_all_or_interrupt<T: 'static>(set: &mut JoinSet<Result<T>>) -> Result<Vec<Result<T>>> {
+    let interrupt = ctrl_c();
+    pin!(interrupt);
+
+    let mut results = vec![];
+    loop {
+        select! {
+            result = set.join_next() => match result {
+                Some(result) => results.push(result?),
+                None => return Ok(results),
+            },
+            _ = &mut interrupt => {
+                set.shutdown().await;
+                return Err(anyhow!("interrupted, partial installs have been cleaned up"));
+            }
+        }
     }
 }
 
 /// Removes the given packages from the workspace.
+///
+/// Packages can be given as globs, such as `python-*`, which remove all matching packages in the
+/// workspace. A glob that matches nothing is an error.
 #[instrument(skip(state))]
 pub async fn remove_packages(state: &State, pkgs: &[String], workspace_name: &str) -> Result<()> {
     let workspace = get_create_workspace(state, workspace_name).await?;
+    let workspace_packages = state.workspace_packages(&workspace).await?;
 
-    let pkg_reqs: Vec<PackageRequest> = pkgs
+    // Expand globs to the names of the workspace packages they match.
+    let mut expanded = vec![];
+    for pkg in pkgs {
+        if !is_glob(pkg) {
+            expanded.push(pkg.clone());
+            continue;
+        }
+        // Like package names, globs match regardless of case.
+        let pattern = normalize_package_name(pkg);
+        let matches = workspace_packages
+            .iter()
+            .filter(|p| glob_matches(&pattern, &p.name))
+            .map(|p| p.name.clone())
+            .collect::<Vec<_>>();
+        if matches.is_empty() {
+            return Err(anyhow!(
+                "{pkg} does not match any package in workspace {workspace}"
+            ));
+        }
+        expanded.extend(matches);
+    }
+
+    let pkg_reqs: Vec<PackageRequest> = expanded
         .iter()
         .map(|pkg| pkg.parse::<PackageRequest>())
         .collect::<Result<Vec<_>>>()?;
 
-    let workspace_packages = state.workspace_packages(&workspace).await?;
     let changeset = PackageChangeSet::remove_packages(&pkg_reqs, &workspace_packages)?;
 
+    // Removing a package other packages still need is allowed, but likely a mistake.
+    for pkg in changeset.removed_packages() {
+        for dependent in state.dependents_of(&pkg.name, &workspace).await? {
+            if !changeset
+                .removed_packages()
+                .any(|p| p.name == dependent.name)
+            {
+                status!(
+                    "Warning: {}@{} depends on {}, which is being removed",
+                    dependent.name,
+                    dependent.version,
+                    pkg.name
+                );
+            }
+        }
+    }
+
     let mut set = JoinSet::new();
     let mpb = MultiProgress::new();
 
@@ -239,37 +984,212 @@ pub async fn remove_package(
         .await
         .wrap_err("failed to resolve package version")?;
 
+    let pre_remove = pre_remove_hook(state, &pkg_spec).await?;
     workspace
-        .remove_package(&pkg_spec)
+        .remove_package(
+            &pkg_spec.installed_package(state).await?,
+            pre_remove.as_deref(),
+        )
         .await
         .wrap_err("failed to remove package from workspace")?;
     state
         .remove_workspace_package(&pkg_spec, workspace)
         .await
         .wrap_err("failed to deregister installed package")?;
+    state
+        .add_package_event(&pkg_spec, workspace, PackageAction::Remove)
+        .await?;
 
     spinner.finish_with_message(format!("{pkg}: Removed package"));
     Ok(())
 }
 
+/// Copies a package from one workspace to another, reusing its installed files.
+///
+/// The package keeps the version request it has in the source workspace. Copying fails if the
+/// destination workspace already has a different version of the package.
+#[instrument(skip(state))]
+pub async fn copy_package(state: &State, pkg: &str, from: &str, to: &str) -> Result<()> {
+    let from = get_create_workspace(state, from).await?;
+    let to = get_create_workspace(state, to).await?;
+
+    let request: PackageRequest = pkg.parse()?;
+    let resolved = request
+        .resolve_workspace_version(state, &from)
+        .await
+        .wrap_err_with(|| format!("failed to resolve package version in workspace {from}"))?;
+    let workspace_package = state
+        .get_workspace_package(&resolved.name, &from)
+        .await?
+        .expect("resolved package not in workspace");
+
+    if let Some(existing) = state.get_workspace_package(&resolved.name, &to).await? {
+        if existing.version == workspace_package.version {
+            status!(
+                "{}@{} is already in workspace {to}",
+                existing.name,
+                existing.version
+            );
+            return Ok(());
+        }
+        return Err(anyhow!(
+            "workspace {to} already has {}@{}, remove it first",
+            existing.name,
+            existing.version
+        ));
+    }
+
+    // Packages installed from a manifest file aren't known, but don't need to be built again.
+    let pkg = match state.get_known_package(&workspace_package).await? {
+        Some(pkg) => pkg,
+        None => Package {
+            name: workspace_package.name.clone(),
+            version: workspace_package.version.clone(),
+            registry: workspace_package.registry.clone(),
+            ..Default::default()
+        },
+    };
+    let options = InstallOptions {
+        link_mode: workspace_package.link_mode,
+        ..Default::default()
+    };
+    pkg.link_installed(state, &to, &options).await?;
+    state
+        .add_workspace_package(&workspace_package, &to)
+        .await
+        .wrap_err("failed to register copied package")?;
+    state
+        .add_package_event(&workspace_package, &to, PackageAction::Install)
+        .await?;
+
+    status!(
+        "Copied {}@{} from workspace {from} to {to}",
+        workspace_package.name,
+        workspace_package.version
+    );
+    Ok(())
+}
+
+/// Reinstalls the given packages in the workspace, rebuilding them from scratch.
+///
+/// The packages are removed and then installed again with their originally requested versions,
+/// from the same registries, placing their binaries the same way as before. If another workspace
+/// still uses the same package, its files are kept and just linked again.
+#[instrument(skip(state))]
+pub async fn reinstall_packages(
+    state: &State,
+    pkgs: &[String],
+    workspace_name: &str,
+) -> Result<()> {
+    let workspace = get_create_workspace(state, workspace_name).await?;
+
+    let mut reinstalled = vec![];
+    for pkg in pkgs {
+        let workspace_package = pkg
+            .parse::<PackageRequest>()?
+            .resolve_workspace_version(state, &workspace)
+            .await
+            .wrap_err("failed to resolve package version")?;
+        reinstalled.push(workspace_package);
+    }
+
+    remove_packages(state, pkgs, workspace_name).await?;
+
+    // Delete the package files, so that they get rebuilt, unless they are still in use. This
+    // waits for running installs, like garbage collection.
+    {
+        let _lock = PackageRootLock::exclusive().await?;
+        let unused = state
+            .remove_installed_packages_if_unused(&reinstalled)
+            .await?;
+        delete_installed_packages(state, unused)
+            .await
+            .wrap_err("failed to delete package files")?;
+    }
+
+    let _lock = PackageRootLock::shared().await?;
+    let mut set = JoinSet::new();
+    let mpb = MultiProgress::new();
+    let versions = VersionCache::default();
+
+    for workspace_package in reinstalled {
+        let request = PackageRequest {
+            registry: workspace_package.registry.clone(),
+            ..PackageRequest::new(
+                &workspace_package.name,
+                workspace_package.requested_version.clone(),
+            )
+        };
+        let options = InstallOptions {
+            link_mode: workspace_package.link_mode,
+            ..Default::default()
+        };
+        let state = state.clone();
+        let workspace = workspace.clone();
+        let mpb = mpb.clone();
+        let versions = versions.clone();
+        set.spawn(async move {
+            install_package(&state, &request, &workspace, &mpb, &options, &versions).await
+        });
+    }
+
+    let results = join_all_or_interrupt(&mut set).await?;
+    let logs = results.into_iter().collect::<Result<Vec<InstallLog>>>()?;
+    let installed = logs.iter().filter(|log| log.is_success()).count();
+    let result = report_install_logs(logs);
+    print_install_summary(installed, 0, &workspace);
+
+    check_path_for_workspace(&workspace);
+
+    result
+}
+
 /// Garbage collects all installed packages that are not referenced by any workspace.
+///
+/// Pre-remove hooks are not run, as they already ran when the packages were removed from their
+/// last workspace.
+///
+/// Waits for running installs to finish first, so that packages they haven't added to a workspace
+/// yet are kept.
 #[instrument(skip(state))]
 pub async fn garbage_collect_installed_packages(state: &State) -> Result<()> {
     let spinner = create_spinner("Garbage-collecting packages...", None);
+    // Installs register their packages with a workspace only after building them.
+    let _lock = PackageRootLock::exclusive().await?;
 
-    let packages = state.unused_installed_packages().await?;
+    // Packages are removed from the state first, so that they can't be added to a workspace while
+    // their files are being deleted.
+    let packages = state.remove_unused_installed_packages().await?;
     let count = packages.len() as u64;
+    delete_installed_packages(state, packages)
+        .await
+        .wrap_err("failed to garbage collect packages")?;
+
+    spinner.finish_with_message(format!(
+        "Garbage collected {count} package{}",
+        if count == 1 { "" } else { "s" }
+    ));
+
+    Ok(())
+}
+
+/// Deletes the files of installed packages that were already removed from the state, along with
+/// the content store entries no other installed package references.
+async fn delete_installed_packages(state: &State, packages: Vec<InstalledPackage>) -> Result<()> {
+    let mut content_hashes = packages
+        .iter()
+        .filter_map(|p| p.content_hash.clone())
+        .collect::<Vec<_>>();
+    content_hashes.sort();
+    content_hashes.dedup();
     let mut set = JoinSet::new();
 
     for package in packages {
-        let state = state.clone();
         set.spawn(async move {
             package
                 .delete()
                 .await
-                .wrap_err("failed to delete unused package")?;
-            state.remove_installed_package(&package).await?;
-            Ok(())
+                .wrap_err("failed to delete unused package")
         });
     }
 
@@ -278,39 +1198,197 @@ pub async fn garbage_collect_installed_packages(state: &State) -> Result<()> {
         results.push(result?);
     }
 
-    results
-        .into_iter()
-        .collect::<Result<()>>()
-        .wrap_err("failed to garbage collect packages")?;
+    results.into_iter().collect::<Result<()>>()?;
 
-    spinner.finish_with_message(format!(
-        "Garbage collected {count} package{}",
-        if count == 1 { "" } else { "s" }
-    ));
+    for content_hash in content_hashes {
+        remove_unused_store_entry(state, &content_hash).await?;
+    }
+
+    Ok(())
+}
 
+/// Deletes a content store entry, unless an installed package still references it.
+#[instrument(skip(state))]
+async fn remove_unused_store_entry(state: &State, content_hash: &str) -> Result<()> {
+    if state.is_content_hash_referenced(content_hash).await? {
+        return Ok(());
+    }
+    let store_path = content_store_directory().join(content_hash);
+    if store_path.try_exists()? {
+        remove_read_only_dir(&store_path)
+            .wrap_err("failed to delete unused content store entry")?;
+    }
     Ok(())
 }
 
 /// Lists all packages in the workspace.
+///
+/// If the workspace is [`ALL_WORKSPACES`], lists the packages of every workspace, grouped by
+/// workspace.
+///
+/// With `long`, also shows the registry each package came from.
 #[instrument(skip(state))]
-pub async fn list_packages(state: &State, workspace_name: &str) -> Result<()> {
+pub async fn list_packages(state: &State, workspace_name: &str, long: bool) -> Result<()> {
+    if workspace_name == ALL_WORKSPACES {
+        for workspace in state.workspaces().await? {
+            println!("{}:", workspace);
+            for (from, pkg) in state.inherited_workspace_packages(&workspace).await? {
+                println!(
+                    "  {}",
+                    format_workspace_package(&pkg, &workspace, &from, long)
+                );
+            }
+        }
+        return Ok(());
+    }
+
     let workspace = get_create_workspace(state, workspace_name).await?;
-    let packages = state.workspace_packages(&workspace).await?;
+    let packages = state.inherited_workspace_packages(&workspace).await?;
 
-    for pkg in packages {
-        println!("{}", pkg);
+    for (from, pkg) in packages {
+        println!(
+            "{}",
+            format_workspace_package(&pkg, &workspace, &from, long)
+        );
     }
 
     Ok(())
 }
 
-/// Adds a registry.
+/// Prints the packages in a workspace pinned to their installed versions, one per line.
+///
+/// The output can be installed again with `install --from-file`.
+#[instrument(skip(state))]
+pub async fn freeze_packages(state: &State, workspace_name: &str) -> Result<()> {
+    let workspace = get_create_workspace(state, workspace_name).await?;
+    let mut packages = state.workspace_packages(&workspace).await?;
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+    for pkg in &packages {
+        println!("{}", PackageRequest::from(pkg));
+    }
+
+    Ok(())
+}
+
+/// Formats a workspace package for listing, optionally with its registry.
+///
+/// Packages inherited from another workspace are marked with the name of that workspace.
+fn format_workspace_package(
+    pkg: &WorkspacePackage,
+    workspace: &Workspace,
+    from: &str,
+    long: bool,
+) -> String {
+    let formatted = match (&pkg.registry, long) {
+        (Some(registry), true) => format!("{pkg} from {registry}"),
+        (None, true) => format!("{pkg} from unknown registry"),
+        (_, false) => pkg.to_string(),
+    };
+    if from == workspace.name {
+        formatted
+    } else {
+        format!("{formatted} [inherited from {from}]")
+    }
+}
+
+/// Prints the package history of a workspace, newest first.
+#[instrument(skip(state))]
+pub async fn package_history(state: &State, workspace_name: &str) -> Result<()> {
+    let Some(workspace) = state.get_workspace(workspace_name).await? else {
+        return Err(anyhow!("workspace {} does not exist", workspace_name));
+    };
+
+    for event in state.package_events(&workspace).await? {
+        println!("{event}");
+    }
+
+    Ok(())
+}
+
+/// Prints the packages in a workspace that depend on the given package.
+#[instrument(skip(state))]
+pub async fn package_dependents(state: &State, pkg: &str, workspace_name: &str) -> Result<()> {
+    let Some(workspace) = state.get_workspace(workspace_name).await? else {
+        return Err(anyhow!("workspace {} does not exist", workspace_name));
+    };
+    let request = pkg.parse::<PackageRequest>()?;
+
+    let dependents = state.dependents_of(&request.name, &workspace).await?;
+    if dependents.is_empty() {
+        status!(
+            "No packages in workspace {workspace} depend on {}",
+            request.name
+        );
+    }
+    for dependent in dependents {
+        println!("{}@{}", dependent.name, dependent.version);
+    }
+
+    Ok(())
+}
+
+/// Adds a registry, and fetches its packages right away.
+///
+/// If a workspace is given, the registry's packages can only be installed into that workspace.
 #[instrument(skip(state, fetcher))]
-pub async fn add_registry(state: &State, uri: &str, fetcher: &impl Fetcher) -> Result<()> {
-    let mut registry = Registry::new(uri);
+pub async fn add_registry(
+    state: &State,
+    uri: &str,
+    workspace: Option<&str>,
+    public_key: Option<&str>,
+    fetcher: &impl Fetcher,
+) -> Result<()> {
+    if let Some(workspace) = workspace {
+        if state.get_workspace(workspace).await?.is_none() {
+            return Err(anyhow!("workspace {} does not exist", workspace));
+        }
+    }
+
+    let uri = Uri::from_str(uri)
+        .wrap_err("invalid registry URI")?
+        .to_string();
+    if let Some(public_key) = public_key {
+        parse_public_key(public_key)?;
+    }
+    let mut registry = Registry::new(&uri);
+    registry.workspace = workspace.map(str::to_string);
+    registry.public_key = public_key.map(|key| key.trim().to_string());
     registry.initialize(state, fetcher).await?;
+    registry
+        .fetch(state, fetcher)
+        .await
+        .wrap_err("failed to fetch packages from new registry")?;
+
+    let count = state.known_packages_for_registry(&registry).await?.len();
+    status!(
+        "Added registry {} with {count} package{}",
+        registry,
+        if count == 1 { "" } else { "s" }
+    );
+    Ok(())
+}
 
-    eprintln!("Added registry {}", registry);
+/// Adds the registries listed in the config that are missing.
+///
+/// A registry that can't be added is skipped with a warning, as the command at hand might not
+/// need it, and it is tried again the next time.
+#[instrument(skip(state, fetcher))]
+pub async fn ensure_configured_registries(
+    state: &State,
+    config: &Config,
+    fetcher: &impl Fetcher,
+) -> Result<()> {
+    for ConfigRegistry { uri, public_key } in &config.registries {
+        if !state
+            .registry_exists(&Uri::from_str(uri)?.to_string())
+            .await?
+        {
+            if let Err(e) = add_registry(state, uri, None, public_key.as_deref(), fetcher).await {
+                status!("Warning: failed to add registry {uri} from config: {e:#}");
+            }
+        }
+    }
     Ok(())
 }
 
@@ -319,25 +1397,103 @@ pub async fn add_registry(state: &State, uri: &str, fetcher: &impl Fetcher) -> R
 pub async fn remove_registry(state: &State, uri: &str) -> Result<()> {
     state.remove_registry(uri).await?;
 
-    eprintln!("Removed registry {}", uri);
+    status!("Removed registry {}", uri);
+    Ok(())
+}
+
+/// Changes the URI of a registry.
+#[instrument(skip(state))]
+pub async fn set_registry_uri(state: &State, old: &str, new: &str) -> Result<()> {
+    let old = Uri::from_str(old)
+        .wrap_err("invalid registry URI")?
+        .to_string();
+    let new = Uri::from_str(new)
+        .wrap_err("invalid registry URI")?
+        .to_string();
+    state.change_registry_uri(&old, &new).await?;
+
+    status!("Moved registry {} to {}", old, new);
     Ok(())
 }
 
 /// Lists all registries.
+///
+/// With `long`, also shows when each registry was last fetched. As JSON, every registry includes
+/// its URI, name, when it was last fetched, and how many packages it provides.
 #[instrument(skip(state))]
-pub async fn list_registries(state: &State) -> Result<()> {
+pub async fn list_registries(state: &State, long: bool, format: OutputFormat) -> Result<()> {
     let registries = state.registries().await?;
+    let now = OffsetDateTime::now_utc();
+
+    if format == OutputFormat::Json {
+        let mut json = vec![];
+        for registry in &registries {
+            let package_count = state.known_package_count_for_registry(registry).await?;
+            json.push(registry.to_json(package_count));
+        }
+        println!("{}", serde_json::Value::Array(json));
+        return Ok(());
+    }
 
     for registry in registries {
-        println!("{}", registry);
+        if long {
+            println!("{}, {}", registry, registry.last_fetched_description(now));
+        } else {
+            println!("{}", registry);
+        }
     }
 
     Ok(())
 }
 
+/// Fetches a single registry, regardless of when it was last fetched.
+///
+/// Reports how many packages were added to and removed from the registry.
+#[instrument(skip(state, fetcher))]
+pub async fn refresh_registry(state: &State, uri: &str, fetcher: &impl Fetcher) -> Result<()> {
+    let uri = configured_registry_uri(state, Some(uri))
+        .await?
+        .expect("registry URI is given");
+    let mut registry = state
+        .registries()
+        .await?
+        .into_iter()
+        .find(|registry| registry.uri.to_string() == uri)
+        .expect("configured registry not found");
+
+    let spec = |pkg: Package| (pkg.name, pkg.version);
+    let before: HashSet<_> = state
+        .known_packages_for_registry(&registry)
+        .await?
+        .into_iter()
+        .map(spec)
+        .collect();
+    registry.fetch(state, fetcher).await?;
+    let after: HashSet<_> = state
+        .known_packages_for_registry(&registry)
+        .await?
+        .into_iter()
+        .map(spec)
+        .collect();
+
+    status!(
+        "Refreshed registry {}: {} package(s) added, {} removed",
+        registry,
+        after.difference(&before).count(),
+        before.difference(&after).count(),
+    );
+    Ok(())
+}
+
+/// How many registries to fetch at once.
+const MAX_CONCURRENT_FETCHES: usize = 8;
+
 /// Ensures all registries are up to date by potentially refetching them.
 ///
-/// Supply `force` to force a refetch of all registries.
+/// Supply `force` to force a refetch of all registries, even if their manifests seem unchanged.
+///
+/// A registry failing to fetch does not prevent the others from updating, all failures are
+/// reported together at the end.
 #[instrument(skip(state, fetcher))]
 pub async fn fetch_registries(
     state: &State,
@@ -349,53 +1505,146 @@ pub async fn fetch_registries(
     let registries = state.registries().await?;
 
     let mut set = JoinSet::new();
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_FETCHES));
 
     for mut registry in registries {
+        if force {
+            // Without validators, the manifest is fetched even if the server considers it unchanged.
+            registry.validators = CacheValidators::default();
+        }
         if force || registry.should_update() {
             let state = state.clone();
             let fetcher = fetcher.clone();
-            set.spawn(async move { registry.fetch(&state, &fetcher).await });
+            let permits = permits.clone();
+            set.spawn(async move {
+                let _permit = permits.acquire().await.expect("semaphore closed");
+                let result = registry.fetch(&state, &fetcher).await;
+                (registry, result)
+            });
         }
     }
 
-    let mut results = vec![];
+    let mut failures = vec![];
     while let Some(result) = set.join_next().await {
-        results.push(result?);
+        if let (registry, Err(e)) = result? {
+            failures.push(format!("  {registry}: {e:#}"));
+        }
     }
+    failures.sort();
 
-    results
-        .into_iter()
-        .collect::<Result<()>>()
-        .wrap_err("failed to update registries")?;
+    spinner.finish_and_clear();
+    if !failures.is_empty() {
+        return Err(FetchFailed(failures).into());
+    }
+    Ok(())
+}
 
+/// Reports what fetching all registries would change, without changing anything.
+///
+/// Every registry is downloaded, even if it was fetched recently.
+#[instrument(skip(state, fetcher))]
+pub async fn preview_fetch_registries(state: &State, fetcher: &impl Fetcher) -> Result<()> {
+    let spinner = create_spinner("Fetching registries...", None);
+    let mut diffs = vec![];
+    for registry in state.registries().await? {
+        let diff = registry
+            .fetch_dry_run(state, fetcher)
+            .await
+            .wrap_err_with(|| format!("failed to fetch registry {registry}"))?;
+        diffs.push((registry, diff));
+    }
     spinner.finish_and_clear();
+
+    for (registry, diff) in diffs {
+        if diff.is_empty() {
+            println!("{registry}: no changes");
+            continue;
+        }
+        println!(
+            "{registry}: {} package(s) added, {} removed, {} updated",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.updated.len()
+        );
+        for (sign, pkgs) in [("+", diff.added), ("-", diff.removed), ("~", diff.updated)] {
+            for pkg in pkgs {
+                println!("  {sign} {pkg}");
+            }
+        }
+    }
     Ok(())
 }
 
-/// Searches for a package.
+/// Searches for a package, optionally only in the given registry.
+///
+/// Results are ordered by name and then newest version first, unless sorted by another key.
 #[instrument(skip(state))]
-pub async fn search_packages(state: &State, query: &str, all_versions: bool) -> Result<()> {
+pub async fn search_packages(
+    state: &State,
+    query: &str,
+    all_versions: bool,
+    registry: Option<&str>,
+    sort: Option<SortKey>,
+    reverse: bool,
+) -> Result<()> {
+    let registry = configured_registry_uri(state, registry).await?;
     let packages = if all_versions {
-        state.search_known_packages(query).await?
+        state
+            .search_known_packages(query, registry.as_deref())
+            .await?
     } else {
-        state.search_known_packages_latest_only(query).await?
+        state
+            .search_known_packages_latest_only(query, registry.as_deref())
+            .await?
     };
 
-    for pkg in packages {
+    for pkg in sort_packages(packages, sort, reverse) {
+        println!("{}", pkg);
+    }
+
+    Ok(())
+}
+
+/// Searches the packages installed in a workspace, instead of the ones known from registries.
+#[instrument(skip(state))]
+pub async fn search_installed_packages(
+    state: &State,
+    query: &str,
+    workspace_name: &str,
+) -> Result<()> {
+    let workspace = get_create_workspace(state, workspace_name).await?;
+    for pkg in state.search_workspace_packages(&workspace, query).await? {
         println!("{}", pkg);
     }
 
     Ok(())
 }
 
+/// Sorts packages by the given key, keeping the existing order for ties.
+fn sort_packages(mut packages: Vec<Package>, sort: Option<SortKey>, reverse: bool) -> Vec<Package> {
+    match sort {
+        Some(SortKey::Name) => packages.sort_by(|a, b| a.name.cmp(&b.name)),
+        Some(SortKey::Version) => packages.sort_by(|a, b| compare_versions(&a.version, &b.version)),
+        Some(SortKey::Registry) => packages.sort_by(|a, b| a.registry.cmp(&b.registry)),
+        None => {}
+    }
+    if reverse {
+        packages.reverse();
+    }
+    packages
+}
+
 /// Shows information about a package.
 #[instrument(skip(state))]
-pub async fn show_package(state: &State, pkg: &str) -> Result<()> {
+pub async fn show_package(state: &State, pkg: &str, all_versions: bool) -> Result<()> {
     let pkg = pkg
         .parse::<PackageRequest>()
         .wrap_err("failed to parse package request")?;
+    if all_versions {
+        return show_package_versions(state, &pkg).await;
+    }
     let pkg = pkg
-        .resolve_known_version(state)
+        .resolve_known_version(state, None)
         .await
         .wrap_err("failed to resolve known package")?;
     let pkg = state
@@ -406,41 +1655,150 @@ pub async fn show_package(state: &State, pkg: &str) -> Result<()> {
     Ok(())
 }
 
-/// Adds a workspace.
+/// Lists every known version of a package matching a request, newest first, with its registry.
+async fn show_package_versions(state: &State, request: &PackageRequest) -> Result<()> {
+    let versions = state
+        .known_package_versions(&request.name, request.registry.as_deref(), None)
+        .await?;
+    let mut found = false;
+    for version in versions
+        .into_iter()
+        .filter(|version| request.version.matches(version))
+    {
+        let spec = KnownPackage::from_request(request, &version);
+        let Some(pkg) = state.get_known_package(&spec).await? else {
+            continue;
+        };
+        found = true;
+        match &pkg.registry {
+            Some(registry) => println!("{spec} from {registry}"),
+            None => println!("{spec} from unknown registry"),
+        }
+    }
+    if !found {
+        return Err(anyhow!("package {} is not known", request));
+    }
+    Ok(())
+}
+
+/// Prints the names of the binaries an installed package places in a workspace.
+///
+/// If the request matches several installed versions, the newest one is used.
+#[instrument(skip(state))]
+pub async fn package_binaries_of(state: &State, pkg: &str) -> Result<()> {
+    let request = pkg
+        .parse::<PackageRequest>()
+        .wrap_err("failed to parse package request")?;
+    let Some(installed) = state
+        .installed_packages()
+        .await?
+        .into_iter()
+        .filter(|p| p.name == request.name && request.version.matches(&p.version))
+        .max_by(|a, b| compare_versions(&a.version, &b.version))
+    else {
+        return Err(anyhow!("package {} is not installed", request));
+    };
+
+    for (binary, _) in package_binaries(&installed.directory()).await? {
+        println!("{binary}");
+    }
+    Ok(())
+}
+
+/// Shows which package in a workspace provides a binary.
 #[instrument(skip(state))]
-pub async fn add_workspace(state: &State, name: &str) -> Result<()> {
-    if !is_file_system_safe(name) {
-        return Err(anyhow!("workspace names can contain [a-zA-Z0-9._-] only"));
+pub async fn which_binary(state: &State, binary: &str, workspace_name: &str) -> Result<()> {
+    let workspace = get_create_workspace(state, workspace_name).await?;
+    if !workspace.has_binary(binary).await? {
+        return Err(anyhow!("{binary} not found in workspace {workspace}"));
     }
+    let Some(pkg) = workspace.bin_owner(state, binary).await? else {
+        return Err(anyhow!(
+            "{binary} in workspace {workspace} does not belong to any installed package"
+        ));
+    };
+    println!("{binary} -> {}@{} ({workspace})", pkg.name, pkg.version);
+    Ok(())
+}
+
+/// Adds a workspace, optionally inheriting the packages of a parent workspace.
+#[instrument(skip(state))]
+pub async fn add_workspace(state: &State, name: &str, parent: Option<&str>) -> Result<()> {
+    validate_workspace_name(name)?;
 
     if state.get_workspace(name).await?.is_some() {
         return Err(anyhow!("workspace {} already exists", name));
     }
 
-    state.add_workspace(&Workspace::new(name).await?).await?;
+    if let Some(parent) = parent {
+        if state.get_workspace(parent).await?.is_none() {
+            return Err(anyhow!("parent workspace {} does not exist", parent));
+        }
+    }
+
+    let mut workspace = Workspace::new(name).await?;
+    workspace.parent = parent.map(str::to_string);
+    state.add_workspace(&workspace).await?;
     Ok(())
 }
 
 /// Removes a workspace.
+///
+/// Its packages are removed from it first, running their pre-remove hooks.
 #[instrument(skip(state))]
 pub async fn remove_workspace(state: &State, name: &str) -> Result<()> {
     if name == "global" {
         return Err(anyhow!("cannot remove global workspace"));
     }
-    if state.get_workspace(name).await?.is_none() {
+    let Some(workspace) = state.get_workspace(name).await? else {
         return Err(anyhow!("workspace {} does not exist", name));
+    };
+    // Removing them along with the workspace would lose track of where its packages came from.
+    let registries = state
+        .registries()
+        .await?
+        .into_iter()
+        .filter(|registry| registry.workspace.as_deref() == Some(name))
+        .map(|registry| registry.uri.to_string())
+        .collect::<Vec<_>>();
+    if !registries.is_empty() {
+        return Err(anyhow!(
+            "workspace {} has registries restricted to it, remove them first: {}",
+            name,
+            registries.join(", ")
+        ));
+    }
+    for pkg in state.workspace_packages(&workspace).await? {
+        let pre_remove = pre_remove_hook(state, &pkg).await?;
+        workspace
+            .remove_package(&pkg.installed_package(state).await?, pre_remove.as_deref())
+            .await
+            .wrap_err(format!("failed to remove {pkg} from workspace"))?;
     }
     state.remove_workspace(name).await?;
     Ok(())
 }
 
-/// Lists all workspaces.
+/// Lists all workspaces, optionally with the number of packages in each.
 #[instrument(skip(state))]
-pub async fn list_workspaces(state: &State) -> Result<()> {
+pub async fn list_workspaces(state: &State, with_counts: bool) -> Result<()> {
     let workspaces = state.workspaces().await?;
 
     for workspace in workspaces {
-        println!("{}", workspace);
+        let inherits = match &workspace.parent {
+            Some(parent) => format!(" (inherits from {parent})"),
+            None => String::new(),
+        };
+        if with_counts {
+            let count = state.workspace_package_count(&workspace).await?;
+            println!(
+                "{} ({count} package{}){inherits}",
+                workspace,
+                if count == 1 { "" } else { "s" }
+            );
+        } else {
+            println!("{}{inherits}", workspace);
+        }
     }
 
     Ok(())
@@ -455,9 +1813,9 @@ pub async fn workspace_shell(state: &State, workspace_name: &str) -> Result<()>
 
     let patched_path = format!(
         "{}:{}",
-        workspace.bin_directory()?.to_str().ok_or(anyhow!(
-            "failed to convert workspace bin directory to string"
-        ))?,
+        inherited_bin_directories(state, &workspace)
+            .await?
+            .join(":"),
         current_path()
     );
     let system_shell = var("SHELL").unwrap_or_else(|_| "zsh".to_string());
@@ -472,18 +1830,293 @@ pub async fn workspace_shell(state: &State, workspace_name: &str) -> Result<()>
     Ok(())
 }
 
-/// Checks if the current workspace bin dir is in $PATH, and emit a message if it isn't.
+/// Checks a workspace for bin symlinks pointing to packages that no longer exist.
+///
+/// Optionally removes the broken links.
+#[instrument(skip(state))]
+pub async fn verify_workspace(state: &State, workspace_name: &str, fix: bool) -> Result<()> {
+    let Some(workspace) = state.get_workspace(workspace_name).await? else {
+        return Err(anyhow!("workspace {} does not exist", workspace_name));
+    };
+
+    let dangling = workspace.dangling_symlinks().await?;
+    for link in &dangling {
+        if fix {
+            remove_file(link)
+                .await
+                .wrap_err("failed to delete broken bin symlink")?;
+            println!("Removed broken link {}", link.display());
+        } else {
+            println!("Broken link {}", link.display());
+        }
+    }
+
+    if !fix && !dangling.is_empty() {
+        return Err(anyhow!(
+            "workspace {} has {} broken link(s), run with --fix to remove them",
+            workspace,
+            dangling.len()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Prints shell commands that activate a workspace in the current shell.
+///
+/// Meant to be used as `eval "$(matcha workspace env <name>)"`.
+#[instrument(skip(state))]
+pub async fn workspace_env(state: &State, workspace_name: &str, shell: ShellKind) -> Result<()> {
+    let Some(workspace) = state.get_workspace(workspace_name).await? else {
+        return Err(anyhow!("workspace {} does not exist", workspace_name));
+    };
+
+    let bin_dirs = inherited_bin_directories(state, &workspace).await?;
+    let name = shell.quote(&workspace.name);
+    match shell {
+        ShellKind::Posix => {
+            println!("export PATH={}:\"$PATH\"", shell.quote(&bin_dirs.join(":")));
+            println!("export MATCHA_WORKSPACE={name}");
+        }
+        ShellKind::Fish => {
+            let bin_dirs = bin_dirs
+                .iter()
+                .map(|dir| shell.quote(dir))
+                .collect::<Vec<_>>();
+            println!("set -x PATH {} $PATH", bin_dirs.join(" "));
+            println!("set -x MATCHA_WORKSPACE {name}");
+        }
+    }
+
+    Ok(())
+}
+
+/// The outcome of a single `doctor` check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum CheckStatus {
+    /// Everything is fine.
+    Pass,
+    /// Something looks off, but matcha still works.
+    Warn,
+    /// Something is broken.
+    Fail,
+}
+
+impl std::fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckStatus::Pass => write!(f, "[pass]"),
+            CheckStatus::Warn => write!(f, "[warn]"),
+            CheckStatus::Fail => write!(f, "[fail]"),
+        }
+    }
+}
+
+/// A single `doctor` check.
+#[derive(Debug, Serialize)]
+struct Check {
+    /// What was checked, such as `workspace global`.
+    name: String,
+    /// The outcome of the check.
+    status: CheckStatus,
+    /// A human-readable description of the outcome.
+    detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: CheckStatus, detail: String) -> Self {
+        Self {
+            name: name.into(),
+            status,
+            detail,
+        }
+    }
+}
+
+/// Checks the installation for common problems.
+///
+/// Returns an error if any of the checks failed, whichever format the results are printed in.
+#[instrument(skip(state, fetcher))]
+pub async fn doctor(state: &State, fetcher: &impl Fetcher, format: OutputFormat) -> Result<()> {
+    let mut checks: Vec<Check> = vec![];
+
+    // State database.
+    let name = "state database";
+    // Loading the state already migrated it, so the schema version has to match the migrations.
+    match (
+        state.schema_version().await,
+        state.migrated_schema_version().await,
+    ) {
+        (Ok(version), Ok(migrated)) if version == migrated => checks.push(Check::new(
+            name,
+            CheckStatus::Pass,
+            format!("state database is at schema version {version}"),
+        )),
+        (Ok(version), Ok(migrated)) => checks.push(Check::new(
+            name,
+            CheckStatus::Fail,
+            format!(
+                "state database claims schema version {version}, but is migrated to {migrated}"
+            ),
+        )),
+        (Err(e), _) | (_, Err(e)) => checks.push(Check::new(
+            name,
+            CheckStatus::Fail,
+            format!("state database is not readable: {e:#}"),
+        )),
+    }
+
+    // Workspaces.
+    for workspace in state.workspaces().await? {
+        let name = format!("workspace {workspace}");
+        let bin_dir = workspace.bin_directory()?;
+        if !bin_dir.is_dir() {
+            checks.push(Check::new(
+                name,
+                CheckStatus::Warn,
+                format!(
+                    "workspace {workspace} has no bin directory at {}",
+                    bin_dir.display()
+                ),
+            ));
+        } else if !is_workspace_in_path(&workspace) {
+            checks.push(Check::new(
+                name,
+                CheckStatus::Warn,
+                format!("workspace {workspace} bin directory is not in $PATH"),
+            ));
+        } else {
+            checks.push(Check::new(
+                name,
+                CheckStatus::Pass,
+                format!("workspace {workspace} is usable"),
+            ));
+        }
+    }
+
+    // Installed packages.
+    for pkg in state.installed_packages().await? {
+        let name = format!("package {}@{}", pkg.name, pkg.version);
+        let dir = pkg.directory();
+        if dir.is_dir() {
+            checks.push(Check::new(
+                name,
+                CheckStatus::Pass,
+                format!("package {}@{} is installed", pkg.name, pkg.version),
+            ));
+        } else {
+            checks.push(Check::new(
+                name,
+                CheckStatus::Fail,
+                format!(
+                    "package {}@{} is missing its directory {}",
+                    pkg.name,
+                    pkg.version,
+                    dir.display()
+                ),
+            ));
+        }
+    }
+
+    // Registries.
+    for registry in state.registries().await? {
+        let name = format!("registry {}", registry.uri);
+        match fetcher.fetch(&registry).await {
+            Ok(_) => checks.push(Check::new(
+                name,
+                CheckStatus::Pass,
+                format!("registry {registry} is reachable"),
+            )),
+            Err(e) => checks.push(Check::new(
+                name,
+                CheckStatus::Fail,
+                format!("registry {registry} is not reachable: {e:#}"),
+            )),
+        }
+    }
+
+    match format {
+        OutputFormat::Text => {
+            for check in &checks {
+                println!("{} {}", check.status, check.detail);
+            }
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::json!({ "checks": checks }));
+        }
+    }
+
+    let failures = checks
+        .iter()
+        .filter(|check| check.status == CheckStatus::Fail)
+        .count();
+    if failures > 0 {
+        return Err(anyhow!(
+            "{failures} check{} failed",
+            if failures == 1 { "" } else { "s" }
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks a manifest file for problems before it is published as a registry.
+///
+/// All problems are reported, not just the first one. This does not touch the state.
 #[instrument]
-fn check_path_for_workspace(workspace: &Workspace) {
+pub async fn validate_manifest(path: &Path, format: OutputFormat) -> Result<()> {
+    let manifest: Manifest = read_to_string(path)
+        .await
+        .wrap_err(format!("failed to read manifest at {}", path.display()))?
+        .parse()
+        .wrap_err(format!("failed to parse manifest at {}", path.display()))?;
+    let problems = manifest.problems();
+
+    match format {
+        OutputFormat::Text => {
+            for problem in &problems {
+                println!("{problem}");
+            }
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::json!({ "problems": problems }));
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(anyhow!(
+            "found {} problem{} in manifest at {}",
+            problems.len(),
+            if problems.len() == 1 { "" } else { "s" },
+            path.display()
+        ));
+    }
+    if format == OutputFormat::Text {
+        status!("Manifest at {} is valid", path.display());
+    }
+
+    Ok(())
+}
+
+/// Returns if the workspace bin dir is in $PATH.
+fn is_workspace_in_path(workspace: &Workspace) -> bool {
     let path = current_path();
     let bin_dir = workspace.bin_directory().unwrap();
-    if !path.split(':').any(|p| p == bin_dir.to_str().unwrap()) {
-        eprintln!(
-            r"Warning: the workspace bin directory is not in $PATH.
+    path.split(':').any(|p| p == bin_dir.to_str().unwrap())
+}
+
+/// Checks if the current workspace bin dir is in $PATH, and emit a message if it isn't.
+#[instrument]
+fn check_path_for_workspace(workspace: &Workspace) {
+    if !is_workspace_in_path(workspace) {
+        let bin_dir = workspace.bin_directory().unwrap();
+        status!(
+            r#"Warning: the workspace bin directory is not in $PATH.
 Add this to your shell's configuration file:
 
-export PATH={0}:$PATH",
-            bin_dir.display()
+export PATH={0}:"$PATH""#,
+            ShellKind::Posix.quote(&bin_dir.to_string_lossy())
         );
     }
 }
@@ -507,6 +2140,61 @@ async fn get_create_workspace(state: &State, name: &str) -> Result<Workspace> {
     Ok(ws)
 }
 
+/// Returns the workspace bin directory as a string, for use in $PATH.
+fn bin_directory_str(workspace: &Workspace) -> Result<String> {
+    Ok(workspace
+        .bin_directory()?
+        .to_str()
+        .ok_or(anyhow!(
+            "failed to convert workspace bin directory to string"
+        ))?
+        .to_string())
+}
+
+/// Returns the bin directories of a workspace and the workspaces it inherits from, in the order
+/// they should be searched.
+async fn inherited_bin_directories(state: &State, workspace: &Workspace) -> Result<Vec<String>> {
+    state
+        .workspace_ancestry(workspace)
+        .await?
+        .iter()
+        .map(bin_directory_str)
+        .collect()
+}
+
+/// Writes a backup of the internal state to the given path.
+#[instrument(skip(state))]
+pub async fn backup_state(state: &State, path: &Path) -> Result<()> {
+    state.backup(path).await?;
+
+    status!("Backed up state to {}", path.display());
+    Ok(())
+}
+
+/// Replaces the internal state at `state_db` with a backup.
+#[instrument(skip(state))]
+pub async fn restore_state(state: State, state_db: &str, path: &Path) -> Result<()> {
+    state.restore(state_db, path).await?;
+
+    status!("Restored state from {}", path.display());
+    Ok(())
+}
+
+/// Normalizes a registry URI given on the command line, ensuring the registry is configured.
+#[instrument(skip(state))]
+async fn configured_registry_uri(state: &State, uri: Option<&str>) -> Result<Option<String>> {
+    let Some(uri) = uri else {
+        return Ok(None);
+    };
+    let uri = Uri::from_str(uri)
+        .wrap_err("invalid registry URI")?
+        .to_string();
+    if !state.registry_exists(&uri).await? {
+        return Err(anyhow!("registry {} is not configured", uri));
+    }
+    Ok(Some(uri))
+}
+
 /// Returns the current value of $PATH.
 fn current_path() -> String {
     var("PATH").unwrap_or_else(|_| "".to_string())
@@ -518,6 +2206,39 @@ mod tests {
 
     use crate::{registry::MockFetcher, workspace::test_workspace};
 
+    #[test]
+    fn test_sort_packages_by_version_reversed() {
+        let package = |name: &str, version: &str| Package {
+            name: name.to_string(),
+            version: version.to_string(),
+            ..Default::default()
+        };
+        let packages = vec![
+            package("bar", "1.0.0"),
+            package("foo", "2.0.0"),
+            package("foo", "0.1.0"),
+            package("qux", "1.5.0"),
+        ];
+
+        let sorted = sort_packages(packages.clone(), Some(SortKey::Version), true)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sorted,
+            vec!["foo@2.0.0", "qux@1.5.0", "bar@1.0.0", "foo@0.1.0"]
+        );
+
+        let unsorted = sort_packages(packages, None, false)
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unsorted,
+            vec!["bar@1.0.0", "foo@2.0.0", "foo@0.1.0", "qux@1.5.0"]
+        );
+    }
+
     #[tokio::test]
     async fn test_update_registry_picks_up_new_packages() {
         let state = State::load(":memory:").await.unwrap();
@@ -546,6 +2267,33 @@ mod tests {
             .is_empty());
     }
 
+    #[tokio::test]
+    async fn test_forced_fetch_ignores_cache_validators() {
+        let state = State::load(":memory:").await.unwrap();
+        let mut registry = Registry::new("https://example.invalid/registry");
+        let fetcher = MockFetcher::default();
+        registry.initialize(&state, &fetcher).await.unwrap();
+        registry.fetch(&state, &fetcher).await.unwrap();
+
+        let unchanged = MockFetcher {
+            not_modified: true,
+            ..MockFetcher::with_packages(&[])
+        };
+        registry.fetch(&state, &unchanged).await.unwrap();
+        assert!(!state
+            .known_packages_for_registry(&registry)
+            .await
+            .unwrap()
+            .is_empty());
+
+        fetch_registries(&state, &unchanged, true).await.unwrap();
+        assert!(state
+            .known_packages_for_registry(&registry)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
     #[tokio::test]
     async fn test_update_registry_removes_gone_packages() {
         let state = State::load(":memory:").await.unwrap();
@@ -574,6 +2322,34 @@ mod tests {
             .is_empty());
     }
 
+    #[tokio::test]
+    async fn test_fetch_registries_isolates_failures() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let mut working = Registry::new("https://example.invalid/working");
+        working.initialize(&state, &MockFetcher::default()).await?;
+        let mut broken = Registry::new("https://example.invalid/broken");
+        broken.initialize(&state, &MockFetcher::default()).await?;
+
+        let err = fetch_registries(
+            &state,
+            &MockFetcher::failing_for(&["https://example.invalid/broken"]),
+            true,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.is::<FetchFailed>());
+        let msg = err.to_string();
+        assert!(msg.contains("https://example.invalid/broken"));
+        assert!(!msg.contains("https://example.invalid/working"));
+
+        assert!(!state
+            .known_packages_for_registry(&working)
+            .await?
+            .is_empty());
+        assert!(state.known_packages_for_registry(&broken).await?.is_empty());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_get_create_workspace_defaults_to_global() {
         let state = State::load(":memory:").await.unwrap();
@@ -0,0 +1,164 @@
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{Error, Result, WrapErr};
+use serde::Deserialize;
+use shellexpand::tilde;
+use tokio::fs::read_to_string;
+use tracing::instrument;
+
+/// The user configuration, read from `registries.toml`.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Registries that should always be present.
+    #[serde(default)]
+    pub registries: Vec<ConfigRegistry>,
+}
+
+/// A registry listed in the config.
+///
+/// Either just a URI, or a table with a URI and the public key the registry's manifest has to be
+/// signed with.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "RawConfigRegistry")]
+pub struct ConfigRegistry {
+    pub uri: String,
+    pub public_key: Option<String>,
+}
+
+impl From<&str> for ConfigRegistry {
+    fn from(uri: &str) -> Self {
+        Self {
+            uri: uri.to_string(),
+            public_key: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawConfigRegistry {
+    Uri(String),
+    Table(ConfigRegistryTable),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigRegistryTable {
+    uri: String,
+    public_key: Option<String>,
+}
+
+impl From<RawConfigRegistry> for ConfigRegistry {
+    fn from(raw: RawConfigRegistry) -> Self {
+        match raw {
+            RawConfigRegistry::Uri(uri) => Self {
+                uri,
+                public_key: None,
+            },
+            RawConfigRegistry::Table(ConfigRegistryTable { uri, public_key }) => {
+                Self { uri, public_key }
+            }
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from a file.
+    ///
+    /// A missing file yields an empty config, unless `required` is set. Relative registry paths
+    /// are resolved against the directory of the config file, anything with a scheme is left as
+    /// is.
+    #[instrument]
+    pub async fn load(path: &Path, required: bool) -> Result<Self> {
+        if !required && !path.try_exists()? {
+            return Ok(Self::default());
+        }
+        let mut config: Self = read_to_string(path)
+            .await
+            .wrap_err(format!("failed to read config at {}", path.display()))?
+            .parse()
+            .wrap_err(format!("failed to parse config at {}", path.display()))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        for ConfigRegistry { uri, .. } in &mut config.registries {
+            if !uri.contains("://") {
+                *uri = base
+                    .join(PathBuf::from(tilde(uri).deref()))
+                    .to_string_lossy()
+                    .into_owned();
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(toml::from_str(s)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = r#"registries = ["https://example.invalid/registry.toml"]"#
+            .parse()
+            .unwrap();
+        assert_eq!(
+            config.registries,
+            vec![ConfigRegistry::from(
+                "https://example.invalid/registry.toml"
+            )]
+        );
+        assert!("registries = []\nunknown = true".parse::<Config>().is_err());
+
+        let config: Config =
+            r#"registries = [{ uri = "https://example.invalid/registry.toml", public_key = "key" }]"#
+                .parse()
+                .unwrap();
+        assert_eq!(
+            config.registries,
+            vec![ConfigRegistry {
+                uri: "https://example.invalid/registry.toml".to_string(),
+                public_key: Some("key".to_string()),
+            }]
+        );
+        assert!(r#"registries = [{ uri = "a.toml", unknown = true }]"#
+            .parse::<Config>()
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_config() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("registries.toml");
+        assert_eq!(Config::load(&path, false).await?, Config::default());
+        assert!(Config::load(&path, true).await.is_err());
+
+        std::fs::write(
+            &path,
+            r#"registries = ["https://example.invalid/registry.toml", "local.toml", "/abs.toml"]"#,
+        )?;
+        let config = Config::load(&path, true).await?;
+        assert_eq!(
+            config.registries,
+            vec![
+                ConfigRegistry::from("https://example.invalid/registry.toml"),
+                ConfigRegistry::from(dir.path().join("local.toml").to_string_lossy().as_ref()),
+                ConfigRegistry::from("/abs.toml"),
+            ]
+        );
+        Ok(())
+    }
+}
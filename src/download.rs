@@ -1,40 +1,169 @@
 use bytes::Bytes;
-use color_eyre::Result;
+use color_eyre::{
+    eyre::{bail, WrapErr},
+    Result,
+};
 use futures_util::{Stream, StreamExt};
-use reqwest::Client;
+use once_cell::sync::Lazy;
+use reqwest::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        IF_RANGE, LAST_MODIFIED, RANGE, USER_AGENT,
+    },
+    Client, RequestBuilder, Response, StatusCode,
+};
+use std::{future::Future, time::Duration};
+use tokio::pin;
 use tracing::instrument;
 
+use crate::{error::TimedOut, HTTP_HEADERS, NETWORK_TIMEOUT};
+
 /// A trait for downloading files.
 pub trait Downloader {
     /// Downloads a file from a URL, and returns the bytes.
-    async fn download_file(&self, url: &str) -> Result<Vec<u8>>;
+    fn download_file(&self, url: &str) -> impl Future<Output = Result<Vec<u8>>> + Send;
     /// Downloads a file from a URL, and returns the content length and a stream of bytes.
-    async fn download_stream(
+    fn download_stream(
+        &self,
+        url: &str,
+    ) -> impl Future<Output = Result<(usize, impl Stream<Item = Result<Bytes>>)>> + Send;
+    /// Downloads the rest of a file from a URL, starting at byte `offset`.
+    ///
+    /// `if_range` is the validator of the file the first `offset` bytes were downloaded from, see
+    /// [`range_validator`]. If the file changed since, or the server doesn't support resuming
+    /// downloads, the stream starts at the beginning of the file instead, at offset 0.
+    ///
+    /// Returns the offset the stream actually starts at, the length of the rest, the validator to
+    /// resume the download with later, if any, and a stream of bytes.
+    fn download_stream_from(
         &self,
         url: &str,
-    ) -> Result<(usize, impl Stream<Item = reqwest::Result<Bytes>>)>;
+        offset: u64,
+        if_range: Option<&str>,
+    ) -> impl Future<
+        Output = Result<(
+            u64,
+            usize,
+            Option<String>,
+            impl Stream<Item = Result<Bytes>>,
+        )>,
+    > + Send;
 }
 
-/// The default downloader, which uses reqwest.
-pub struct DefaultDownloader;
+/// How long an idle pooled connection is kept around for reuse.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
 
-impl Downloader for DefaultDownloader {
-    async fn download_file(&self, url: &str) -> Result<Vec<u8>> {
-        download_file(url).await
+/// How long to wait for a connection, or for the next chunk of a response,
+/// unless overridden with `--network-timeout`.
+pub const DEFAULT_NETWORK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The user agent sent with every request, unless overridden with `--user-agent`.
+pub const DEFAULT_USER_AGENT: &str = concat!("matcha/", env!("CARGO_PKG_VERSION"));
+
+/// Builds the headers sent with every request from a user agent and extra headers given as
+/// `Name: value`.
+///
+/// Extra headers replace earlier ones with the same name, including the user agent.
+pub fn request_headers(user_agent: &str, extra: &[String]) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        USER_AGENT,
+        HeaderValue::from_str(user_agent).wrap_err("invalid user agent")?,
+    );
+    for header in extra {
+        let Some((name, value)) = header.split_once(':') else {
+            bail!("invalid header '{header}', expected 'Name: value'");
+        };
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .wrap_err_with(|| format!("invalid header name in '{header}'"))?;
+        let value = HeaderValue::from_str(value.trim())
+            .wrap_err_with(|| format!("invalid header value in '{header}'"))?;
+        headers.insert(name, value);
     }
+    Ok(headers)
+}
 
-    async fn download_stream(
-        &self,
-        url: &str,
-    ) -> Result<(usize, impl Stream<Item = reqwest::Result<Bytes>>)> {
-        download_stream(url).await
+/// Returns the configured request headers.
+fn configured_headers() -> HeaderMap {
+    HTTP_HEADERS.get().cloned().unwrap_or_else(|| {
+        request_headers(DEFAULT_USER_AGENT, &[]).expect("invalid default user agent")
+    })
+}
+
+/// Returns the configured network timeout.
+fn network_timeout() -> Duration {
+    NETWORK_TIMEOUT
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_NETWORK_TIMEOUT)
+}
+
+/// Awaits `future`, failing if it doesn't resolve within `timeout`.
+async fn with_timeout<T>(
+    url: &str,
+    timeout: Duration,
+    future: impl Future<Output = T>,
+) -> Result<T> {
+    tokio::time::timeout(timeout, future)
+        .await
+        .map_err(|_| TimedOut(url.to_string()).into())
+}
+
+/// The HTTP client shared by all downloads, so that connections are pooled
+/// across packages and registries.
+static CLIENT: Lazy<Client> = Lazy::new(|| {
+    Client::builder()
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .connect_timeout(network_timeout())
+        .build()
+        .expect("failed to build HTTP client")
+});
+
+/// HTTP cache validators of a response, used to only download a file again if it changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheValidators {
+    /// The `ETag` header of the response.
+    pub etag: Option<String>,
+    /// The `Last-Modified` header of the response.
+    pub last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    /// Reads the validators from a response's headers.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value: &HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
     }
 }
 
-/// Downloads a file from a URL, and returns the bytes.
-#[instrument]
-pub async fn download_file(url: &str) -> Result<Vec<u8>> {
-    let (_, mut stream) = download_stream(url).await?;
+/// Returns the validator to send as `If-Range` when resuming a download of a response, which is
+/// its `ETag`, or its `Last-Modified` date if it only has a weak one.
+fn range_validator(headers: &HeaderMap) -> Option<String> {
+    let validators = CacheValidators::from_headers(headers);
+    validators
+        .etag
+        .filter(|etag| !etag.starts_with("W/"))
+        .or(validators.last_modified)
+}
+
+/// Returns the first byte of a partial response, according to its `Content-Range` header.
+fn content_range_start(headers: &HeaderMap) -> Option<u64> {
+    let range = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.parse().ok()
+}
+
+/// Collects the chunks of a response body into a single buffer.
+async fn collect_body(stream: impl Stream<Item = Result<Bytes>>) -> Result<Vec<u8>> {
+    pin!(stream);
     let mut bytes = vec![];
 
     while let Some(chunk) = stream.next().await {
@@ -45,33 +174,209 @@ pub async fn download_file(url: &str) -> Result<Vec<u8>> {
     Ok(bytes)
 }
 
-/// Downloads a file from a URL, and returns the content length and a stream of bytes.
-#[instrument]
-pub async fn download_stream(
-    url: &str,
-) -> Result<(usize, impl Stream<Item = reqwest::Result<Bytes>>)> {
-    let client = Client::new();
-    let resp = client
-        .get(url)
-        .header("User-Agent", "matcha")
-        .send()
-        .await?;
-
+/// Returns the content length and a stream of the body of a response.
+fn body_stream(url: &str, resp: Response) -> (usize, impl Stream<Item = Result<Bytes>>) {
+    let timeout = network_timeout();
     let content_length = resp.content_length().unwrap_or(0) as usize;
-    let stream = resp.bytes_stream();
+    // Every chunk has to arrive within the timeout, so a server that stops
+    // sending halfway through doesn't hang the download.
+    let url = url.to_string();
+    let stream = futures_util::stream::unfold(Box::pin(resp.bytes_stream()), move |mut stream| {
+        let url = url.clone();
+        async move {
+            match with_timeout(&url, timeout, stream.next()).await {
+                Ok(Some(chunk)) => Some((chunk.map_err(Into::into), stream)),
+                Ok(None) => None,
+                Err(e) => Some((Err(e), stream)),
+            }
+        }
+    });
+
+    (content_length, stream)
+}
+
+/// The default downloader, which uses reqwest.
+#[derive(Debug, Clone)]
+pub struct DefaultDownloader {
+    client: Client,
+    /// Headers sent with every request.
+    headers: HeaderMap,
+}
+
+impl Default for DefaultDownloader {
+    fn default() -> Self {
+        Self::with_headers(configured_headers())
+    }
+}
+
+impl DefaultDownloader {
+    /// Creates a downloader that sends the given headers instead of the configured ones.
+    pub fn with_headers(headers: HeaderMap) -> Self {
+        Self {
+            client: CLIENT.clone(),
+            headers,
+        }
+    }
+
+    /// Prepares a request for a URL, with all headers attached.
+    fn request(&self, url: &str) -> RequestBuilder {
+        self.client.get(url).headers(self.headers.clone())
+    }
+
+    /// Prepares a request for a URL that the server can answer with `304 Not Modified` if the
+    /// file is unchanged since it was downloaded with the given validators.
+    fn conditional_request(&self, url: &str, validators: &CacheValidators) -> RequestBuilder {
+        let mut request = self.request(url);
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    }
+
+    /// Downloads a file from a URL, unless it is unchanged since it was downloaded with the given
+    /// validators.
+    ///
+    /// Returns `None` if the file is unchanged, otherwise the file and its new validators.
+    #[instrument(skip(self))]
+    pub async fn download_if_modified(
+        &self,
+        url: &str,
+        validators: &CacheValidators,
+    ) -> Result<Option<(Vec<u8>, CacheValidators)>> {
+        let request = self.conditional_request(url, validators);
+        let resp = with_timeout(url, network_timeout(), request.send()).await??;
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let resp = resp.error_for_status()?;
+
+        let validators = CacheValidators::from_headers(resp.headers());
+        let (_, stream) = body_stream(url, resp);
+        Ok(Some((collect_body(stream).await?, validators)))
+    }
+}
+
+impl Downloader for DefaultDownloader {
+    /// Downloads a file from a URL, and returns the bytes.
+    #[instrument(skip(self))]
+    async fn download_file(&self, url: &str) -> Result<Vec<u8>> {
+        let (_, stream) = self.download_stream(url).await?;
+        collect_body(stream).await
+    }
 
-    Ok((content_length, stream))
+    /// Downloads a file from a URL, and returns the content length and a stream of bytes.
+    #[instrument(skip(self))]
+    async fn download_stream(
+        &self,
+        url: &str,
+    ) -> Result<(usize, impl Stream<Item = Result<Bytes>>)> {
+        let resp = with_timeout(url, network_timeout(), self.request(url).send())
+            .await??
+            .error_for_status()?;
+        Ok(body_stream(url, resp))
+    }
+
+    /// Downloads the rest of a file from a URL with a `Range` request, starting at byte `offset`.
+    ///
+    /// The request only gets a partial response if the file is unchanged according to `if_range`,
+    /// and the partial response has to start at `offset`, otherwise the whole file is downloaded.
+    #[instrument(skip(self))]
+    async fn download_stream_from(
+        &self,
+        url: &str,
+        offset: u64,
+        if_range: Option<&str>,
+    ) -> Result<(
+        u64,
+        usize,
+        Option<String>,
+        impl Stream<Item = Result<Bytes>>,
+    )> {
+        let mut request = self.request(url);
+        if offset > 0 {
+            request = request.header(RANGE, format!("bytes={offset}-"));
+            if let Some(validator) = if_range {
+                request = request.header(IF_RANGE, validator);
+            }
+        }
+        let mut resp = with_timeout(url, network_timeout(), request.send()).await??;
+        let start = match resp.status() {
+            StatusCode::PARTIAL_CONTENT
+                if offset > 0 && content_range_start(resp.headers()) == Some(offset) =>
+            {
+                offset
+            }
+            // The server sent another part than requested, or the file changed since the partial
+            // download, so start over.
+            StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE => {
+                resp = with_timeout(url, network_timeout(), self.request(url).send())
+                    .await??
+                    .error_for_status()?;
+                0
+            }
+            // The server ignored the range, or the file changed, and it sends the whole file,
+            // unless it failed to send anything at all.
+            _ => {
+                resp = resp.error_for_status()?;
+                0
+            }
+        };
+        let validator = range_validator(resp.headers());
+        let (len, stream) = body_stream(url, resp);
+        Ok((start, len, validator, stream))
+    }
 }
 
+/// The size of the chunks the mock downloader streams.
+#[cfg(test)]
+const MOCK_CHUNK_SIZE: usize = 16;
+
 #[cfg(test)]
 pub struct MockDownloader {
     pub file: Vec<u8>,
+    /// URLs that fail to download.
+    pub failing_urls: Vec<String>,
+    /// Whether downloads can be resumed from an offset.
+    pub supports_ranges: bool,
+    /// Breaks off the next download after this many bytes.
+    pub interrupt_after: std::sync::Mutex<Option<usize>>,
+    /// The offsets downloads were requested from.
+    pub requested_offsets: std::sync::Mutex<Vec<u64>>,
+    /// The validator of the file, which resumed downloads have to send to resume.
+    pub validator: std::sync::Mutex<Option<String>>,
 }
 
 #[cfg(test)]
 impl MockDownloader {
     pub fn new(file: Vec<u8>) -> Self {
-        Self { file }
+        Self {
+            file,
+            failing_urls: vec![],
+            supports_ranges: true,
+            interrupt_after: Default::default(),
+            requested_offsets: Default::default(),
+            validator: std::sync::Mutex::new(Some("\"mock\"".to_string())),
+        }
+    }
+
+    /// Creates a mock downloader that fails to download any of the given URLs.
+    pub fn failing_for(file: Vec<u8>, urls: &[&str]) -> Self {
+        Self {
+            failing_urls: urls.iter().map(|url| url.to_string()).collect(),
+            ..Self::new(file)
+        }
+    }
+
+    /// Creates a mock downloader whose first download breaks off after `bytes`.
+    pub fn interrupted_after(file: Vec<u8>, bytes: usize, supports_ranges: bool) -> Self {
+        Self {
+            supports_ranges,
+            interrupt_after: std::sync::Mutex::new(Some(bytes)),
+            ..Self::new(file)
+        }
     }
 }
 
@@ -83,11 +388,330 @@ impl Downloader for MockDownloader {
 
     async fn download_stream(
         &self,
-        _: &str,
-    ) -> Result<(usize, impl Stream<Item = reqwest::Result<Bytes>>)> {
+        url: &str,
+    ) -> Result<(usize, impl Stream<Item = Result<Bytes>>)> {
+        let (_, len, _, stream) = self.download_stream_from(url, 0, None).await?;
+        Ok((len, stream))
+    }
+
+    async fn download_stream_from(
+        &self,
+        url: &str,
+        offset: u64,
+        if_range: Option<&str>,
+    ) -> Result<(
+        u64,
+        usize,
+        Option<String>,
+        impl Stream<Item = Result<Bytes>>,
+    )> {
+        if self.failing_urls.iter().any(|u| u == url) {
+            color_eyre::eyre::bail!("mock download failure");
+        }
+        self.requested_offsets.lock().unwrap().push(offset);
+        let validator = self.validator.lock().unwrap().clone();
+        let unchanged = if_range.is_none() || if_range == validator.as_deref();
+        let start = if self.supports_ranges && unchanged {
+            offset
+        } else {
+            0
+        };
+        let rest = &self.file[start as usize..];
+        // Stream in small chunks, like a real download would.
+        let mut chunks = rest
+            .chunks(MOCK_CHUNK_SIZE)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        if let Some(bytes) = self.interrupt_after.lock().unwrap().take() {
+            chunks.truncate(bytes.div_ceil(MOCK_CHUNK_SIZE));
+            chunks.push(Err(color_eyre::eyre::eyre!("mock connection reset")));
+        }
         Ok((
-            self.file.len(),
-            futures_util::stream::once(async move { Ok(Bytes::from(self.file.clone())) }),
+            start,
+            rest.len(),
+            validator,
+            futures_util::stream::iter(chunks),
         ))
     }
 }
+
+/// Answers every request with `status` and `body` over plain HTTP on a local port, keeping
+/// connections alive, and returns the URL to fetch from.
+#[cfg(test)]
+pub async fn serve_with_status(status: &'static str, body: &'static str) -> String {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while let Ok(n) = socket.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                    let response = format!(
+                        "HTTP/1.1 {status}\r\ncontent-length: {}\r\n\r\n{body}",
+                        body.len()
+                    );
+                    if socket.write_all(response.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    format!("http://{addr}/file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serves `body` over plain HTTP on a local port, keeping connections
+    /// alive, and returns the URL to fetch it from.
+    async fn serve(body: &'static str) -> String {
+        serve_with_status("200 OK", body).await
+    }
+
+    #[tokio::test]
+    async fn test_default_downloader_reuses_client() {
+        let url = serve("hello").await;
+        let downloader = DefaultDownloader::default();
+        assert_eq!(downloader.download_file(&url).await.unwrap(), b"hello");
+        assert_eq!(downloader.download_file(&url).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_default_downloader_fails_on_error_statuses() {
+        let downloader = DefaultDownloader::default();
+        for status in ["404 Not Found", "500 Internal Server Error"] {
+            let url = serve_with_status(status, "error page").await;
+            assert!(downloader.download_file(&url).await.is_err());
+            assert!(downloader
+                .download_stream_from(&url, 0, None)
+                .await
+                .is_err());
+            assert!(downloader
+                .download_stream_from(&url, 6, None)
+                .await
+                .is_err());
+            assert!(downloader
+                .download_if_modified(&url, &CacheValidators::default())
+                .await
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_request_headers() {
+        let headers = request_headers(
+            DEFAULT_USER_AGENT,
+            &[
+                "X-Api-Version: 2".to_string(),
+                "Accept:text/plain".to_string(),
+            ],
+        )
+        .unwrap();
+        let request = DefaultDownloader::with_headers(headers)
+            .request("https://example.invalid/file")
+            .build()
+            .unwrap();
+        let headers = request.headers();
+        assert_eq!(headers[USER_AGENT], DEFAULT_USER_AGENT);
+        assert!(DEFAULT_USER_AGENT.starts_with("matcha/"));
+        assert_eq!(headers["x-api-version"], "2");
+        assert_eq!(headers["accept"], "text/plain");
+
+        let headers = request_headers("custom/1.0", &["User-Agent: other".to_string()]).unwrap();
+        assert_eq!(headers[USER_AGENT], "other");
+
+        assert!(request_headers(DEFAULT_USER_AGENT, &["no-colon".to_string()]).is_err());
+        assert!(request_headers(DEFAULT_USER_AGENT, &["bad name: x".to_string()]).is_err());
+    }
+
+    /// Serves `body` with an ETag over plain HTTP on a local port, answering requests that
+    /// send the same ETag with `304 Not Modified`, and returns the URL to fetch it from.
+    async fn serve_with_etag(body: &'static str, etag: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while let Ok(n) = socket.read(&mut buf).await {
+                        if n == 0 {
+                            break;
+                        }
+                        let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                        let response = if request.contains(&format!("if-none-match: {etag}")) {
+                            "HTTP/1.1 304 Not Modified\r\ncontent-length: 0\r\n\r\n".to_string()
+                        } else {
+                            format!(
+                                "HTTP/1.1 200 OK\r\netag: {etag}\r\ncontent-length: {}\r\n\r\n{body}",
+                                body.len()
+                            )
+                        };
+                        if socket.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        format!("http://{addr}/file")
+    }
+
+    /// Serves `body` with an ETag over plain HTTP on a local port, answering `Range` requests with
+    /// the rest of the body, unless they send another ETag as `If-Range`, and returns the URL to
+    /// fetch it from.
+    ///
+    /// A server that isn't `honest` answers every `Range` request with the whole body, as if it
+    /// was the requested part.
+    async fn serve_with_ranges(body: &'static str, etag: &'static str, honest: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 1024];
+                    while let Ok(n) = socket.read(&mut buf).await {
+                        if n == 0 {
+                            break;
+                        }
+                        let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                        let header = |name: &str| {
+                            request
+                                .lines()
+                                .find_map(|line| line.strip_prefix(name))
+                                .map(str::to_string)
+                        };
+                        let offset = header("range: bytes=")
+                            .and_then(|range| range.trim_end_matches('-').parse::<usize>().ok())
+                            .filter(|_| header("if-range: ").is_none_or(|tag| tag == etag));
+                        let response = match offset {
+                            Some(offset) => {
+                                let start = if honest { offset } else { 0 };
+                                format!(
+                                    "HTTP/1.1 206 Partial Content\r\netag: {etag}\r\ncontent-range: bytes {start}-{}/{}\r\ncontent-length: {}\r\n\r\n{}",
+                                    body.len() - 1,
+                                    body.len(),
+                                    body.len() - start,
+                                    &body[start..]
+                                )
+                            }
+                            None => format!(
+                                "HTTP/1.1 200 OK\r\netag: {etag}\r\ncontent-length: {}\r\n\r\n{body}",
+                                body.len()
+                            ),
+                        };
+                        if socket.write_all(response.as_bytes()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        format!("http://{addr}/file")
+    }
+
+    #[tokio::test]
+    async fn test_download_stream_from_offset() {
+        let downloader = DefaultDownloader::default();
+
+        let url = serve_with_ranges("hello world", "\"v1\"", true).await;
+        let (start, len, validator, stream) = downloader
+            .download_stream_from(&url, 6, Some("\"v1\""))
+            .await
+            .unwrap();
+        assert_eq!((start, len), (6, 5));
+        assert_eq!(validator.as_deref(), Some("\"v1\""));
+        assert_eq!(collect_body(stream).await.unwrap(), b"world");
+
+        // A file that changed since the partial download is sent whole.
+        let (start, _, _, stream) = downloader
+            .download_stream_from(&url, 6, Some("\"v0\""))
+            .await
+            .unwrap();
+        assert_eq!(start, 0);
+        assert_eq!(collect_body(stream).await.unwrap(), b"hello world");
+
+        // So is a partial response that doesn't start at the requested offset.
+        let url = serve_with_ranges("hello world", "\"v1\"", false).await;
+        let (start, _, _, stream) = downloader
+            .download_stream_from(&url, 6, Some("\"v1\""))
+            .await
+            .unwrap();
+        assert_eq!(start, 0);
+        assert_eq!(collect_body(stream).await.unwrap(), b"hello world");
+
+        // Servers without range support send the whole file.
+        let url = serve("hello world").await;
+        let (start, _, _, stream) = downloader
+            .download_stream_from(&url, 6, None)
+            .await
+            .unwrap();
+        assert_eq!(start, 0);
+        assert_eq!(collect_body(stream).await.unwrap(), b"hello world");
+    }
+
+    #[tokio::test]
+    async fn test_download_if_modified() {
+        let url = serve_with_etag("hello", "\"v1\"").await;
+        let downloader = DefaultDownloader::default();
+
+        let (bytes, validators) = downloader
+            .download_if_modified(&url, &CacheValidators::default())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(bytes, b"hello");
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+
+        assert!(downloader
+            .download_if_modified(&url, &validators)
+            .await
+            .unwrap()
+            .is_none());
+
+        let stale = CacheValidators {
+            etag: Some("\"v0\"".to_string()),
+            last_modified: None,
+        };
+        assert!(downloader
+            .download_if_modified(&url, &stale)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_with_timeout_gives_up_on_hung_requests() {
+        let timeout = Duration::from_millis(50);
+        let started = std::time::Instant::now();
+        let err = with_timeout(
+            "https://example.invalid/hung",
+            timeout,
+            std::future::pending::<()>(),
+        )
+        .await
+        .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(
+            err.to_string(),
+            "timed out fetching https://example.invalid/hung"
+        );
+        assert_eq!(crate::error::exit_code(&err), crate::error::EXIT_NETWORK);
+    }
+}
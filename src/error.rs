@@ -1,7 +1,43 @@
-use std::{collections::HashSet, error::Error, fmt::Display};
+use std::{error::Error, fmt::Display};
+
+use color_eyre::Report;
 
 use crate::package::VersionSpec;
 
+/// Exit code for errors that don't fall into any more specific category.
+pub const EXIT_GENERIC: i32 = 1;
+/// Exit code for requests of packages, or package versions, that are not known.
+pub const EXIT_UNKNOWN_PACKAGE: i32 = 2;
+/// Exit code for conflicting version requests.
+pub const EXIT_CONFLICT: i32 = 3;
+/// Exit code for failed package builds.
+pub const EXIT_BUILD_FAILED: i32 = 4;
+/// Exit code for failed downloads or registry fetches.
+pub const EXIT_NETWORK: i32 = 5;
+
+/// Returns the process exit code for an error, based on the errors in its chain.
+pub fn exit_code(report: &Report) -> i32 {
+    for error in report.chain() {
+        if error.is::<UnknownPackage>() {
+            return EXIT_UNKNOWN_PACKAGE;
+        }
+        if error.is::<Conflicts>() {
+            return EXIT_CONFLICT;
+        }
+        if error.is::<BuildFailed>() {
+            return EXIT_BUILD_FAILED;
+        }
+        if error.is::<DownloadFailed>()
+            || error.is::<FetchFailed>()
+            || error.is::<TimedOut>()
+            || error.is::<reqwest::Error>()
+        {
+            return EXIT_NETWORK;
+        }
+    }
+    EXIT_GENERIC
+}
+
 /// An invalid version spec was encountered.
 #[derive(Debug, Clone)]
 pub struct InvalidVersonSpec(pub String);
@@ -15,18 +51,29 @@ impl Display for InvalidVersonSpec {
 /// Conflicts between dependency requests.
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct Conflicts {
-    /// The conflicting dependency requests.
-    pub inner: Vec<(String, HashSet<VersionSpec>)>,
+    /// The conflicting dependency requests, each with a description of what requested it.
+    pub inner: Vec<(String, Vec<(VersionSpec, String)>)>,
 }
 
 impl Conflicts {
-    /// Adds a conflict.
-    pub fn add_conflict(&mut self, name: String, a: VersionSpec, b: VersionSpec) {
-        if let Some((_, versions)) = self.inner.iter_mut().find(|(n, _)| n == &name) {
-            versions.insert(a);
-            versions.insert(b);
-        } else {
-            self.inner.push((name, HashSet::from([a, b])));
+    /// Adds a conflict between two requests, each with a description of what requested it.
+    pub fn add_conflict(
+        &mut self,
+        name: String,
+        a: (VersionSpec, String),
+        b: (VersionSpec, String),
+    ) {
+        let requests = match self.inner.iter_mut().find(|(n, _)| n == &name) {
+            Some((_, requests)) => requests,
+            None => {
+                self.inner.push((name, vec![]));
+                &mut self.inner.last_mut().unwrap().1
+            }
+        };
+        for request in [a, b] {
+            if !requests.contains(&request) {
+                requests.push(request);
+            }
         }
     }
 
@@ -40,8 +87,8 @@ impl Display for Conflicts {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for (name, requests) in &self.inner {
             writeln!(f, "conflicting requests for dependency '{}':", name)?;
-            for request in requests {
-                writeln!(f, "  {:?}", request)?;
+            for (version, requester) in requests {
+                writeln!(f, "  {:?}, requested by {}", version, requester)?;
             }
         }
         Ok(())
@@ -49,3 +96,77 @@ impl Display for Conflicts {
 }
 
 impl Error for Conflicts {}
+
+/// A requested package, or the requested version of it, is not known.
+#[derive(Debug, Clone)]
+pub struct UnknownPackage {
+    /// The package request that could not be resolved.
+    pub request: String,
+    /// The versions of the package that are known, if any.
+    pub known_versions: Vec<String>,
+}
+impl Error for UnknownPackage {}
+impl Display for UnknownPackage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.known_versions.is_empty() {
+            write!(f, "package {} is not known", self.request)
+        } else {
+            write!(
+                f,
+                "package {} is not known, but these versions are: {}",
+                self.request,
+                self.known_versions.join(", ")
+            )
+        }
+    }
+}
+
+/// One or more package builds failed.
+#[derive(Debug, Clone)]
+pub struct BuildFailed(pub Vec<String>);
+impl Error for BuildFailed {}
+impl Display for BuildFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to build {}", self.0.join(", "))
+    }
+}
+
+/// A package source could not be downloaded from any of its sources.
+#[derive(Debug, Clone)]
+pub struct DownloadFailed {
+    /// The package that failed to download.
+    pub package: String,
+    /// The failure for each source, in order.
+    pub failures: Vec<String>,
+}
+impl Error for DownloadFailed {}
+impl Display for DownloadFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "failed to download {} from any source:\n{}",
+            self.package,
+            self.failures.join("\n")
+        )
+    }
+}
+
+/// One or more registries could not be fetched.
+#[derive(Debug, Clone)]
+pub struct FetchFailed(pub Vec<String>);
+impl Error for FetchFailed {}
+impl Display for FetchFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "failed to update registries:\n{}", self.0.join("\n"))
+    }
+}
+
+/// A network request did not complete within the network timeout.
+#[derive(Debug, Clone)]
+pub struct TimedOut(pub String);
+impl Error for TimedOut {}
+impl Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "timed out fetching {}", self.0)
+    }
+}
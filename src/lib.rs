@@ -0,0 +1,74 @@
+//! Matcha is a package manager that builds packages from source and makes them available in
+//! isolated workspaces.
+//!
+//! The `matcha` binary is a thin command-line interface over this library. The items re-exported
+//! at the crate root are the stable API for embedding matcha:
+//!
+//! - [`State`] holds the internal state, such as registries and installed packages.
+//! - [`Workspace`] is an environment using a subset of the installed packages.
+//! - [`PackageRequest`] and [`PackageChangeSet`] describe which packages to install.
+//! - [`install`], [`remove`], and [`search`] are the high-level package operations.
+//!
+//! [`WORKSPACE_ROOT`] and [`PACKAGE_ROOT`] have to be set before installing packages.
+
+use std::{path::PathBuf, time::Duration};
+
+use once_cell::sync::OnceCell;
+use reqwest::header::HeaderMap;
+
+/// Prints an informational message to stderr, unless `--quiet` was given.
+///
+/// Errors are reported regardless, and should not use this.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !$crate::util::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub mod command;
+pub mod config;
+pub mod download;
+pub mod error;
+pub mod manifest;
+pub mod package;
+pub mod registry;
+pub mod state;
+pub mod util;
+pub mod version;
+pub mod workspace;
+
+pub use command::{
+    install_packages as install, remove_packages as remove, search_packages as search,
+};
+pub use package::{PackageChangeSet, PackageRequest};
+pub use state::State;
+pub use workspace::Workspace;
+
+/// The root directory that holds all the workspaces.
+pub static WORKSPACE_ROOT: OnceCell<PathBuf> = OnceCell::new();
+
+/// The root directory that holds all installed packages.
+pub static PACKAGE_ROOT: OnceCell<PathBuf> = OnceCell::new();
+
+/// The directory to create build directories in, if not the system's temporary directory.
+pub static BUILD_ROOT: OnceCell<PathBuf> = OnceCell::new();
+
+/// How long network requests may stall before giving up.
+pub static NETWORK_TIMEOUT: OnceCell<Duration> = OnceCell::new();
+
+/// How many versions of each package to keep known when fetching registries, if limited.
+pub static KEEP_VERSIONS: OnceCell<usize> = OnceCell::new();
+
+/// Whether to suppress spinners, warnings, and other informational output.
+pub static QUIET: OnceCell<bool> = OnceCell::new();
+
+/// Whether to hide spinners and progress bars, such as when stderr is not a terminal.
+pub static NO_PROGRESS: OnceCell<bool> = OnceCell::new();
+
+/// Whether to print output without ANSI colors.
+pub static NO_COLOR: OnceCell<bool> = OnceCell::new();
+
+/// Headers sent with every network request, including the user agent.
+pub static HTTP_HEADERS: OnceCell<HeaderMap> = OnceCell::new();
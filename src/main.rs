@@ -1,98 +1,288 @@
-use std::{ops::Deref, path::PathBuf};
+use std::{io::IsTerminal, path::PathBuf, time::Duration};
 
 use clap::Parser;
-use color_eyre::{eyre::WrapErr, Result};
-use once_cell::sync::OnceCell;
-use shellexpand::tilde;
+use color_eyre::{
+    config::{HookBuilder, Theme},
+    eyre::WrapErr,
+    Result,
+};
+use once_cell::sync::Lazy;
 use tracing::instrument;
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-pub(crate) mod command;
-pub(crate) mod download;
-pub(crate) mod error;
-pub(crate) mod manifest;
-pub(crate) mod package;
-pub(crate) mod registry;
-pub(crate) mod state;
-pub(crate) mod util;
-pub(crate) mod workspace;
+use matcha::{
+    command::*,
+    config, download, error,
+    manifest::InstallOptions,
+    registry::{CachingFetcher, DefaultFetcher},
+    state,
+    util::{self, expand_tilde},
+    BUILD_ROOT, HTTP_HEADERS, KEEP_VERSIONS, NETWORK_TIMEOUT, NO_COLOR, NO_PROGRESS, PACKAGE_ROOT,
+    QUIET, WORKSPACE_ROOT,
+};
 
-use crate::command::*;
+/// Name of the file that selects the workspace to use in a directory and its subdirectories.
+const WORKSPACE_FILE: &str = ".matcha-workspace";
 
-use registry::DefaultFetcher;
-
-/// The root directory that holds all the workspaces.
-static WORKSPACE_ROOT: OnceCell<PathBuf> = OnceCell::new();
-
-/// The root directory that holds all installed packages.
-static PACKAGE_ROOT: OnceCell<PathBuf> = OnceCell::new();
+/// The workspace to use unless one is given with `--workspace` or `MATCHA_WORKSPACE`.
+static DEFAULT_WORKSPACE: Lazy<String> = Lazy::new(default_workspace);
 
 #[tokio::main]
 #[instrument]
 async fn main() -> Result<()> {
+    let args = Cli::parse();
+    let color = std::io::stderr().is_terminal() && !no_color_requested();
+
     tracing_subscriber::registry()
         .with(
             EnvFilter::try_from_env("MATCHA_LOG")
-                .or_else(|_| EnvFilter::try_new("error"))
+                .or_else(|_| EnvFilter::try_new(log_level(args.verbose, args.quiet)))
                 .unwrap(),
         )
-        .with(fmt::layer().with_target(false))
+        .with(
+            fmt::layer()
+                .with_target(false)
+                .with_ansi(color)
+                .with_writer(std::io::stderr),
+        )
         .with(ErrorLayer::default())
         .init();
-    color_eyre::install()?;
+    HookBuilder::default()
+        .theme(if color { Theme::dark() } else { Theme::new() })
+        .install()?;
 
-    let args = Cli::parse();
+    if let Err(e) = run(args).await {
+        eprintln!("Error: {e:?}");
+        std::process::exit(error::exit_code(&e));
+    }
+
+    Ok(())
+}
+
+/// Runs the command given on the command line.
+#[instrument]
+async fn run(args: Cli) -> Result<()> {
+    QUIET
+        .set(args.quiet)
+        .expect("double initialization of QUIET");
+    NO_PROGRESS
+        .set(args.no_progress || !std::io::stderr().is_terminal())
+        .expect("double initialization of NO_PROGRESS");
+    NO_COLOR
+        .set(no_color_requested())
+        .expect("double initialization of NO_COLOR");
     let state = state::State::load(&args.state_db)
         .await
         .wrap_err("Failed to load internal state")?;
 
     WORKSPACE_ROOT
-        .set(PathBuf::from(
-            tilde(&args.workspace_root.to_string_lossy()).deref(),
-        ))
+        .set(expand_tilde(&args.workspace_root))
         .expect("double initialization of WORKSPACE_ROOT");
     PACKAGE_ROOT
-        .set(PathBuf::from(
-            tilde(&args.package_root.to_string_lossy()).deref(),
-        ))
+        .set(expand_tilde(&args.package_root))
         .expect("double initialization of PACKAGE_ROOT");
+    if let Some(build_root) = &args.build_root {
+        BUILD_ROOT
+            .set(expand_tilde(build_root))
+            .expect("double initialization of BUILD_ROOT");
+    }
+    NETWORK_TIMEOUT
+        .set(Duration::from_secs(args.network_timeout))
+        .expect("double initialization of NETWORK_TIMEOUT");
+    if let Some(keep_versions) = args.keep_versions {
+        KEEP_VERSIONS
+            .set(keep_versions as usize)
+            .expect("double initialization of KEEP_VERSIONS");
+    }
+    HTTP_HEADERS
+        .set(download::request_headers(&args.user_agent, &args.header)?)
+        .expect("double initialization of HTTP_HEADERS");
+
+    let config_path = args
+        .config
+        .as_deref()
+        .map(expand_tilde)
+        .unwrap_or_else(default_config);
+    let config = config::Config::load(&config_path, args.config.is_some()).await?;
+    let fetcher = CachingFetcher::new(
+        DefaultFetcher,
+        expand_tilde(&args.cache_dir),
+        args.use_cache_only,
+    );
+    if args.command.resolves_packages() {
+        ensure_configured_registries(&state, &config, &fetcher).await?;
+    }
 
     match args.command {
         Command::Package(cmd) => match cmd {
-            PackageCommand::Install { pkgs, workspace } => {
-                fetch_registries(&state, &DefaultFetcher, false).await?;
-                install_packages(&state, &pkgs, &workspace).await?;
+            PackageCommand::Install {
+                mut pkgs,
+                from_file,
+                workspace,
+                from: Some(manifest),
+                keep_build,
+                allow_downgrade,
+                force,
+                link_mode,
+                merge_output,
+                serial,
+                ..
+            } => {
+                if let Some(path) = from_file {
+                    pkgs.extend(read_package_list(&expand_tilde(&path)).await?);
+                }
+                let options = InstallOptions {
+                    keep_build,
+                    allow_downgrade,
+                    force,
+                    link_mode,
+                    merge_output,
+                    serial,
+                    ..Default::default()
+                };
+                let manifest = expand_tilde(&manifest);
+                install_packages_from_manifest(&state, &manifest, &pkgs, &workspace, &options)
+                    .await?
             }
-            PackageCommand::Update { pkgs, workspace } => {
-                fetch_registries(&state, &DefaultFetcher, false).await?;
-                update_packages(&state, &pkgs, &workspace).await?;
+            PackageCommand::Install {
+                mut pkgs,
+                from_file,
+                workspace,
+                from: None,
+                registry,
+                keep_build,
+                build_override,
+                allow_downgrade,
+                force,
+                link_mode,
+                no_build,
+                merge_output,
+                serial,
+            } => {
+                if let Some(path) = from_file {
+                    pkgs.extend(read_package_list(&expand_tilde(&path)).await?);
+                }
+                fetch_registries(&state, &fetcher, false).await?;
+                let options = InstallOptions {
+                    keep_build,
+                    build_override,
+                    allow_downgrade,
+                    force,
+                    link_mode,
+                    no_build,
+                    merge_output,
+                    serial,
+                };
+                install_packages(&state, &pkgs, &workspace, registry.as_deref(), &options).await?;
+            }
+            PackageCommand::Update {
+                pkgs,
+                workspace,
+                changelog,
+            } => {
+                fetch_registries(&state, &fetcher, false).await?;
+                update_packages(&state, &pkgs, &workspace, changelog).await?;
             }
             PackageCommand::Remove { pkgs, workspace } => {
                 remove_packages(&state, &pkgs, &workspace).await?
             }
+            PackageCommand::Reinstall { pkgs, workspace } => {
+                fetch_registries(&state, &fetcher, false).await?;
+                reinstall_packages(&state, &pkgs, &workspace).await?;
+            }
+            PackageCommand::Search {
+                query,
+                installed: true,
+                workspace,
+                ..
+            } => search_installed_packages(&state, &query, &workspace).await?,
             PackageCommand::Search {
                 query,
                 all_versions,
+                registry,
+                sort,
+                reverse,
+                ..
             } => {
-                fetch_registries(&state, &DefaultFetcher, false).await?;
-                search_packages(&state, &query, all_versions).await?;
+                fetch_registries(&state, &fetcher, false).await?;
+                search_packages(
+                    &state,
+                    &query,
+                    all_versions,
+                    registry.as_deref(),
+                    sort,
+                    reverse,
+                )
+                .await?;
+            }
+            PackageCommand::Show { pkg, versions } => show_package(&state, &pkg, versions).await?,
+            PackageCommand::ExportBin { pkg } => package_binaries_of(&state, &pkg).await?,
+            PackageCommand::Copy { pkg, from, to } => {
+                copy_package(&state, &pkg, &from, &to).await?
+            }
+            PackageCommand::Which { binary, workspace } => {
+                which_binary(&state, &binary, &workspace).await?
+            }
+            PackageCommand::List { workspace, long } => {
+                list_packages(&state, &workspace, long).await?
+            }
+            PackageCommand::Freeze { workspace } => freeze_packages(&state, &workspace).await?,
+            PackageCommand::History { workspace } => package_history(&state, &workspace).await?,
+            PackageCommand::Depends { pkg, workspace } => {
+                package_dependents(&state, &pkg, &workspace).await?
             }
-            PackageCommand::Show { pkg } => show_package(&state, &pkg).await?,
-            PackageCommand::List { workspace } => list_packages(&state, &workspace).await?,
             PackageCommand::GarbageCollect => garbage_collect_installed_packages(&state).await?,
         },
         Command::Workspace(cmd) => match cmd {
-            WorkspaceCommand::Add { workspace } => add_workspace(&state, &workspace).await?,
+            WorkspaceCommand::Add { workspace, parent } => {
+                add_workspace(&state, &workspace, parent.as_deref()).await?
+            }
             WorkspaceCommand::Remove { workspace } => remove_workspace(&state, &workspace).await?,
-            WorkspaceCommand::List => list_workspaces(&state).await?,
+            WorkspaceCommand::List { with_counts } => list_workspaces(&state, with_counts).await?,
             WorkspaceCommand::Shell { workspace } => workspace_shell(&state, &workspace).await?,
+            WorkspaceCommand::Verify { workspace, fix } => {
+                verify_workspace(&state, &workspace, fix).await?
+            }
+            WorkspaceCommand::Env { workspace, shell } => {
+                workspace_env(&state, &workspace, shell).await?
+            }
         },
+        Command::Doctor { format } => doctor(&state, &fetcher, format).await?,
         Command::Registry(cmd) => match cmd {
-            RegistryCommand::Add { uri } => add_registry(&state, &uri, &DefaultFetcher).await?,
+            RegistryCommand::Add {
+                uri,
+                workspace,
+                public_key,
+            } => {
+                add_registry(
+                    &state,
+                    &uri,
+                    workspace.as_deref(),
+                    public_key.as_deref(),
+                    &fetcher,
+                )
+                .await?
+            }
             RegistryCommand::Remove { uri } => remove_registry(&state, &uri).await?,
-            RegistryCommand::List => list_registries(&state).await?,
-            RegistryCommand::Fetch => fetch_registries(&state, &DefaultFetcher, true).await?,
+            RegistryCommand::SetUri { old, new } => set_registry_uri(&state, &old, &new).await?,
+            RegistryCommand::List { long, format } => list_registries(&state, long, format).await?,
+            RegistryCommand::Fetch { dry_run: false } => {
+                fetch_registries(&state, &fetcher, true).await?
+            }
+            RegistryCommand::Fetch { dry_run: true } => {
+                preview_fetch_registries(&state, &fetcher).await?
+            }
+            RegistryCommand::Refresh { uri } => refresh_registry(&state, &uri, &fetcher).await?,
+            RegistryCommand::Validate { path, format } => {
+                validate_manifest(&expand_tilde(&path), format).await?
+            }
+        },
+        Command::State(cmd) => match cmd {
+            StateCommand::Backup { path } => backup_state(&state, &expand_tilde(&path)).await?,
+            StateCommand::Restore { path } => {
+                restore_state(state, &args.state_db, &expand_tilde(&path)).await?
+            }
         },
     }
 
@@ -111,25 +301,154 @@ struct Cli {
     #[arg(
         long,
         env = "MATCHA_STATE_DB",
-        default_value = "~/.local/matcha/state.db"
+        default_value_t = default_state_db()
     )]
     state_db: String,
 
     /// Path to the workspace directory
     #[arg(
         long,
+        global = true,
         env = "MATCHA_WORKSPACE_ROOT",
-        default_value = "~/.local/matcha/workspaces"
+        default_value_os_t = xdg_matcha_dir("XDG_DATA_HOME").join("workspaces")
     )]
     workspace_root: PathBuf,
 
-    /// Path to the packge directory
+    /// Path to the package directory (alias: --prefix)
     #[arg(
         long,
+        visible_alias = "prefix",
+        global = true,
         env = "MATCHA_PACKAGE_ROOT",
-        default_value = "~/.local/matcha/packages"
+        default_value_os_t = xdg_matcha_dir("XDG_DATA_HOME").join("packages")
     )]
     package_root: PathBuf,
+
+    /// Path to the directory to build packages in [default: the system's temporary directory]
+    #[arg(long, global = true, alias = "build-dir", env = "MATCHA_BUILD_ROOT")]
+    build_root: Option<PathBuf>,
+
+    /// Path to the config file listing registries to always have [default:
+    /// $XDG_CONFIG_HOME/matcha/registries.toml]
+    #[arg(long, env = "MATCHA_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Seconds to wait for a connection or data before giving up on a download
+    #[arg(
+        long,
+        global = true,
+        env = "MATCHA_NETWORK_TIMEOUT",
+        value_name = "SECONDS",
+        default_value_t = download::DEFAULT_NETWORK_TIMEOUT.as_secs()
+    )]
+    network_timeout: u64,
+
+    /// User agent to send with network requests
+    #[arg(
+        long,
+        global = true,
+        env = "MATCHA_USER_AGENT",
+        default_value = download::DEFAULT_USER_AGENT
+    )]
+    user_agent: String,
+
+    /// Extra header to send with network requests, can be repeated
+    #[arg(long, global = true, value_name = "NAME: VALUE")]
+    header: Vec<String>,
+
+    /// Path to the directory that keeps a copy of fetched registry manifests
+    #[arg(
+        long,
+        global = true,
+        env = "MATCHA_CACHE_DIR",
+        default_value_os_t = xdg_matcha_dir("XDG_CACHE_HOME").join("registries")
+    )]
+    cache_dir: PathBuf,
+
+    /// Only keep the newest versions of each package when fetching registries, installed versions
+    /// are always kept
+    #[arg(
+        long,
+        global = true,
+        env = "MATCHA_KEEP_VERSIONS",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    keep_versions: Option<u64>,
+
+    /// Read registry manifests from the cache directory instead of fetching them
+    #[arg(long, global = true, env = "MATCHA_USE_CACHE_ONLY")]
+    use_cache_only: bool,
+
+    /// Only print errors, without spinners, warnings, or other messages
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Don't show spinners or progress bars, which is the default if stderr is not a terminal
+    #[arg(long, global = true, env = "MATCHA_NO_PROGRESS")]
+    no_progress: bool,
+
+    /// Log more details, repeat for even more (-v, -vv, -vvv)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+/// Returns if colors were turned off with a non-empty `NO_COLOR`, see <https://no-color.org>.
+fn no_color_requested() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+/// Returns the log level for the number of times `--verbose` was given, or for `--quiet`.
+///
+/// `MATCHA_LOG` takes precedence over this.
+fn log_level(verbose: u8, quiet: bool) -> &'static str {
+    if quiet {
+        return "error";
+    }
+    match verbose {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    }
+}
+
+/// Returns the matcha directory inside an XDG base directory.
+///
+/// Falls back to `~/.local/matcha` if the variable is unset, empty, or not an absolute path, which
+/// the XDG base directory specification says to ignore.
+fn xdg_matcha_dir(var: &str) -> PathBuf {
+    std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| dir.join("matcha"))
+        .unwrap_or_else(|| PathBuf::from("~/.local/matcha"))
+}
+
+/// Returns the workspace named in the closest `.matcha-workspace` file in the current directory or
+/// one of its ancestors, or `global` if there is none.
+fn default_workspace() -> String {
+    let Ok(dir) = std::env::current_dir() else {
+        return "global".to_string();
+    };
+    dir.ancestors()
+        .filter_map(|dir| std::fs::read_to_string(dir.join(WORKSPACE_FILE)).ok())
+        .map(|name| name.trim().to_string())
+        .find(|name| !name.is_empty())
+        .unwrap_or_else(|| "global".to_string())
+}
+
+/// Returns the default path of the internal state database.
+fn default_state_db() -> String {
+    xdg_matcha_dir("XDG_STATE_HOME")
+        .join("state.db")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Returns the default path of the config file.
+fn default_config() -> PathBuf {
+    expand_tilde(&xdg_matcha_dir("XDG_CONFIG_HOME").join("registries.toml"))
 }
 
 #[derive(Parser, Debug)]
@@ -145,6 +464,34 @@ enum Command {
     /// Manage registries (alias: reg, r)
     #[command(subcommand, arg_required_else_help = true, alias = "reg", alias = "r")]
     Registry(RegistryCommand),
+
+    /// Check the installation for common problems
+    Doctor {
+        /// Format to print the results in
+        #[arg(long, value_enum, default_value_t)]
+        format: util::OutputFormat,
+    },
+
+    /// Back up or restore the internal state
+    #[command(subcommand, arg_required_else_help = true)]
+    State(StateCommand),
+}
+
+impl Command {
+    /// Returns if the command resolves packages from the registries, so that the registries
+    /// listed in the config have to be added first. A dry run must not add them either.
+    fn resolves_packages(&self) -> bool {
+        matches!(
+            self,
+            Command::Package(
+                PackageCommand::Install { .. }
+                    | PackageCommand::Update { .. }
+                    | PackageCommand::Reinstall { .. }
+                    | PackageCommand::Search { .. }
+                    | PackageCommand::Show { .. }
+            ) | Command::Registry(RegistryCommand::Fetch { dry_run: false })
+        )
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -153,21 +500,73 @@ enum PackageCommand {
     #[command(arg_required_else_help = true, alias = "i")]
     Install {
         /// Workspace to use
-        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = "global")]
+        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = DEFAULT_WORKSPACE.as_str())]
         workspace: String,
 
         /// Packages to install
-        #[arg(required = true)]
+        #[arg(required_unless_present = "from_file")]
         pkgs: Vec<String>,
+
+        /// Also install the packages listed in this file, one per line
+        #[arg(long, value_name = "FILE")]
+        from_file: Option<PathBuf>,
+
+        /// Only install packages from the registry with this URI
+        #[arg(long)]
+        registry: Option<String>,
+
+        /// Keep the build directory of failed builds for inspection
+        #[arg(long)]
+        keep_build: bool,
+
+        /// Show the output of failed builds as one stream, instead of separate stdout and stderr
+        #[arg(long)]
+        merge_output: bool,
+
+        /// Build the package with this script instead of its own build command
+        #[arg(long, value_name = "SCRIPT")]
+        build_override: Option<String>,
+
+        /// Allow replacing installed packages with older versions
+        #[arg(long)]
+        allow_downgrade: bool,
+
+        /// Replace binaries of other packages in the workspace that have the same name
+        #[arg(long)]
+        force: bool,
+
+        /// How to place package binaries in the workspace
+        #[arg(long, value_enum, default_value_t)]
+        link_mode: util::LinkMode,
+
+        /// Install packages from this manifest file, without adding it as a registry
+        #[arg(
+            long,
+            value_name = "MANIFEST",
+            conflicts_with_all = ["registry", "build_override", "no_build"]
+        )]
+        from: Option<PathBuf>,
+
+        /// Only download the package sources for a later install, without building anything
+        #[arg(long, conflicts_with_all = ["build_override", "keep_build"])]
+        no_build: bool,
+
+        /// Install packages one at a time, ordered by name, and print every build's output
+        #[arg(long, conflicts_with = "no_build")]
+        serial: bool,
     },
 
     /// Update all or select packages (alias: u)
     #[command(alias = "u")]
     Update {
-        /// Workspace to use
-        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = "global")]
+        /// Workspace to use, or "all" for every workspace
+        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = DEFAULT_WORKSPACE.as_str())]
         workspace: String,
 
+        /// Print the old and new version of every updated package
+        #[arg(long)]
+        changelog: bool,
+
         /// Select packages to update
         pkgs: Vec<String>,
     },
@@ -176,7 +575,7 @@ enum PackageCommand {
     #[command(arg_required_else_help = true, alias = "rm")]
     Remove {
         /// Workspace to use
-        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = "global")]
+        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = DEFAULT_WORKSPACE.as_str())]
         workspace: String,
 
         /// Packages to uninstall
@@ -184,11 +583,49 @@ enum PackageCommand {
         pkgs: Vec<String>,
     },
 
+    /// Rebuild and reinstall one or more packages
+    #[command(arg_required_else_help = true)]
+    Reinstall {
+        /// Workspace to use
+        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = DEFAULT_WORKSPACE.as_str())]
+        workspace: String,
+
+        /// Packages to reinstall
+        #[arg(required = true)]
+        pkgs: Vec<String>,
+    },
+
+    /// Copy a package to another workspace, without building it again (alias: cp)
+    #[command(arg_required_else_help = true, alias = "cp")]
+    Copy {
+        /// Workspace to copy the package from
+        #[arg(long)]
+        from: String,
+
+        /// Workspace to copy the package to
+        #[arg(long)]
+        to: String,
+
+        /// Package to copy
+        pkg: String,
+    },
+
     /// List all installed packages (alias: ls)
     #[command(alias = "ls")]
     List {
+        /// Workspace to use, or "all" for every workspace
+        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = DEFAULT_WORKSPACE.as_str())]
+        workspace: String,
+
+        /// Show more details, such as the registry each package came from
+        #[arg(short, long)]
+        long: bool,
+    },
+
+    /// Print installed packages pinned to their versions, for use with `install --from-file`
+    Freeze {
         /// Workspace to use
-        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = "global")]
+        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = DEFAULT_WORKSPACE.as_str())]
         workspace: String,
     },
 
@@ -201,6 +638,26 @@ enum PackageCommand {
         /// Return all versions instead of just the latest
         #[arg(long)]
         all_versions: bool,
+
+        /// Only search the registry with this URI
+        #[arg(long)]
+        registry: Option<String>,
+
+        /// Sort results by this field instead of by name and newest version
+        #[arg(long, value_enum)]
+        sort: Option<util::SortKey>,
+
+        /// Reverse the order of the results
+        #[arg(long)]
+        reverse: bool,
+
+        /// Search the packages installed in a workspace instead of the registries
+        #[arg(long, conflicts_with_all = ["all_versions", "registry", "sort", "reverse"])]
+        installed: bool,
+
+        /// Workspace to search with --installed
+        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = DEFAULT_WORKSPACE.as_str())]
+        workspace: String,
     },
 
     /// Show details for a package
@@ -208,6 +665,46 @@ enum PackageCommand {
         /// Package to show
         #[arg(required = true)]
         pkg: String,
+
+        /// List every known version of the package and its registry instead
+        #[arg(long)]
+        versions: bool,
+    },
+
+    /// List the binaries an installed package provides
+    #[command(arg_required_else_help = true)]
+    ExportBin {
+        /// Installed package to list the binaries of
+        pkg: String,
+    },
+
+    /// Show which package provides a binary
+    #[command(arg_required_else_help = true)]
+    Which {
+        /// Workspace to use
+        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = DEFAULT_WORKSPACE.as_str())]
+        workspace: String,
+
+        /// Name of the binary
+        binary: String,
+    },
+
+    /// Show when packages were installed, updated, or removed, newest first
+    History {
+        /// Workspace to use
+        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = DEFAULT_WORKSPACE.as_str())]
+        workspace: String,
+    },
+
+    /// Show which packages in a workspace depend on a package
+    #[command(arg_required_else_help = true)]
+    Depends {
+        /// Package to look up
+        pkg: String,
+
+        /// Workspace to use
+        #[arg(short, long, env = "MATCHA_WORKSPACE", default_value = DEFAULT_WORKSPACE.as_str())]
+        workspace: String,
     },
 
     /// Garbage collect all installed packages that are not referenced by any workspace (alias: gc)
@@ -219,7 +716,13 @@ enum PackageCommand {
 enum WorkspaceCommand {
     /// Add a workspace (alias: a)
     #[command(arg_required_else_help = true, alias = "a")]
-    Add { workspace: String },
+    Add {
+        workspace: String,
+
+        /// Inherit the packages of this workspace
+        #[arg(long)]
+        parent: Option<String>,
+    },
 
     /// Remove a workspace (alias: rm)
     #[command(arg_required_else_help = true, alias = "rm")]
@@ -227,11 +730,35 @@ enum WorkspaceCommand {
 
     /// List all workspaces (alias: ls)
     #[command(alias = "ls")]
-    List,
+    List {
+        /// Show how many packages each workspace contains
+        #[arg(long)]
+        with_counts: bool,
+    },
 
     /// Run a shell in the context of a workspace (alias: sh)
     #[command(alias = "sh")]
     Shell { workspace: String },
+
+    /// Check a workspace for broken bin links
+    #[command(arg_required_else_help = true)]
+    Verify {
+        workspace: String,
+
+        /// Remove any broken links found
+        #[arg(long)]
+        fix: bool,
+    },
+
+    /// Print shell commands that activate a workspace, for use with eval
+    #[command(arg_required_else_help = true)]
+    Env {
+        workspace: String,
+
+        /// Shell syntax to print
+        #[arg(short, long, value_enum, default_value_t)]
+        shell: util::ShellKind,
+    },
 }
 
 #[derive(Parser, Debug)]
@@ -241,6 +768,14 @@ enum RegistryCommand {
     Add {
         /// Registry to add
         uri: String,
+
+        /// Only use this registry's packages in this workspace
+        #[arg(short, long)]
+        workspace: Option<String>,
+
+        /// Base64-encoded ed25519 key the registry's manifest has to be signed with
+        #[arg(long, value_name = "KEY")]
+        public_key: Option<String>,
     },
 
     /// Remove a package registry (alias: rm)
@@ -250,10 +785,67 @@ enum RegistryCommand {
         uri: String,
     },
 
+    /// Change the URI of a package registry (alias: update)
+    #[command(arg_required_else_help = true, alias = "update")]
+    SetUri {
+        /// Current URI of the registry
+        old: String,
+
+        /// New URI of the registry
+        new: String,
+    },
+
     /// List all registries (alias: ls)
     #[command(alias = "ls")]
-    List,
+    List {
+        /// Show more details, such as when each registry was last fetched
+        #[arg(short, long)]
+        long: bool,
+
+        /// Format to print the registries in, JSON includes the number of packages of each
+        #[arg(long, value_enum, default_value_t)]
+        format: util::OutputFormat,
+    },
 
     /// Fetch all registries
-    Fetch,
+    Fetch {
+        /// Only report which packages would be added, removed, or updated
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Fetch a single registry, even if it is up to date
+    #[command(arg_required_else_help = true)]
+    Refresh {
+        /// Registry to fetch
+        uri: String,
+    },
+
+    /// Check a manifest file for problems before publishing it
+    #[command(arg_required_else_help = true)]
+    Validate {
+        /// Path of the manifest to check
+        path: PathBuf,
+
+        /// Format to print the problems in
+        #[arg(long, value_enum, default_value_t)]
+        format: util::OutputFormat,
+    },
+}
+
+#[derive(Parser, Debug)]
+enum StateCommand {
+    /// Write a consistent copy of the internal state database
+    #[command(arg_required_else_help = true)]
+    Backup {
+        /// Path to write the backup to
+        path: PathBuf,
+    },
+
+    /// Replace the internal state database with a backup
+    #[command(arg_required_else_help = true)]
+    Restore {
+        /// Path of the backup to restore
+        path: PathBuf,
+    },
 }
@@ -1,34 +1,57 @@
 use std::{
+    collections::{HashMap, HashSet},
+    env::{join_paths, split_paths},
+    ffi::OsString,
     fmt::{Debug, Display},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     process::Stdio,
     str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use color_eyre::eyre::{anyhow, Context, Error, Result};
 use futures_util::StreamExt;
-use indicatif::MultiProgress;
+use indicatif::{MultiProgress, ProgressBar};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
 use sqlx::FromRow;
 use tempfile::TempDir;
 use tokio::{
-    fs::{create_dir_all, metadata, read_dir, rename, symlink, File},
-    io::AsyncWriteExt,
+    fs::{
+        copy, create_dir_all, metadata, read_dir, read_link, read_to_string, remove_dir_all,
+        remove_file, rename, symlink, symlink_metadata, write, File, OpenOptions,
+    },
+    io::{AsyncReadExt, AsyncWriteExt},
     pin,
     process::Command,
+    sync::{Mutex, OwnedMutexGuard},
 };
-use tracing::instrument;
+use tracing::{debug, info, instrument};
 use url::Url;
 
 use crate::{
     download::{DefaultDownloader, Downloader},
-    package::{KnownPackage, PackageSpec},
+    error::{DownloadFailed, UnknownPackage},
+    package::{
+        content_store_directory, package_directory, resolve_build_dependencies,
+        source_cache_directory, InstalledPackage, KnownPackage, PackageRequest, PackageSpec,
+    },
     state::State,
-    util::create_spinner,
+    util::{
+        create_spinner, current_platform, hash_directory, is_normalized_package_name,
+        is_safe_path_component, remove_read_only_dir, set_read_only, switch_to_progress_bar,
+        switch_to_spinner, LinkMode,
+    },
+    version::compare_versions,
     workspace::Workspace,
-    PACKAGE_ROOT,
+    BUILD_ROOT, PACKAGE_ROOT,
 };
 
+/// The manifest schema version this version of matcha understands.
+pub const MANIFEST_SCHEMA_VERSION: u32 = 1;
+
 /// Manifest metadata.
 #[derive(Debug, Default, Serialize)]
 pub struct Manifest {
@@ -46,6 +69,147 @@ pub struct Manifest {
 }
 
 impl Manifest {
+    /// Checks that the manifest can be used as a registry, failing with its first problem, see
+    /// [`Manifest::problems`].
+    pub fn validate(&self) -> Result<()> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(anyhow!(problem)),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a cycle of build dependencies between packages of this manifest, if there is one,
+    /// as the packages along it, starting and ending with the same one.
+    ///
+    /// Build dependencies are resolved within this manifest, those it doesn't have are ignored.
+    fn build_dependency_cycle(&self) -> Option<Vec<String>> {
+        fn visit<'a>(
+            manifest: &'a Manifest,
+            pkg: &'a Package,
+            path: &mut Vec<&'a Package>,
+            visited: &mut HashSet<(&'a str, &'a str)>,
+        ) -> Option<Vec<String>> {
+            if let Some(start) = path
+                .iter()
+                .position(|p| p.name == pkg.name && p.version == pkg.version)
+            {
+                return Some(
+                    path[start..]
+                        .iter()
+                        .chain([&pkg])
+                        .map(|p| p.to_string())
+                        .collect(),
+                );
+            }
+            if !visited.insert((&pkg.name, &pkg.version)) {
+                return None;
+            }
+            path.push(pkg);
+            for dependency in &pkg.build_dependencies {
+                let Ok(request) = dependency.parse::<PackageRequest>() else {
+                    continue;
+                };
+                if let Ok(dependency) = manifest.find_package(&request) {
+                    if let Some(cycle) = visit(manifest, dependency, path, visited) {
+                        return Some(cycle);
+                    }
+                }
+            }
+            path.pop();
+            None
+        }
+
+        let mut visited = HashSet::new();
+        self.packages
+            .iter()
+            .find_map(|pkg| visit(self, pkg, &mut vec![], &mut visited))
+    }
+
+    /// Returns a description of every problem with this manifest, such as an unsupported schema
+    /// version, package names that are not safe to use, or invalid sources.
+    ///
+    /// Names and versions become directories in the package root, so they must not be `.` or `..`,
+    /// or start with a dot at all. Package names are case-sensitive in the database, but might not
+    /// be on the file system, so only lowercase names are allowed. Sources must be absolute URLs
+    /// that name a file to download to, and each version of a package must only be listed once, as
+    /// the last entry would silently win otherwise.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+
+        if self.schema_version != MANIFEST_SCHEMA_VERSION {
+            problems.push(format!(
+                "unsupported schema version {}, expected {}",
+                self.schema_version, MANIFEST_SCHEMA_VERSION
+            ));
+        }
+
+        let mut seen = HashSet::new();
+        for pkg in &self.packages {
+            if !is_safe_path_component(&pkg.name) {
+                problems.push(format!("invalid package name {:?}", pkg.name));
+            } else if !is_normalized_package_name(&pkg.name) {
+                problems.push(format!(
+                    "invalid package name {}, package names must be lowercase",
+                    pkg.name
+                ));
+            }
+            if !is_safe_path_component(&pkg.version) {
+                problems.push(format!("invalid version {:?} of {}", pkg.version, pkg.name));
+            }
+            for name in pkg
+                .provides
+                .iter()
+                .filter(|n| !is_normalized_package_name(n))
+            {
+                problems.push(format!(
+                    "invalid provided name {name} of {pkg}, package names must be lowercase"
+                ));
+            }
+            for source in &pkg.sources {
+                if let Err(e) = source_file_name(source) {
+                    problems.push(format!("invalid source {source} of {pkg}: {e}"));
+                }
+            }
+            for dependency in pkg.dependencies.iter().chain(&pkg.build_dependencies) {
+                if let Err(e) = dependency.parse::<PackageRequest>() {
+                    problems.push(format!("invalid dependency {dependency} of {pkg}: {e}"));
+                }
+            }
+            if !seen.insert((&pkg.name, &pkg.version)) {
+                problems.push(format!("duplicate package {pkg}"));
+            }
+        }
+
+        if let Some(cycle) = self.build_dependency_cycle() {
+            problems.push(format!("build dependency cycle: {}", cycle.join(" -> ")));
+        }
+
+        problems
+    }
+
+    /// Returns the latest package in this manifest that satisfies the request.
+    pub fn find_package(&self, request: &PackageRequest) -> Result<&Package> {
+        let mut candidates = self
+            .packages
+            .iter()
+            .filter(|p| p.name == request.name)
+            .collect::<Vec<_>>();
+        // Same order as known package versions in the database.
+        candidates.sort_by(|a, b| compare_versions(&b.version, &a.version));
+
+        candidates
+            .iter()
+            .find(|p| request.version.matches(&p.version))
+            .copied()
+            .ok_or_else(|| {
+                UnknownPackage {
+                    request: request.to_string(),
+                    known_versions: candidates.iter().map(|p| p.version.clone()).collect(),
+                }
+                .into()
+            })
+    }
+
     /// Sets the URI of the registry this manifest is from.
     pub fn set_registry_uri(&mut self, uri: &str) {
         self.uri = Some(uri.to_string());
@@ -68,7 +232,23 @@ impl<'de> Deserialize<'de> for Manifest {
             homepage: Option<String>,
             license: Option<String>,
             source: Option<String>,
+            #[serde(default)]
+            sources: Vec<String>,
             build: Option<String>,
+            #[serde(default)]
+            env: HashMap<String, String>,
+            #[serde(default)]
+            provides: Vec<String>,
+            #[serde(default)]
+            dependencies: Vec<String>,
+            #[serde(default)]
+            build_dependencies: Vec<String>,
+            post_install: Option<String>,
+            pre_remove: Option<String>,
+            #[serde(default)]
+            artifacts: Vec<String>,
+            #[serde(default)]
+            platforms: Vec<String>,
         }
 
         #[derive(Deserialize)]
@@ -90,8 +270,21 @@ impl<'de> Deserialize<'de> for Manifest {
                 description: temp_package.description,
                 homepage: temp_package.homepage,
                 license: temp_package.license,
-                source: temp_package.source,
+                // `source` is a single-source alias for `sources`, and tried first.
+                sources: temp_package
+                    .source
+                    .into_iter()
+                    .chain(temp_package.sources)
+                    .collect(),
                 build: temp_package.build,
+                env: temp_package.env,
+                provides: temp_package.provides,
+                dependencies: temp_package.dependencies,
+                build_dependencies: temp_package.build_dependencies,
+                post_install: temp_package.post_install,
+                pre_remove: temp_package.pre_remove,
+                artifacts: temp_package.artifacts,
+                platforms: temp_package.platforms,
                 ..Default::default()
             })
             .collect();
@@ -127,16 +320,54 @@ pub struct Package {
     pub homepage: Option<String>,
     /// The license of the package.
     pub license: Option<String>,
-    /// The source of the package. Can be `None` for meta packages.
-    pub source: Option<String>,
+    /// The sources of the package, tried in order until one succeeds. Can be empty for meta
+    /// packages.
+    #[sqlx(json)]
+    pub sources: Vec<String>,
     /// The build command of the package.
     pub build: Option<String>,
+    /// Extra environment variables for the build command.
+    #[sqlx(json)]
+    pub env: HashMap<String, String>,
+    /// Virtual package names this package provides, so that it can be installed by them.
+    #[sqlx(json)]
+    pub provides: Vec<String>,
+    /// Requests for packages this package needs, such as `foo` or `foo@~1`.
+    #[sqlx(json)]
+    pub dependencies: Vec<String>,
+    /// Requests for packages only needed to build this package, such as compilers.
+    ///
+    /// Their binaries are on `PATH` during the build, but they are not added to the workspace.
+    #[sqlx(json)]
+    pub build_dependencies: Vec<String>,
+    /// A script to run after the package has been added to a workspace.
+    pub post_install: Option<String>,
+    /// A script to run before the package is removed from a workspace.
+    pub pre_remove: Option<String>,
+    /// Files the build is expected to produce, relative to the output directory.
+    #[sqlx(json)]
+    pub artifacts: Vec<String>,
+    /// Platforms the package can be installed on, as `<os>-<arch>`. Empty means any platform.
+    #[sqlx(json)]
+    pub platforms: Vec<String>,
     /// The registry this package is from.
     #[serde(skip)]
     pub registry: Option<String>,
 }
 
 impl Package {
+    /// Returns an error if the package can't be installed on the current platform.
+    pub fn check_platform(&self) -> Result<()> {
+        let platform = current_platform();
+        if !self.platforms.is_empty() && !self.platforms.contains(&platform) {
+            return Err(anyhow!(
+                "package {} is not available for your platform ({platform})",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
     /// Returns whether this package is tied to a registry.
     ///
     /// If this is false, we have encountered a bug, because all packages should come from a
@@ -159,12 +390,16 @@ pub struct InstallLog {
     pub package_name: String,
     /// The exit code of the build.
     pub exit_code: i32,
-    /// The stdout of the build.
+    /// The stdout of the build, or both stdout and stderr if the output was merged.
     pub stdout: String,
-    /// The stderr of the build.
+    /// The stderr of the build, empty if the output was merged.
     pub stderr: String,
-    /// Whether this package was freshly installed.
-    pub new_install: bool,
+    /// Whether stdout and stderr were captured as a single stream.
+    pub merged_output: bool,
+    /// How long the build took.
+    pub duration: Duration,
+    /// Where the build directory of a failed build was kept, if it was.
+    pub kept_build_dir: Option<PathBuf>,
 }
 
 impl InstallLog {
@@ -175,7 +410,9 @@ impl InstallLog {
             exit_code: 0,
             stdout: String::new(),
             stderr: String::new(),
-            new_install: false,
+            merged_output: false,
+            duration: Duration::ZERO,
+            kept_build_dir: None,
         }
     }
 
@@ -185,104 +422,777 @@ impl InstallLog {
     }
 }
 
+/// Options that control how packages are installed.
+#[derive(Debug, Clone, Default)]
+pub struct InstallOptions {
+    /// Keep the build directories of failed builds for inspection.
+    pub keep_build: bool,
+    /// Replaces the build command of the package, only allowed when installing a single package.
+    pub build_override: Option<String>,
+    /// Allow replacing packages in the workspace with older versions.
+    pub allow_downgrade: bool,
+    /// Replace binaries of other packages in the workspace with the same name.
+    pub force: bool,
+    /// How to place package binaries in the workspace.
+    pub link_mode: LinkMode,
+    /// Only download the package sources, without building or adding the packages.
+    pub no_build: bool,
+    /// Capture the build's stdout and stderr as a single interleaved stream.
+    pub merge_output: bool,
+    /// Install packages one at a time, ordered by name, printing each build's output right away.
+    pub serial: bool,
+}
+
+/// The stage an [`InstallPlan`] is in, i.e. what it does when advanced next.
+#[derive(Debug)]
+pub enum InstallStage {
+    /// The package source has to be downloaded.
+    Download,
+    /// The package has to be built from the downloaded source.
+    Build {
+        /// The temporary directory the build runs in.
+        build_dir: TempDir,
+        /// The absolute path of the downloaded source, if the package has one.
+        source: Option<PathBuf>,
+    },
+    /// The build outputs have to be moved to the package directory, and the package registered as
+    /// installed.
+    Stage {
+        /// The temporary directory holding the build outputs.
+        output_dir: TempDir,
+    },
+    /// The package's binaries have to be placed in the workspace.
+    Link {
+        /// The directory of the installed package.
+        pkg_dir: PathBuf,
+    },
+    /// The install is finished, either successfully or with a failed build.
+    Done,
+}
+
+/// A package install, split into stages that can be advanced one at a time.
+///
+/// Packages that are already installed start out at [`InstallStage::Link`], everything else at
+/// [`InstallStage::Download`]. A failed build skips straight to [`InstallStage::Done`], with the
+/// failure recorded in the log. [`Package::install`] drives a plan through all of its stages.
+///
+/// Until a plan is done or dropped, planning another install of the same package in this process
+/// waits for it.
+pub struct InstallPlan<'a> {
+    package: &'a Package,
+    state: &'a State,
+    workspace: &'a Workspace,
+    options: &'a InstallOptions,
+    mpb: MultiProgress,
+    spinner: ProgressBar,
+    /// The installed package this install rebuilds, if its files were corrupted.
+    rebuilds: Option<InstalledPackage>,
+    /// The packages being built that this one is a build dependency of, outermost first.
+    dependents: Vec<KnownPackage>,
+    /// The build dependencies the package was built with.
+    build_dependencies: Vec<KnownPackage>,
+    log: InstallLog,
+    stage: InstallStage,
+    /// Keeps other installs of the same package in this process waiting until this one is done.
+    lock: Option<OwnedMutexGuard<()>>,
+}
+
+/// Per-package locks, so that concurrent installs sharing a build dependency don't build and stage
+/// it at the same time.
+static INSTALL_LOCKS: Lazy<std::sync::Mutex<HashMap<KnownPackage, Arc<Mutex<()>>>>> =
+    Lazy::new(Default::default);
+
+/// Waits until no other install of the package is running in this process, and locks it.
+async fn lock_package(package: &Package) -> OwnedMutexGuard<()> {
+    let lock = INSTALL_LOCKS
+        .lock()
+        .expect("poisoned install locks")
+        .entry(KnownPackage::from(package))
+        .or_default()
+        .clone();
+    lock.lock_owned().await
+}
+
+impl<'a> InstallPlan<'a> {
+    /// Plans the install of a package into a workspace.
+    ///
+    /// Installed packages whose files are corrupted are built again. Their files are only replaced
+    /// once the new build is stored, so a failed rebuild leaves them as they were.
+    pub async fn new(
+        package: &'a Package,
+        state: &'a State,
+        workspace: &'a Workspace,
+        mpb: &MultiProgress,
+        options: &'a InstallOptions,
+    ) -> Result<InstallPlan<'a>> {
+        package.check_platform()?;
+        let spinner = create_spinner(&format!("{package}: Preparing..."), Some(mpb));
+        let lock = lock_package(package).await;
+
+        let installed_package = state
+            .get_installed_package(&KnownPackage::from(package))
+            .await?;
+        if let Some(installed_package) = &installed_package {
+            package.check_installed_registry(installed_package)?;
+            package.check_installed_build(installed_package)?;
+        }
+        let (stage, rebuilds) = match installed_package {
+            Some(installed_package) if package.is_intact(&installed_package).await => (
+                InstallStage::Link {
+                    pkg_dir: installed_package.directory(),
+                },
+                None,
+            ),
+            Some(installed_package) => {
+                mpb.suspend(|| {
+                    status!("Warning: installed files of {package} are corrupted, rebuilding it")
+                });
+                (InstallStage::Download, Some(installed_package))
+            }
+            None => (InstallStage::Download, None),
+        };
+
+        Ok(Self {
+            package,
+            state,
+            workspace,
+            options,
+            mpb: mpb.clone(),
+            spinner,
+            rebuilds,
+            dependents: vec![],
+            build_dependencies: vec![],
+            log: InstallLog::new(package),
+            stage,
+            lock: Some(lock),
+        })
+    }
+
+    /// Returns the stage the install is in.
+    pub fn stage(&self) -> &InstallStage {
+        &self.stage
+    }
+
+    /// Returns if all stages have run.
+    pub fn is_done(&self) -> bool {
+        matches!(self.stage, InstallStage::Done)
+    }
+
+    /// Runs the current stage, and moves on to the next one.
+    pub async fn advance(self) -> Result<InstallPlan<'a>> {
+        self.advance_with(&DefaultDownloader::default()).await
+    }
+
+    /// Like [`InstallPlan::advance`], but downloads sources with the given downloader.
+    #[instrument(skip_all, fields(package = %self.package))]
+    pub async fn advance_with(mut self, downloader: &impl Downloader) -> Result<InstallPlan<'a>> {
+        self.stage = match std::mem::replace(&mut self.stage, InstallStage::Done) {
+            InstallStage::Download => self.download(downloader).await?,
+            InstallStage::Build { build_dir, source } => {
+                self.build(build_dir, source.as_deref(), downloader).await?
+            }
+            InstallStage::Stage { output_dir } => self.stage_output(output_dir).await?,
+            InstallStage::Link { pkg_dir } => self.link(&pkg_dir).await?,
+            InstallStage::Done => InstallStage::Done,
+        };
+        if self.is_done() {
+            self.lock = None;
+        }
+        Ok(self)
+    }
+
+    /// Runs all remaining stages, and returns the install log.
+    pub async fn run(mut self) -> Result<InstallLog> {
+        while !self.is_done() {
+            self = self.advance().await?;
+        }
+        Ok(self.log)
+    }
+
+    async fn download(&self, downloader: &impl Downloader) -> Result<InstallStage> {
+        self.spinner
+            .set_message(format!("{}: Downloading...", self.package));
+        let (build_dir, source) = self
+            .package
+            .download_source(downloader, &self.spinner)
+            .await?;
+        switch_to_spinner(&self.spinner);
+        Ok(InstallStage::Build { build_dir, source })
+    }
+
+    async fn build(
+        &mut self,
+        build_dir: TempDir,
+        source: Option<&Path>,
+        downloader: &impl Downloader,
+    ) -> Result<InstallStage> {
+        let package = self.package;
+        let build_dependencies = self.install_build_dependencies(downloader).await?;
+        self.spinner.set_message(format!("{package}: Building..."));
+        let prefix = PACKAGE_ROOT.get().map(|_| self.package_directory());
+        let (output_dir, mut log) = package
+            .build(
+                &build_dir,
+                source,
+                prefix.as_deref(),
+                &build_dependencies,
+                self.options.merge_output,
+            )
+            .await?;
+        if !log.is_success() {
+            if self.options.keep_build {
+                log.kept_build_dir = Some(build_dir.keep());
+            }
+            self.log = log;
+            self.spinner
+                .finish_with_message(format!("{package}: Build failed"));
+            return Ok(InstallStage::Done);
+        }
+        self.log = log;
+
+        let missing_artifacts = package.missing_artifacts(output_dir.path());
+        if !missing_artifacts.is_empty() {
+            self.spinner
+                .finish_with_message(format!("{package}: Build failed"));
+            return Err(anyhow!(
+                "build of {package} did not produce the declared artifacts: {}",
+                missing_artifacts.join(", ")
+            ));
+        }
+
+        Ok(InstallStage::Stage { output_dir })
+    }
+
+    /// Installs the build dependencies of the package, without adding them to the workspace, and
+    /// returns their package directories.
+    ///
+    /// Fails if a build dependency needs this package to be built itself, directly or indirectly.
+    async fn install_build_dependencies(
+        &mut self,
+        downloader: &impl Downloader,
+    ) -> Result<Vec<PathBuf>> {
+        let package = self.package;
+        let dependencies = resolve_build_dependencies(package, self.state, self.workspace).await?;
+        let mut dependents = self.dependents.clone();
+        dependents.push(KnownPackage::from(package));
+        let mut dirs = vec![];
+        for dependency in &dependencies {
+            let known_dependency = KnownPackage::from(dependency);
+            if let Some(start) = dependents.iter().position(|p| *p == known_dependency) {
+                let cycle = dependents[start..]
+                    .iter()
+                    .chain([&known_dependency])
+                    .map(|p| p.to_string())
+                    .collect::<Vec<_>>();
+                return Err(anyhow!("build dependency cycle: {}", cycle.join(" -> ")));
+            }
+            self.spinner.set_message(format!(
+                "{package}: Installing build dependency {dependency}..."
+            ));
+            let mut plan = Box::pin(InstallPlan::new(
+                dependency,
+                self.state,
+                self.workspace,
+                &self.mpb,
+                self.options,
+            ))
+            .await?;
+            plan.dependents = dependents.clone();
+            // Stop before the link stage, which would add it to the workspace.
+            while !matches!(plan.stage, InstallStage::Link { .. } | InstallStage::Done) {
+                plan = Box::pin(plan.advance_with(downloader)).await?;
+            }
+            plan.spinner.finish_and_clear();
+            let InstallStage::Link { pkg_dir } = plan.stage else {
+                return Err(anyhow!(
+                    "build dependency {dependency} of {package} failed to build, exited with code {}",
+                    plan.log.exit_code
+                ));
+            };
+            dirs.push(pkg_dir);
+            self.build_dependencies.push(known_dependency);
+        }
+        Ok(dirs)
+    }
+
+    async fn stage_output(&self, output_dir: TempDir) -> Result<InstallStage> {
+        let package = self.package;
+        self.spinner
+            .set_message(format!("{package}: Installing..."));
+        let known_package = KnownPackage::from(package);
+        let pkg_dir = match &self.rebuilds {
+            Some(corrupted) => self.replace_corrupted(corrupted, &output_dir).await?,
+            None => {
+                let pkg_dir = self.package_directory();
+                let mut partial_install = PartialInstall::new(pkg_dir.clone());
+                let (pkg_dir, content_hash) = package
+                    .add_to_package_directory(
+                        &output_dir,
+                        &pkg_dir,
+                        false,
+                        Some(&mut partial_install),
+                    )
+                    .await?;
+                self.state
+                    .add_installed_package(
+                        &known_package,
+                        package.registry.as_deref(),
+                        Some(&content_hash),
+                    )
+                    .await?;
+                if let Some(build) = &self.options.build_override {
+                    self.state.set_build_override(&known_package, build).await?;
+                }
+                partial_install.complete();
+                pkg_dir
+            }
+        };
+        self.state
+            .add_build_dependencies(&known_package, &self.build_dependencies)
+            .await?;
+        package.remove_cached_source().await?;
+
+        Ok(InstallStage::Link { pkg_dir })
+    }
+
+    /// Returns the directory the package is installed to, which is where a rebuilt package is
+    /// already installed, even if its registry moved since.
+    fn package_directory(&self) -> PathBuf {
+        match &self.rebuilds {
+            Some(installed) => installed.directory(),
+            None => self.package.package_directory(),
+        }
+    }
+
+    /// Replaces the files of a corrupted installed package with the build outputs, and returns its
+    /// package directory.
+    ///
+    /// The old content store entry is removed once no installed package uses it anymore.
+    async fn replace_corrupted(
+        &self,
+        corrupted: &InstalledPackage,
+        output_dir: &TempDir,
+    ) -> Result<PathBuf> {
+        let package = self.package;
+        let (pkg_dir, content_hash) = package
+            .add_to_package_directory(output_dir, &corrupted.directory(), true, None)
+            .await?;
+        self.state
+            .set_content_hash(&KnownPackage::from(package), &content_hash)
+            .await?;
+
+        if let Some(old_hash) = corrupted.content_hash.as_ref() {
+            let old_store_path = content_store_directory().join(old_hash);
+            if *old_hash != content_hash
+                && old_store_path.try_exists()?
+                && !self.state.is_content_hash_referenced(old_hash).await?
+            {
+                remove_read_only_dir(&old_store_path)
+                    .wrap_err("failed to remove corrupted content store entry")?;
+            }
+        }
+        Ok(pkg_dir)
+    }
+
+    async fn link(&self, pkg_dir: &Path) -> Result<InstallStage> {
+        let package = self.package;
+        self.spinner
+            .set_message(format!("{package}: Adding to workspace..."));
+        package
+            .add_to_workspace(
+                self.state,
+                pkg_dir,
+                self.workspace,
+                self.options.force,
+                self.options.link_mode,
+            )
+            .await?;
+        if let Err(e) = package.run_post_install(pkg_dir, self.workspace).await {
+            // The package isn't added to the workspace, so its binaries must not stay either.
+            self.workspace
+                .remove_binaries(pkg_dir)
+                .await
+                .wrap_err("failed to remove binaries after failed post-install hook")?;
+            return Err(e);
+        }
+
+        self.spinner
+            .finish_with_message(format!("{package}: Installed"));
+        Ok(InstallStage::Done)
+    }
+}
+
 impl Package {
     /// Downloads, builds, and installs the package.
+    ///
+    /// Fresh installs are registered as installed packages. If the build fails, or the install is
+    /// interrupted before it is registered, no package directory is left behind.
+    ///
+    /// If `keep_build` is set, the build directory of a failed build is kept for inspection. If
+    /// `force` is set, binaries of other packages in the workspace with the same name are replaced.
+    /// Binaries are placed in the workspace according to `link_mode`. Options that concern which
+    /// packages get installed are ignored here.
+    ///
+    /// See [`InstallPlan`] to run the install one stage at a time.
     #[instrument(skip(state))]
     pub async fn install(
         &self,
         state: &State,
         workspace: &Workspace,
         mpb: &MultiProgress,
+        options: &InstallOptions,
     ) -> Result<InstallLog> {
-        let spinner = create_spinner(&format!("{self}: Preparing..."), Some(mpb));
-
-        if let Some(installed_package) = state
-            .get_installed_package(&KnownPackage::from_manifest_package(self))
+        InstallPlan::new(self, state, workspace, mpb, options)
             .await?
-        {
-            spinner.set_message(format!("{self}: Adding to workspace..."));
-            self.add_to_workspace(&installed_package.directory(), workspace)
-                .await?;
+            .run()
+            .await
+    }
 
-            spinner.finish_with_message(format!("{self}: Installed"));
-            Ok(InstallLog::new(self))
-        } else {
-            spinner.set_message(format!("{self}: Downloading..."));
-            let (build_dir, download_file_name) = self.download_source(&DefaultDownloader).await?;
+    /// Adds the already installed package to a workspace, without downloading or building it.
+    ///
+    /// Fails if the package is not installed, or if its installed files are corrupted.
+    #[instrument(skip(state))]
+    pub async fn link_installed(
+        &self,
+        state: &State,
+        workspace: &Workspace,
+        options: &InstallOptions,
+    ) -> Result<()> {
+        let installed = state
+            .get_installed_package(&KnownPackage::from(self))
+            .await?
+            .ok_or_else(|| anyhow!("package {self} is not installed"))?;
+        self.check_installed_registry(&installed)?;
+        self.check_installed_build(&installed)?;
+        if !self.is_intact(&installed).await {
+            return Err(anyhow!(
+                "installed files of {self} are corrupted, reinstall it instead"
+            ));
+        }
+        let pkg_dir = installed.directory();
+        self.add_to_workspace(state, &pkg_dir, workspace, options.force, options.link_mode)
+            .await?;
+        self.run_post_install(&pkg_dir, workspace).await
+    }
 
-            spinner.set_message(format!("{self}: Building..."));
-            let (output_dir, log) = self.build(&build_dir, &download_file_name).await?;
+    /// Returns an error if the installed package of the same name and version was installed from
+    /// another registry.
+    ///
+    /// Installed packages are only identified by their name and version, so the two can't be
+    /// installed side by side. Packages installed before their registry was recorded match any.
+    fn check_installed_registry(&self, installed: &InstalledPackage) -> Result<()> {
+        match (&self.registry, &installed.registry) {
+            (Some(registry), Some(installed_registry)) if registry != installed_registry => {
+                Err(anyhow!(
+                    "{self} from {registry} collides with the one installed from \
+                     {installed_registry}, remove it from all workspaces and garbage collect it \
+                     first"
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
 
-            spinner.set_message(format!("{self}: Installing..."));
-            let pkg_dir = self.add_to_package_directory(&output_dir).await?;
+    /// Returns an error if the installed package of the same name and version was built with a
+    /// build override other than this package's build command.
+    ///
+    /// Such a build doesn't match the registry, so it must not stand in for the registry's.
+    fn check_installed_build(&self, installed: &InstalledPackage) -> Result<()> {
+        match &installed.build_override {
+            Some(build) if self.build.as_ref() != Some(build) => Err(anyhow!(
+                "{self} is installed with an overridden build command, remove it from all \
+                 workspaces and garbage collect it first"
+            )),
+            _ => Ok(()),
+        }
+    }
 
-            spinner.set_message(format!("{self}: Adding to workspace..."));
-            self.add_to_workspace(&pkg_dir, workspace).await?;
+    /// Returns if an installed package's files still match the content hash they were installed
+    /// with.
+    ///
+    /// Packages installed before content hashes were recorded can't be checked, and are assumed to
+    /// be intact.
+    async fn is_intact(&self, installed: &InstalledPackage) -> bool {
+        let Some(content_hash) = &installed.content_hash else {
+            return true;
+        };
+        let dir = installed.directory();
+        dir.is_dir() && hash_directory(&dir).await.ok().as_ref() == Some(content_hash)
+    }
 
-            spinner.finish_with_message(format!("{self}: Installed"));
-            Ok(log)
+    /// Runs the package's post-install hook, if any.
+    async fn run_post_install(&self, pkg_dir: &Path, workspace: &Workspace) -> Result<()> {
+        if let Some(hook) = &self.post_install {
+            workspace
+                .run_hook(hook, pkg_dir)
+                .await
+                .wrap_err(format!("post-install hook of {self} failed"))?;
         }
+        Ok(())
     }
 
-    /// Downloads the package source to a temporary build directory.
+    /// Downloads the package source to the `src` directory of a temporary build directory.
+    ///
+    /// A prefetched source is used if there is one, otherwise sources are tried in order until one
+    /// of them succeeds.
     ///
-    /// Returns the build directory and the name of the downloaded file.
-    #[instrument(skip(downloader))]
-    async fn download_source(&self, downloader: &impl Downloader) -> Result<(TempDir, String)> {
-        let build_dir = TempDir::new().wrap_err("failed to create build directory")?;
+    /// If the download reports a content length, `progress` is turned into a progress bar that
+    /// tracks the downloaded bytes, otherwise it is left as is.
+    ///
+    /// Returns the build directory and the absolute path of the downloaded file, if any.
+    #[instrument(skip(downloader, progress))]
+    async fn download_source(
+        &self,
+        downloader: &impl Downloader,
+        progress: &ProgressBar,
+    ) -> Result<(TempDir, Option<PathBuf>)> {
+        let build_dir = create_build_temp_dir().wrap_err("failed to create build directory")?;
 
         // Download the package source, if any.
-        let mut download_file_name = String::new();
-        if let Some(source) = &self.source {
-            let source = Url::parse(source).wrap_err("invalid source URL")?;
-
-            // Stream the download to a file.
-            let (_size, download) = downloader.download_stream(source.as_str()).await?;
-            pin!(download);
-            download_file_name = source
-                .path_segments()
-                .ok_or(anyhow!("invalid package download source"))?
-                .last()
-                .unwrap_or("matcha_download")
-                .to_string();
-            let mut file = File::create(build_dir.path().join(&download_file_name)).await?;
-            while let Some(chunk) = download.next().await {
-                let chunk = chunk?;
-                file.write_all(&chunk).await?;
+        if self.sources.is_empty() {
+            return Ok((build_dir, None));
+        }
+        let src_dir = build_dir.path().join("src");
+        create_dir_all(&src_dir)
+            .await
+            .wrap_err("failed to create source directory")?;
+
+        // Use a prefetched source, if there is one.
+        if let Some(cached) = self.cached_source().await? {
+            let download_path =
+                src_dir.join(cached.file_name().expect("cached source has no name"));
+            copy(&cached, &download_path)
+                .await
+                .wrap_err("failed to copy prefetched source")?;
+            return Ok((build_dir, Some(download_path)));
+        }
+
+        let download_path = self.download_to(&src_dir, downloader, progress).await?;
+        Ok((build_dir, Some(download_path)))
+    }
+
+    /// Downloads the package source into the source cache, without building the package.
+    ///
+    /// A later install uses the cached source instead of downloading it again. Returns the path of
+    /// the cached source, if the package has one.
+    #[instrument(skip(downloader, progress))]
+    pub async fn prefetch_source(
+        &self,
+        downloader: &impl Downloader,
+        progress: &ProgressBar,
+    ) -> Result<Option<PathBuf>> {
+        if self.sources.is_empty() {
+            return Ok(None);
+        }
+        if let Some(cached) = self.cached_source().await? {
+            return Ok(Some(cached));
+        }
+        let cache_dir = self.source_cache_directory()?;
+        create_dir_all(&cache_dir)
+            .await
+            .wrap_err("failed to create source cache directory")?;
+        let download_path = self.download_to(&cache_dir, downloader, progress).await?;
+        Ok(Some(download_path))
+    }
+
+    /// Returns the prefetched source of this package, if there is one.
+    async fn cached_source(&self) -> Result<Option<PathBuf>> {
+        // Without a package root there is no cache to look in.
+        if PACKAGE_ROOT.get().is_none() {
+            return Ok(None);
+        }
+        let mut reader = match read_dir(self.source_cache_directory()?).await {
+            Ok(reader) => reader,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).wrap_err("failed to read source cache"),
+        };
+        // Skip downloads that were interrupted.
+        while let Some(entry) = reader.next_entry().await? {
+            if !entry.file_name().to_string_lossy().ends_with(PART_SUFFIX) {
+                return Ok(Some(entry.path()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Removes the prefetched source of this package, if there is one.
+    async fn remove_cached_source(&self) -> Result<()> {
+        match remove_dir_all(self.source_cache_directory()?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).wrap_err("failed to remove prefetched source")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the directory this package's source is prefetched to.
+    fn source_cache_directory(&self) -> Result<PathBuf> {
+        PACKAGE_ROOT
+            .get()
+            .ok_or(anyhow!("package root is not initialized"))?;
+        Ok(source_cache_directory(
+            &self.name,
+            &self.version,
+            self.registry.as_deref(),
+        ))
+    }
+
+    /// Downloads the package source into a directory.
+    ///
+    /// Sources are tried in order until one of them succeeds. Returns the path of the downloaded
+    /// file.
+    #[instrument(skip(downloader, progress))]
+    async fn download_to(
+        &self,
+        dir: &Path,
+        downloader: &impl Downloader,
+        progress: &ProgressBar,
+    ) -> Result<PathBuf> {
+        let mut failures = vec![];
+        for source in &self.sources {
+            match self.download_from(source, dir, downloader, progress).await {
+                Ok((download_path, sha256)) => {
+                    info!(%sha256, "downloaded {}", download_path.display());
+                    return Ok(download_path);
+                }
+                Err(e) => failures.push(format!("  {source}: {e:#}")),
             }
         }
 
-        Ok((build_dir, download_file_name))
+        Err(DownloadFailed {
+            package: self.to_string(),
+            failures,
+        }
+        .into())
+    }
+
+    /// Downloads a single source into the source directory.
+    ///
+    /// The download is written to a `.part` file first, which is moved into place once complete.
+    /// If the download breaks off after making progress, it is resumed from where it stopped, up
+    /// to [`DOWNLOAD_ATTEMPTS`] times. A `.part` file left behind by an earlier, interrupted
+    /// download is resumed as well.
+    ///
+    /// The SHA-256 of the download is computed while streaming it, so that large sources don't
+    /// need to be read a second time.
+    ///
+    /// Returns the path of the downloaded file, and its hex-encoded SHA-256.
+    #[instrument(skip(downloader, progress))]
+    async fn download_from(
+        &self,
+        source: &str,
+        src_dir: &Path,
+        downloader: &impl Downloader,
+        progress: &ProgressBar,
+    ) -> Result<(PathBuf, String)> {
+        let download_file_name = source_file_name(source)?;
+        let source = Url::parse(source).wrap_err("invalid source URL")?;
+        let download_path = src_dir.join(&download_file_name);
+        let part_path = src_dir.join(format!("{download_file_name}{PART_SUFFIX}"));
+
+        let mut attempt = 1;
+        let result = loop {
+            let before = file_size(&part_path).await;
+            match download_part(source.as_str(), &part_path, downloader, progress).await {
+                Err(e) if attempt < DOWNLOAD_ATTEMPTS && file_size(&part_path).await > before => {
+                    debug!("resuming interrupted download of {source}: {e:#}");
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+
+        let validator_path = part_validator_path(&part_path);
+        if validator_path.exists() {
+            remove_file(&validator_path).await?;
+        }
+        match result {
+            Ok(sha256) => {
+                rename(&part_path, &download_path)
+                    .await
+                    .wrap_err("failed to move finished download into place")?;
+                Ok((download_path, sha256))
+            }
+            Err(e) => {
+                // Don't leave partial downloads around for the next source.
+                if part_path.exists() {
+                    remove_file(&part_path).await?;
+                }
+                Err(e)
+            }
+        }
     }
 
     /// Builds the package.
     ///
+    /// The build runs in the build directory, and gets passed `MATCHA_BUILD_DIR`, `MATCHA_SOURCE`
+    /// (the absolute path of the downloaded source, or empty), `MATCHA_OUTPUT`, and
+    /// `MATCHA_PREFIX`. The prefix is the given final package directory, which points to the output
+    /// directory while building, so that builds can bake in paths that stay valid once installed.
+    /// Without one, `MATCHA_PREFIX` is empty.
+    ///
+    /// With `merge_output`, stderr is redirected to stdout, so that the log keeps the order in
+    /// which the build wrote to both.
+    ///
+    /// The `bin` directories of the given build dependency directories are put on `PATH`.
+    ///
     /// Returns the output directory.
     #[instrument]
     async fn build(
         &self,
         build_dir: &TempDir,
-        download_file_name: &str,
+        source: Option<&Path>,
+        prefix: Option<&Path>,
+        build_dependencies: &[PathBuf],
+        merge_output: bool,
     ) -> Result<(TempDir, InstallLog)> {
-        let output_dir = TempDir::new().wrap_err("failed to create output directory")?;
+        let output_dir = create_build_temp_dir().wrap_err("failed to create output directory")?;
         let mut log = InstallLog::new(self);
-        log.new_install = true;
+        log.merged_output = merge_output;
 
         // Perform build steps, if any.
         if let Some(build) = &self.build {
+            let start = Instant::now();
+            let redirect = if merge_output { "exec 2>&1\n" } else { "" };
+            let path = build_path(build_dependencies, self.env.get("PATH"))?;
+            let prefix = match prefix {
+                Some(pkg_dir) => Some(stage_prefix(pkg_dir, output_dir.path()).await?),
+                None => None,
+            };
             let output = Command::new("zsh")
+                // Don't keep building if the install gets interrupted.
+                .kill_on_drop(true)
                 .arg("-c")
-                .arg(format!("set -e\n{build}"))
+                .arg(format!("set -e\n{redirect}{build}"))
                 .current_dir(build_dir.path())
-                .env("MATCHA_SOURCE", download_file_name)
+                // Set these first, so that they cannot override matcha's own variables.
+                .envs(&self.env)
+                .envs(path.iter().map(|path| ("PATH", path)))
+                .env("MATCHA_BUILD_DIR", build_dir.path())
+                .env("MATCHA_SOURCE", source.unwrap_or(Path::new("")))
                 .env("MATCHA_OUTPUT", output_dir.path())
+                .env(
+                    "MATCHA_PREFIX",
+                    prefix
+                        .as_ref()
+                        .map_or(Path::new(""), |(prefix, _)| prefix.as_path()),
+                )
                 .stdout(Stdio::piped())
                 .stderr(Stdio::piped())
                 .spawn()
-                .wrap_err("failed to spawn build command")?
-                .wait_with_output()
-                .await?;
+                .wrap_err("failed to spawn build command");
+            let output = match output {
+                Ok(child) => child.wait_with_output().await.map_err(Error::from),
+                Err(e) => Err(e),
+            };
+            if let Some((prefix, previous)) = &prefix {
+                unstage_prefix(prefix, previous.as_deref())
+                    .await
+                    .wrap_err("failed to unlink package directory from build output")?;
+            }
+            let output = output?;
 
+            log.duration = start.elapsed();
             log.exit_code = output.status.code().unwrap_or(1);
             log.stdout = String::from_utf8_lossy(&output.stdout).to_string();
             log.stderr = String::from_utf8_lossy(&output.stderr).to_string();
@@ -291,87 +1201,504 @@ impl Package {
         Ok((output_dir, log))
     }
 
+    /// Returns the declared artifacts that are missing from the build outputs.
+    ///
+    /// Artifacts pointing outside of the output directory are always considered missing.
+    fn missing_artifacts(&self, output_dir: &Path) -> Vec<String> {
+        self.artifacts
+            .iter()
+            .filter(|artifact| {
+                let path = Path::new(artifact);
+                let inside = path
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+                !inside || !output_dir.join(path).exists()
+            })
+            .cloned()
+            .collect()
+    }
+
     /// Installs the package's build outputs to the package directory.
     ///
-    /// Returns the package's directory.
-    #[instrument]
-    async fn add_to_package_directory(&self, output_dir: &TempDir) -> Result<PathBuf> {
-        // Create the package directory.
-        let pkg_path = PACKAGE_ROOT
-            .get()
-            .ok_or(anyhow!("package root is not initialized"))?
-            .join(&self.name)
-            .join(&self.version);
-        create_dir_all(&pkg_path)
+    /// The outputs are stored in the content store, keyed by their hash, and the package directory
+    /// links to the store entry. If identical outputs are already stored, they are reused. Store
+    /// entries are read-only, as other packages might share them. A new store entry is removed
+    /// along with the package directory if `partial_install` doesn't complete.
+    ///
+    /// With `replace`, the package directory replaces an existing one, and a store entry whose
+    /// files no longer match its hash is replaced by the outputs. Either is swapped in only once
+    /// the outputs are in place, so nothing is missing in between.
+    ///
+    /// Returns the package's directory and the content hash.
+    #[instrument(skip(partial_install))]
+    async fn add_to_package_directory(
+        &self,
+        output_dir: &TempDir,
+        pkg_path: &Path,
+        replace: bool,
+        mut partial_install: Option<&mut PartialInstall>,
+    ) -> Result<(PathBuf, String)> {
+        let content_hash = hash_directory(output_dir.path())
             .await
-            .wrap_err("failed to create package directory")?;
+            .wrap_err("failed to hash build outputs")?;
+
+        // Move build outputs to the content store, unless they are already there.
+        let store_path = content_store_directory().join(&content_hash);
+        if !store_path.try_exists()? {
+            create_dir_all(content_store_directory())
+                .await
+                .wrap_err("failed to create content store")?;
+            match rename(output_dir, &store_path).await {
+                Ok(()) => {
+                    if let Some(partial_install) = partial_install.as_mut() {
+                        partial_install.add_store_entry(store_path.clone());
+                    }
+                    set_read_only(&store_path)
+                        .wrap_err("failed to make content store entry read-only")?;
+                }
+                // Another install might have stored the same outputs in the meantime.
+                Err(_) if store_path.try_exists()? => {}
+                Err(e) => {
+                    return Err(e).wrap_err("failed to move build outputs into content store")
+                }
+            }
+        } else if replace && hash_directory(&store_path).await.ok() != Some(content_hash.clone()) {
+            replace_store_entry(output_dir.path(), &store_path).await?;
+        }
 
-        // Move build outputs to the workspace/package directory.
-        rename(output_dir, &pkg_path)
+        // Link the package directory to the store entry.
+        create_dir_all(pkg_path.parent().expect("package directory has no parent"))
             .await
-            .wrap_err("failed to move build outputs into package directory")?;
+            .wrap_err("failed to create package directory")?;
+        if replace {
+            replace_symlink(&store_path, pkg_path).await
+        } else {
+            symlink(&store_path, pkg_path).await.map_err(Error::from)
+        }
+        .wrap_err("failed to link package directory to content store")?;
 
-        Ok(pkg_path)
+        Ok((pkg_path.to_path_buf(), content_hash))
     }
 
-    /// Sets up symlinks from the package directory to the workspace bin directory.
-    #[instrument]
-    async fn add_to_workspace(&self, pkg_dir: &Path, workspace: &Workspace) -> Result<()> {
-        let pkg_bin_path = pkg_dir.join("bin");
+    /// Returns the directory this package is installed to.
+    fn package_directory(&self) -> PathBuf {
+        package_directory(&self.name, &self.version, self.registry.as_deref())
+    }
+
+    /// Places the package's binaries in the workspace bin directory, according to `link_mode`.
+    ///
+    /// If another package in the workspace already has a binary of the same name, nothing is
+    /// linked, unless `force` is set, which replaces the other package's binary, even if its link
+    /// is dangling. Binaries of other versions of this package, and dangling links no package owns,
+    /// are always replaced.
+    #[instrument(skip(state))]
+    async fn add_to_workspace(
+        &self,
+        state: &State,
+        pkg_dir: &Path,
+        workspace: &Workspace,
+        force: bool,
+        link_mode: LinkMode,
+    ) -> Result<()> {
         let workspace_bin_path = workspace.bin_directory()?;
         create_dir_all(workspace_bin_path.clone())
             .await
             .wrap_err("failed to create workspace bin directory")?;
-        if metadata(&pkg_bin_path).await.is_ok_and(|m| m.is_dir()) {
-            let mut pkg_bin_dir_reader = read_dir(&pkg_bin_path).await?;
-            while let Some(entry) = pkg_bin_dir_reader.next_entry().await? {
-                let target = entry.path();
-                let link = workspace_bin_path.join(entry.file_name());
-                symlink(&target, &link).await?;
+
+        // Check all binaries first, so that nothing is linked if any of them conflict.
+        let mut links = vec![];
+        for (binary, target) in package_binaries(pkg_dir).await? {
+            let link = workspace_bin_path.join(&binary);
+            if symlink_metadata(&link).await.is_err() {
+                links.push((target, link, false));
+                continue;
+            }
+            if link_mode.is_placed(&target, &link).await? {
+                // Already placed, e.g. when reinstalling.
+                continue;
+            }
+            if links_into(&link, pkg_dir).await {
+                links.push((target, link, true));
+                continue;
             }
+            let owner = workspace.bin_owner(state, &binary).await?;
+            let owner = match &owner {
+                Some(owner) if owner.name == self.name => None,
+                Some(owner) => Some(format!("package {}@{}", owner.name, owner.version)),
+                // A dangling link no package owns, such as one left behind by a removed package.
+                None if metadata(&link).await.is_err() => None,
+                None => Some(format!("a file in {}", workspace_bin_path.display())),
+            };
+            if let Some(owner) = owner {
+                if !force {
+                    return Err(anyhow!(
+                        "binary '{binary}' conflicts with {owner}, pass --force to replace it"
+                    ));
+                }
+                status!("Warning: replacing binary '{binary}' of {owner}");
+            }
+            links.push((target, link, true));
+        }
+
+        for (target, link, replace) in links {
+            if replace {
+                remove_file(&link)
+                    .await
+                    .wrap_err("failed to remove conflicting binary")?;
+            }
+            link_mode
+                .place(&target, &link)
+                .await
+                .wrap_err(format!("failed to place binary {}", link.display()))?;
         }
 
         Ok(())
     }
 }
 
-impl Display for Package {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}@{}", self.name, self.version)
+/// Returns the name of the file a source is downloaded to, which is the last segment of its path.
+///
+/// Query and fragment are not part of the file name. Fails for relative URLs, and for URLs whose
+/// path does not end in a file name, such as `https://example.com/`.
+pub fn source_file_name(source: &str) -> Result<String> {
+    let url = Url::parse(source).wrap_err("invalid source URL")?;
+    match url.path_segments().and_then(|mut s| s.next_back()) {
+        Some(name) if !name.is_empty() && name != "." && name != ".." => Ok(name.to_string()),
+        _ => Err(anyhow!("source URL {source} does not end in a file name")),
     }
 }
 
-impl Debug for Package {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}@{}", self.name, self.version)?;
-        if let Some(desc) = &self.description {
-            write!(f, "\n  Description: {}", desc)?;
-        }
-        if let Some(url) = &self.homepage {
-            write!(f, "\n  Homepage: {}", url)?;
-        }
-        if let Some(license) = &self.license {
-            write!(f, "\n  License: {}", license)?;
-        }
-        write!(
-            f,
-            "\n  Registry: {}",
-            self.registry
-                .as_ref()
-                .expect("package not tied to registry")
-        )
+/// Returns the `PATH` for a build with the given build dependency directories, or `None` if there
+/// are none and the `PATH` can stay as is.
+///
+/// The dependencies' `bin` directories go in front of the package's own `PATH`, if it sets one.
+fn build_path(build_dependencies: &[PathBuf], path: Option<&String>) -> Result<Option<OsString>> {
+    if build_dependencies.is_empty() {
+        return Ok(None);
     }
+    let path = path
+        .map(OsString::from)
+        .or_else(|| std::env::var_os("PATH"))
+        .unwrap_or_default();
+    let path = join_paths(
+        build_dependencies
+            .iter()
+            .map(|dir| dir.join("bin"))
+            .chain(split_paths(&path)),
+    )
+    .wrap_err("invalid build dependency directory")?;
+    Ok(Some(path))
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::download::MockDownloader;
+/// How often a download that breaks off after making progress is tried.
+const DOWNLOAD_ATTEMPTS: usize = 3;
 
-    use super::*;
+/// The suffix of files that are still being downloaded.
+const PART_SUFFIX: &str = ".part";
 
-    #[test]
-    fn test_parse_manifest() -> Result<()> {
-        let manifest = r#"
+/// Downloads a file into `part_path`, continuing after what is already there.
+///
+/// Returns the hex-encoded SHA-256 of the whole file, including the part that was downloaded
+/// before.
+async fn download_part(
+    url: &str,
+    part_path: &Path,
+    downloader: &impl Downloader,
+    progress: &ProgressBar,
+) -> Result<String> {
+    let offset = file_size(part_path).await;
+    let validator_path = part_validator_path(part_path);
+    let validator = if offset > 0 {
+        read_to_string(&validator_path).await.ok()
+    } else {
+        None
+    };
+    let (start, size, new_validator, download) = downloader
+        .download_stream_from(url, offset, validator.as_deref())
+        .await?;
+    pin!(download);
+    if start == 0 {
+        match new_validator {
+            Some(validator) => write(&validator_path, validator).await?,
+            None if validator_path.exists() => remove_file(&validator_path).await?,
+            None => {}
+        }
+    }
+
+    let mut hasher = Sha256::new();
+    let mut file = if start > 0 {
+        debug!("resuming download of {url} at byte {start}");
+        hash_file_into(&mut hasher, part_path).await?;
+        OpenOptions::new().append(true).open(part_path).await?
+    } else {
+        // The server doesn't support resuming, so start over.
+        File::create(part_path).await?
+    };
+    if size > 0 {
+        switch_to_progress_bar(progress, start + size as u64);
+        progress.set_position(start);
+    }
+
+    while let Some(chunk) = download.next().await {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+        progress.inc(chunk.len() as u64);
+    }
+    file.flush().await?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Returns the path of the file that keeps the validator of a partial download, which makes sure
+/// it is only resumed if the file didn't change since.
+fn part_validator_path(part_path: &Path) -> PathBuf {
+    let mut path = part_path.as_os_str().to_owned();
+    path.push(".validator");
+    PathBuf::from(path)
+}
+
+/// Returns the size of a file, or 0 if it doesn't exist.
+async fn file_size(path: &Path) -> u64 {
+    metadata(path).await.map(|m| m.len()).unwrap_or(0)
+}
+
+/// Feeds the contents of a file into a hasher, without reading it into memory at once.
+async fn hash_file_into(hasher: &mut Sha256, path: &Path) -> Result<()> {
+    let mut file = File::open(path).await?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// Returns the binaries in a package directory, by name, with their paths.
+///
+/// These are the entries of the package's `bin` directory, which get placed in a workspace's bin
+/// directory.
+pub async fn package_binaries(pkg_dir: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let pkg_bin_path = pkg_dir.join("bin");
+    if !metadata(&pkg_bin_path).await.is_ok_and(|m| m.is_dir()) {
+        return Ok(vec![]);
+    }
+
+    let mut binaries = vec![];
+    let mut pkg_bin_dir_reader = read_dir(&pkg_bin_path).await?;
+    while let Some(entry) = pkg_bin_dir_reader.next_entry().await? {
+        binaries.push((
+            entry.file_name().to_string_lossy().into_owned(),
+            entry.path(),
+        ));
+    }
+    binaries.sort();
+    Ok(binaries)
+}
+
+/// Returns if `link` is a symlink into the package being placed, which can be replaced without
+/// asking, even if it is dangling.
+async fn links_into(link: &Path, pkg_dir: &Path) -> bool {
+    read_link(link)
+        .await
+        .is_ok_and(|target| target.starts_with(pkg_dir))
+}
+
+/// Returns if a package directory link points to a build output directory that no longer exists,
+/// which is what an interrupted build leaves behind.
+async fn is_stale_build_link(target: &Path) -> bool {
+    let build_root = BUILD_ROOT.get().cloned().unwrap_or_else(std::env::temp_dir);
+    // Build directories are temporary directories right in the build root.
+    let is_build_dir = target.parent() == Some(build_root.as_path())
+        && target
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with(".tmp"));
+    is_build_dir && metadata(target).await.is_err()
+}
+
+/// Links the package directory to a build's output directory, so that the build can install
+/// into the package's final location, and returns the package directory along with the link
+/// it replaced, see [`unstage_prefix`].
+///
+/// A package directory that is still in use, such as when rebuilding a corrupted package, is
+/// swapped atomically, even if its files are missing. Only links left behind by an interrupted
+/// build are removed.
+async fn stage_prefix(pkg_dir: &Path, output_dir: &Path) -> Result<(PathBuf, Option<PathBuf>)> {
+    let mut previous = None;
+    if let Ok(target) = read_link(pkg_dir).await {
+        if is_stale_build_link(&target).await {
+            remove_file(pkg_dir)
+                .await
+                .wrap_err("failed to remove stale package directory link")?;
+        } else {
+            previous = Some(target);
+        }
+    }
+    create_dir_all(pkg_dir.parent().expect("package directory has no parent"))
+        .await
+        .wrap_err("failed to create package directory")?;
+    replace_symlink(output_dir, pkg_dir)
+        .await
+        .wrap_err("failed to link package directory to build output")?;
+    Ok((pkg_dir.to_path_buf(), previous))
+}
+
+/// Undoes [`stage_prefix`], restoring the link to the previous package directory, if there
+/// was one.
+async fn unstage_prefix(pkg_dir: &Path, previous: Option<&Path>) -> Result<()> {
+    match previous {
+        Some(previous) => replace_symlink(previous, pkg_dir).await,
+        None => Ok(remove_file(pkg_dir).await?),
+    }
+}
+
+/// Points `link` to `target`, atomically replacing any link that is already there.
+async fn replace_symlink(target: &Path, link: &Path) -> Result<()> {
+    let file_name = link.file_name().expect("link has no file name");
+    let tmp = link.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    if symlink_metadata(&tmp).await.is_ok() {
+        remove_file(&tmp).await?;
+    }
+    symlink(target, &tmp).await?;
+    rename(&tmp, link).await?;
+    Ok(())
+}
+
+/// Replaces a corrupted content store entry with the given outputs.
+///
+/// The corrupted entry is moved aside first, and only deleted once the outputs are in its place. If
+/// that fails, it is moved back.
+async fn replace_store_entry(output_dir: &Path, store_path: &Path) -> Result<()> {
+    let aside = store_path.with_extension(format!("corrupted-{}", std::process::id()));
+    rename(store_path, &aside)
+        .await
+        .wrap_err("failed to move corrupted content store entry aside")?;
+    if let Err(e) = rename(output_dir, store_path).await {
+        // Better a corrupted entry than none at all.
+        let _ = rename(&aside, store_path).await;
+        return Err(e).wrap_err("failed to move build outputs into content store");
+    }
+    set_read_only(store_path).wrap_err("failed to make content store entry read-only")?;
+    remove_read_only_dir(&aside).wrap_err("failed to remove corrupted content store entry")
+}
+
+/// Creates a temporary directory for a build, inside the build root if one is configured.
+///
+/// Like any [`TempDir`], it is removed when dropped.
+fn create_build_temp_dir() -> std::io::Result<TempDir> {
+    match BUILD_ROOT.get() {
+        Some(build_root) => {
+            std::fs::create_dir_all(build_root)?;
+            TempDir::new_in(build_root)
+        }
+        None => TempDir::new(),
+    }
+}
+
+/// A package directory that is removed when dropped, unless the install is completed.
+///
+/// This cleans up after installs that fail or get cancelled between creating the package directory
+/// and registering the package, including the content store entry the install created, which no
+/// installed package would reference.
+struct PartialInstall {
+    /// The package directory, or `None` once the install is complete.
+    dir: Option<PathBuf>,
+    /// The content store entry created by the install, if any.
+    store_entry: Option<PathBuf>,
+}
+
+impl PartialInstall {
+    fn new(dir: PathBuf) -> Self {
+        Self {
+            dir: Some(dir),
+            store_entry: None,
+        }
+    }
+
+    /// Records a content store entry created by the install, to remove it as well.
+    fn add_store_entry(&mut self, store_entry: PathBuf) {
+        self.store_entry = Some(store_entry);
+    }
+
+    /// Marks the install as complete, keeping the package directory and store entry.
+    fn complete(mut self) {
+        self.dir = None;
+        self.store_entry = None;
+    }
+}
+
+impl Drop for PartialInstall {
+    fn drop(&mut self) {
+        // This can run during task cancellation, so it has to be synchronous.
+        if let Some(dir) = self.dir.take() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        if let Some(store_entry) = self.store_entry.take() {
+            let _ = remove_read_only_dir(&store_entry);
+        }
+    }
+}
+
+impl Display for Package {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.name, self.version)
+    }
+}
+
+impl Debug for Package {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.name, self.version)?;
+        if let Some(desc) = &self.description {
+            write!(f, "\n  Description: {}", desc)?;
+        }
+        if let Some(url) = &self.homepage {
+            write!(f, "\n  Homepage: {}", url)?;
+        }
+        if let Some(license) = &self.license {
+            write!(f, "\n  License: {}", license)?;
+        }
+        write!(
+            f,
+            "\n  Registry: {}",
+            self.registry
+                .as_ref()
+                .expect("package not tied to registry")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::download::{serve_with_status, MockDownloader};
+
+    use super::*;
+
+    #[test]
+    fn test_source_file_name() {
+        assert_eq!(
+            source_file_name("https://host/file.tar.gz?x=1").unwrap(),
+            "file.tar.gz"
+        );
+        assert_eq!(
+            source_file_name("https://host/a/file.tar.gz#top").unwrap(),
+            "file.tar.gz"
+        );
+        assert!(source_file_name("https://host/").is_err());
+        assert!(source_file_name("https://host").is_err());
+        assert!(source_file_name("https://host/dir/").is_err());
+        assert!(source_file_name("file.tar.gz").is_err());
+    }
+
+    #[test]
+    fn test_parse_manifest() -> Result<()> {
+        let manifest = r#"
             schema_version = 1
             name = "test"
             description = "A test manifest"
@@ -385,6 +1712,13 @@ mod tests {
             source = "https://example.invalid/test-package/archive/0.1.0.tar.gz"
             build = "cargo build --release"
             artifacts = ["target/release/test-package"]
+            env = { CFLAGS = "-O2" }
+            provides = ["test-tool"]
+            dependencies = ["other-package@~1"]
+            build_dependencies = ["compiler"]
+            post_install = "echo installed"
+            pre_remove = "echo removing"
+            platforms = ["linux-x86_64", "macos-aarch64"]
         "#;
 
         let manifest: Manifest = manifest.parse()?;
@@ -405,13 +1739,42 @@ mod tests {
         );
         assert_eq!(manifest.packages[0].license, Some("MIT".to_string()));
         assert_eq!(
-            manifest.packages[0].source,
-            Some("https://example.invalid/test-package/archive/0.1.0.tar.gz".to_string())
+            manifest.packages[0].sources,
+            vec!["https://example.invalid/test-package/archive/0.1.0.tar.gz".to_string()]
         );
         assert_eq!(
             manifest.packages[0].build,
             Some("cargo build --release".to_string())
         );
+        assert_eq!(
+            manifest.packages[0].env,
+            HashMap::from([("CFLAGS".to_string(), "-O2".to_string())])
+        );
+        assert_eq!(manifest.packages[0].provides, vec!["test-tool".to_string()]);
+        assert_eq!(
+            manifest.packages[0].dependencies,
+            vec!["other-package@~1".to_string()]
+        );
+        assert_eq!(
+            manifest.packages[0].build_dependencies,
+            vec!["compiler".to_string()]
+        );
+        assert_eq!(
+            manifest.packages[0].post_install.as_deref(),
+            Some("echo installed")
+        );
+        assert_eq!(
+            manifest.packages[0].pre_remove.as_deref(),
+            Some("echo removing")
+        );
+        assert_eq!(
+            manifest.packages[0].artifacts,
+            vec!["target/release/test-package".to_string()]
+        );
+        assert_eq!(
+            manifest.packages[0].platforms,
+            vec!["linux-x86_64".to_string(), "macos-aarch64".to_string()]
+        );
         Ok(())
     }
 
@@ -421,16 +1784,263 @@ mod tests {
             name: "test-package".to_string(),
             version: "0.1.0".to_string(),
             registry: Some("https://example.invalid/registry".to_string()),
-            source: Some("https://example.invalid/test-package/archive/0.1.0.tar.gz".to_string()),
+            sources: vec!["https://example.invalid/test-package/archive/0.1.0.tar.gz".to_string()],
             ..Default::default()
         };
 
-        let (build_dir, download_file_name) = package
-            .download_source(&MockDownloader::new(vec![]))
+        let (build_dir, source) = package
+            .download_source(&MockDownloader::new(vec![]), &ProgressBar::hidden())
             .await?;
         assert!(build_dir.path().exists());
         assert!(build_dir.path().is_dir());
-        assert!(download_file_name.ends_with(".tar.gz"));
+        let source = source.unwrap();
+        assert!(source.is_absolute());
+        assert_eq!(
+            source.parent(),
+            Some(build_dir.path().join("src").as_path())
+        );
+        assert!(source.to_str().unwrap().ends_with(".tar.gz"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_hashes_while_streaming() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            ..Default::default()
+        };
+        let bytes = "some source archive contents, in several chunks"
+            .repeat(10)
+            .into_bytes();
+        let src_dir = TempDir::new()?;
+
+        let (path, sha256) = package
+            .download_from(
+                "https://example.invalid/test-package-0.1.0.tar.gz",
+                src_dir.path(),
+                &MockDownloader::new(bytes.clone()),
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        assert_eq!(std::fs::read(path)?, bytes);
+        assert_eq!(sha256, format!("{:x}", Sha256::digest(&bytes)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes_after_interruption() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            ..Default::default()
+        };
+        let bytes = "some source archive contents, in several chunks"
+            .repeat(10)
+            .into_bytes();
+
+        for supports_ranges in [true, false] {
+            let src_dir = TempDir::new()?;
+            let downloader = MockDownloader::interrupted_after(bytes.clone(), 100, supports_ranges);
+            let (path, sha256) = package
+                .download_from(
+                    "https://example.invalid/test-package-0.1.0.tar.gz",
+                    src_dir.path(),
+                    &downloader,
+                    &ProgressBar::hidden(),
+                )
+                .await?;
+            assert_eq!(std::fs::read(path)?, bytes);
+            assert_eq!(sha256, format!("{:x}", Sha256::digest(&bytes)));
+            // The retry asks for the rest, even if the server then sends everything again.
+            let offsets = downloader.requested_offsets.lock().unwrap().clone();
+            assert_eq!(offsets.len(), 2);
+            assert_eq!(offsets[0], 0);
+            assert!(offsets[1] >= 100);
+            assert!(!src_dir
+                .path()
+                .join("test-package-0.1.0.tar.gz.part")
+                .exists());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_resumes_leftover_part_file() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            ..Default::default()
+        };
+        let bytes = b"0123456789abcdefghijklmnopqrstuvwxyz".to_vec();
+        let src_dir = TempDir::new()?;
+        std::fs::write(src_dir.path().join("source.tar.gz.part"), &bytes[..10])?;
+
+        let downloader = MockDownloader::new(bytes.clone());
+        let (path, sha256) = package
+            .download_from(
+                "https://example.invalid/source.tar.gz",
+                src_dir.path(),
+                &downloader,
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        assert_eq!(std::fs::read(path)?, bytes);
+        assert_eq!(sha256, format!("{:x}", Sha256::digest(&bytes)));
+        assert_eq!(*downloader.requested_offsets.lock().unwrap(), [10]);
+
+        // A part file of a file that changed since is downloaded again from the start.
+        std::fs::write(src_dir.path().join("source.tar.gz.part"), b"stale part")?;
+        std::fs::write(
+            src_dir.path().join("source.tar.gz.part.validator"),
+            "\"stale\"",
+        )?;
+        let (path, sha256) = package
+            .download_from(
+                "https://example.invalid/source.tar.gz",
+                src_dir.path(),
+                &downloader,
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        assert_eq!(std::fs::read(path)?, bytes);
+        assert_eq!(sha256, format!("{:x}", Sha256::digest(&bytes)));
+        assert!(!src_dir.path().join("source.tar.gz.part.validator").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_package_source_falls_back_to_next_source() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            sources: vec![
+                "https://first.invalid/test-package-0.1.0.tar.gz".to_string(),
+                "https://second.invalid/test-package-0.1.0.zip".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let (build_dir, source) = package
+            .download_source(
+                &MockDownloader::failing_for(
+                    "foo".as_bytes().to_vec(),
+                    &["https://first.invalid/test-package-0.1.0.tar.gz"],
+                ),
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        let source = source.unwrap();
+        assert_eq!(
+            source,
+            build_dir.path().join("src").join("test-package-0.1.0.zip")
+        );
+        assert!(!build_dir
+            .path()
+            .join("src")
+            .join("test-package-0.1.0.tar.gz")
+            .exists());
+        assert_eq!(tokio::fs::read_to_string(source).await?, "foo");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_package_source_falls_back_on_error_status() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            sources: vec![
+                serve_with_status("404 Not Found", "not found").await,
+                serve_with_status("200 OK", "foo").await,
+            ],
+            ..Default::default()
+        };
+
+        let (_build_dir, source) = package
+            .download_source(&DefaultDownloader::default(), &ProgressBar::hidden())
+            .await?;
+        assert_eq!(tokio::fs::read_to_string(source.unwrap()).await?, "foo");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_package_source_lists_all_failures() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            sources: vec![
+                "https://first.invalid/test-package.tar.gz".to_string(),
+                "https://second.invalid/test-package.tar.gz".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let err = package
+            .download_source(
+                &MockDownloader::failing_for(
+                    vec![],
+                    &[
+                        "https://first.invalid/test-package.tar.gz",
+                        "https://second.invalid/test-package.tar.gz",
+                    ],
+                ),
+                &ProgressBar::hidden(),
+            )
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("https://first.invalid/test-package.tar.gz"));
+        assert!(err.contains("https://second.invalid/test-package.tar.gz"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_manifest_with_multiple_sources() -> Result<()> {
+        let manifest = r#"
+            schema_version = 1
+            name = "test"
+
+            [[packages]]
+            name = "test-package"
+            version = "0.1.0"
+            source = "https://example.invalid/primary.tar.gz"
+            sources = ["https://mirror.invalid/mirror.tar.gz"]
+        "#;
+
+        let manifest: Manifest = manifest.parse()?;
+
+        assert_eq!(
+            manifest.packages[0].sources,
+            vec![
+                "https://example.invalid/primary.tar.gz".to_string(),
+                "https://mirror.invalid/mirror.tar.gz".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_package_source_tracks_progress() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            sources: vec!["https://example.invalid/test-package/archive/0.1.0.tar.gz".to_string()],
+            ..Default::default()
+        };
+
+        let progress = ProgressBar::hidden();
+        package
+            .download_source(&MockDownloader::new("foo".as_bytes().to_vec()), &progress)
+            .await?;
+        assert_eq!(progress.length(), Some(3));
+        assert_eq!(progress.position(), 3);
         Ok(())
     }
 
@@ -440,17 +2050,22 @@ mod tests {
             name: "test-package".to_string(),
             version: "0.1.0".to_string(),
             registry: Some("https://example.invalid/registry".to_string()),
-            source: Some("https://example.invalid/test-source".to_string()),
+            sources: vec!["https://example.invalid/test-source".to_string()],
             build: Some(
                 "mkdir $MATCHA_OUTPUT/bin && cp $MATCHA_SOURCE $MATCHA_OUTPUT/bin/".to_string(),
             ),
             ..Default::default()
         };
 
-        let (build_dir, download_file_name) = package
-            .download_source(&MockDownloader::new("foo".as_bytes().to_vec()))
+        let (build_dir, source) = package
+            .download_source(
+                &MockDownloader::new("foo".as_bytes().to_vec()),
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        let (output_dir, _log) = package
+            .build(&build_dir, source.as_deref(), None, &[], false)
             .await?;
-        let (output_dir, _log) = package.build(&build_dir, &download_file_name).await?;
 
         let output_bin_dir = output_dir.path().join("bin");
         assert!(output_bin_dir.exists());
@@ -473,10 +2088,15 @@ mod tests {
             ..Default::default()
         };
 
-        let (build_dir, download_file_name) = package
-            .download_source(&MockDownloader::new("foo".as_bytes().to_vec()))
+        let (build_dir, source) = package
+            .download_source(
+                &MockDownloader::new("foo".as_bytes().to_vec()),
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        let (output_dir, _log) = package
+            .build(&build_dir, source.as_deref(), None, &[], false)
             .await?;
-        let (output_dir, _log) = package.build(&build_dir, &download_file_name).await?;
 
         assert!(output_dir.path().exists());
         assert!(output_dir.path().is_dir());
@@ -487,6 +2107,150 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_build_package_sets_build_dir() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            sources: vec!["https://example.invalid/test-source".to_string()],
+            build: Some(
+                "test -d $MATCHA_BUILD_DIR && echo $MATCHA_BUILD_DIR > $MATCHA_OUTPUT/output"
+                    .to_string(),
+            ),
+            ..Default::default()
+        };
+
+        let (build_dir, source) = package
+            .download_source(
+                &MockDownloader::new("foo".as_bytes().to_vec()),
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        let (output_dir, log) = package
+            .build(&build_dir, source.as_deref(), None, &[], false)
+            .await?;
+
+        assert!(log.is_success());
+        assert_eq!(
+            tokio::fs::read_to_string(output_dir.path().join("output")).await?,
+            format!("{}\n", build_dir.path().display())
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_build_package_merges_output() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            build: Some("echo out; echo err >&2; echo more out; exit 1".to_string()),
+            ..Default::default()
+        };
+        let build_dir = TempDir::new()?;
+
+        let (_output_dir, log) = package.build(&build_dir, None, None, &[], false).await?;
+        assert_eq!(log.stdout, "out\nmore out\n");
+        assert_eq!(log.stderr, "err\n");
+        assert!(!log.merged_output);
+
+        let (_output_dir, log) = package.build(&build_dir, None, None, &[], true).await?;
+        assert!(!log.is_success());
+        assert_eq!(log.stdout, "out\nerr\nmore out\n");
+        assert_eq!(log.stderr, "");
+        assert!(log.merged_output);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_build_in_build_root() -> Result<()> {
+        let build_root = TempDir::new()?;
+        crate::BUILD_ROOT
+            .set(build_root.path().join("builds"))
+            .unwrap();
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            build: Some("echo $MATCHA_BUILD_DIR > $MATCHA_OUTPUT/build-dir".to_string()),
+            ..Default::default()
+        };
+
+        let (build_dir, source) = package
+            .download_source(&MockDownloader::new(vec![]), &ProgressBar::hidden())
+            .await?;
+        let (output_dir, log) = package
+            .build(&build_dir, source.as_deref(), None, &[], false)
+            .await?;
+        assert!(log.is_success());
+
+        let builds = build_root.path().join("builds");
+        assert!(build_dir.path().starts_with(&builds));
+        assert!(output_dir.path().starts_with(&builds));
+        let recorded = tokio::fs::read_to_string(output_dir.path().join("build-dir")).await?;
+        assert_eq!(Path::new(recorded.trim()), build_dir.path());
+
+        drop(build_dir);
+        drop(output_dir);
+        assert_eq!(std::fs::read_dir(&builds)?.count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_build_package_records_duration() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            build: Some("sleep 0.1".to_string()),
+            ..Default::default()
+        };
+
+        let (build_dir, source) = package
+            .download_source(&MockDownloader::new(vec![]), &ProgressBar::hidden())
+            .await?;
+        let (_output_dir, log) = package
+            .build(&build_dir, source.as_deref(), None, &[], false)
+            .await?;
+
+        assert!(log.is_success());
+        assert!(log.duration >= Duration::from_millis(100));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_build_package_with_env() -> Result<()> {
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            build: Some("echo $GREETING > $MATCHA_OUTPUT/output".to_string()),
+            env: HashMap::from([
+                ("GREETING".to_string(), "hullo".to_string()),
+                ("MATCHA_OUTPUT".to_string(), "/nonexistent".to_string()),
+            ]),
+            ..Default::default()
+        };
+
+        let (build_dir, source) = package
+            .download_source(
+                &MockDownloader::new("foo".as_bytes().to_vec()),
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        let (output_dir, log) = package
+            .build(&build_dir, source.as_deref(), None, &[], false)
+            .await?;
+
+        assert!(log.is_success());
+        assert_eq!(
+            tokio::fs::read_to_string(output_dir.path().join("output")).await?,
+            "hullo\n"
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_build_package_exists_on_first_error() -> Result<()> {
         let package = Package {
@@ -497,10 +2261,15 @@ mod tests {
             ..Default::default()
         };
 
-        let (build_dir, download_file_name) = package
-            .download_source(&MockDownloader::new("foo".as_bytes().to_vec()))
+        let (build_dir, source) = package
+            .download_source(
+                &MockDownloader::new("foo".as_bytes().to_vec()),
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        let (_output_dir, log) = package
+            .build(&build_dir, source.as_deref(), None, &[], false)
             .await?;
-        let (_output_dir, log) = package.build(&build_dir, &download_file_name).await?;
 
         assert!(!log.is_success());
         Ok(())
@@ -520,24 +2289,35 @@ mod tests {
             name: "test-package".to_string(),
             version: "0.1.0".to_string(),
             registry: Some("https://example.invalid/registry".to_string()),
-            source: Some("https://example.invalid/test-source".to_string()),
+            sources: vec!["https://example.invalid/test-source".to_string()],
             build: Some(
                 "mkdir $MATCHA_OUTPUT/bin && cp $MATCHA_SOURCE $MATCHA_OUTPUT/bin/".to_string(),
             ),
             ..Default::default()
         };
 
-        let (build_dir, download_file_name) = package
-            .download_source(&MockDownloader::new("foo".as_bytes().to_vec()))
+        let (build_dir, source) = package
+            .download_source(
+                &MockDownloader::new("foo".as_bytes().to_vec()),
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        let (output_dir, _log) = package
+            .build(&build_dir, source.as_deref(), None, &[], false)
+            .await?;
+        let (pkg_path, _) = package
+            .add_to_package_directory(&output_dir, &package.package_directory(), false, None)
             .await?;
-        let (output_dir, _log) = package.build(&build_dir, &download_file_name).await?;
-        package.add_to_package_directory(&output_dir).await?;
 
-        let pkg_path = crate::PACKAGE_ROOT
-            .get()
-            .unwrap()
-            .join(&package.name)
-            .join(&package.version);
+        // The package directory is namespaced by its registry.
+        let registry_dir = pkg_path.parent().unwrap().parent().unwrap();
+        assert_eq!(registry_dir.parent().unwrap(), package_root.path());
+        assert_ne!(registry_dir, package_root.path().join(&package.name));
+        let other_registry = Package {
+            registry: Some("https://example.invalid/other-registry".to_string()),
+            ..package.clone()
+        };
+        assert_ne!(other_registry.package_directory(), pkg_path);
         assert!(pkg_path.exists());
         assert!(pkg_path.is_dir());
         assert!(pkg_path.join("bin").exists());
@@ -550,6 +2330,96 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_identical_outputs_are_stored_once() -> Result<()> {
+        let package_root = TempDir::new()?;
+        crate::PACKAGE_ROOT
+            .set(package_root.path().to_owned())
+            .unwrap();
+        let build =
+            Some("mkdir $MATCHA_OUTPUT/bin && echo foo > $MATCHA_OUTPUT/bin/foo".to_string());
+        let first = Package {
+            name: "first-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            build: build.clone(),
+            ..Default::default()
+        };
+        let second = Package {
+            name: "second-package".to_string(),
+            version: "0.2.0".to_string(),
+            build,
+            ..first.clone()
+        };
+
+        let mut hashes = vec![];
+        for package in [&first, &second] {
+            let (build_dir, source) = package
+                .download_source(&MockDownloader::new(vec![]), &ProgressBar::hidden())
+                .await?;
+            let (output_dir, _log) = package
+                .build(&build_dir, source.as_deref(), None, &[], false)
+                .await?;
+            let (pkg_dir, content_hash) = package
+                .add_to_package_directory(&output_dir, &package.package_directory(), false, None)
+                .await?;
+            assert_eq!(
+                tokio::fs::read_to_string(pkg_dir.join("bin").join("foo")).await?,
+                "foo\n"
+            );
+            hashes.push(content_hash);
+        }
+
+        assert_eq!(hashes[0], hashes[1]);
+        assert_eq!(std::fs::read_dir(content_store_directory())?.count(), 1);
+        assert_eq!(
+            first.package_directory().canonicalize()?,
+            second.package_directory().canonicalize()?
+        );
+
+        // The shared store entry is read-only.
+        let binary = first.package_directory().join("bin").join("foo");
+        assert_eq!(std::fs::metadata(binary)?.permissions().mode() & 0o222, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stage_prefix_keeps_installed_package_directory() -> Result<()> {
+        let package_root = TempDir::new()?;
+        crate::PACKAGE_ROOT
+            .set(package_root.path().to_owned())
+            .unwrap();
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            ..Default::default()
+        };
+        let pkg_dir = package.package_directory();
+        std::fs::create_dir_all(pkg_dir.parent().unwrap())?;
+        let output_dir = create_build_temp_dir()?;
+
+        // Installed packages are swapped back once the build is done, even if their files are gone.
+        let installed = package_root.path().join(".store").join("abc");
+        std::os::unix::fs::symlink(&installed, &pkg_dir)?;
+        let (prefix, previous) =
+            stage_prefix(&package.package_directory(), output_dir.path()).await?;
+        assert_eq!(std::fs::read_link(&prefix)?, output_dir.path());
+        assert_eq!(previous.as_deref(), Some(installed.as_path()));
+        unstage_prefix(&prefix, previous.as_deref()).await?;
+        assert_eq!(std::fs::read_link(&pkg_dir)?, installed);
+
+        // Links to the outputs of interrupted builds are removed.
+        std::fs::remove_file(&pkg_dir)?;
+        let interrupted = create_build_temp_dir()?.path().to_owned();
+        std::os::unix::fs::symlink(&interrupted, &pkg_dir)?;
+        let (prefix, previous) =
+            stage_prefix(&package.package_directory(), output_dir.path()).await?;
+        assert!(previous.is_none());
+        unstage_prefix(&prefix, previous.as_deref()).await?;
+        assert!(std::fs::symlink_metadata(&pkg_dir).is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_installed_package_to_workspace() -> Result<()> {
         let workspace_root = TempDir::new()?;
@@ -565,19 +2435,29 @@ mod tests {
             name: "test-package".to_string(),
             version: "0.1.0".to_string(),
             registry: Some("https://example.invalid/registry".to_string()),
-            source: Some("https://example.invalid/test-source".to_string()),
+            sources: vec!["https://example.invalid/test-source".to_string()],
             build: Some(
                 "mkdir $MATCHA_OUTPUT/bin && cp $MATCHA_SOURCE $MATCHA_OUTPUT/bin/".to_string(),
             ),
             ..Default::default()
         };
 
-        let (build_dir, download_file_name) = package
-            .download_source(&MockDownloader::new("foo".as_bytes().to_vec()))
+        let (build_dir, source) = package
+            .download_source(
+                &MockDownloader::new("foo".as_bytes().to_vec()),
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        let (output_dir, _log) = package
+            .build(&build_dir, source.as_deref(), None, &[], false)
+            .await?;
+        let (pkg_dir, _) = package
+            .add_to_package_directory(&output_dir, &package.package_directory(), false, None)
+            .await?;
+        let state = State::load(":memory:").await?;
+        package
+            .add_to_workspace(&state, &pkg_dir, &workspace, false, LinkMode::Symlink)
             .await?;
-        let (output_dir, _log) = package.build(&build_dir, &download_file_name).await?;
-        let pkg_dir = package.add_to_package_directory(&output_dir).await?;
-        package.add_to_workspace(&pkg_dir, &workspace).await?;
 
         let workspace_bin_path = workspace.bin_directory()?;
         assert!(workspace_bin_path.exists());
@@ -591,4 +2471,319 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_add_to_workspace_twice() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let (workspace, _workspace_root) = crate::workspace::test_workspace("global").await;
+        let pkg_dir = TempDir::new()?;
+        std::fs::create_dir(pkg_dir.path().join("bin"))?;
+        std::fs::write(pkg_dir.path().join("bin").join("test-binary"), "foo")?;
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            ..Default::default()
+        };
+
+        for _ in 0..2 {
+            package
+                .add_to_workspace(&state, pkg_dir.path(), &workspace, false, LinkMode::Symlink)
+                .await?;
+        }
+        let link = workspace.bin_directory()?.join("test-binary");
+        let target = pkg_dir.path().join("bin").join("test-binary");
+        assert_eq!(std::fs::read_link(&link)?, target);
+
+        // A dangling link, such as one left behind by a removed package, is replaced.
+        std::fs::remove_file(&link)?;
+        std::os::unix::fs::symlink(pkg_dir.path().join("missing"), &link)?;
+        package
+            .add_to_workspace(&state, pkg_dir.path(), &workspace, false, LinkMode::Symlink)
+            .await?;
+        assert_eq!(std::fs::read_link(&link)?, target);
+
+        // Even if it points outside of the package.
+        let elsewhere = TempDir::new()?;
+        std::fs::remove_file(&link)?;
+        std::os::unix::fs::symlink(elsewhere.path().join("missing"), &link)?;
+        package
+            .add_to_workspace(&state, pkg_dir.path(), &workspace, false, LinkMode::Symlink)
+            .await?;
+        assert_eq!(std::fs::read_link(&link)?, target);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_install_removes_directory_unless_completed() -> Result<()> {
+        let root = TempDir::new()?;
+        let completed = root.path().join("completed");
+        let interrupted = root.path().join("interrupted");
+        let completed_entry = root.path().join("completed-entry");
+        let interrupted_entry = root.path().join("interrupted-entry");
+        std::fs::create_dir(&completed)?;
+        std::fs::create_dir(&interrupted)?;
+        for entry in [&completed_entry, &interrupted_entry] {
+            std::fs::create_dir(entry)?;
+            std::fs::write(entry.join("file"), "")?;
+            set_read_only(entry)?;
+        }
+
+        let mut partial_install = PartialInstall::new(completed.clone());
+        partial_install.add_store_entry(completed_entry.clone());
+        partial_install.complete();
+        let mut partial_install = PartialInstall::new(interrupted.clone());
+        partial_install.add_store_entry(interrupted_entry.clone());
+        drop(partial_install);
+
+        assert!(completed.exists());
+        assert!(completed_entry.exists());
+        assert!(!interrupted.exists());
+        assert!(!interrupted_entry.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_registration_leaves_no_package_files() -> Result<()> {
+        let state_dir = TempDir::new()?;
+        let state_db = state_dir.path().join("state.db");
+        let state = State::load(state_db.to_str().unwrap()).await?;
+        // Fail the install right after the outputs were stored, when the package is registered.
+        let db = sqlx::SqlitePool::connect(&format!("sqlite:{}", state_db.display())).await?;
+        sqlx::query(
+            "CREATE TRIGGER fail_install BEFORE INSERT ON installed_packages
+            BEGIN SELECT RAISE(FAIL, 'registration failed'); END",
+        )
+        .execute(&db)
+        .await?;
+        let (workspace, _workspace_root) = crate::workspace::test_workspace("global").await;
+        let package_root = TempDir::new()?;
+        crate::PACKAGE_ROOT
+            .set(package_root.path().to_owned())
+            .unwrap();
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            build: Some("mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/foo".to_string()),
+            ..Default::default()
+        };
+
+        let mpb = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let result = package
+            .install(&state, &workspace, &mpb, &InstallOptions::default())
+            .await;
+        assert!(result.is_err());
+
+        assert!(package.package_directory().symlink_metadata().is_err());
+        assert_eq!(std::fs::read_dir(content_store_directory())?.count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_source_does_not_build() -> Result<()> {
+        let (workspace, _workspace_root) = crate::workspace::test_workspace("global").await;
+        let package_root = TempDir::new()?;
+        crate::PACKAGE_ROOT
+            .set(package_root.path().to_owned())
+            .unwrap();
+        let source = "https://example.invalid/test-package-0.1.0.tar.gz";
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            sources: vec![source.to_string()],
+            build: Some(
+                "mkdir $MATCHA_OUTPUT/bin && cp $MATCHA_SOURCE $MATCHA_OUTPUT/bin/".to_string(),
+            ),
+            ..Default::default()
+        };
+
+        let cached = package
+            .prefetch_source(
+                &MockDownloader::new("foo".as_bytes().to_vec()),
+                &ProgressBar::hidden(),
+            )
+            .await?
+            .unwrap();
+        assert_eq!(tokio::fs::read_to_string(&cached).await?, "foo");
+        assert!(!package.package_directory().try_exists()?);
+        assert!(read_dir(workspace.bin_directory()?)
+            .await?
+            .next_entry()
+            .await?
+            .is_none());
+
+        // Later downloads use the prefetched source.
+        let (_build_dir, downloaded) = package
+            .download_source(
+                &MockDownloader::failing_for(vec![], &[source]),
+                &ProgressBar::hidden(),
+            )
+            .await?;
+        assert_eq!(tokio::fs::read_to_string(downloaded.unwrap()).await?, "foo");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_install_fails_on_missing_artifacts() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let (workspace, _workspace_root) = crate::workspace::test_workspace("global").await;
+        let package_root = TempDir::new()?;
+        crate::PACKAGE_ROOT
+            .set(package_root.path().to_owned())
+            .unwrap();
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            build: Some("mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/present".to_string()),
+            artifacts: vec![
+                "bin/present".to_string(),
+                "bin/missing".to_string(),
+                "../outside".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        let mpb = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let err = package
+            .install(&state, &workspace, &mpb, &InstallOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "build of test-package@0.1.0 did not produce the declared artifacts: bin/missing, ../outside"
+        );
+        assert!(!package.package_directory().try_exists()?);
+        assert!(state
+            .get_installed_package(&KnownPackage::from(&package))
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_install_plan_stages() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let (workspace, _workspace_root) = crate::workspace::test_workspace("global").await;
+        let package_root = TempDir::new()?;
+        crate::PACKAGE_ROOT
+            .set(package_root.path().to_owned())
+            .unwrap();
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            sources: vec!["https://example.invalid/test-source".to_string()],
+            build: Some(
+                "mkdir $MATCHA_OUTPUT/bin && cp $MATCHA_SOURCE $MATCHA_OUTPUT/bin/".to_string(),
+            ),
+            ..Default::default()
+        };
+        let downloader = MockDownloader::new("foo".as_bytes().to_vec());
+        let mpb = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let options = InstallOptions::default();
+
+        let plan = InstallPlan::new(&package, &state, &workspace, &mpb, &options).await?;
+        assert!(matches!(plan.stage(), InstallStage::Download));
+
+        let plan = plan.advance_with(&downloader).await?;
+        let InstallStage::Build { source, .. } = plan.stage() else {
+            panic!("expected build stage, got {:?}", plan.stage());
+        };
+        assert_eq!(std::fs::read_to_string(source.as_ref().unwrap())?, "foo");
+
+        let plan = plan.advance_with(&downloader).await?;
+        let InstallStage::Stage { output_dir } = plan.stage() else {
+            panic!("expected stage stage, got {:?}", plan.stage());
+        };
+        assert!(output_dir.path().join("bin").join("test-source").exists());
+        // Nothing is installed until the outputs are staged.
+        assert!(!package.package_directory().try_exists()?);
+
+        let plan = plan.advance_with(&downloader).await?;
+        let InstallStage::Link { pkg_dir } = plan.stage() else {
+            panic!("expected link stage, got {:?}", plan.stage());
+        };
+        assert!(pkg_dir.join("bin").join("test-source").exists());
+        assert!(state
+            .get_installed_package(&KnownPackage::from(&package))
+            .await?
+            .is_some());
+        assert!(!workspace.bin_directory()?.join("test-source").exists());
+
+        let plan = plan.advance_with(&downloader).await?;
+        assert!(plan.is_done());
+        assert!(workspace.bin_directory()?.join("test-source").exists());
+
+        // An installed package only needs to be linked.
+        let plan = InstallPlan::new(&package, &state, &workspace, &mpb, &options).await?;
+        assert!(matches!(plan.stage(), InstallStage::Link { .. }));
+        assert!(plan.run().await?.is_success());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_install_plan_stops_after_failed_build() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let (workspace, _workspace_root) = crate::workspace::test_workspace("global").await;
+        let package_root = TempDir::new()?;
+        crate::PACKAGE_ROOT
+            .set(package_root.path().to_owned())
+            .unwrap();
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            build: Some("exit 3".to_string()),
+            ..Default::default()
+        };
+        let mpb = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let options = InstallOptions::default();
+
+        let plan = InstallPlan::new(&package, &state, &workspace, &mpb, &options)
+            .await?
+            .advance()
+            .await?;
+        assert!(matches!(
+            plan.stage(),
+            InstallStage::Build { source: None, .. }
+        ));
+        let plan = plan.advance().await?;
+        assert!(plan.is_done());
+        assert_eq!(plan.run().await?.exit_code, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_install_refuses_other_platforms() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let (workspace, _workspace_root) = crate::workspace::test_workspace("global").await;
+        let package = Package {
+            name: "test-package".to_string(),
+            version: "0.1.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            platforms: vec!["plan9-mips".to_string()],
+            ..Default::default()
+        };
+
+        let mpb = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+        let err = package
+            .install(&state, &workspace, &mpb, &InstallOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "package test-package is not available for your platform ({})",
+                current_platform()
+            )
+        );
+
+        let package = Package {
+            platforms: vec![current_platform()],
+            ..package
+        };
+        assert!(package.check_platform().is_ok());
+        Ok(())
+    }
 }
@@ -1,14 +1,27 @@
-use std::{fmt::Display, ops::BitAnd, path::PathBuf, str::FromStr};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt::Display,
+    ops::BitAnd,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
 
 use color_eyre::eyre::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
 use sqlx::FromRow;
-use tokio::fs::remove_dir_all;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{fs::remove_dir_all, task::spawn_blocking};
 use tracing::instrument;
 
 use crate::{
-    error::{Conflicts, InvalidVersonSpec},
+    error::{Conflicts, InvalidVersonSpec, UnknownPackage},
     manifest::Package,
     state::State,
+    util::{normalize_package_name, LinkMode},
+    version::compare_versions,
     workspace::Workspace,
     PACKAGE_ROOT,
 };
@@ -99,12 +112,20 @@ impl PackageChangeSet {
     /// Resolves the changeset based on the current workflow packages.
     #[instrument]
     fn resolve(&mut self, current: &[WorkspacePackage]) -> Result<()> {
-        // Get all the requests currently in the workspace.
+        // Get all the requests currently in the workspace. Requests that conflict with a new one
+        // are replaced by it, so that installing `foo@2` moves a workspace off `foo@1`, while
+        // installing `foo` keeps it there.
         let current_requests = current
             .iter()
+            .filter(|p| {
+                !self
+                    .add
+                    .iter()
+                    .any(|r| r.name == p.name && !r.version.is_compatible(&p.requested_version))
+            })
             .map(|p| PackageRequest {
-                name: p.name.clone(),
-                version: p.requested_version.clone(),
+                requested_by: Some(format!("workspace package {}@{}", p.name, p.version)),
+                ..PackageRequest::new(&p.name, p.requested_version.clone())
             })
             .collect::<Vec<PackageRequest>>();
 
@@ -139,6 +160,67 @@ impl PackageChangeSet {
     }
 }
 
+/// Known versions of packages, so that each package's versions are only looked up once while
+/// resolving many requests.
+///
+/// Clones share the same cache. Entries are not keyed by workspace, so a cache must only be used
+/// to resolve requests for a single workspace.
+#[derive(Debug, Clone, Default)]
+pub struct VersionCache(Arc<Mutex<HashMap<VersionCacheKey, Vec<String>>>>);
+
+/// The package name and registry a [`VersionCache`] entry was looked up for.
+type VersionCacheKey = (String, Option<String>);
+
+impl VersionCache {
+    /// Returns the known versions of a package, newest first, querying the state on a cache miss.
+    async fn known_package_versions(
+        &self,
+        state: &State,
+        name: &str,
+        registry: Option<&str>,
+        workspace: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let key = (name.to_string(), registry.map(str::to_string));
+        if let Some(versions) = self.0.lock().expect("poisoned version cache").get(&key) {
+            return Ok(versions.clone());
+        }
+
+        let versions = state
+            .known_package_versions(name, registry, workspace)
+            .await?;
+        self.0
+            .lock()
+            .expect("poisoned version cache")
+            .insert(key, versions.clone());
+        Ok(versions)
+    }
+}
+
+/// Resolves the build dependencies of a package to the known packages to build it with.
+///
+/// Like the package itself, they are resolved from the registries visible in the workspace.
+#[instrument(skip(state))]
+pub async fn resolve_build_dependencies(
+    pkg: &Package,
+    state: &State,
+    workspace: &Workspace,
+) -> Result<Vec<Package>> {
+    let mut dependencies = vec![];
+    for request in &pkg.build_dependencies {
+        let request = request.parse::<PackageRequest>()?;
+        let spec = request
+            .resolve_known_version(state, Some(workspace))
+            .await
+            .wrap_err_with(|| format!("failed to resolve build dependency {request} of {pkg}"))?;
+        let dependency = state
+            .get_known_package(&spec)
+            .await?
+            .ok_or_else(|| anyhow!("package {spec} not found"))?;
+        dependencies.push(dependency);
+    }
+    Ok(dependencies)
+}
+
 /// A request for a package.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct PackageRequest {
@@ -146,28 +228,88 @@ pub struct PackageRequest {
     pub name: String,
     /// The requested version of the package.
     pub version: VersionSpec,
+    /// The name or URI of the registry to resolve the package from, if restricted to one.
+    pub registry: Option<String>,
+    /// What requested the package, for explaining conflicts. `None` for direct requests.
+    pub requested_by: Option<String>,
 }
 
 impl PackageRequest {
+    /// Creates a direct request for a package from any registry.
+    pub fn new(name: impl Into<String>, version: VersionSpec) -> Self {
+        Self {
+            name: name.into(),
+            version,
+            registry: None,
+            requested_by: None,
+        }
+    }
+
     /// Resolves this request to a known package that can be installed.
     ///
     /// If the version isn't fully qualified, resolves it to the latest known one. Returns an error
     /// if the package is not known. If multiple versions of the package are known, the first
     /// (latest) one that matches is used.
+    ///
+    /// If the request names a registry, only packages from that registry are considered. If a
+    /// workspace is given, only packages from registries visible in that workspace are considered.
+    ///
+    /// If no package has the requested name, but exactly one package provides it as a virtual
+    /// package, the request is resolved against that package instead.
     #[instrument(skip(state))]
-    pub async fn resolve_known_version(&self, state: &State) -> Result<KnownPackage> {
-        let known_versions = state.known_package_versions(&self.name).await?;
+    pub async fn resolve_known_version(
+        &self,
+        state: &State,
+        workspace: Option<&Workspace>,
+    ) -> Result<KnownPackage> {
+        self.resolve_known_version_cached(state, workspace, &VersionCache::default())
+            .await
+    }
+
+    /// Resolves this request like [`Self::resolve_known_version`], looking up known versions in a
+    /// cache shared with other requests first.
+    #[instrument(skip(state, cache))]
+    pub async fn resolve_known_version_cached(
+        &self,
+        state: &State,
+        workspace: Option<&Workspace>,
+        cache: &VersionCache,
+    ) -> Result<KnownPackage> {
+        let workspace_name = workspace.map(|ws| ws.name.as_str());
+        let known_versions = cache
+            .known_package_versions(state, &self.name, self.registry.as_deref(), workspace_name)
+            .await?;
 
         if known_versions.is_empty() {
-            return Err(anyhow!("package {} is not known", self.name));
+            let providers = state
+                .providers_of(&self.name, self.registry.as_deref(), workspace_name)
+                .await?;
+            match providers.as_slice() {
+                [] => {}
+                [provider] => {
+                    let request = Self {
+                        name: provider.clone(),
+                        ..self.clone()
+                    };
+                    return Box::pin(request.resolve_known_version_cached(state, workspace, cache))
+                        .await;
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "package {} is provided by several packages, pick one of: {}",
+                        self,
+                        providers.join(", ")
+                    ))
+                }
+            }
         }
 
         let Some(resolved) = known_versions.iter().find(|v| self.version.matches(v)) else {
-            return Err(anyhow!(
-                "package {} is not known, but these versions are: {}",
-                self,
-                known_versions.join(", ")
-            ));
+            return Err(UnknownPackage {
+                request: self.to_string(),
+                known_versions,
+            }
+            .into());
         };
 
         Ok(KnownPackage::from_request(self, resolved))
@@ -177,6 +319,9 @@ impl PackageRequest {
     ///
     /// If the version isn't fully qualified, resolves it to the latest installed one. Returns an
     /// error if the package is not installed in this workspace.
+    ///
+    /// The workspace package is returned as registered, with the requested version, registry and
+    /// link mode it was originally installed with, rather than the ones of this request.
     #[instrument(skip(state))]
     pub async fn resolve_workspace_version(
         &self,
@@ -195,7 +340,14 @@ impl PackageRequest {
             ));
         }
 
-        Ok(WorkspacePackage::from_request(self, &installed.version))
+        Ok(installed)
+    }
+
+    /// Describes what requested this package.
+    fn requester(&self) -> String {
+        self.requested_by
+            .clone()
+            .unwrap_or_else(|| "direct request".to_string())
     }
 }
 
@@ -204,29 +356,42 @@ impl FromStr for PackageRequest {
 
     #[instrument]
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (registry, s) = match s.split_once("::") {
+            Some(("", _)) => color_eyre::eyre::bail!("invalid dependency request: {}", s),
+            Some((registry, rest)) => (Some(registry.to_string()), rest),
+            None => (None, s),
+        };
         let mut parts = s.splitn(2, '@');
         let Some(name) = parts.next() else {
             color_eyre::eyre::bail!("invalid dependency request: {}", s);
         };
         let version = parts.next().unwrap_or("");
         Ok(Self {
-            name: name.into(),
+            name: normalize_package_name(name),
             version: version.parse()?,
+            registry,
+            requested_by: None,
         })
     }
 }
 
-impl From<WorkspacePackage> for PackageRequest {
-    fn from(value: WorkspacePackage) -> Self {
+/// Requests exactly the version a workspace package resolved to.
+impl From<&WorkspacePackage> for PackageRequest {
+    fn from(pkg: &WorkspacePackage) -> Self {
         Self {
-            name: value.name,
-            version: value.version.parse().expect("invalid version spec"),
+            name: pkg.name.clone(),
+            version: VersionSpec::exact(&pkg.version),
+            registry: pkg.registry.clone(),
+            requested_by: None,
         }
     }
 }
 
 impl Display for PackageRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(registry) = &self.registry {
+            write!(f, "{}::", registry)?;
+        }
         if let VersionSpec::Any = self.version {
             write!(f, "{}", self.name)
         } else {
@@ -247,7 +412,11 @@ pub enum VersionSpec {
     /// Any version at all.
     #[default]
     Any,
-    /// A version matching this prefix.
+    /// A version matching this prefix, component-wise.
+    ///
+    /// `~1.2` matches `1.2` itself, and any version that continues `1.2` with a `.`, `-`, or `+`,
+    /// such as `1.2.0`, `1.2.9`, or `1.2-beta`, i.e. roughly `>=1.2.0, <1.3.0`. It does not match
+    /// `1.20` or `1.3`.
     Partial(String),
     /// Exactly this version.
     Exact(String),
@@ -266,15 +435,11 @@ impl VersionSpec {
 
     /// Returns `true` if `version` matches this version spec.
     #[instrument]
-    fn matches(&self, version: &str) -> bool {
+    pub fn matches(&self, version: &str) -> bool {
         match self {
             VersionSpec::Any => true,
             VersionSpec::Exact(exact) => version == exact,
-            VersionSpec::Partial(prefix) => {
-                version.starts_with(prefix)
-                    && (version.len() == prefix.len()
-                        || !version.as_bytes()[prefix.len()].is_ascii_digit())
-            }
+            VersionSpec::Partial(prefix) => extends_prefix(version, prefix),
         }
     }
 
@@ -289,12 +454,22 @@ impl VersionSpec {
             (VersionSpec::Exact(a), VersionSpec::Partial(_)) => other.matches(a),
             (VersionSpec::Partial(_), VersionSpec::Exact(b)) => self.matches(b),
             (VersionSpec::Partial(a), VersionSpec::Partial(b)) => {
-                self.matches(b) || other.matches(a)
+                extends_prefix(b, a) || extends_prefix(a, b)
             }
         }
     }
 }
 
+/// Returns `true` if `version` is `prefix`, or continues it with a new component, pre-release, or
+/// build suffix.
+fn extends_prefix(version: &str, prefix: &str) -> bool {
+    match version.strip_prefix(prefix) {
+        Some("") => true,
+        Some(rest) => rest.starts_with(['.', '-', '+']),
+        None => false,
+    }
+}
+
 impl Display for VersionSpec {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -305,6 +480,10 @@ impl Display for VersionSpec {
     }
 }
 
+/// Intersects two version specs, returning `None` if no version could satisfy both.
+///
+/// Partial specs intersect to the more specific one, so `~1 & ~1.2` and `~1.2 & ~1` are both
+/// `~1.2`, while `~1.2 & ~1.3` is `None`.
 impl BitAnd for VersionSpec {
     type Output = Option<Self>;
 
@@ -322,7 +501,7 @@ impl BitAnd for VersionSpec {
             (VersionSpec::Exact(a), VersionSpec::Exact(_)) => VersionSpec::Exact(a),
             (VersionSpec::Exact(a), VersionSpec::Partial(_)) => VersionSpec::Exact(a),
             (VersionSpec::Partial(_), VersionSpec::Exact(b)) => VersionSpec::Exact(b),
-            (VersionSpec::Partial(a), VersionSpec::Partial(b)) if a.len() <= b.len() => rhs,
+            (VersionSpec::Partial(a), VersionSpec::Partial(b)) if extends_prefix(&b, &a) => rhs,
             _ => self,
         })
     }
@@ -359,38 +538,54 @@ fn merge_dependency_requests(
     requests: impl IntoIterator<Item = PackageRequest>,
 ) -> Result<Vec<PackageRequest>, Conflicts> {
     let mut rv: Vec<PackageRequest> = Vec::new();
+    // The requesters whose requests were merged into each entry of `rv`.
+    let mut requesters: Vec<Vec<String>> = Vec::new();
     let mut conflicts: Conflicts = Conflicts::default();
 
     for request in requests {
+        let requester = request.requester();
+
         // New request, just add it.
-        let Some(existing_request) = rv.iter_mut().find(|r| r.name == request.name) else {
+        let Some(idx) = rv.iter().position(|r| r.name == request.name) else {
             rv.push(request);
+            requesters.push(vec![requester]);
             continue;
         };
+        let existing_request = &mut rv[idx];
 
         // Existing compatible request, merge the version specs.
         if let Some(merged) = existing_request.version.clone() & request.version.clone() {
             existing_request.version = merged;
+            if !requesters[idx].contains(&requester) {
+                requesters[idx].push(requester);
+            }
             continue;
         }
 
         // Incompatible request, either add a new conflict or add to an existing one.
         conflicts.add_conflict(
             request.name,
-            existing_request.version.clone(),
-            request.version,
+            (existing_request.version.clone(), requesters[idx].join(", ")),
+            (request.version, requester),
         )
     }
 
     if conflicts.is_empty() {
-        Ok(rv)
+        // Merged requests can come from several requesters, which only matter for conflicts.
+        Ok(rv
+            .into_iter()
+            .map(|request| PackageRequest {
+                requested_by: None,
+                ..request
+            })
+            .collect())
     } else {
         Err(conflicts)
     }
 }
 
 /// A [`PackageRequest`] with a resolved version based on known packages.
-#[derive(Clone, Debug, FromRow)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, FromRow)]
 pub struct KnownPackage {
     /// The name of the package.
     pub name: String,
@@ -399,14 +594,7 @@ pub struct KnownPackage {
 }
 
 impl KnownPackage {
-    /// Creates a new spec from a [`crate::manifest::Package`].
-    pub fn from_manifest_package(pkg: &Package) -> Self {
-        Self {
-            name: pkg.name.clone(),
-            version: pkg.version.clone(),
-        }
-    }
-
+    /// Creates a new spec for a request resolved to the given version.
     pub fn from_request(request: &PackageRequest, version: &str) -> Self {
         Self {
             name: request.name.clone(),
@@ -427,13 +615,29 @@ impl Display for KnownPackage {
     }
 }
 
-// Test-only crutch.
-#[cfg(test)]
-impl From<WorkspacePackage> for KnownPackage {
-    fn from(spec: WorkspacePackage) -> Self {
+impl From<&Package> for KnownPackage {
+    fn from(pkg: &Package) -> Self {
+        Self {
+            name: pkg.name.clone(),
+            version: pkg.version.clone(),
+        }
+    }
+}
+
+impl From<&WorkspacePackage> for KnownPackage {
+    fn from(pkg: &WorkspacePackage) -> Self {
         Self {
-            name: spec.name,
-            version: spec.version,
+            name: pkg.name.clone(),
+            version: pkg.version.clone(),
+        }
+    }
+}
+
+impl From<&InstalledPackage> for KnownPackage {
+    fn from(pkg: &InstalledPackage) -> Self {
+        Self {
+            name: pkg.name.clone(),
+            version: pkg.version.clone(),
         }
     }
 }
@@ -448,28 +652,57 @@ pub struct WorkspacePackage {
     /// The unresolved version that was requested.
     #[sqlx(try_from = "String")]
     pub requested_version: VersionSpec,
+    /// The URI of the registry this package came from, if known.
+    pub registry: Option<String>,
+    /// How the package's binaries are placed in the workspace.
+    #[sqlx(try_from = "String")]
+    pub link_mode: LinkMode,
 }
 
 impl WorkspacePackage {
+    /// Creates a new workspace package for a request resolved to the given version.
+    ///
+    /// The registry is unknown, and has to be set separately, and binaries are symlinked.
     pub fn from_request(request: &PackageRequest, version: &str) -> Self {
         Self {
             name: request.name.clone(),
             version: version.to_string(),
             requested_version: request.version.clone(),
+            registry: None,
+            link_mode: LinkMode::default(),
         }
     }
 
+    /// Returns the installed package this workspace package uses.
+    ///
+    /// Falls back to deriving it from the workspace package if it isn't registered as installed.
+    #[instrument(skip(state))]
+    pub async fn installed_package(&self, state: &State) -> Result<InstalledPackage> {
+        Ok(state
+            .get_installed_package(self)
+            .await?
+            .unwrap_or_else(|| InstalledPackage::from(self)))
+    }
+
     /// Returns the latest known version of this package, if it is newer than the installed one.
+    ///
+    /// Only considers registries visible in the given workspace.
     #[instrument(skip(state))]
-    pub async fn available_update(&self, state: &State) -> Result<Option<KnownPackage>> {
-        let known_versions = state.known_package_versions(&self.name).await?;
+    pub async fn available_update(
+        &self,
+        state: &State,
+        workspace: &Workspace,
+    ) -> Result<Option<KnownPackage>> {
+        let known_versions = state
+            .known_package_versions(&self.name, None, Some(&workspace.name))
+            .await?;
         let Some(latest) = known_versions
             .into_iter()
             .find(|v| self.requested_version.matches(v))
         else {
             return Ok(None);
         };
-        if self.version < latest {
+        if compare_versions(&self.version, &latest) == Ordering::Less {
             Ok(Some(KnownPackage {
                 name: self.name.clone(),
                 version: latest,
@@ -478,15 +711,6 @@ impl WorkspacePackage {
             Ok(None)
         }
     }
-
-    /// Removes this package's files from a workspace.
-    #[instrument]
-    pub async fn remove(&self, workspace: &Workspace) -> Result<()> {
-        workspace
-            .remove_package(self)
-            .await
-            .wrap_err("failed to remove package from workspace")
-    }
 }
 
 impl PackageSpec for WorkspacePackage {
@@ -505,45 +729,194 @@ impl Display for WorkspacePackage {
     }
 }
 
-// Test-only crutch.
-#[cfg(test)]
-impl From<KnownPackage> for WorkspacePackage {
-    fn from(spec: KnownPackage) -> Self {
-        Self {
-            name: spec.name,
-            version: spec.version,
-            requested_version: VersionSpec::Any,
-        }
+/// Something that was done to a package in a workspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageAction {
+    Install,
+    Update,
+    Remove,
+}
+
+impl Display for PackageAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Install => "install",
+            Self::Update => "update",
+            Self::Remove => "remove",
+        })
     }
 }
 
+/// An entry in the package history of a workspace.
+#[derive(Clone, Debug, FromRow)]
+pub struct PackageEvent {
+    /// The name of the package.
+    pub name: String,
+    /// The version of the package.
+    pub version: String,
+    /// What was done to the package, see [`PackageAction`].
+    pub action: String,
+    /// When it happened.
+    pub timestamp: OffsetDateTime,
+}
+
+impl Display for PackageEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let timestamp = self
+            .timestamp
+            .format(&Rfc3339)
+            .map_err(|_| std::fmt::Error)?;
+        write!(
+            f,
+            "{} {} {}@{}",
+            timestamp, self.action, self.name, self.version
+        )
+    }
+}
+
+/// Returns the directory of the content store, which holds the actual files of installed
+/// packages, keyed by their content hash.
+///
+/// Packages with identical files share a single store entry.
+pub fn content_store_directory() -> PathBuf {
+    PACKAGE_ROOT
+        .get()
+        .expect("uninitialized package root")
+        .join(".store")
+}
+
+/// A lock on the package root, held shared by installs and exclusively by garbage collection.
+///
+/// This keeps garbage collection from removing packages that an install has built, but not yet
+/// added to a workspace. The lock is released when dropped, or when the process exits.
+pub struct PackageRootLock {
+    _file: std::fs::File,
+}
+
+impl PackageRootLock {
+    /// Takes a shared lock, waiting for a running garbage collection to finish.
+    pub async fn shared() -> Result<Self> {
+        Self::lock(libc::LOCK_SH).await
+    }
+
+    /// Takes an exclusive lock, waiting for running installs to finish.
+    pub async fn exclusive() -> Result<Self> {
+        Self::lock(libc::LOCK_EX).await
+    }
+
+    async fn lock(operation: libc::c_int) -> Result<Self> {
+        let root = PACKAGE_ROOT
+            .get()
+            .expect("uninitialized package root")
+            .clone();
+        spawn_blocking(move || {
+            std::fs::create_dir_all(&root)?;
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(root.join(".lock"))?;
+            // SAFETY: The file descriptor stays open for as long as `file` is alive.
+            if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(Self { _file: file })
+        })
+        .await?
+        .wrap_err("failed to lock package root")
+    }
+}
+
+/// The number of hex digits of the registry URI's hash used to namespace package directories.
+const REGISTRY_DIRECTORY_HASH_LENGTH: usize = 16;
+
+/// Returns the directory a package is installed to.
+///
+/// Packages from a registry are namespaced by a hash of its URI, as in
+/// `PACKAGE_ROOT/<registry-hash>/<name>/<version>`, so that registries can't clash. Packages
+/// installed before this layout existed live directly in `PACKAGE_ROOT/<name>/<version>`.
+pub fn package_directory(name: &str, version: &str, registry: Option<&str>) -> PathBuf {
+    let root = PACKAGE_ROOT.get().expect("uninitialized package root");
+    registry_scoped_directory(root, name, version, registry)
+}
+
+/// Returns the directory a package is installed to, relative to the package root.
+pub fn relative_package_directory(name: &str, version: &str, registry: Option<&str>) -> PathBuf {
+    registry_scoped_directory(Path::new(""), name, version, registry)
+}
+
+/// Returns the directory prefetched sources of a package are kept in until it is installed.
+///
+/// This mirrors the layout of [`package_directory`] inside `PACKAGE_ROOT/.sources`.
+pub fn source_cache_directory(name: &str, version: &str, registry: Option<&str>) -> PathBuf {
+    let root = PACKAGE_ROOT
+        .get()
+        .expect("uninitialized package root")
+        .join(".sources");
+    registry_scoped_directory(&root, name, version, registry)
+}
+
+/// Returns `<root>/<registry-hash>/<name>/<version>`, or `<root>/<name>/<version>` without a
+/// registry.
+fn registry_scoped_directory(
+    root: &Path,
+    name: &str,
+    version: &str,
+    registry: Option<&str>,
+) -> PathBuf {
+    let mut dir = root.to_path_buf();
+    if let Some(registry) = registry {
+        let hash = format!("{:x}", Sha256::digest(registry.as_bytes()));
+        dir.push(&hash[..REGISTRY_DIRECTORY_HASH_LENGTH]);
+    }
+    dir.join(name).join(version)
+}
+
 /// An installed package.
 ///
-/// This is mostly a shorter alias for [`crate::manifest::Package`], which only has the name and
-/// version, as it is stored in the database.
+/// This is mostly a shorter alias for [`crate::manifest::Package`], which only has the name,
+/// version, and registry, as it is stored in the database.
 #[derive(Clone, Debug, FromRow)]
 pub struct InstalledPackage {
     /// The name of the package.
     pub name: String,
     /// The version of the package.
     pub version: String,
+    /// The URI of the registry the package was installed from.
+    ///
+    /// Packages installed before package directories were namespaced by registry don't have one.
+    pub registry: Option<String>,
+    /// The hash of the package's entry in the content store.
+    ///
+    /// Packages installed before the content store existed don't have one.
+    pub content_hash: Option<String>,
+    /// The package directory relative to the package root, if it is not the one the registry
+    /// implies, because the registry's URI changed since the package was installed.
+    pub directory: Option<String>,
+    /// The build command the package was built with instead of its registry's, if any.
+    pub build_override: Option<String>,
 }
 
 impl InstalledPackage {
     /// Returns the directory of this package.
+    ///
+    /// For packages in the content store, this is a symlink to the store entry.
     pub fn directory(&self) -> PathBuf {
-        PACKAGE_ROOT
-            .get()
-            .expect("uninitialized package root")
-            .join(&self.name)
-            .join(&self.version)
+        match &self.directory {
+            Some(directory) => PACKAGE_ROOT
+                .get()
+                .expect("uninitialized package root")
+                .join(directory),
+            None => package_directory(&self.name, &self.version, self.registry.as_deref()),
+        }
     }
 
     /// Deletes this package's files from the package root.
     #[instrument]
     pub async fn delete(&self) -> Result<()> {
         let dir = self.directory();
-        if dir.try_exists()? {
+        // Don't follow the link into the content store, which might be shared.
+        if dir.symlink_metadata().is_ok() {
             remove_dir_all(dir).await?;
         }
         Ok(())
@@ -556,28 +929,40 @@ impl PackageSpec for InstalledPackage {
     }
 }
 
+impl Display for InstalledPackage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.name, self.version)
+    }
+}
+
 impl From<&WorkspacePackage> for InstalledPackage {
     fn from(spec: &WorkspacePackage) -> Self {
         Self {
             name: spec.name.clone(),
             version: spec.version.clone(),
+            registry: spec.registry.clone(),
+            content_hash: None,
+            directory: None,
+            build_override: None,
         }
     }
 }
 
-impl From<Package> for InstalledPackage {
-    fn from(pkg: Package) -> Self {
+impl From<&Package> for InstalledPackage {
+    fn from(pkg: &Package) -> Self {
         Self {
-            name: pkg.name,
-            version: pkg.version,
+            name: pkg.name.clone(),
+            version: pkg.version.clone(),
+            registry: pkg.registry.clone(),
+            content_hash: None,
+            directory: None,
+            build_override: None,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::HashSet;
-
     use crate::{
         manifest::Package as ManifestPackage,
         registry::{MockFetcher, Registry},
@@ -586,6 +971,24 @@ mod tests {
 
     use super::*;
 
+    #[tokio::test]
+    async fn test_package_root_lock() -> Result<()> {
+        let package_root = tempfile::TempDir::new()?;
+        PACKAGE_ROOT.set(package_root.path().to_owned()).unwrap();
+        let wait = std::time::Duration::from_millis(100);
+
+        let install = PackageRootLock::shared().await?;
+        let other_install = PackageRootLock::shared().await?;
+        assert!(tokio::time::timeout(wait, PackageRootLock::exclusive())
+            .await
+            .is_err());
+
+        drop(install);
+        drop(other_install);
+        let _gc = PackageRootLock::exclusive().await?;
+        Ok(())
+    }
+
     #[test]
     fn test_matches_any_version() {
         assert!(VersionSpec::Any.matches("1.0.0"));
@@ -615,6 +1018,20 @@ mod tests {
         assert!(VersionSpec::partial("1").matches("1-alpha2"));
     }
 
+    #[test]
+    fn test_matches_partial_version_by_component() {
+        assert!(VersionSpec::partial("1.2").matches("1.2"));
+        assert!(VersionSpec::partial("1.2").matches("1.2.0"));
+        assert!(VersionSpec::partial("1.2").matches("1.2.9"));
+        assert!(VersionSpec::partial("1.2").matches("1.2-beta"));
+        assert!(VersionSpec::partial("1.2").matches("1.2+build1"));
+
+        assert!(!VersionSpec::partial("1.2").matches("1.20"));
+        assert!(!VersionSpec::partial("1.2").matches("1.2x"));
+        assert!(!VersionSpec::partial("1.2").matches("1.3.0"));
+        assert!(!VersionSpec::partial("1.2").matches("1"));
+    }
+
     #[test]
     fn test_is_compatible_any() {
         assert!(VersionSpec::Any.is_compatible(&VersionSpec::Any));
@@ -699,6 +1116,30 @@ mod tests {
         assert_eq!(VersionSpec::partial("1") & VersionSpec::partial("2"), None);
     }
 
+    #[test]
+    fn test_bit_and_nested_partials() {
+        assert_eq!(
+            VersionSpec::partial("1") & VersionSpec::partial("1.2"),
+            Some(VersionSpec::partial("1.2"))
+        );
+        assert_eq!(
+            VersionSpec::partial("1.2") & VersionSpec::partial("1"),
+            Some(VersionSpec::partial("1.2"))
+        );
+        assert_eq!(
+            VersionSpec::partial("1.2") & VersionSpec::partial("1.2.3"),
+            Some(VersionSpec::partial("1.2.3"))
+        );
+        assert_eq!(
+            VersionSpec::partial("1.2") & VersionSpec::partial("1.3"),
+            None
+        );
+        assert_eq!(
+            VersionSpec::partial("1.2") & VersionSpec::partial("1.20"),
+            None
+        );
+    }
+
     #[test]
     fn test_parse_version_spec() {
         assert_eq!(VersionSpec::from_str("").unwrap(), VersionSpec::Any);
@@ -717,19 +1158,10 @@ mod tests {
     fn test_merge_dependency_requests_all_any() -> Result<()> {
         assert_eq!(
             merge_dependency_requests(vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::Any
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::Any
-                }
+                PackageRequest::new("foo", VersionSpec::Any),
+                PackageRequest::new("foo", VersionSpec::Any)
             ])?,
-            vec![PackageRequest {
-                name: "foo".into(),
-                version: VersionSpec::Any
-            }]
+            vec![PackageRequest::new("foo", VersionSpec::Any)]
         );
         Ok(())
     }
@@ -738,19 +1170,10 @@ mod tests {
     fn test_merge_dependency_requests_any_exact() -> Result<()> {
         assert_eq!(
             merge_dependency_requests(vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::Any
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.0")
-                }
+                PackageRequest::new("foo", VersionSpec::Any),
+                PackageRequest::new("foo", VersionSpec::exact("1.0.0"))
             ])?,
-            vec![PackageRequest {
-                name: "foo".into(),
-                version: VersionSpec::exact("1.0.0")
-            }]
+            vec![PackageRequest::new("foo", VersionSpec::exact("1.0.0"))]
         );
         Ok(())
     }
@@ -759,19 +1182,10 @@ mod tests {
     fn test_merge_dependency_requests_any_partial() -> Result<()> {
         assert_eq!(
             merge_dependency_requests(vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::Any
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::partial("1")
-                }
+                PackageRequest::new("foo", VersionSpec::Any),
+                PackageRequest::new("foo", VersionSpec::partial("1"))
             ])?,
-            vec![PackageRequest {
-                name: "foo".into(),
-                version: VersionSpec::partial("1")
-            }]
+            vec![PackageRequest::new("foo", VersionSpec::partial("1"))]
         );
         Ok(())
     }
@@ -780,19 +1194,10 @@ mod tests {
     fn test_merge_dependency_requests_matching_partials() -> Result<()> {
         assert_eq!(
             merge_dependency_requests(vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::partial("1")
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::partial("1.0")
-                }
+                PackageRequest::new("foo", VersionSpec::partial("1")),
+                PackageRequest::new("foo", VersionSpec::partial("1.0"))
             ])?,
-            vec![PackageRequest {
-                name: "foo".into(),
-                version: VersionSpec::partial("1.0")
-            }]
+            vec![PackageRequest::new("foo", VersionSpec::partial("1.0"))]
         );
         Ok(())
     }
@@ -801,19 +1206,16 @@ mod tests {
     fn test_merge_dependency_requests_mismatching_partials() {
         assert_eq!(
             merge_dependency_requests(vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::partial("1")
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::partial("2")
-                }
+                PackageRequest::new("foo", VersionSpec::partial("1")),
+                PackageRequest::new("foo", VersionSpec::partial("2"))
             ]),
             Err(Conflicts {
                 inner: vec![(
                     "foo".into(),
-                    HashSet::from([VersionSpec::partial("1"), VersionSpec::partial("2")])
+                    vec![
+                        (VersionSpec::partial("1"), "direct request".into()),
+                        (VersionSpec::partial("2"), "direct request".into())
+                    ]
                 )]
             })
         );
@@ -823,19 +1225,10 @@ mod tests {
     fn test_merge_dependency_requests_partial_exact() -> Result<()> {
         assert_eq!(
             merge_dependency_requests(vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::partial("1")
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.0")
-                }
+                PackageRequest::new("foo", VersionSpec::partial("1")),
+                PackageRequest::new("foo", VersionSpec::exact("1.0.0"))
             ])?,
-            vec![PackageRequest {
-                name: "foo".into(),
-                version: VersionSpec::exact("1.0.0")
-            }]
+            vec![PackageRequest::new("foo", VersionSpec::exact("1.0.0"))]
         );
         Ok(())
     }
@@ -844,23 +1237,17 @@ mod tests {
     fn test_merge_dependency_requests_exact_mismatch() {
         assert_eq!(
             merge_dependency_requests(vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::Any
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.0")
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.1")
-                }
+                PackageRequest::new("foo", VersionSpec::Any),
+                PackageRequest::new("foo", VersionSpec::exact("1.0.0")),
+                PackageRequest::new("foo", VersionSpec::exact("1.0.1"))
             ]),
             Err(Conflicts {
                 inner: vec![(
                     "foo".into(),
-                    HashSet::from([VersionSpec::exact("1.0.0"), VersionSpec::exact("1.0.1")])
+                    vec![
+                        (VersionSpec::exact("1.0.0"), "direct request".into()),
+                        (VersionSpec::exact("1.0.1"), "direct request".into())
+                    ]
                 )]
             })
         );
@@ -870,19 +1257,10 @@ mod tests {
     fn test_merge_dependency_requests_matching_exact() -> Result<()> {
         assert_eq!(
             merge_dependency_requests(vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.0")
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.0")
-                }
+                PackageRequest::new("foo", VersionSpec::exact("1.0.0")),
+                PackageRequest::new("foo", VersionSpec::exact("1.0.0"))
             ])?,
-            vec![PackageRequest {
-                name: "foo".into(),
-                version: VersionSpec::exact("1.0.0")
-            }]
+            vec![PackageRequest::new("foo", VersionSpec::exact("1.0.0"))]
         );
         Ok(())
     }
@@ -891,24 +1269,12 @@ mod tests {
     fn test_merge_dependency_requests_different_names() -> Result<()> {
         assert_eq!(
             merge_dependency_requests(vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.0")
-                },
-                PackageRequest {
-                    name: "bar".into(),
-                    version: VersionSpec::exact("2.0.0")
-                }
+                PackageRequest::new("foo", VersionSpec::exact("1.0.0")),
+                PackageRequest::new("bar", VersionSpec::exact("2.0.0"))
             ])?,
             vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.0")
-                },
-                PackageRequest {
-                    name: "bar".into(),
-                    version: VersionSpec::exact("2.0.0")
-                }
+                PackageRequest::new("foo", VersionSpec::exact("1.0.0")),
+                PackageRequest::new("bar", VersionSpec::exact("2.0.0"))
             ]
         );
         Ok(())
@@ -918,27 +1284,18 @@ mod tests {
     fn test_merge_dependency_requests_triple_conflict() {
         assert_eq!(
             merge_dependency_requests(vec![
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.0")
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.1")
-                },
-                PackageRequest {
-                    name: "foo".into(),
-                    version: VersionSpec::exact("1.0.2")
-                }
+                PackageRequest::new("foo", VersionSpec::exact("1.0.0")),
+                PackageRequest::new("foo", VersionSpec::exact("1.0.1")),
+                PackageRequest::new("foo", VersionSpec::exact("1.0.2"))
             ]),
             Err(Conflicts {
                 inner: vec![(
                     "foo".into(),
-                    HashSet::from([
-                        VersionSpec::exact("1.0.0"),
-                        VersionSpec::exact("1.0.1"),
-                        VersionSpec::exact("1.0.2")
-                    ])
+                    vec![
+                        (VersionSpec::exact("1.0.0"), "direct request".into()),
+                        (VersionSpec::exact("1.0.1"), "direct request".into()),
+                        (VersionSpec::exact("1.0.2"), "direct request".into())
+                    ]
                 )]
             })
         );
@@ -955,6 +1312,12 @@ mod tests {
         let any = "foo";
         assert_eq!(format!("{}", any.parse::<PackageRequest>()?), any);
 
+        let qualified = "reg::foo@1.0";
+        assert_eq!(
+            format!("{}", qualified.parse::<PackageRequest>()?),
+            qualified
+        );
+
         Ok(())
     }
 
@@ -976,8 +1339,143 @@ mod tests {
             .await
             .unwrap();
         let pkg: PackageRequest = "foo".parse()?;
-        let spec = pkg.resolve_known_version(&state).await.unwrap();
+        let spec = pkg.resolve_known_version(&state, None).await.unwrap();
+        assert_eq!(spec.version, "1.0.0");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_known_version_cached() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let mut registry = Registry::new("https://example.invalid/registry");
+        registry.initialize(&state, &MockFetcher::default()).await?;
+        let foo = ManifestPackage {
+            name: "foo".to_string(),
+            version: "1.0.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            ..Default::default()
+        };
+        state.add_known_packages(std::slice::from_ref(&foo)).await?;
+
+        let cache = VersionCache::default();
+        let pkg: PackageRequest = "foo".parse()?;
+        let spec = pkg
+            .resolve_known_version_cached(&state, None, &cache)
+            .await?;
+        assert_eq!(spec.version, "1.0.0");
+
+        // Later requests for the same package are resolved without querying the state again.
+        state.remove_known_package(&foo).await?;
+        let pkg: PackageRequest = "foo@~1".parse()?;
+        let spec = pkg
+            .resolve_known_version_cached(&state, None, &cache)
+            .await?;
+        assert_eq!(spec.version, "1.0.0");
+        assert_eq!(cache.0.lock().unwrap().len(), 1);
+
+        assert!(pkg.resolve_known_version(&state, None).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_registry_qualified_request() -> Result<()> {
+        let req: PackageRequest = "reg::foo@1.0".parse()?;
+        assert_eq!(req.registry.as_deref(), Some("reg"));
+        assert_eq!(req.name, "foo");
+        assert_eq!(req.version, VersionSpec::exact("1.0"));
+
+        let req: PackageRequest = "foo@1.0".parse()?;
+        assert_eq!(req.registry, None);
+
+        assert!("::foo".parse::<PackageRequest>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_request_normalizes_name() -> Result<()> {
+        let req: PackageRequest = "Reg::Foo-Bar@1.0".parse()?;
+        assert_eq!(req.name, "foo-bar");
+        // Registry names are not package names, so they are left alone.
+        assert_eq!(req.registry.as_deref(), Some("Reg"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_known_version_restricted_to_registry() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let fetcher = MockFetcher::with_packages(&[ManifestPackage {
+            name: "foo".to_string(),
+            version: "1.0.0".to_string(),
+            ..Default::default()
+        }]);
+        let mut registry = Registry::new("https://example.invalid/registry");
+        registry.initialize(&state, &fetcher).await?;
+        registry.fetch(&state, &fetcher).await?;
+
+        let other_fetcher = MockFetcher {
+            manifest: r#"
+                schema_version = 1
+                name = "other"
+
+                [[packages]]
+                name = "foo"
+                version = "2.0.0"
+            "#
+            .into(),
+            ..Default::default()
+        };
+        let mut other_registry = Registry::new("https://example.invalid/other");
+        other_registry.initialize(&state, &other_fetcher).await?;
+        other_registry.fetch(&state, &other_fetcher).await?;
+
+        let pkg: PackageRequest = "foo".parse()?;
+        assert_eq!(
+            pkg.resolve_known_version(&state, None).await?.version,
+            "2.0.0"
+        );
+
+        let pkg: PackageRequest = "test::foo".parse()?;
+        assert_eq!(
+            pkg.resolve_known_version(&state, None).await?.version,
+            "1.0.0"
+        );
+
+        let pkg: PackageRequest = "other::foo".parse()?;
+        assert_eq!(
+            pkg.resolve_known_version(&state, None).await?.version,
+            "2.0.0"
+        );
+
+        let pkg: PackageRequest = "test::foo@2.0.0".parse()?;
+        assert!(pkg.resolve_known_version(&state, None).await.is_err());
+
+        let pkg: PackageRequest = "unknown::foo".parse()?;
+        assert!(pkg.resolve_known_version(&state, None).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_known_version_via_provides() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let mut registry = Registry::new("https://example.invalid/registry");
+        registry.initialize(&state, &MockFetcher::default()).await?;
+        let provider = |name: &str| ManifestPackage {
+            name: name.to_string(),
+            version: "1.0.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            provides: vec!["editor".to_string()],
+            ..Default::default()
+        };
+        state.add_known_packages(&[provider("vim")]).await?;
+
+        let pkg: PackageRequest = "editor".parse()?;
+        let spec = pkg.resolve_known_version(&state, None).await?;
+        assert_eq!(spec.name, "vim");
         assert_eq!(spec.version, "1.0.0");
+
+        state.add_known_packages(&[provider("emacs")]).await?;
+        let err = pkg.resolve_known_version(&state, None).await.unwrap_err();
+        assert!(err.to_string().contains("pick one of: emacs, vim"));
         Ok(())
     }
 
@@ -985,7 +1483,7 @@ mod tests {
     async fn test_resolve_known_version_fails_if_not_known() -> Result<()> {
         let state = State::load(":memory:").await.unwrap();
         let pkg: PackageRequest = "foo".parse()?;
-        assert!(pkg.resolve_known_version(&state).await.is_err());
+        assert!(pkg.resolve_known_version(&state, None).await.is_err());
         Ok(())
     }
 
@@ -1008,7 +1506,7 @@ mod tests {
         state.add_known_packages(&[known_package]).await?;
 
         let pkg: PackageRequest = "foo@2.0.0".parse()?;
-        assert!(pkg.resolve_known_version(&state).await.is_err());
+        assert!(pkg.resolve_known_version(&state, None).await.is_err());
         Ok(())
     }
 
@@ -1021,7 +1519,9 @@ mod tests {
         let known_package = KnownPackage::from_request(&req, "1.0.0");
         let workspace_package = WorkspacePackage::from_request(&req, "1.0.0");
 
-        state.add_installed_package(&known_package).await?;
+        state
+            .add_installed_package(&known_package, None, None)
+            .await?;
         state
             .add_workspace_package(&workspace_package, &workspace)
             .await?;
@@ -1053,7 +1553,9 @@ mod tests {
         let known_package = KnownPackage::from_request(&req, "1.0.0");
         let workspace_package = WorkspacePackage::from_request(&req, "1.0.0");
 
-        state.add_installed_package(&known_package).await?;
+        state
+            .add_installed_package(&known_package, None, None)
+            .await?;
 
         state
             .add_workspace_package(&workspace_package, &workspace)
@@ -1066,6 +1568,32 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_changeset_conflicts_name_requesters() -> Result<()> {
+        let dependency = PackageRequest {
+            requested_by: Some("bar@1.0.0".to_string()),
+            .."foo@1".parse()?
+        };
+        let err = PackageChangeSet::add_packages(&[dependency, "foo@2".parse()?], &[]).unwrap_err();
+
+        let conflicts = err.downcast_ref::<Conflicts>().unwrap();
+        assert_eq!(
+            conflicts.inner,
+            vec![(
+                "foo".to_string(),
+                vec![
+                    (VersionSpec::exact("1"), "bar@1.0.0".to_string()),
+                    (VersionSpec::exact("2"), "direct request".to_string()),
+                ]
+            )]
+        );
+        let msg = conflicts.to_string();
+        assert!(msg.contains("conflicting requests for dependency 'foo'"));
+        assert!(msg.contains("requested by bar@1.0.0"));
+        assert!(msg.contains("requested by direct request"));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_changeset_resolve_add_new_in_vacuum() -> Result<()> {
         let changeset = PackageChangeSet::add_packages(&["foo@1.0.0".parse()?], &[])?;
@@ -1112,14 +1640,25 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_changeset_resolve_add_new_preexisting_conflicts() -> Result<()> {
+    async fn test_changeset_resolve_add_new_preexisting_pinned_needs_change() -> Result<()> {
         let changeset = PackageChangeSet::add_packages(
             &["foo@2".parse()?],
             &[WorkspacePackage::from_request(
                 &"foo@1".parse::<PackageRequest>()?,
                 "1",
             )],
-        );
+        )?;
+
+        let changed = changeset.changed_packages().collect::<Vec<_>>();
+        assert_eq!(changed.len(), 1);
+        assert!(changed.contains(&"foo@2".parse()?));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_changeset_resolve_add_new_conflicting_requests() -> Result<()> {
+        let changeset = PackageChangeSet::add_packages(&["foo@1".parse()?, "foo@2".parse()?], &[]);
 
         assert!(changeset.unwrap_err().to_string().contains("conflict"));
 
@@ -1159,4 +1698,39 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_package_spec_conversions() -> Result<()> {
+        let pkg = ManifestPackage {
+            name: "foo".into(),
+            version: "1.0.0".into(),
+            registry: Some("https://example.invalid/registry".into()),
+            ..Default::default()
+        };
+        let installed = InstalledPackage::from(&pkg);
+        assert_eq!(installed.registry, pkg.registry);
+        assert_eq!(KnownPackage::from(&pkg).spec(), installed.spec());
+        assert_eq!(KnownPackage::from(&installed).spec(), installed.spec());
+
+        let workspace_package = WorkspacePackage::from_request(&"foo@~1".parse()?, "1.0.0");
+        assert_eq!(
+            KnownPackage::from(&workspace_package).spec(),
+            installed.spec()
+        );
+        let request = PackageRequest::from(&workspace_package);
+        assert_eq!(request.version, VersionSpec::exact("1.0.0"));
+        assert_eq!(request.spec(), ("foo".into(), "1.0.0".into()));
+        assert_eq!(request.to_string(), "foo@1.0.0");
+
+        // The registry is kept, so that the request resolves to the same package.
+        let workspace_package = WorkspacePackage {
+            registry: Some("reg".to_string()),
+            ..workspace_package
+        };
+        let request = PackageRequest::from(&workspace_package);
+        assert_eq!(request.to_string(), "reg::foo@1.0.0");
+        assert_eq!(request.to_string().parse::<PackageRequest>()?, request);
+
+        Ok(())
+    }
 }
@@ -1,14 +1,31 @@
-use std::{fmt::Display, future::Future, path::PathBuf, str::FromStr, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    future::Future,
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use color_eyre::eyre::{anyhow, Context, Result};
+use ed25519_dalek::{Signature, VerifyingKey, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+use flate2::read::GzDecoder;
+use sha2::{Digest, Sha256};
 use sqlx::{sqlite::SqliteRow, FromRow, Row};
-use time::OffsetDateTime;
-use tokio::fs::read_to_string;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::fs::{create_dir_all, read, write};
 use tracing::instrument;
 
 use crate::{
-    download::download_file, manifest::Manifest, package::KnownPackage, state::State,
-    util::is_file_system_safe,
+    download::{CacheValidators, DefaultDownloader, Downloader},
+    manifest::Manifest,
+    package::KnownPackage,
+    state::State,
+    util::expand_tilde,
+    version::compare_versions,
+    KEEP_VERSIONS,
 };
 
 #[cfg(test)]
@@ -17,6 +34,12 @@ use crate::manifest::Package;
 /// How often to update registries.
 const UPDATE_AFTER: Duration = Duration::from_secs(60 * 24);
 
+/// How much of a broken manifest to show in error messages.
+const SNIPPET_LENGTH: usize = 200;
+
+/// The magic bytes gzip-compressed data starts with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
 /// A registry is a place that has manifests.
 #[derive(Debug)]
 pub struct Registry {
@@ -28,6 +51,32 @@ pub struct Registry {
     pub uri: Uri,
     /// The last time this registry was fetched.
     pub last_fetched: Option<OffsetDateTime>,
+    /// The workspace this registry is restricted to.
+    ///
+    /// Registries without a workspace are used in all workspaces.
+    pub workspace: Option<String>,
+    /// Validators of the last fetched manifest, to skip fetching it again if it is unchanged.
+    pub validators: CacheValidators,
+    /// The base64-encoded ed25519 key manifests of this registry have to be signed with, if any.
+    pub public_key: Option<String>,
+}
+
+/// The changes fetching a registry makes to its known packages.
+#[derive(Debug, Default)]
+pub struct RegistryDiff {
+    /// Packages that are new in the manifest.
+    pub added: Vec<KnownPackage>,
+    /// Packages that are no longer in the manifest.
+    pub removed: Vec<KnownPackage>,
+    /// Packages whose metadata, such as the build command, changed.
+    pub updated: Vec<KnownPackage>,
+}
+
+impl RegistryDiff {
+    /// Returns if fetching would not change anything.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.updated.is_empty()
+    }
 }
 
 /// A registry URI.
@@ -48,6 +97,9 @@ impl Registry {
             name: None,
             uri: uri.into(),
             last_fetched: None,
+            workspace: None,
+            validators: CacheValidators::default(),
+            public_key: None,
         }
     }
 
@@ -68,29 +120,79 @@ impl Registry {
     }
 
     /// Fetches the manifest from the registry and stores updates in the database.
+    ///
+    /// If the registry reports the manifest as unchanged since the last fetch, only the fetch time
+    /// is updated.
     #[instrument(skip(state, fetcher))]
     pub async fn fetch(&mut self, state: &State, fetcher: &impl Fetcher) -> Result<()> {
-        let manifest = self.download(fetcher).await?;
+        let manifest = match fetcher.fetch_if_modified(self).await? {
+            Fetched::NotModified => {
+                // The manifest didn't change, but how many versions to keep might have.
+                if let Some(keep) = KEEP_VERSIONS.get() {
+                    self.prune_versions(state, *keep).await?;
+                }
+                self.last_fetched = Some(OffsetDateTime::now_utc());
+                return state
+                    .update_registry(self)
+                    .await
+                    .wrap_err("failed to update registry in database");
+            }
+            Fetched::Modified {
+                manifest,
+                validators,
+            } => {
+                self.verify_manifest(fetcher, &manifest).await?;
+                self.validators = validators;
+                self.decode_manifest(manifest)?
+            }
+        };
 
         // TODO: Keep and compare a manifest hash to avoid unnecessary updates.
 
-        if let Some(pkg) = manifest
-            .packages
-            .iter()
-            .find(|p| !is_file_system_safe(&p.name) || !is_file_system_safe(&p.version))
-        {
-            return Err(anyhow!("invalid package name or version: {}", pkg));
+        let diff = self.diff(state, &manifest).await?;
+        self.apply_diff(state, &manifest, &diff).await
+    }
+
+    /// Downloads the manifest and returns what fetching it would change, without writing anything
+    /// to the database.
+    ///
+    /// Versions that `--keep-versions` would prune are not included.
+    #[instrument(skip(state, fetcher))]
+    pub async fn fetch_dry_run(
+        &self,
+        state: &State,
+        fetcher: &impl Fetcher,
+    ) -> Result<RegistryDiff> {
+        let manifest = self.download(fetcher).await?;
+        let mut diff = self.diff(state, &manifest).await?;
+        if let Some(keep) = KEEP_VERSIONS.get() {
+            let packages = manifest.packages.iter().map(KnownPackage::from).collect();
+            for pkg in versions_to_prune(state, packages, *keep).await? {
+                // Added versions are pruned right away, known ones are removed.
+                if let Some(i) = diff.added.iter().position(|added| *added == pkg) {
+                    diff.added.remove(i);
+                } else {
+                    diff.updated.retain(|updated| *updated != pkg);
+                    diff.removed.push(pkg);
+                }
+            }
         }
+        Ok(diff)
+    }
+
+    /// Compares a freshly fetched manifest to the known packages of this registry.
+    ///
+    /// Fails if the manifest is invalid, or if any of its packages collide with another
+    /// registry's.
+    async fn diff(&self, state: &State, manifest: &Manifest) -> Result<RegistryDiff> {
+        manifest.validate()?;
 
         // Check if any packages collide with another registry's ones.
         let collisions = {
             let mut collisions = Vec::new();
             for pkg in &manifest.packages {
                 if let Some(other) = state
-                    .get_known_package(&KnownPackage {
-                        name: pkg.name.clone(),
-                        version: pkg.version.clone(),
-                    })
+                    .get_known_package(&KnownPackage::from(pkg))
                     .await
                     .wrap_err("failed to check for pre-existing known package")?
                 {
@@ -116,25 +218,53 @@ impl Registry {
             return Err(anyhow!(msg));
         }
 
-        // Remove packages that are no longer in the manifest.
-        let know_packages = state.known_packages_for_registry(self).await?;
-        for pkg in &know_packages {
-            if !manifest.packages.contains(pkg) {
-                state
-                    .remove_known_package(&KnownPackage {
-                        name: pkg.name.clone(),
-                        version: pkg.version.clone(),
-                    })
-                    .await?;
+        let known_packages = state.known_packages_for_registry(self).await?;
+        let mut diff = RegistryDiff::default();
+        for pkg in &manifest.packages {
+            match known_packages
+                .iter()
+                .find(|p| p.name == pkg.name && p.version == pkg.version)
+            {
+                None => diff.added.push(KnownPackage::from(pkg)),
+                Some(known) if known != pkg => diff.updated.push(KnownPackage::from(pkg)),
+                Some(_) => {}
+            }
+        }
+        for pkg in &known_packages {
+            if !manifest
+                .packages
+                .iter()
+                .any(|p| p.name == pkg.name && p.version == pkg.version)
+            {
+                diff.removed.push(KnownPackage::from(pkg));
             }
         }
 
-        // Add new packages.
+        Ok(diff)
+    }
+
+    /// Writes the changes of a fetched manifest to the database.
+    async fn apply_diff(
+        &mut self,
+        state: &State,
+        manifest: &Manifest,
+        diff: &RegistryDiff,
+    ) -> Result<()> {
+        // Remove packages that are no longer in the manifest.
+        for pkg in &diff.removed {
+            state.remove_known_package(pkg).await?;
+        }
+
+        // Add new packages, and update changed ones.
         state
             .add_known_packages(&manifest.packages)
             .await
             .wrap_err("failed to add new known packages")?;
 
+        if let Some(keep) = KEEP_VERSIONS.get() {
+            self.prune_versions(state, *keep).await?;
+        }
+
         // Update name if changed.
         self.name = Some(manifest.name.clone());
         self.last_fetched = Some(OffsetDateTime::now_utc());
@@ -146,15 +276,131 @@ impl Registry {
         Ok(())
     }
 
+    /// Forgets all but the newest `keep` versions of each of this registry's packages.
+    ///
+    /// Installed versions are always kept, so that they can still be reinstalled.
+    #[instrument(skip(state))]
+    pub async fn prune_versions(&self, state: &State, keep: usize) -> Result<()> {
+        let packages = state
+            .known_packages_for_registry(self)
+            .await?
+            .iter()
+            .map(KnownPackage::from)
+            .collect();
+        for pkg in versions_to_prune(state, packages, keep).await? {
+            state
+                .remove_known_package(&pkg)
+                .await
+                .wrap_err("failed to remove old package version")?;
+        }
+
+        Ok(())
+    }
+
     /// Fetches the manifest from the registry.
+    ///
+    /// Gzip-compressed manifests are decompressed transparently.
     #[instrument(skip(fetcher))]
     async fn download(&self, fetcher: &impl Fetcher) -> Result<Manifest> {
-        let s = fetcher.fetch(self).await?;
-        let mut manifest: Manifest = s.parse().wrap_err("failed to parse manifest")?;
+        let bytes = fetcher.fetch(self).await?;
+        self.verify_manifest(fetcher, &bytes).await?;
+        self.decode_manifest(bytes)
+    }
+
+    /// Checks a raw manifest against its signature, if the registry has a public key.
+    ///
+    /// The signature is fetched from next to the manifest, with a `.sig` suffix.
+    async fn verify_manifest(&self, fetcher: &impl Fetcher, bytes: &[u8]) -> Result<()> {
+        let Some(public_key) = &self.public_key else {
+            return Ok(());
+        };
+        let signature = fetcher.fetch_signature(self).await.wrap_err(format!(
+            "failed to fetch signature of registry {}",
+            self.uri
+        ))?;
+        verify_signature(public_key, bytes, &signature).wrap_err(format!(
+            "manifest of registry {} failed signature verification",
+            self.uri
+        ))
+    }
+
+    /// Decompresses and parses a fetched manifest.
+    fn decode_manifest(&self, mut bytes: Vec<u8>) -> Result<Manifest> {
+        if bytes.starts_with(&GZIP_MAGIC) {
+            bytes = self.decompress_manifest(&bytes)?;
+        }
+        let mut manifest = self.parse_manifest(bytes)?;
         manifest.set_registry_uri(&self.uri.to_string());
         Ok(manifest)
     }
 
+    /// Decompresses a gzip-compressed manifest.
+    fn decompress_manifest(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut decompressed = vec![];
+        GzDecoder::new(bytes)
+            .read_to_end(&mut decompressed)
+            .wrap_err(format!(
+                "registry {} returned a corrupt gzip-compressed manifest",
+                self.uri
+            ))?;
+        Ok(decompressed)
+    }
+
+    /// Parses a fetched manifest, explaining what is wrong with it if it is broken.
+    fn parse_manifest(&self, bytes: Vec<u8>) -> Result<Manifest> {
+        let s = String::from_utf8(bytes).map_err(|e| {
+            anyhow!(
+                "registry {} did not return valid UTF-8, got: {}",
+                self.uri,
+                snippet(&String::from_utf8_lossy(e.as_bytes()))
+            )
+        })?;
+        if let Err(e) = s.parse::<toml::Table>() {
+            return Err(anyhow!(
+                "registry {} did not return valid TOML ({}), got: {}",
+                self.uri,
+                e.message(),
+                snippet(&s)
+            ));
+        }
+        toml::from_str(&s).map_err(|e: toml::de::Error| {
+            anyhow!(
+                "registry {} returned TOML that is not a valid manifest, missing required fields or invalid values: {}",
+                self.uri,
+                e.message()
+            )
+        })
+    }
+
+    /// Describes how long ago the registry was last fetched, e.g. "fetched 3h ago".
+    pub fn last_fetched_description(&self, now: OffsetDateTime) -> String {
+        let Some(last_fetched) = self.last_fetched else {
+            return "never fetched".to_string();
+        };
+        let elapsed = now - last_fetched;
+        if elapsed.whole_minutes() < 1 {
+            "fetched just now".to_string()
+        } else if elapsed.whole_hours() < 1 {
+            format!("fetched {}m ago", elapsed.whole_minutes())
+        } else if elapsed.whole_days() < 1 {
+            format!("fetched {}h ago", elapsed.whole_hours())
+        } else {
+            format!("fetched {}d ago", elapsed.whole_days())
+        }
+    }
+
+    /// Describes the registry as JSON, together with the number of packages it provides.
+    pub fn to_json(&self, package_count: i64) -> serde_json::Value {
+        serde_json::json!({
+            "uri": self.uri.to_string(),
+            "name": self.name,
+            "last_fetched": self
+                .last_fetched
+                .and_then(|last_fetched| last_fetched.format(&Rfc3339).ok()),
+            "package_count": package_count,
+        })
+    }
+
     /// Returns if the registry should be fetched.
     #[instrument]
     pub fn should_update(&self) -> bool {
@@ -170,12 +416,46 @@ impl Registry {
     }
 }
 
+/// Parses a base64-encoded ed25519 public key.
+pub fn parse_public_key(public_key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; PUBLIC_KEY_LENGTH] = BASE64
+        .decode(public_key.trim())
+        .wrap_err("public key is not valid base64")?
+        .try_into()
+        .map_err(|_| anyhow!("public key is not {PUBLIC_KEY_LENGTH} bytes long"))?;
+    VerifyingKey::from_bytes(&bytes).wrap_err("invalid ed25519 public key")
+}
+
+/// Verifies a base64-encoded ed25519 signature of a message.
+pub fn verify_signature(public_key: &str, message: &[u8], signature: &[u8]) -> Result<()> {
+    let key = parse_public_key(public_key)?;
+    let signature: [u8; SIGNATURE_LENGTH] = BASE64
+        .decode(String::from_utf8_lossy(signature).trim())
+        .wrap_err("signature is not valid base64")?
+        .try_into()
+        .map_err(|_| anyhow!("signature is not {SIGNATURE_LENGTH} bytes long"))?;
+    key.verify_strict(message, &Signature::from_bytes(&signature))
+        .map_err(|_| anyhow!("signature does not match"))
+}
+
+/// Returns the start of `s`, for use in error messages.
+fn snippet(s: &str) -> String {
+    let mut snippet: String = s.trim().chars().take(SNIPPET_LENGTH).collect();
+    if s.trim().chars().count() > SNIPPET_LENGTH {
+        snippet.push_str("...");
+    }
+    snippet
+}
+
 impl Display for Registry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.uri)?;
         if let Some(name) = &self.name {
             write!(f, " ({})", name)?;
         }
+        if let Some(workspace) = &self.workspace {
+            write!(f, " in workspace {}", workspace)?;
+        }
         Ok(())
     }
 }
@@ -195,10 +475,18 @@ impl FromRow<'_, SqliteRow> for Registry {
         let name: String = row.try_get("name")?;
         let uri: String = row.try_get("uri")?;
         let last_fetched: Option<OffsetDateTime> = row.try_get("last_fetched")?;
+        let workspace: Option<String> = row.try_get("workspace")?;
+        let validators = CacheValidators {
+            etag: row.try_get("etag")?,
+            last_modified: row.try_get("last_modified")?,
+        };
         Ok(Self {
             name: Some(name),
             uri: uri.into(),
             last_fetched,
+            workspace,
+            validators,
+            public_key: row.try_get("public_key")?,
         })
     }
 }
@@ -216,7 +504,7 @@ impl From<&str> for Uri {
         } else if s.starts_with("https://") {
             Self::Https(s.into())
         } else {
-            let path = PathBuf::from(s);
+            let path = expand_tilde(Path::new(s));
             // Resolve to absolute path.
             let path = if path.is_relative() {
                 std::env::current_dir()
@@ -236,8 +524,24 @@ impl From<&str> for Uri {
 impl FromStr for Uri {
     type Err = color_eyre::eyre::Error;
 
+    /// Parses a registry URI given by the user.
+    ///
+    /// Unlike [`Uri::from`], this rejects URLs with schemes other than `http`, `https`, and
+    /// `file`, instead of treating them as file paths.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self::from(s))
+        let Some((scheme, rest)) = s.split_once("://") else {
+            return Ok(Self::from(s));
+        };
+        let is_scheme = !scheme.is_empty()
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+        match scheme {
+            "http" | "https" => Ok(Self::from(s)),
+            "file" => Ok(Self::from(rest)),
+            _ if is_scheme => Err(anyhow!("unsupported registry scheme '{scheme}'")),
+            _ => Ok(Self::from(s)),
+        }
     }
 }
 
@@ -248,16 +552,83 @@ impl Default for Registry {
             name: Some("test".into()),
             uri: "https://example.invalid/test".into(),
             last_fetched: None,
+            workspace: None,
+            validators: CacheValidators::default(),
+            public_key: None,
         }
     }
 }
 
+/// Returns all but the newest `keep` versions of each of the given packages, except for installed
+/// versions.
+async fn versions_to_prune(
+    state: &State,
+    packages: Vec<KnownPackage>,
+    keep: usize,
+) -> Result<Vec<KnownPackage>> {
+    let mut versions: HashMap<String, Vec<String>> = HashMap::new();
+    for pkg in packages {
+        versions.entry(pkg.name).or_default().push(pkg.version);
+    }
+
+    let mut prune = vec![];
+    for (name, mut versions) in versions {
+        versions.sort_by(|a, b| compare_versions(b, a));
+        for version in versions.into_iter().skip(keep) {
+            let pkg = KnownPackage {
+                name: name.clone(),
+                version,
+            };
+            if state.get_installed_package(&pkg).await?.is_none() {
+                prune.push(pkg);
+            }
+        }
+    }
+    prune.sort_by(|a, b| {
+        a.name
+            .cmp(&b.name)
+            .then(compare_versions(&a.version, &b.version))
+    });
+    Ok(prune)
+}
+
 /// A fetcher fetches a manifest from a registry.
 ///
 /// This trait exists so that we can mock out fetching for tests.
 pub trait Fetcher: Send + Sync + Clone {
-    /// Fetches the manifest string from the registry.
-    fn fetch(&self, reg: &Registry) -> impl Future<Output = Result<String>> + Send;
+    /// Fetches the raw manifest from the registry.
+    fn fetch(&self, reg: &Registry) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// Fetches the signature of the registry's manifest, which lives next to it with a `.sig`
+    /// suffix.
+    fn fetch_signature(&self, reg: &Registry) -> impl Future<Output = Result<Vec<u8>>> + Send;
+
+    /// Fetches the raw manifest from the registry, unless it is unchanged since it was fetched
+    /// with the registry's validators.
+    ///
+    /// Fetchers that can't tell always fetch the manifest.
+    fn fetch_if_modified(&self, reg: &Registry) -> impl Future<Output = Result<Fetched>> + Send {
+        async move {
+            Ok(Fetched::Modified {
+                manifest: self.fetch(reg).await?,
+                validators: CacheValidators::default(),
+            })
+        }
+    }
+}
+
+/// The outcome of a conditional fetch of a manifest.
+#[derive(Debug)]
+pub enum Fetched {
+    /// The manifest changed, or the fetcher can't tell.
+    Modified {
+        /// The raw manifest.
+        manifest: Vec<u8>,
+        /// Validators to send with the next fetch.
+        validators: CacheValidators,
+    },
+    /// The manifest is unchanged since the last fetch.
+    NotModified,
 }
 
 /// The default fetcher, which fetches from the filesystem or HTTP.
@@ -266,19 +637,167 @@ pub struct DefaultFetcher;
 
 impl Fetcher for DefaultFetcher {
     #[instrument]
-    async fn fetch(&self, reg: &Registry) -> Result<String> {
-        let s = match &reg.uri {
-            Uri::File(path) => read_to_string(path)
+    async fn fetch(&self, reg: &Registry) -> Result<Vec<u8>> {
+        let bytes = match &reg.uri {
+            Uri::File(path) => read(path)
+                .await
+                .wrap_err(format!("failed to read manifest at {}", path.display()))?,
+            Uri::Http(uri) | Uri::Https(uri) => DefaultDownloader::default()
+                .download_file(uri)
                 .await
-                .wrap_err("failed to read manifest at {path}")?,
+                .wrap_err(format!("failed to fetch manifest from {uri}"))?,
+        };
+        Ok(bytes)
+    }
+
+    #[instrument]
+    async fn fetch_signature(&self, reg: &Registry) -> Result<Vec<u8>> {
+        let bytes = match &reg.uri {
+            Uri::File(path) => {
+                let mut path = path.clone().into_os_string();
+                path.push(".sig");
+                read(&path).await.wrap_err(format!(
+                    "failed to read signature at {}",
+                    Path::new(&path).display()
+                ))?
+            }
             Uri::Http(uri) | Uri::Https(uri) => {
-                let bytes = download_file(uri)
+                let uri = format!("{uri}.sig");
+                DefaultDownloader::default()
+                    .download_file(&uri)
                     .await
-                    .wrap_err("failed to fetch manifest from {uri}")?;
-                String::from_utf8(bytes).wrap_err("failed to parse downloaded manifest as utf-8")?
+                    .wrap_err(format!("failed to fetch signature from {uri}"))?
             }
         };
-        Ok(s)
+        Ok(bytes)
+    }
+
+    #[instrument]
+    async fn fetch_if_modified(&self, reg: &Registry) -> Result<Fetched> {
+        let (Uri::Http(uri) | Uri::Https(uri)) = &reg.uri else {
+            return Ok(Fetched::Modified {
+                manifest: self.fetch(reg).await?,
+                validators: CacheValidators::default(),
+            });
+        };
+        let fetched = DefaultDownloader::default()
+            .download_if_modified(uri, &reg.validators)
+            .await
+            .wrap_err(format!("failed to fetch manifest from {uri}"))?;
+        Ok(match fetched {
+            Some((manifest, validators)) => Fetched::Modified {
+                manifest,
+                validators,
+            },
+            None => Fetched::NotModified,
+        })
+    }
+}
+
+/// A fetcher that keeps a copy of every fetched manifest on disk.
+///
+/// The raw manifests are stored in a directory keyed by a hash of the registry URI. In cache-only
+/// mode, manifests are read back from there instead of being fetched.
+#[derive(Debug, Clone)]
+pub struct CachingFetcher<F> {
+    /// The fetcher to use if not in cache-only mode.
+    inner: F,
+    /// The directory holding the cached manifests.
+    cache_dir: PathBuf,
+    /// Whether to only read from the cache.
+    cache_only: bool,
+}
+
+impl<F: Fetcher> CachingFetcher<F> {
+    /// Creates a new caching fetcher wrapping another fetcher.
+    pub fn new(inner: F, cache_dir: PathBuf, cache_only: bool) -> Self {
+        Self {
+            inner,
+            cache_dir,
+            cache_only,
+        }
+    }
+
+    /// Returns the path of the cached manifest for a registry.
+    fn cache_path(&self, reg: &Registry) -> PathBuf {
+        let hash = Sha256::digest(reg.uri.to_string().as_bytes());
+        self.cache_dir.join(format!("{hash:x}"))
+    }
+
+    /// Writes a fetched manifest to the cache.
+    async fn store(&self, reg: &Registry, bytes: &[u8]) -> Result<()> {
+        create_dir_all(&self.cache_dir)
+            .await
+            .wrap_err("failed to create registry cache directory")?;
+        write(self.cache_path(reg), bytes)
+            .await
+            .wrap_err(format!("failed to cache manifest of {}", reg.uri))
+    }
+}
+
+impl<F: Fetcher + std::fmt::Debug> Fetcher for CachingFetcher<F> {
+    #[instrument]
+    async fn fetch(&self, reg: &Registry) -> Result<Vec<u8>> {
+        let path = self.cache_path(reg);
+        if self.cache_only {
+            return read(&path).await.wrap_err(format!(
+                "no cached manifest for {}, fetch it once without --use-cache-only",
+                reg.uri
+            ));
+        }
+
+        let bytes = self.inner.fetch(reg).await?;
+        self.store(reg, &bytes).await?;
+        Ok(bytes)
+    }
+
+    #[instrument]
+    async fn fetch_signature(&self, reg: &Registry) -> Result<Vec<u8>> {
+        let path = self.cache_path(reg).with_extension("sig");
+        if self.cache_only {
+            return read(&path).await.wrap_err(format!(
+                "no cached signature for {}, fetch it once without --use-cache-only",
+                reg.uri
+            ));
+        }
+
+        let bytes = self.inner.fetch_signature(reg).await?;
+        create_dir_all(&self.cache_dir)
+            .await
+            .wrap_err("failed to create registry cache directory")?;
+        write(path, &bytes)
+            .await
+            .wrap_err(format!("failed to cache signature of {}", reg.uri))?;
+        Ok(bytes)
+    }
+
+    #[instrument]
+    async fn fetch_if_modified(&self, reg: &Registry) -> Result<Fetched> {
+        if self.cache_only {
+            return Ok(Fetched::Modified {
+                manifest: self.fetch(reg).await?,
+                validators: reg.validators.clone(),
+            });
+        }
+
+        match self.inner.fetch_if_modified(reg).await? {
+            Fetched::Modified {
+                manifest,
+                validators,
+            } => {
+                self.store(reg, &manifest).await?;
+                Ok(Fetched::Modified {
+                    manifest,
+                    validators,
+                })
+            }
+            // Without a cached copy, cache-only mode couldn't read the manifest later.
+            Fetched::NotModified if !self.cache_path(reg).exists() => Ok(Fetched::Modified {
+                manifest: self.fetch(reg).await?,
+                validators: reg.validators.clone(),
+            }),
+            Fetched::NotModified => Ok(Fetched::NotModified),
+        }
     }
 }
 
@@ -286,7 +805,13 @@ impl Fetcher for DefaultFetcher {
 /// A mock fetcher, which returns a pre-defined manifest.
 #[derive(Debug, Clone)]
 pub struct MockFetcher {
-    pub manifest: String,
+    pub manifest: Vec<u8>,
+    /// Registry URIs that fail to fetch.
+    pub failing_uris: Vec<String>,
+    /// Whether to report the manifest as unchanged to conditional fetches.
+    pub not_modified: bool,
+    /// The signature of the manifest, if it is signed.
+    pub signature: Option<Vec<u8>>,
 }
 
 #[cfg(test)]
@@ -300,7 +825,18 @@ impl MockFetcher {
             ..Default::default()
         };
         Self {
-            manifest: toml::to_string_pretty(&manifest).unwrap(),
+            manifest: toml::to_string_pretty(&manifest).unwrap().into_bytes(),
+            failing_uris: vec![],
+            not_modified: false,
+            signature: None,
+        }
+    }
+
+    /// Creates a mock fetcher that fails to fetch any of the given registry URIs.
+    pub fn failing_for(uris: &[&str]) -> Self {
+        Self {
+            failing_uris: uris.iter().map(|uri| uri.to_string()).collect(),
+            ..Default::default()
         }
     }
 }
@@ -333,19 +869,47 @@ impl Default for MockFetcher {
                 build = "exit 1"
             "#
             .into(),
+            failing_uris: vec![],
+            not_modified: false,
+            signature: None,
         }
     }
 }
 
 #[cfg(test)]
 impl Fetcher for MockFetcher {
-    async fn fetch(&self, _reg: &Registry) -> Result<String> {
+    async fn fetch(&self, reg: &Registry) -> Result<Vec<u8>> {
+        if self.failing_uris.contains(&reg.uri.to_string()) {
+            color_eyre::eyre::bail!("mock fetch failure");
+        }
         Ok(self.manifest.clone())
     }
+
+    async fn fetch_signature(&self, _: &Registry) -> Result<Vec<u8>> {
+        self.signature
+            .clone()
+            .ok_or_else(|| anyhow!("mock manifest is not signed"))
+    }
+
+    async fn fetch_if_modified(&self, reg: &Registry) -> Result<Fetched> {
+        // Like a server, only conditional fetches can be answered with "not modified".
+        if self.not_modified && reg.validators != CacheValidators::default() {
+            return Ok(Fetched::NotModified);
+        }
+        Ok(Fetched::Modified {
+            manifest: self.fetch(reg).await?,
+            validators: CacheValidators {
+                etag: Some("\"mock\"".to_string()),
+                last_modified: None,
+            },
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io::Write;
+
     use super::*;
 
     #[test]
@@ -364,6 +928,23 @@ mod tests {
             Uri::from_str("example")?,
             Uri::File(pwd.join("example").to_str().unwrap().into())
         );
+        assert_eq!(
+            Uri::from_str("./local")?,
+            Uri::File(pwd.join("./local").to_str().unwrap().into())
+        );
+        assert_eq!(
+            Uri::from_str("file:///tmp/registry.toml")?,
+            Uri::File("/tmp/registry.toml".into())
+        );
+        let home = std::env::var("HOME")?;
+        assert_eq!(
+            Uri::from_str("~/registry.toml")?,
+            Uri::File(PathBuf::from(home).join("registry.toml"))
+        );
+        assert_eq!(
+            Uri::from_str("htps://x").unwrap_err().to_string(),
+            "unsupported registry scheme 'htps'"
+        );
         Ok(())
     }
 
@@ -411,6 +992,99 @@ mod tests {
         assert!(registry.last_fetched.is_some());
     }
 
+    #[tokio::test]
+    async fn test_verify_signed_manifest() -> Result<()> {
+        use ed25519_dalek::{Signer, SigningKey};
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = BASE64.encode(key.verifying_key().as_bytes());
+        let mut fetcher = MockFetcher::default();
+        fetcher.signature = Some(
+            BASE64
+                .encode(key.sign(&fetcher.manifest).to_bytes())
+                .into_bytes(),
+        );
+
+        let state = State::load(":memory:").await?;
+        let mut registry = Registry::new("https://example.invalid/registry");
+        registry.public_key = Some(public_key.clone());
+        registry.initialize(&state, &fetcher).await?;
+        registry.fetch(&state, &fetcher).await?;
+        assert!(!state
+            .known_packages_for_registry(&registry)
+            .await?
+            .is_empty());
+
+        let mut tampered = fetcher.clone();
+        tampered.manifest = String::from_utf8(fetcher.manifest.clone())?
+            .replace("0.2.0", "6.6.6")
+            .into_bytes();
+        let err = registry.fetch(&state, &tampered).await.unwrap_err();
+        assert!(format!("{err:#}").contains("signature does not match"));
+
+        let mut unsigned = fetcher.clone();
+        unsigned.signature = None;
+        assert!(registry.fetch(&state, &unsigned).await.is_err());
+
+        // Moving the registry keeps its key.
+        let new = "https://example.invalid/moved";
+        state
+            .change_registry_uri(&registry.uri.to_string(), new)
+            .await?;
+        let mut moved = state
+            .registries()
+            .await?
+            .into_iter()
+            .find(|reg| reg.uri.to_string() == new)
+            .unwrap();
+        assert_eq!(moved.public_key, Some(public_key.clone()));
+        assert!(moved.fetch(&state, &unsigned).await.is_err());
+
+        // Registries without a key don't need signatures.
+        let mut registry = Registry::new("https://example.invalid/other");
+        registry.initialize(&state, &unsigned).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune_versions() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let mut registry = Registry::new("https://example.invalid/registry");
+        registry.initialize(&state, &MockFetcher::default()).await?;
+        let packages = ["1.0.0", "1.2.0", "1.10.0", "0.9.0", "1.9.0"]
+            .into_iter()
+            .map(|version| Package {
+                name: "foo".into(),
+                version: version.into(),
+                registry: Some(registry.uri.to_string()),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        registry
+            .fetch(&state, &MockFetcher::with_packages(&packages))
+            .await?;
+        let installed = KnownPackage {
+            name: "foo".into(),
+            version: "0.9.0".into(),
+        };
+        state.add_installed_package(&installed, None, None).await?;
+
+        registry.prune_versions(&state, 2).await?;
+
+        let mut versions = state
+            .known_packages_for_registry(&registry)
+            .await?
+            .into_iter()
+            .filter(|pkg| pkg.name == "foo")
+            .map(|pkg| pkg.version)
+            .collect::<Vec<_>>();
+        versions.sort_by(|a, b| compare_versions(a, b));
+        assert_eq!(versions, vec!["0.9.0", "1.9.0", "1.10.0"]);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_update_registry_refuses_unsafe_package_names() {
         let state = State::load(":memory:").await.unwrap();
@@ -430,6 +1104,272 @@ mod tests {
             .is_err());
     }
 
+    #[tokio::test]
+    async fn test_fetch_rejects_dot_package_names_and_versions() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        for dots in [".", "..", "..."] {
+            for (name, version) in [(dots, "1.0.0"), ("foo", dots)] {
+                let fetcher = MockFetcher::with_packages(&[Package {
+                    name: name.into(),
+                    version: version.into(),
+                    ..Default::default()
+                }]);
+                let mut registry = Registry::new("https://example.invalid/registry");
+                let err = registry.fetch(&state, &fetcher).await.unwrap_err();
+                let expected = if name == dots {
+                    format!("invalid package name {dots:?}")
+                } else {
+                    format!("invalid version {dots:?} of foo")
+                };
+                assert_eq!(err.to_string(), expected);
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_rejects_uppercase_package_names() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        for pkg in [
+            Package {
+                name: "Foo".into(),
+                version: "1.0.0".into(),
+                ..Default::default()
+            },
+            Package {
+                name: "foo".into(),
+                version: "1.0.0".into(),
+                provides: vec!["Bar".into()],
+                ..Default::default()
+            },
+        ] {
+            let fetcher = MockFetcher::with_packages(&[pkg]);
+            let mut registry = Registry::new("https://example.invalid/registry");
+            let err = registry.fetch(&state, &fetcher).await.unwrap_err();
+            assert!(err.to_string().contains("must be lowercase"));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_rejects_duplicate_packages() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let package = |source: &str| Package {
+            name: "foo".into(),
+            version: "1.0.0".into(),
+            sources: vec![source.into()],
+            ..Default::default()
+        };
+        let fetcher = MockFetcher::with_packages(&[
+            package("https://example.invalid/a.tar.gz"),
+            package("https://example.invalid/b.tar.gz"),
+        ]);
+        let mut registry = Registry::new("https://example.invalid/registry");
+        let err = registry.fetch(&state, &fetcher).await.unwrap_err();
+        assert_eq!(err.to_string(), "duplicate package foo@1.0.0");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_rejects_build_dependency_cycles() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let package = |name: &str, build_dependency: &str| Package {
+            name: name.into(),
+            version: "1.0.0".into(),
+            build_dependencies: vec![build_dependency.into()],
+            ..Default::default()
+        };
+        let fetcher = MockFetcher::with_packages(&[
+            package("foo", "bar"),
+            package("bar", "baz@~1"),
+            package("baz", "bar"),
+        ]);
+        let mut registry = Registry::new("https://example.invalid/registry");
+        let err = registry.fetch(&state, &fetcher).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "build dependency cycle: bar@1.0.0 -> baz@1.0.0 -> bar@1.0.0"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_rejects_sources_without_file_name() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let fetcher = MockFetcher::with_packages(&[Package {
+            name: "foo".into(),
+            version: "1.0.0".into(),
+            sources: vec!["https://example.invalid/".into()],
+            ..Default::default()
+        }]);
+        let mut registry = Registry::new("https://example.invalid/registry");
+        let err = registry.fetch(&state, &fetcher).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("invalid source https://example.invalid/ of foo@1.0.0"));
+        assert!(state
+            .known_packages_for_registry(&registry)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_rejects_unsupported_schema_version() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let fetcher = MockFetcher {
+            manifest: b"schema_version = 2\nname = \"test\"\npackages = []\n".to_vec(),
+            ..MockFetcher::default()
+        };
+        let mut registry = Registry::new("https://example.invalid/registry");
+        let err = registry.fetch(&state, &fetcher).await.unwrap_err();
+        assert_eq!(err.to_string(), "unsupported schema version 2, expected 1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fetch_skips_unchanged_manifest() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let mut registry = Registry::new("https://example.invalid/registry");
+        let fetcher = MockFetcher::default();
+        registry.initialize(&state, &fetcher).await?;
+        registry.fetch(&state, &fetcher).await?;
+        let first_fetched = registry.last_fetched;
+
+        let stored = state.registries().await?.remove(0);
+        assert_eq!(stored.validators.etag.as_deref(), Some("\"mock\""));
+
+        // A changed manifest would drop all packages, but the registry reports no change.
+        let fetcher = MockFetcher {
+            not_modified: true,
+            ..MockFetcher::with_packages(&[])
+        };
+        registry.fetch(&state, &fetcher).await?;
+
+        assert_eq!(state.known_packages_for_registry(&registry).await?.len(), 4);
+        assert!(registry.last_fetched > first_fetched);
+        let stored = state.registries().await?.remove(0);
+        assert_eq!(stored.last_fetched, registry.last_fetched);
+        assert_eq!(stored.validators.etag.as_deref(), Some("\"mock\""));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_fetcher_reads_from_cache() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let cache_dir = tempfile::tempdir()?;
+        let uri = "https://example.invalid/registry";
+        let cache_only = CachingFetcher::new(
+            MockFetcher::failing_for(&[uri]),
+            cache_dir.path().to_path_buf(),
+            true,
+        );
+
+        let mut registry = Registry::new(uri);
+        assert!(registry.initialize(&state, &cache_only).await.is_err());
+
+        let fetcher = CachingFetcher::new(
+            MockFetcher::default(),
+            cache_dir.path().to_path_buf(),
+            false,
+        );
+        registry.initialize(&state, &fetcher).await?;
+
+        registry.fetch(&state, &cache_only).await?;
+        assert_eq!(registry.name.as_deref(), Some("test"));
+        assert_eq!(
+            cache_only.fetch(&registry).await?,
+            MockFetcher::default().manifest
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_fetched_description() {
+        let now = OffsetDateTime::now_utc();
+        let mut registry = Registry::default();
+        assert_eq!(registry.last_fetched_description(now), "never fetched");
+
+        registry.last_fetched = Some(now - time::Duration::seconds(10));
+        assert_eq!(registry.last_fetched_description(now), "fetched just now");
+        registry.last_fetched = Some(now - time::Duration::minutes(5));
+        assert_eq!(registry.last_fetched_description(now), "fetched 5m ago");
+        registry.last_fetched = Some(now - time::Duration::minutes(200));
+        assert_eq!(registry.last_fetched_description(now), "fetched 3h ago");
+        registry.last_fetched = Some(now - time::Duration::days(2));
+        assert_eq!(registry.last_fetched_description(now), "fetched 2d ago");
+    }
+
+    #[tokio::test]
+    async fn test_download_gzip_compressed_manifest() -> Result<()> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&MockFetcher::default().manifest)?;
+        let fetcher = MockFetcher {
+            manifest: encoder.finish()?,
+            ..Default::default()
+        };
+
+        let registry = Registry::new("https://example.invalid/registry.toml.gz");
+        let manifest = registry.download(&fetcher).await?;
+        assert_eq!(manifest.name, "test");
+        assert_eq!(manifest.packages.len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_explains_corrupt_gzip() {
+        let fetcher = MockFetcher {
+            manifest: vec![0x1f, 0x8b, 0x00, 0x01],
+            ..Default::default()
+        };
+        let registry = Registry::new("https://example.invalid/registry.toml.gz");
+        let err = registry.download(&fetcher).await.unwrap_err().to_string();
+        assert!(err.contains("corrupt gzip-compressed manifest"));
+    }
+
+    #[tokio::test]
+    async fn test_download_explains_html_response() {
+        let registry = Registry::new("https://example.invalid/registry");
+        let fetcher = MockFetcher {
+            manifest: "<html><body>404 Not Found</body></html>".into(),
+            ..Default::default()
+        };
+        let err = registry.download(&fetcher).await.unwrap_err().to_string();
+        assert!(err.contains("https://example.invalid/registry"));
+        assert!(err.contains("valid TOML"));
+        assert!(err.contains("404 Not Found"));
+    }
+
+    #[test]
+    fn test_parse_manifest_explains_invalid_utf8() {
+        let registry = Registry::new("https://example.invalid/registry");
+        let err = registry
+            .parse_manifest(vec![0xff, 0xfe, b'x'])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("https://example.invalid/registry"));
+        assert!(err.contains("UTF-8"));
+    }
+
+    #[test]
+    fn test_parse_manifest_explains_missing_fields() {
+        let registry = Registry::new("https://example.invalid/registry");
+        let err = registry
+            .parse_manifest(b"name = \"test\"".to_vec())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("https://example.invalid/registry"));
+        assert!(err.contains("missing required fields"));
+        assert!(err.contains("schema_version"));
+    }
+
+    #[test]
+    fn test_snippet_truncates_long_content() {
+        let long = "x".repeat(SNIPPET_LENGTH * 2);
+        assert_eq!(snippet(&long).len(), SNIPPET_LENGTH + 3);
+        assert_eq!(snippet("  short  "), "short");
+    }
+
     #[tokio::test]
     async fn test_update_package_refuses_overwriting_other_registrys_package() -> Result<()> {
         let state = State::load(":memory:").await?;
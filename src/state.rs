@@ -1,20 +1,42 @@
-use std::{path::Path, str::FromStr};
+use std::{collections::HashSet, path::Path, str::FromStr, time::Duration};
 
 use color_eyre::eyre::{anyhow, bail, Context, Result};
 use sqlx::{
     migrate,
-    sqlite::{Sqlite, SqliteConnectOptions, SqlitePool},
+    sqlite::{Sqlite, SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqliteSynchronous},
+    types::Json,
+    FromRow,
 };
-use tokio::fs::create_dir_all;
+use time::OffsetDateTime;
+use tokio::fs::{copy, create_dir_all, remove_file, rename};
 use tracing::instrument;
 
 use crate::{
     manifest::Package,
-    package::{InstalledPackage, PackageSpec, WorkspacePackage},
+    package::{
+        relative_package_directory, InstalledPackage, KnownPackage, PackageAction, PackageEvent,
+        PackageRequest, PackageSpec, VersionSpec, WorkspacePackage,
+    },
     registry::Registry,
+    version::compare_versions,
     workspace::Workspace,
 };
 
+/// The database schema version this build of matcha understands.
+const SCHEMA_VERSION: i64 = 21;
+
+/// Selects the installed packages that are in use as `used`, which are all workspace packages and,
+/// recursively, the build dependencies of those.
+const USED_PACKAGES: &str = "WITH RECURSIVE used (name, version) AS (
+        SELECT name, version FROM workspace_packages
+        UNION
+        SELECT dependency_name, dependency_version FROM installed_build_dependencies
+            JOIN used USING (name, version)
+    )";
+
+/// How long to wait for another process to release a lock on the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// The internal state of the application, backed by a SQLite database.
 #[derive(Clone)]
 pub struct State {
@@ -37,29 +59,121 @@ impl State {
                 .wrap_err("failed to connect to database")?
         };
 
-        let schema_version: String =
-            sqlx::query_scalar("SELECT value FROM meta WHERE key = 'schema_version'")
-                .fetch_one(&db)
-                .await
-                .wrap_err("failed to fetch schema version from database")?;
-        if schema_version
-            .parse::<i64>()
-            .wrap_err("failed to parse database schema version")?
-            > 1
-        {
+        let state = Self { db };
+        let schema_version = state.schema_version().await?;
+        if schema_version > SCHEMA_VERSION {
             return Err(anyhow!(
                 "unsupported database schema version {}",
                 schema_version
             ));
         }
 
-        Ok(Self { db })
+        // Bring older databases up to date.
+        migrate!("./migrations")
+            .run(&state.db)
+            .await
+            .wrap_err("failed to migrate database")?;
+
+        Ok(state)
+    }
+
+    /// Returns the schema version of the database.
+    #[instrument(skip(self))]
+    pub async fn schema_version(&self) -> Result<i64> {
+        let schema_version: String =
+            sqlx::query_scalar("SELECT value FROM meta WHERE key = 'schema_version'")
+                .fetch_one(&self.db)
+                .await
+                .wrap_err("failed to fetch schema version from database")?;
+        schema_version
+            .parse()
+            .wrap_err("failed to parse database schema version")
+    }
+
+    /// Returns the version of the last migration applied to the database, which the schema version
+    /// should match.
+    #[instrument(skip(self))]
+    pub async fn migrated_schema_version(&self) -> Result<i64> {
+        sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM _sqlx_migrations WHERE success")
+            .fetch_one(&self.db)
+            .await
+            .wrap_err("failed to fetch applied migrations from database")
+    }
+
+    /// Writes a consistent copy of the database to `path`, which must not exist yet.
+    ///
+    /// Safe to run while other processes are using the database.
+    #[instrument(skip(self))]
+    pub async fn backup(&self, path: &Path) -> Result<()> {
+        if path.exists() {
+            bail!("{} already exists", path.display());
+        }
+        sqlx::query("VACUUM INTO $1")
+            .bind(path.to_string_lossy())
+            .execute(&self.db)
+            .await
+            .wrap_err("failed to write backup")?;
+        Ok(())
+    }
+
+    /// Replaces the database at `path` with the backup at `backup`, and loads it.
+    ///
+    /// Refuses backups with a newer schema version than this build of matcha understands. Older
+    /// backups are migrated when loading them.
+    #[instrument(skip(self))]
+    pub async fn restore(self, path: &str, backup: &Path) -> Result<Self> {
+        if !backup.exists() {
+            bail!("backup {} does not exist", backup.display());
+        }
+        let backup_state = Self {
+            db: SqlitePool::connect_with(
+                SqliteConnectOptions::new().filename(backup).read_only(true),
+            )
+            .await
+            .wrap_err("failed to open backup")?,
+        };
+        let schema_version = backup_state
+            .schema_version()
+            .await
+            .wrap_err("backup is not a matcha state database")?;
+        backup_state.db.close().await;
+        if schema_version > SCHEMA_VERSION {
+            bail!(
+                "backup has schema version {}, but this version of matcha only supports up to {}",
+                schema_version,
+                SCHEMA_VERSION
+            );
+        }
+
+        self.db.close().await;
+
+        // Copy next to the database first, so that it only gets replaced once the copy is
+        // complete.
+        let path = shellexpand::tilde(path).to_string();
+        let tmp_path = format!("{path}.restore");
+        copy(backup, &tmp_path)
+            .await
+            .wrap_err("failed to copy backup")?;
+        // Stale WAL files would otherwise be applied to the restored database.
+        for suffix in ["-wal", "-shm"] {
+            let wal_path = format!("{path}{suffix}");
+            if Path::new(&wal_path).exists() {
+                remove_file(&wal_path)
+                    .await
+                    .wrap_err("failed to remove write-ahead log")?;
+            }
+        }
+        rename(&tmp_path, &path)
+            .await
+            .wrap_err("failed to replace database")?;
+
+        Self::load(&path).await
     }
 
     /// Initializes the internal state database at the given path.
     #[instrument]
     async fn init(path: &str) -> Result<SqlitePool> {
-        eprintln!("No state database found, creating a new one at {}", path);
+        status!("No state database found, creating a new one at {}", path);
 
         // Create the directory if it doesn't exist.
         let dir = Path::new(path).parent().unwrap();
@@ -81,23 +195,27 @@ impl State {
     }
 
     /// Connects to the database at the given path, creating it if it doesn't exist.
+    ///
+    /// Uses WAL mode and a busy timeout, so that several matcha processes can use the same database
+    /// at once, with readers not blocking on writers.
     #[instrument]
     async fn connect_db(path: &str) -> Result<SqlitePool> {
-        let db =
-            SqlitePool::connect_with(SqliteConnectOptions::from_str(path)?.create_if_missing(true))
-                .await?;
+        let options = SqliteConnectOptions::from_str(path)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(BUSY_TIMEOUT);
+        let db = SqlitePool::connect_with(options).await?;
 
         Ok(db)
     }
 
     /// Begins a transaction.
-    #[allow(dead_code)]
     pub async fn begin_transaction(&self) -> Result<sqlx::Transaction<'_, Sqlite>> {
         Ok(self.db.begin().await?)
     }
 
     /// Commits a transaction.
-    #[allow(dead_code)]
     pub async fn commit_transaction(&self, tx: sqlx::Transaction<'_, Sqlite>) -> Result<()> {
         tx.commit().await?;
         Ok(())
@@ -121,16 +239,128 @@ impl State {
         Ok(packages)
     }
 
+    /// Searches the packages in a workspace for a query matching their name or version.
+    #[instrument(skip(self))]
+    pub async fn search_workspace_packages(
+        &self,
+        workspace: &Workspace,
+        query: &str,
+    ) -> Result<Vec<WorkspacePackage>> {
+        let query = format!("%{}%", query);
+        let packages = sqlx::query_as(
+            r"SELECT *
+                FROM workspace_packages
+                WHERE workspace = $1
+                AND (name LIKE $2 OR version LIKE $2)
+                ORDER BY name",
+        )
+        .bind(&workspace.name)
+        .bind(&query)
+        .fetch_all(&self.db)
+        .await
+        .wrap_err("failed to search workspace packages in database")?;
+        Ok(packages)
+    }
+
+    /// Returns the packages in a workspace together with the ones it inherits from its parent
+    /// workspaces, each with the name of the workspace it is from.
+    ///
+    /// A workspace's own packages override inherited ones with the same name.
+    #[instrument(skip(self))]
+    pub async fn inherited_workspace_packages(
+        &self,
+        workspace: &Workspace,
+    ) -> Result<Vec<(String, WorkspacePackage)>> {
+        let mut packages: Vec<(String, WorkspacePackage)> = vec![];
+        for ws in self.workspace_ancestry(workspace).await? {
+            for pkg in self.workspace_packages(&ws).await? {
+                if !packages.iter().any(|(_, p)| p.name == pkg.name) {
+                    packages.push((ws.name.clone(), pkg));
+                }
+            }
+        }
+        Ok(packages)
+    }
+
+    /// Returns a workspace followed by its parent, that one's parent, and so on.
+    #[instrument(skip(self))]
+    pub async fn workspace_ancestry(&self, workspace: &Workspace) -> Result<Vec<Workspace>> {
+        let mut seen = HashSet::new();
+        let mut ancestry = vec![];
+        let mut current = Some(workspace.clone());
+        while let Some(ws) = current {
+            if !seen.insert(ws.name.clone()) {
+                bail!("workspace {} inherits from itself", ws.name);
+            }
+            current = match &ws.parent {
+                Some(parent) => Some(
+                    self.get_workspace(parent)
+                        .await?
+                        .ok_or_else(|| anyhow!("parent workspace {parent} does not exist"))?,
+                ),
+                None => None,
+            };
+            ancestry.push(ws);
+        }
+        Ok(ancestry)
+    }
+
+    /// Replaces the version a workspace package was requested with.
+    #[instrument(skip(self))]
+    pub async fn set_requested_version(
+        &self,
+        pkg: &impl PackageSpec,
+        workspace: &Workspace,
+        requested_version: &VersionSpec,
+    ) -> Result<()> {
+        let (name, version) = pkg.spec();
+        sqlx::query(
+            "UPDATE workspace_packages SET requested_version = $1 WHERE name = $2 AND version = $3 AND workspace = $4",
+        )
+        .bind(requested_version.to_string())
+        .bind(name)
+        .bind(version)
+        .bind(&workspace.name)
+        .execute(&self.db)
+        .await
+        .wrap_err("failed to update requested version in database")?;
+        Ok(())
+    }
+
+    /// Returns the number of packages in a workspace.
+    #[instrument(skip(self))]
+    pub async fn workspace_package_count(&self, workspace: &Workspace) -> Result<i64> {
+        let count =
+            sqlx::query_scalar("SELECT COUNT(*) FROM workspace_packages WHERE workspace = $1")
+                .bind(&workspace.name)
+                .fetch_one(&self.db)
+                .await
+                .wrap_err("failed to count workspace packages in database")?;
+        Ok(count)
+    }
+
     /// Adds an installed package to the internal state.
+    ///
+    /// The registry determines where the package directory lives, and the content hash identifies
+    /// the package's entry in the content store, if it has one.
     #[instrument(skip(self))]
-    pub async fn add_installed_package(&self, pkg: &impl PackageSpec) -> Result<()> {
+    pub async fn add_installed_package(
+        &self,
+        pkg: &impl PackageSpec,
+        registry: Option<&str>,
+        content_hash: Option<&str>,
+    ) -> Result<()> {
         let (name, version) = pkg.spec();
-        sqlx::query("INSERT INTO installed_packages (name, version) VALUES ($1, $2)")
-            .bind(name)
-            .bind(version)
-            .execute(&self.db)
-            .await
-            .wrap_err("failed to insert installed package into database")?;
+        sqlx::query(
+            "INSERT INTO installed_packages (name, version, registry, content_hash) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(name)
+        .bind(version)
+        .bind(registry)
+        .bind(content_hash)
+        .execute(&self.db)
+        .await
+        .wrap_err("failed to insert installed package into database")?;
         Ok(())
     }
 
@@ -159,31 +389,173 @@ impl State {
         workspace: &Workspace,
     ) -> Result<()> {
         sqlx::query(
-            "INSERT INTO workspace_packages (name, version, requested_version, workspace) VALUES ($1, $2, $3, $4)",
+            "INSERT INTO workspace_packages (name, version, requested_version, workspace, registry, link_mode) VALUES ($1, $2, $3, $4, $5, $6)",
         )
         .bind(&pkg.name)
         .bind(&pkg.version)
-        .bind(&format!("{}", pkg.requested_version))
+        .bind(format!("{}", pkg.requested_version))
         .bind(&workspace.name)
+        .bind(&pkg.registry)
+        .bind(pkg.link_mode.to_string())
         .execute(&self.db)
         .await
         .wrap_err("failed to insert workspace package into database")?;
         Ok(())
     }
 
+    /// Returns all installed packages.
+    #[instrument(skip(self))]
+    pub async fn installed_packages(&self) -> Result<Vec<InstalledPackage>> {
+        let mut packages: Vec<InstalledPackage> =
+            sqlx::query_as("SELECT * FROM installed_packages")
+                .fetch_all(&self.db)
+                .await
+                .wrap_err("failed to fetch installed packages from database")?;
+        packages.sort_by(|a, b| {
+            a.name
+                .cmp(&b.name)
+                .then_with(|| compare_versions(&a.version, &b.version))
+        });
+        Ok(packages)
+    }
+
+    /// Records the packages an installed package was built with.
+    #[instrument(skip(self))]
+    pub async fn add_build_dependencies(
+        &self,
+        pkg: &impl PackageSpec,
+        dependencies: &[KnownPackage],
+    ) -> Result<()> {
+        let (name, version) = pkg.spec();
+        let mut tx = self.begin_transaction().await?;
+        for dependency in dependencies {
+            sqlx::query(
+                "INSERT OR IGNORE INTO installed_build_dependencies
+                    (name, version, dependency_name, dependency_version) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(&name)
+            .bind(&version)
+            .bind(&dependency.name)
+            .bind(&dependency.version)
+            .execute(&mut *tx)
+            .await
+            .wrap_err("failed to insert build dependency into database")?;
+        }
+        self.commit_transaction(tx).await?;
+        Ok(())
+    }
+
     /// Returns all installed packages that are not tied to a workspace.
+    ///
+    /// Build dependencies of packages that are in use count as used as well, so that they are
+    /// around when the package has to be built again.
     #[instrument(skip(self))]
     pub async fn unused_installed_packages(&self) -> Result<Vec<InstalledPackage>> {
-        let packages = sqlx::query_as(
-            "SELECT * FROM installed_packages WHERE (name, version) NOT IN
-               (SELECT name, version FROM workspace_packages)",
-        )
+        let packages = sqlx::query_as(&format!(
+            "{USED_PACKAGES} SELECT * FROM installed_packages WHERE (name, version) NOT IN
+               (SELECT name, version FROM used)"
+        ))
         .fetch_all(&self.db)
         .await
         .wrap_err("failed to fetch unused installed packages from database")?;
         Ok(packages)
     }
 
+    /// Removes all installed packages that are not tied to a workspace, and returns them.
+    ///
+    /// References are checked again as part of the removal, in a single transaction, so that a
+    /// package that was added to a workspace since [`State::unused_installed_packages`] was called
+    /// is kept. Its files should only be deleted after this.
+    #[instrument(skip(self))]
+    pub async fn remove_unused_installed_packages(&self) -> Result<Vec<InstalledPackage>> {
+        let mut tx = self.begin_transaction().await?;
+        let packages = sqlx::query_as(&format!(
+            "{USED_PACKAGES} DELETE FROM installed_packages WHERE (name, version) NOT IN
+               (SELECT name, version FROM used)
+             RETURNING *"
+        ))
+        .fetch_all(&mut *tx)
+        .await
+        .wrap_err("failed to remove unused installed packages from database")?;
+        self.commit_transaction(tx).await?;
+        Ok(packages)
+    }
+
+    /// Removes the given installed packages, unless they are tied to a workspace, and returns the
+    /// removed ones.
+    ///
+    /// Like [`State::remove_unused_installed_packages`], references are checked as part of the
+    /// removal, so the files of the returned packages can be deleted after this.
+    #[instrument(skip(self, pkgs))]
+    pub async fn remove_installed_packages_if_unused(
+        &self,
+        pkgs: &[impl PackageSpec],
+    ) -> Result<Vec<InstalledPackage>> {
+        let mut tx = self.begin_transaction().await?;
+        let mut removed = vec![];
+        for pkg in pkgs {
+            let (name, version) = pkg.spec();
+            let package: Option<InstalledPackage> = sqlx::query_as(&format!(
+                "{USED_PACKAGES} DELETE FROM installed_packages WHERE name = $1 AND version = $2
+                   AND (name, version) NOT IN (SELECT name, version FROM used)
+                 RETURNING *"
+            ))
+            .bind(name)
+            .bind(version)
+            .fetch_optional(&mut *tx)
+            .await
+            .wrap_err("failed to remove unused installed package from database")?;
+            removed.extend(package);
+        }
+        self.commit_transaction(tx).await?;
+        Ok(removed)
+    }
+
+    /// Replaces the content hash of an installed package, after it was built again.
+    #[instrument(skip(self))]
+    pub async fn set_content_hash(&self, pkg: &impl PackageSpec, content_hash: &str) -> Result<()> {
+        let (name, version) = pkg.spec();
+        sqlx::query(
+            "UPDATE installed_packages SET content_hash = $1 WHERE name = $2 AND version = $3",
+        )
+        .bind(content_hash)
+        .bind(name)
+        .bind(version)
+        .execute(&self.db)
+        .await
+        .wrap_err("failed to update content hash in database")?;
+        Ok(())
+    }
+
+    /// Records the build command an installed package was built with instead of its registry's.
+    #[instrument(skip(self))]
+    pub async fn set_build_override(&self, pkg: &impl PackageSpec, build: &str) -> Result<()> {
+        let (name, version) = pkg.spec();
+        sqlx::query(
+            "UPDATE installed_packages SET build_override = $1 WHERE name = $2 AND version = $3",
+        )
+        .bind(build)
+        .bind(name)
+        .bind(version)
+        .execute(&self.db)
+        .await
+        .wrap_err("failed to record build override in database")?;
+        Ok(())
+    }
+
+    /// Returns if any installed package uses the content store entry with the given hash.
+    #[instrument(skip(self))]
+    pub async fn is_content_hash_referenced(&self, content_hash: &str) -> Result<bool> {
+        let referenced = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM installed_packages WHERE content_hash = $1)",
+        )
+        .bind(content_hash)
+        .fetch_one(&self.db)
+        .await
+        .wrap_err("failed to check content hash references in database")?;
+        Ok(referenced)
+    }
+
     /// Removes an installed package from the internal state.
     #[instrument(skip(self))]
     pub async fn remove_installed_package(&self, pkg: &impl PackageSpec) -> Result<()> {
@@ -217,6 +589,84 @@ impl State {
         Ok(())
     }
 
+    /// Records an action on a package in a workspace's package history.
+    #[instrument(skip(self))]
+    pub async fn add_package_event(
+        &self,
+        pkg: &impl PackageSpec,
+        workspace: &Workspace,
+        action: PackageAction,
+    ) -> Result<()> {
+        let (name, version) = pkg.spec();
+        sqlx::query(
+            "INSERT INTO package_events (workspace, name, version, action, timestamp) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&workspace.name)
+        .bind(name)
+        .bind(version)
+        .bind(action.to_string())
+        .bind(OffsetDateTime::now_utc())
+        .execute(&self.db)
+        .await
+        .wrap_err("failed to insert package event into database")?;
+        Ok(())
+    }
+
+    /// Returns the package history of a workspace, newest first.
+    #[instrument(skip(self))]
+    pub async fn package_events(&self, workspace: &Workspace) -> Result<Vec<PackageEvent>> {
+        let events = sqlx::query_as(
+            "SELECT name, version, action, timestamp FROM package_events WHERE workspace = $1 ORDER BY timestamp DESC, id DESC",
+        )
+        .bind(&workspace.name)
+        .fetch_all(&self.db)
+        .await
+        .wrap_err("failed to fetch package events from database")?;
+        Ok(events)
+    }
+
+    /// Returns the packages in a workspace that declare a dependency on the named package.
+    #[instrument(skip(self))]
+    pub async fn dependents_of(
+        &self,
+        name: &str,
+        workspace: &Workspace,
+    ) -> Result<Vec<WorkspacePackage>> {
+        /// A workspace package, together with one of its dependencies.
+        #[derive(FromRow)]
+        struct DependencyRow {
+            #[sqlx(flatten)]
+            package: WorkspacePackage,
+            dependency: String,
+        }
+
+        let rows: Vec<DependencyRow> = sqlx::query_as(
+            "SELECT wp.name, wp.version, wp.requested_version, wp.registry, wp.link_mode, json_each.value AS dependency
+            FROM workspace_packages wp
+            JOIN known_packages kp ON kp.name = wp.name AND kp.version = wp.version,
+                json_each(kp.dependencies)
+            WHERE wp.workspace = $1
+            ORDER BY wp.name",
+        )
+        .bind(&workspace.name)
+        .fetch_all(&self.db)
+        .await
+        .wrap_err("failed to fetch package dependencies from database")?;
+
+        // Dependencies are stored as requests, which only the request parser can take apart.
+        let mut dependents = rows
+            .into_iter()
+            .filter(|row| {
+                row.dependency
+                    .parse::<PackageRequest>()
+                    .is_ok_and(|request| request.name == name)
+            })
+            .map(|row| row.package)
+            .collect::<Vec<_>>();
+        dependents.dedup_by(|a, b| a.name == b.name);
+        Ok(dependents)
+    }
+
     /// Returns a workspace package matching the name, if any.
     #[instrument(skip(self))]
     pub async fn get_workspace_package(
@@ -243,12 +693,16 @@ impl State {
         if self.registry_exists(&reg.uri.to_string()).await? {
             return Err(anyhow!("registry {} already exists", reg.uri));
         }
-        sqlx::query("INSERT INTO registries (name, uri) VALUES ($1, $2)")
-            .bind(reg.name.as_ref().unwrap())
-            .bind(reg.uri.to_string())
-            .execute(&self.db)
-            .await
-            .wrap_err("failed to insert registry into database")?;
+        sqlx::query(
+            "INSERT INTO registries (name, uri, workspace, public_key) VALUES ($1, $2, $3, $4)",
+        )
+        .bind(reg.name.as_ref().unwrap())
+        .bind(reg.uri.to_string())
+        .bind(&reg.workspace)
+        .bind(&reg.public_key)
+        .execute(&self.db)
+        .await
+        .wrap_err("failed to insert registry into database")?;
         Ok(())
     }
 
@@ -269,10 +723,12 @@ impl State {
     /// Returns all registries.
     #[instrument(skip(self))]
     pub async fn registries(&self) -> Result<Vec<Registry>> {
-        let registries = sqlx::query_as("SELECT name, uri, last_fetched FROM registries")
-            .fetch_all(&self.db)
-            .await
-            .wrap_err("failed to fetch registries from database")?;
+        let registries = sqlx::query_as(
+            "SELECT name, uri, last_fetched, workspace, etag, last_modified, public_key FROM registries",
+        )
+        .fetch_all(&self.db)
+        .await
+        .wrap_err("failed to fetch registries from database")?;
         Ok(registries)
     }
 
@@ -287,50 +743,152 @@ impl State {
         Ok(exists)
     }
 
-    /// Updates the database record of a registry with a new name and last_fetched.
+    /// Updates the database record of a registry with a new name, last_fetched, and validators.
     #[instrument(skip(self))]
     pub async fn update_registry(&self, reg: &Registry) -> Result<()> {
         if !self.registry_exists(&reg.uri.to_string()).await? {
             return Err(anyhow!("registry {} does not exist", &reg.uri));
         }
-        sqlx::query("UPDATE registries SET name = $1, last_fetched = $2 WHERE uri = $3")
-            .bind(&reg.name)
-            .bind(reg.last_fetched)
-            .bind(&reg.uri.to_string())
+        sqlx::query(
+            "UPDATE registries SET name = $1, last_fetched = $2, etag = $3, last_modified = $4 WHERE uri = $5",
+        )
+        .bind(&reg.name)
+        .bind(reg.last_fetched)
+        .bind(&reg.validators.etag)
+        .bind(&reg.validators.last_modified)
+        .bind(reg.uri.to_string())
             .execute(&self.db)
             .await
             .wrap_err("failed to update registry last_fetched in database")?;
         Ok(())
     }
 
-    /// Returns all known packages for a registry.
+    /// Changes the URI of a registry, keeping its known packages and fetch history.
+    ///
+    /// Installed and workspace packages from the registry are moved over as well. Installed
+    /// packages keep their package directories, which are recorded, as they no longer follow from
+    /// the registry.
     #[instrument(skip(self))]
-    pub async fn known_packages_for_registry(&self, reg: &Registry) -> Result<Vec<Package>> {
-        let pkgs = sqlx::query_as(
-            "SELECT * FROM known_packages WHERE registry = $1 ORDER BY name ASC, version DESC",
+    pub async fn change_registry_uri(&self, old: &str, new: &str) -> Result<()> {
+        let mut tx = self.begin_transaction().await?;
+        // The checks run in the transaction, so that the registries can't change in between.
+        let query = "SELECT EXISTS(SELECT 1 FROM registries WHERE uri = $1)";
+        let old_exists: bool = sqlx::query_scalar(query)
+            .bind(old)
+            .fetch_one(&mut *tx)
+            .await
+            .wrap_err("failed to check if registry exists in database")?;
+        if !old_exists {
+            bail!("registry {} does not exist", old);
+        }
+        let new_exists: bool = sqlx::query_scalar(query)
+            .bind(new)
+            .fetch_one(&mut *tx)
+            .await
+            .wrap_err("failed to check if registry exists in database")?;
+        if new_exists {
+            bail!("registry {} already exists", new);
+        }
+
+        // Known packages reference the registry URI, so we need to add the new registry before
+        // moving them over, and only then remove the old one.
+        sqlx::query(
+            "INSERT INTO registries
+                (uri, name, last_fetched, workspace, etag, last_modified, public_key)
+                SELECT $1, name, last_fetched, workspace, etag, last_modified, public_key
+                FROM registries WHERE uri = $2",
         )
-        .bind(&reg.uri.to_string())
-        .fetch_all(&self.db)
+        .bind(new)
+        .bind(old)
+        .execute(&mut *tx)
         .await
-        .wrap_err("failed to fetch known packages from database")?;
-        Ok(pkgs)
+        .wrap_err("failed to insert registry into database")?;
+        sqlx::query("UPDATE known_packages SET registry = $1 WHERE registry = $2")
+            .bind(new)
+            .bind(old)
+            .execute(&mut *tx)
+            .await
+            .wrap_err("failed to move known packages to new registry URI")?;
+        let installed: Vec<InstalledPackage> = sqlx::query_as(
+            "SELECT * FROM installed_packages WHERE registry = $1 AND directory IS NULL",
+        )
+        .bind(old)
+        .fetch_all(&mut *tx)
+        .await
+        .wrap_err("failed to fetch installed packages from database")?;
+        for pkg in installed {
+            let directory = relative_package_directory(&pkg.name, &pkg.version, Some(old));
+            sqlx::query(
+                "UPDATE installed_packages SET directory = $1 WHERE name = $2 AND version = $3",
+            )
+            .bind(directory.to_string_lossy())
+            .bind(&pkg.name)
+            .bind(&pkg.version)
+            .execute(&mut *tx)
+            .await
+            .wrap_err("failed to record installed package directory")?;
+        }
+        for table in ["installed_packages", "workspace_packages"] {
+            sqlx::query(&format!(
+                "UPDATE {table} SET registry = $1 WHERE registry = $2"
+            ))
+            .bind(new)
+            .bind(old)
+            .execute(&mut *tx)
+            .await
+            .wrap_err("failed to move packages to new registry URI")?;
+        }
+        sqlx::query("DELETE FROM registries WHERE uri = $1")
+            .bind(old)
+            .execute(&mut *tx)
+            .await
+            .wrap_err("failed to remove old registry from database")?;
+        self.commit_transaction(tx).await?;
+
+        Ok(())
+    }
+
+    /// Returns all known packages for a registry.
+    #[instrument(skip(self))]
+    pub async fn known_packages_for_registry(&self, reg: &Registry) -> Result<Vec<Package>> {
+        let pkgs = sqlx::query_as("SELECT * FROM known_packages WHERE registry = $1")
+            .bind(reg.uri.to_string())
+            .fetch_all(&self.db)
+            .await
+            .wrap_err("failed to fetch known packages from database")?;
+        Ok(sort_known_packages(pkgs))
+    }
+
+    /// Returns the number of known packages of a registry.
+    #[instrument(skip(self))]
+    pub async fn known_package_count_for_registry(&self, reg: &Registry) -> Result<i64> {
+        let count = sqlx::query_scalar("SELECT COUNT(*) FROM known_packages WHERE registry = $1")
+            .bind(reg.uri.to_string())
+            .fetch_one(&self.db)
+            .await
+            .wrap_err("failed to count known packages in database")?;
+        Ok(count)
     }
 
     /// Adds known packages to the database.
+    ///
+    /// All packages are added in a single transaction, so either all or none of them are added.
     #[instrument(skip(self))]
     pub async fn add_known_packages(&self, pkgs: &[Package]) -> Result<()> {
         // TODO: We might actually be overwriting another registry's packages. Don't do that.
         if pkgs.iter().any(|p| !p.is_tied_to_registry()) {
             bail!("known packages must be tied to a registry; this is a bug");
         }
+        // Dropping the transaction on error rolls it back.
+        let mut tx = self.begin_transaction().await?;
         for pkg in pkgs {
             sqlx::query(
                 "INSERT INTO known_packages
-                    (name, version, description, homepage, license, registry, source, build)
-                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                    (name, version, description, homepage, license, registry, sources, build, env, provides, post_install, pre_remove, artifacts, platforms, dependencies, build_dependencies)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
                     ON CONFLICT (name, version)
                     DO UPDATE
-                    SET description = $3, homepage = $4, license = $5, registry = $6, source = $7, build = $8
+                    SET description = $3, homepage = $4, license = $5, registry = $6, sources = $7, build = $8, env = $9, provides = $10, post_install = $11, pre_remove = $12, artifacts = $13, platforms = $14, dependencies = $15, build_dependencies = $16
                     WHERE name = $1 AND version = $2",
             )
             .bind(&pkg.name)
@@ -339,70 +897,119 @@ impl State {
             .bind(&pkg.homepage)
             .bind(&pkg.license)
             .bind(&pkg.registry)
-            .bind(&pkg.source)
+            .bind(Json(&pkg.sources))
             .bind(&pkg.build)
-            .execute(&self.db)
+            .bind(Json(&pkg.env))
+            .bind(Json(&pkg.provides))
+            .bind(&pkg.post_install)
+            .bind(&pkg.pre_remove)
+            .bind(Json(&pkg.artifacts))
+            .bind(Json(&pkg.platforms))
+            .bind(Json(&pkg.dependencies))
+            .bind(Json(&pkg.build_dependencies))
+            .execute(&mut *tx)
             .await
             .wrap_err("failed to insert known package into database")?;
         }
+        self.commit_transaction(tx).await?;
         Ok(())
     }
 
     /// Searches known packages for a query.
     #[instrument(skip(self))]
-    pub async fn search_known_packages(&self, query: &str) -> Result<Vec<Package>> {
+    pub async fn search_known_packages(
+        &self,
+        query: &str,
+        registry: Option<&str>,
+    ) -> Result<Vec<Package>> {
         let query = format!("%{}%", query);
         let pkgs = sqlx::query_as(
             r"SELECT *
                 FROM known_packages
-                WHERE name LIKE $1
-                OR description LIKE $1
-                OR homepage LIKE $1
-                ORDER BY name ASC, version DESC",
+                WHERE (name LIKE $1 OR description LIKE $1 OR homepage LIKE $1)
+                AND ($2 IS NULL OR registry = $2)",
         )
         .bind(&query)
+        .bind(registry)
         .fetch_all(&self.db)
         .await
         .wrap_err("failed to fetch known packages from database")?;
-        Ok(pkgs)
+        Ok(sort_known_packages(pkgs))
     }
 
     /// Searches know packages for a query, returning only the latest version of each package.
     #[instrument(skip(self))]
-    pub async fn search_known_packages_latest_only(&self, query: &str) -> Result<Vec<Package>> {
-        let query = format!("%{}%", query);
-        let pkgs = sqlx::query_as(
-            r"SELECT *
-            FROM (
-                SELECT *
-                FROM known_packages
-                WHERE name LIKE $1
-                OR description LIKE $1
-                OR homepage LIKE $1
-                ORDER BY name ASC, version DESC
-            )
-            GROUP BY name",
-        )
-        .bind(&query)
-        .fetch_all(&self.db)
-        .await
-        .wrap_err("failed to fetch known packages from database")?;
+    pub async fn search_known_packages_latest_only(
+        &self,
+        query: &str,
+        registry: Option<&str>,
+    ) -> Result<Vec<Package>> {
+        let mut pkgs = self.search_known_packages(query, registry).await?;
+        pkgs.dedup_by(|a, b| a.name == b.name);
         Ok(pkgs)
     }
 
     /// Returns all versions versions of a package, ordered newest to oldest.
+    ///
+    /// If `registry` is given, only versions from the registry with that name or URI are returned.
+    /// If `workspace` is given, only versions from registries visible in that workspace are
+    /// returned.
     #[instrument(skip(self))]
-    pub async fn known_package_versions(&self, name: &str) -> Result<Vec<String>> {
-        let versions = sqlx::query_scalar(
-            "SELECT version FROM known_packages WHERE name = $1 ORDER BY version DESC",
+    pub async fn known_package_versions(
+        &self,
+        name: &str,
+        registry: Option<&str>,
+        workspace: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let mut versions: Vec<String> = sqlx::query_scalar(
+            "SELECT version FROM known_packages
+            WHERE name = $1
+            AND ($2 IS NULL OR registry = $2
+                OR registry IN (SELECT uri FROM registries WHERE name = $2))
+            AND ($3 IS NULL OR registry IN
+                (SELECT uri FROM registries WHERE workspace IS NULL OR workspace = $3))",
         )
         .bind(name)
+        .bind(registry)
+        .bind(workspace)
         .fetch_all(&self.db)
         .await
         .wrap_err("failed to fetch known package versions from database")?;
+        versions.sort_by(|a, b| compare_versions(b, a));
         Ok(versions)
     }
 
+    /// Returns the names of all packages that provide the given virtual package name.
+    ///
+    /// If `registry` is given, only packages from the registry with that name or URI are returned.
+    /// If `workspace` is given, only packages from registries visible in that workspace are
+    /// returned.
+    #[instrument(skip(self))]
+    pub async fn providers_of(
+        &self,
+        name: &str,
+        registry: Option<&str>,
+        workspace: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let providers = sqlx::query_scalar(
+            "SELECT DISTINCT known_packages.name
+            FROM known_packages, json_each(known_packages.provides)
+            WHERE json_each.value = $1
+            AND ($2 IS NULL OR registry = $2
+                OR registry IN (SELECT uri FROM registries WHERE name = $2))
+            AND ($3 IS NULL OR registry IN
+                (SELECT uri FROM registries WHERE workspace IS NULL OR workspace = $3))
+            ORDER BY known_packages.name",
+        )
+        .bind(name)
+        .bind(registry)
+        .bind(workspace)
+        .fetch_all(&self.db)
+        .await
+        .wrap_err("failed to fetch package providers from database")?;
+        Ok(providers)
+    }
+
     /// Get the full package from a spec.
     #[instrument(skip(self))]
     pub async fn get_known_package(&self, pkg: &impl PackageSpec) -> Result<Option<Package>> {
@@ -432,8 +1039,9 @@ impl State {
     /// Adds a workspace.
     #[instrument(skip(self))]
     pub async fn add_workspace(&self, workspace: &Workspace) -> Result<()> {
-        sqlx::query("INSERT INTO workspaces (name) VALUES ($1)")
+        sqlx::query("INSERT INTO workspaces (name, parent) VALUES ($1, $2)")
             .bind(&workspace.name)
+            .bind(&workspace.parent)
             .execute(&self.db)
             .await
             .wrap_err("failed to insert workspace into database")?;
@@ -473,17 +1081,21 @@ impl State {
     }
 }
 
+/// Sorts known packages by name, and each package's versions from newest to oldest.
+fn sort_known_packages(mut pkgs: Vec<Package>) -> Vec<Package> {
+    pkgs.sort_by(|a, b| {
+        a.name
+            .cmp(&b.name)
+            .then_with(|| compare_versions(&b.version, &a.version))
+    });
+    pkgs
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use time::OffsetDateTime;
-
-    use crate::{
-        package::{KnownPackage, PackageRequest},
-        registry::MockFetcher,
-        workspace::test_workspace,
-    };
+    use crate::{registry::MockFetcher, workspace::test_workspace};
 
     /// Convenience function to setup the default test state.
     async fn setup_state_with_registry() -> Result<State> {
@@ -501,6 +1113,99 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_backup_and_restore() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("state.db");
+        let path = path.to_str().unwrap();
+        let backup = dir.path().join("backup.db");
+        let state = State::load(path).await?;
+
+        state.backup(&backup).await?;
+        assert!(state.backup(&backup).await.is_err());
+
+        state
+            .add_workspace(&Workspace {
+                name: "after-backup".to_string(),
+                parent: None,
+            })
+            .await?;
+        assert_eq!(state.workspaces().await?.len(), 2);
+
+        let state = state.restore(path, &backup).await?;
+        assert_eq!(state.workspaces().await?.len(), 1);
+        assert!(state.get_workspace("after-backup").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_migrated_schema_version() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        assert_eq!(state.migrated_schema_version().await?, SCHEMA_VERSION);
+        assert_eq!(state.schema_version().await?, SCHEMA_VERSION);
+
+        sqlx::query("UPDATE meta SET value = '1' WHERE key = 'schema_version'")
+            .execute(&state.db)
+            .await?;
+        assert_eq!(state.migrated_schema_version().await?, SCHEMA_VERSION);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_refuses_newer_schema_version() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("state.db");
+        let path = path.to_str().unwrap();
+        let backup = dir.path().join("backup.db");
+        let state = State::load(path).await?;
+
+        state.backup(&backup).await?;
+        let backup_state = State::load(backup.to_str().unwrap()).await?;
+        sqlx::query("UPDATE meta SET value = '999' WHERE key = 'schema_version'")
+            .execute(&backup_state.db)
+            .await?;
+        backup_state.db.close().await;
+
+        let Err(err) = state.restore(path, &backup).await else {
+            panic!("restored a backup with a newer schema version");
+        };
+        assert!(err.to_string().contains("schema version 999"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_read_and_write() -> Result<()> {
+        let dir = tempfile::TempDir::new()?;
+        let path = dir.path().join("state.db");
+        let path = path.to_str().unwrap();
+        // Two separately loaded states behave like two matcha processes.
+        let writer = State::load(path).await?;
+        let reader = State::load(path).await?;
+
+        let write = tokio::spawn(async move {
+            for i in 0..50 {
+                writer
+                    .add_workspace(&Workspace {
+                        name: format!("workspace-{i}"),
+                        parent: None,
+                    })
+                    .await?;
+            }
+            Ok::<_, color_eyre::eyre::Error>(())
+        });
+        let read = tokio::spawn(async move {
+            for _ in 0..50 {
+                reader.workspaces().await?;
+            }
+            Ok::<_, color_eyre::eyre::Error>(reader)
+        });
+
+        write.await??;
+        let reader = read.await??;
+        assert_eq!(reader.workspaces().await?.len(), 51);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_workspace_package_add_list_remove() -> Result<()> {
         let state = State::load(":memory:").await?;
@@ -510,7 +1215,9 @@ mod tests {
         let known_package = KnownPackage::from_request(&req, "0.1.0");
         let workspace_package = WorkspacePackage::from_request(&req, "0.1.0");
 
-        state.add_installed_package(&known_package).await?;
+        state
+            .add_installed_package(&known_package, None, None)
+            .await?;
         state
             .add_workspace_package(&workspace_package, &workspace)
             .await?;
@@ -526,6 +1233,70 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_inherited_workspace_packages() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let (parent, _workspace_root) = test_workspace("parent").await;
+        state.add_workspace(&parent).await?;
+        let child = Workspace {
+            name: "child".to_string(),
+            parent: Some("parent".to_string()),
+        };
+        state.add_workspace(&child).await?;
+
+        for (request, workspace) in [
+            ("foo@1.0.0", &parent),
+            ("bar@1.0.0", &parent),
+            ("foo@2.0.0", &child),
+        ] {
+            let req: PackageRequest = request.parse()?;
+            let version = req.version.to_string();
+            state
+                .add_installed_package(&KnownPackage::from_request(&req, &version), None, None)
+                .await?;
+            state
+                .add_workspace_package(&WorkspacePackage::from_request(&req, &version), workspace)
+                .await?;
+        }
+
+        let packages = state
+            .inherited_workspace_packages(&child)
+            .await?
+            .into_iter()
+            .map(|(from, pkg)| format!("{}@{} from {from}", pkg.name, pkg.version))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            packages,
+            vec!["foo@2.0.0 from child", "bar@1.0.0 from parent"]
+        );
+        assert_eq!(state.inherited_workspace_packages(&parent).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_workspace_package_registry_round_trips() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        let (workspace, _workspace_root) = test_workspace("global").await;
+
+        let req: PackageRequest = "test-package@0.1.0".parse()?;
+        let known_package = KnownPackage::from_request(&req, "0.1.0");
+        let mut workspace_package = WorkspacePackage::from_request(&req, "0.1.0");
+        workspace_package.registry = Some("https://example.invalid/registry".to_string());
+
+        state
+            .add_installed_package(&known_package, None, None)
+            .await?;
+        state
+            .add_workspace_package(&workspace_package, &workspace)
+            .await?;
+        let packages = state.workspace_packages(&workspace).await?;
+        assert_eq!(
+            packages[0].registry.as_deref(),
+            Some("https://example.invalid/registry")
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_add_workspace_package_refuses_same_version_twice() -> Result<()> {
         let state = State::load(":memory:").await?;
@@ -535,7 +1306,9 @@ mod tests {
         let known_package = KnownPackage::from_request(&req, "0.1.0");
         let workspace_package = WorkspacePackage::from_request(&req, "0.1.0");
 
-        state.add_installed_package(&known_package).await?;
+        state
+            .add_installed_package(&known_package, None, None)
+            .await?;
         state
             .add_workspace_package(&workspace_package, &workspace)
             .await?;
@@ -555,7 +1328,9 @@ mod tests {
         let known_package = KnownPackage::from_request(&req, "0.1.0");
         let workspace_package = WorkspacePackage::from_request(&req, "0.1.0");
 
-        state.add_installed_package(&known_package).await?;
+        state
+            .add_installed_package(&known_package, None, None)
+            .await?;
         assert!(state
             .get_workspace_package(&req.name, &workspace)
             .await?
@@ -633,7 +1408,7 @@ mod tests {
             .remove_registry("https://example.invalid/registry")
             .await
             .unwrap();
-        let results = state.search_known_packages("foo").await.unwrap();
+        let results = state.search_known_packages("foo", None).await.unwrap();
         assert!(results.is_empty());
     }
 
@@ -668,6 +1443,76 @@ mod tests {
         assert_eq!(registries[0].last_fetched, Some(last_fetched));
     }
 
+    #[tokio::test]
+    async fn test_known_package_count_for_registry() -> Result<()> {
+        let state = setup_state_with_registry().await?;
+        let registry = state.registries().await?.remove(0);
+        assert_eq!(state.known_package_count_for_registry(&registry).await?, 0);
+
+        let package = |version: &str| Package {
+            name: "foo".to_string(),
+            version: version.to_string(),
+            registry: Some(registry.uri.to_string()),
+            ..Default::default()
+        };
+        state
+            .add_known_packages(&[package("1.0.0"), package("1.1.0")])
+            .await?;
+        assert_eq!(state.known_package_count_for_registry(&registry).await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_change_registry_uri() -> Result<()> {
+        let state = setup_state_with_registry().await?;
+        let old = "https://example.invalid/registry";
+        let new = "https://example.invalid/new-registry";
+        state
+            .add_known_packages(&[Package {
+                name: "foo".to_string(),
+                version: "1.0.0".to_string(),
+                registry: Some(old.to_string()),
+                ..Default::default()
+            }])
+            .await?;
+
+        state.change_registry_uri(old, new).await?;
+
+        assert!(!state.registry_exists(old).await?);
+        assert!(state.registry_exists(new).await?);
+        let pkg = state
+            .get_known_package(&known_package("foo", "1.0.0"))
+            .await?
+            .unwrap();
+        assert_eq!(pkg.registry.as_deref(), Some(new));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_change_registry_uri_refuses_unknown_and_colliding() -> Result<()> {
+        let state = setup_state_with_registry().await?;
+        let mut other = Registry::new("https://example.invalid/other");
+        other
+            .initialize(&state, &MockFetcher::with_packages(&[]))
+            .await?;
+
+        assert!(state
+            .change_registry_uri(
+                "https://example.invalid/unknown",
+                "https://example.invalid/new"
+            )
+            .await
+            .is_err());
+        assert!(state
+            .change_registry_uri(
+                "https://example.invalid/registry",
+                "https://example.invalid/other"
+            )
+            .await
+            .is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_search_known_packages() {
         let state = setup_state_with_registry().await.unwrap();
@@ -699,7 +1544,7 @@ mod tests {
             },
         ];
         state.add_known_packages(&pkgs).await.unwrap();
-        let results = state.search_known_packages("foo").await.unwrap();
+        let results = state.search_known_packages("foo", None).await.unwrap();
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].name, "foo");
         assert_eq!(results[0].version, "1.0.0");
@@ -746,7 +1591,7 @@ mod tests {
         ];
         state.add_known_packages(&pkgs).await.unwrap();
         let results = state
-            .search_known_packages_latest_only("foo")
+            .search_known_packages_latest_only("foo", None)
             .await
             .unwrap();
         assert_eq!(results.len(), 1);
@@ -776,7 +1621,7 @@ mod tests {
             ..Default::default()
         }];
         state.add_known_packages(&pkgs).await.unwrap();
-        let results = state.search_known_packages("foo").await.unwrap();
+        let results = state.search_known_packages("foo", None).await.unwrap();
         assert_eq!(results.len(), 1);
         assert_eq!(results[0].name, "test-package");
         assert_eq!(results[0].version, "0.1.0");
@@ -791,6 +1636,53 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_add_known_packages_rolls_back_on_failure() -> Result<()> {
+        let state = setup_state_with_registry().await?;
+
+        let pkgs = vec![
+            Package {
+                name: "foo".to_string(),
+                version: "1.0.0".to_string(),
+                registry: Some("https://example.invalid/registry".to_string()),
+                ..Default::default()
+            },
+            Package {
+                name: "bar".to_string(),
+                version: "1.0.0".to_string(),
+                // Violates the foreign key constraint on registries.
+                registry: Some("https://example.invalid/unknown".to_string()),
+                ..Default::default()
+            },
+        ];
+        assert!(state.add_known_packages(&pkgs).await.is_err());
+        assert!(state
+            .get_known_package(&known_package("foo", "1.0.0"))
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_known_package_env_round_trips() -> Result<()> {
+        let state = setup_state_with_registry().await?;
+
+        let pkg = Package {
+            name: "foo".to_string(),
+            version: "1.0.0".to_string(),
+            registry: Some("https://example.invalid/registry".to_string()),
+            env: [("CFLAGS".to_string(), "-O2".to_string())].into(),
+            ..Default::default()
+        };
+        state.add_known_packages(std::slice::from_ref(&pkg)).await?;
+        let stored = state
+            .get_known_package(&known_package("foo", "1.0.0"))
+            .await?
+            .unwrap();
+        assert_eq!(stored.env, pkg.env);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_known_package_versions_is_in_descending_order() {
         let state = setup_state_with_registry().await.unwrap();
@@ -814,13 +1706,19 @@ mod tests {
                 registry: Some("https://example.invalid/registry".to_string()),
                 ..Default::default()
             },
+            Package {
+                name: "foo".to_string(),
+                version: "0.10.0".to_string(),
+                registry: Some("https://example.invalid/registry".to_string()),
+                ..Default::default()
+            },
         ];
         state.add_known_packages(&pkgs).await.unwrap();
-        let versions = state.known_package_versions("foo").await.unwrap();
-        assert_eq!(versions.len(), 3);
-        assert_eq!(versions[0], "1.0.0");
-        assert_eq!(versions[1], "0.2.0");
-        assert_eq!(versions[2], "0.1.0");
+        let versions = state
+            .known_package_versions("foo", None, None)
+            .await
+            .unwrap();
+        assert_eq!(versions, vec!["1.0.0", "0.10.0", "0.2.0", "0.1.0"]);
     }
 
     #[tokio::test]
@@ -881,6 +1779,9 @@ mod tests {
                 name: None,
                 uri: "https://example.invalid/registry".into(),
                 last_fetched: None,
+                workspace: None,
+                validators: Default::default(),
+                public_key: None,
             })
             .await
             .unwrap();
@@ -909,7 +1810,7 @@ mod tests {
     async fn test_get_installed_package() -> Result<()> {
         let state = setup_state_with_registry().await?;
         let spec = known_package("test-package", "0.1.0");
-        state.add_installed_package(&spec).await?;
+        state.add_installed_package(&spec, None, None).await?;
         let pkg = state.get_installed_package(&spec).await?.unwrap();
         assert_eq!(pkg.name, spec.name);
         assert_eq!(pkg.version, spec.version);
@@ -928,6 +1829,66 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_is_content_hash_referenced() -> Result<()> {
+        let state = State::load(":memory:").await?;
+        state
+            .add_installed_package(&known_package("foo", "1.0.0"), None, Some("abc"))
+            .await?;
+        state
+            .add_installed_package(&known_package("bar", "1.0.0"), None, Some("abc"))
+            .await?;
+
+        assert!(state.is_content_hash_referenced("abc").await?);
+        assert!(!state.is_content_hash_referenced("def").await?);
+
+        state
+            .remove_installed_package(&known_package("foo", "1.0.0"))
+            .await?;
+        assert!(state.is_content_hash_referenced("abc").await?);
+        state
+            .remove_installed_package(&known_package("bar", "1.0.0"))
+            .await?;
+        assert!(!state.is_content_hash_referenced("abc").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_garbage_collection_keeps_packages_being_installed() -> Result<()> {
+        let state = setup_state_with_registry().await?;
+        let (workspace, _workspace_root) = test_workspace("global").await;
+
+        let req: PackageRequest = "test-package@0.1.0".parse()?;
+        let known_package = KnownPackage::from_request(&req, "0.1.0");
+        let workspace_package = WorkspacePackage::from_request(&req, "0.1.0");
+        state
+            .add_installed_package(&known_package, None, None)
+            .await?;
+
+        // The garbage collection sees the package as unused, but an install adds it to a
+        // workspace before it is removed.
+        assert_eq!(state.unused_installed_packages().await?.len(), 1);
+        state
+            .add_workspace_package(&workspace_package, &workspace)
+            .await?;
+        assert!(state.remove_unused_installed_packages().await?.is_empty());
+        assert!(state.get_installed_package(&known_package).await?.is_some());
+
+        state
+            .remove_workspace_package(&workspace_package, &workspace)
+            .await?;
+        let removed = state.remove_unused_installed_packages().await?;
+        assert_eq!(removed.len(), 1);
+        assert!(state.get_installed_package(&known_package).await?.is_none());
+
+        // Once removed, the package can't be added to a workspace anymore.
+        assert!(state
+            .add_workspace_package(&workspace_package, &workspace)
+            .await
+            .is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_unused_installed_packages() -> Result<()> {
         let state = setup_state_with_registry().await?;
@@ -937,7 +1898,9 @@ mod tests {
         let known_package = KnownPackage::from_request(&req, "0.1.0");
         let workspace_package = WorkspacePackage::from_request(&req, "0.1.0");
 
-        state.add_installed_package(&known_package).await?;
+        state
+            .add_installed_package(&known_package, None, None)
+            .await?;
         assert_eq!(state.unused_installed_packages().await?.len(), 1);
         state
             .add_workspace_package(&workspace_package, &workspace)
@@ -949,4 +1912,35 @@ mod tests {
         assert_eq!(state.unused_installed_packages().await?.len(), 1);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_build_dependencies_of_used_packages_are_used() -> Result<()> {
+        let state = setup_state_with_registry().await?;
+        let (workspace, _workspace_root) = test_workspace("global").await;
+
+        let req: PackageRequest = "test-package@0.1.0".parse()?;
+        let workspace_package = WorkspacePackage::from_request(&req, "0.1.0");
+        let pkg = KnownPackage::from(&workspace_package);
+        let compiler = known_package("compiler", "1.0.0");
+        let assembler = known_package("assembler", "1.0.0");
+        for installed in [&pkg, &compiler, &assembler] {
+            state.add_installed_package(installed, None, None).await?;
+        }
+        state
+            .add_build_dependencies(&pkg, std::slice::from_ref(&compiler))
+            .await?;
+        state
+            .add_build_dependencies(&compiler, std::slice::from_ref(&assembler))
+            .await?;
+        state
+            .add_workspace_package(&workspace_package, &workspace)
+            .await?;
+        assert!(state.unused_installed_packages().await?.is_empty());
+
+        state
+            .remove_workspace_package(&workspace_package, &workspace)
+            .await?;
+        assert_eq!(state.remove_unused_installed_packages().await?.len(), 3);
+        Ok(())
+    }
 }
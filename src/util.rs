@@ -1,30 +1,414 @@
-use std::time::Duration;
+use std::{
+    fs::Permissions,
+    io::ErrorKind,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
+use color_eyre::eyre::{anyhow, Result};
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
+use tokio::fs::{copy, hard_link, metadata, read, read_dir, read_link, symlink, symlink_metadata};
+
+use crate::{command::ALL_WORKSPACES, NO_COLOR, NO_PROGRESS, QUIET};
+
+/// Expands a leading `~` in a user-supplied path to the home directory.
+///
+/// The shell does this for unquoted arguments, but not for quoted ones, environment variables, or
+/// config files.
+pub fn expand_tilde(path: &Path) -> PathBuf {
+    PathBuf::from(shellexpand::tilde(&path.to_string_lossy()).as_ref())
+}
+
+/// Shell syntax to emit environment exports in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ShellKind {
+    /// POSIX-style `export` lines, as understood by sh, bash, and zsh.
+    #[default]
+    Posix,
+    /// fish-style `set -x` lines.
+    Fish,
+}
+
+impl ShellKind {
+    /// Quotes a string as a single word for this shell, so that it is taken literally.
+    pub fn quote(&self, s: &str) -> String {
+        match self {
+            // Nothing is special inside single quotes, so a quote has to end them, be escaped,
+            // and start them again.
+            Self::Posix => format!("'{}'", s.replace('\'', r"'\''")),
+            // Inside single quotes, backslashes escape only quotes and themselves.
+            Self::Fish => format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'")),
+        }
+    }
+}
+
+/// Field to sort search results by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortKey {
+    /// Package name.
+    Name,
+    /// Package version.
+    Version,
+    /// URI of the registry the package is from.
+    Registry,
+}
+
+/// Format to print command output in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    /// A single JSON object, for other programs to consume.
+    Json,
+}
+
+/// How package binaries are placed in a workspace's bin directory.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LinkMode {
+    /// Symlink to the package directory.
+    #[default]
+    Symlink,
+    /// Copy the files, so the workspace doesn't depend on the package directory.
+    Copy,
+    /// Hardlink the files, falling back to copying them across file systems.
+    Hardlink,
+}
+
+impl std::fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use clap::ValueEnum;
+        let value = self.to_possible_value().expect("link mode without value");
+        f.write_str(value.get_name())
+    }
+}
+
+impl TryFrom<String> for LinkMode {
+    type Error = color_eyre::Report;
+
+    fn try_from(value: String) -> Result<Self> {
+        <Self as clap::ValueEnum>::from_str(&value, false)
+            .map_err(|_| anyhow!("invalid link mode {value}"))
+    }
+}
+
+impl LinkMode {
+    /// Places `target` at `link` according to this mode.
+    pub async fn place(&self, target: &Path, link: &Path) -> Result<()> {
+        match self {
+            Self::Symlink => symlink(target, link).await?,
+            Self::Copy => {
+                copy(target, link).await?;
+            }
+            Self::Hardlink => match hard_link(target, link).await {
+                Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+                    copy(target, link).await?;
+                }
+                result => result?,
+            },
+        }
+        Ok(())
+    }
+
+    /// Returns if `link` was placed from `target` in this mode.
+    pub async fn is_placed(&self, target: &Path, link: &Path) -> Result<bool> {
+        match self {
+            Self::Symlink => Ok(read_link(link).await.is_ok_and(|t| t == target)),
+            Self::Copy | Self::Hardlink => Ok(!symlink_metadata(link).await?.is_symlink()
+                && files_are_identical(target, link).await?),
+        }
+    }
+}
+
+/// Returns if two files are the same file or have identical contents.
+pub async fn files_are_identical(a: &Path, b: &Path) -> Result<bool> {
+    let (a_metadata, b_metadata) = (metadata(a).await?, metadata(b).await?);
+    if a_metadata.dev() == b_metadata.dev() && a_metadata.ino() == b_metadata.ino() {
+        return Ok(true);
+    }
+    if !a_metadata.is_file() || !b_metadata.is_file() || a_metadata.len() != b_metadata.len() {
+        return Ok(false);
+    }
+    Ok(read(a).await? == read(b).await?)
+}
+
+/// Returns if informational output should be suppressed.
+pub fn is_quiet() -> bool {
+    QUIET.get().copied().unwrap_or_default()
+}
+
+/// Returns if spinners and progress bars should be hidden.
+pub fn is_progress_disabled() -> bool {
+    NO_PROGRESS.get().copied().unwrap_or_default()
+}
+
+/// Returns if output should not be colored.
+pub fn is_color_disabled() -> bool {
+    NO_COLOR.get().copied().unwrap_or_default()
+}
 
 /// Creates a default style spinnner, optionally adding it to a multi-progress bar.
+///
+/// The spinner is hidden if informational output is suppressed, or progress is disabled.
 pub fn create_spinner(msg: &str, mpb: Option<&MultiProgress>) -> ProgressBar {
+    if is_quiet() || is_progress_disabled() {
+        return ProgressBar::hidden();
+    }
     let spinner = if let Some(mpb) = mpb {
         mpb.add(ProgressBar::new_spinner())
     } else {
         ProgressBar::new_spinner()
     };
     spinner.enable_steady_tick(Duration::from_millis(100));
-    spinner.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+    switch_to_spinner(&spinner);
     spinner.set_message(msg.to_string());
     spinner
 }
 
+/// Turns a progress bar into an indeterminate spinner.
+pub fn switch_to_spinner(pb: &ProgressBar) {
+    let template = if is_color_disabled() {
+        "{spinner} {msg}"
+    } else {
+        "{spinner:.green} {msg}"
+    };
+    pb.set_style(ProgressStyle::with_template(template).unwrap());
+}
+
+/// Turns a spinner into a byte-level progress bar of the given length.
+pub fn switch_to_progress_bar(pb: &ProgressBar, len: u64) {
+    pb.set_length(len);
+    pb.set_position(0);
+    let template = if is_color_disabled() {
+        "{spinner} {msg} [{bar:30}] {bytes}/{total_bytes}"
+    } else {
+        "{spinner:.green} {msg} [{bar:30.green/white}] {bytes}/{total_bytes}"
+    };
+    pb.set_style(
+        ProgressStyle::with_template(template)
+            .unwrap()
+            .progress_chars("=> "),
+    );
+}
+
 /// Returns if the given string is safe to use in a file system path.
 pub fn is_file_system_safe(s: &str) -> bool {
     s.chars()
         .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
 }
 
+/// Returns if the given string can be used as a single component of a file system path.
+///
+/// Beyond [`is_file_system_safe`], this rejects empty strings and leading dots, which rules out
+/// `.` and `..`, so that joining it to a directory can't escape that directory.
+pub fn is_safe_path_component(s: &str) -> bool {
+    !s.is_empty() && !s.starts_with('.') && is_file_system_safe(s)
+}
+
+/// Names that cannot be used for workspaces, because they select something else on the command
+/// line, or refer to another directory.
+pub const RESERVED_WORKSPACE_NAMES: &[&str] = &[ALL_WORKSPACES, ".", ".."];
+
+/// Checks that a name can be used for a new workspace.
+pub fn validate_workspace_name(name: &str) -> Result<()> {
+    if !is_file_system_safe(name) {
+        return Err(anyhow!("workspace names can contain [a-zA-Z0-9._-] only"));
+    }
+    if RESERVED_WORKSPACE_NAMES.contains(&name) {
+        return Err(anyhow!(
+            "{} is a reserved name and cannot be used for a workspace",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Returns if the given package name is in its normalized, lowercase form.
+pub fn is_normalized_package_name(name: &str) -> bool {
+    name == normalize_package_name(name)
+}
+
+/// Normalizes a package name, so that it matches regardless of case.
+///
+/// Registries may only contain normalized package names, see [`is_normalized_package_name`].
+pub fn normalize_package_name(name: &str) -> String {
+    name.to_ascii_lowercase()
+}
+
+/// Returns if a string contains glob metacharacters, see [`glob_matches`].
+pub fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+/// Returns if a name matches a glob pattern.
+///
+/// `*` matches any number of characters, and `?` matches exactly one.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    // Positions to backtrack to after the last `*`, in the pattern and the name.
+    let mut backtrack = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` consume one more character.
+                Some((star, consumed)) => {
+                    backtrack = Some((star, consumed + 1));
+                    p = star + 1;
+                    n = consumed + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns the platform matcha is running on, as `<os>-<arch>`, e.g. `linux-x86_64`.
+pub fn current_platform() -> String {
+    format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH)
+}
+
+/// Computes a content hash of a directory tree.
+///
+/// The hash covers relative paths, file contents, executable bits, and symlink targets, but not
+/// timestamps or ownership, so identical build outputs hash the same.
+pub async fn hash_directory(root: &Path) -> Result<String> {
+    let mut paths = vec![];
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let mut reader = read_dir(&dir).await?;
+        while let Some(entry) = reader.next_entry().await? {
+            // Doesn't follow symlinks, so linked directories are hashed as links.
+            if entry.file_type().await?.is_dir() {
+                pending.push(entry.path());
+            }
+            paths.push(entry.path());
+        }
+    }
+    paths.sort();
+
+    let mut hasher = Sha256::new();
+    for path in paths {
+        hasher.update(path.strip_prefix(root)?.as_os_str().as_bytes());
+        hasher.update([0]);
+        let metadata = symlink_metadata(&path).await?;
+        if metadata.is_symlink() {
+            hasher.update(b"l");
+            hasher.update(read_link(&path).await?.as_os_str().as_bytes());
+        } else if metadata.is_dir() {
+            hasher.update(b"d");
+        } else {
+            let executable = metadata.permissions().mode() & 0o111 != 0;
+            hasher.update(if executable { b"x" } else { b"f" });
+            hasher.update(metadata.len().to_le_bytes());
+            hasher.update(read(&path).await?);
+        }
+        hasher.update([0]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Makes a directory tree read-only, so that files shared between packages can't be modified
+/// through one of them.
+pub fn set_read_only(root: &Path) -> std::io::Result<()> {
+    set_writable(root, false)
+}
+
+/// Removes a directory tree, including one made read-only with [`set_read_only`].
+pub fn remove_read_only_dir(root: &Path) -> std::io::Result<()> {
+    // Entries can only be removed from writable directories.
+    set_writable(root, true)?;
+    std::fs::remove_dir_all(root)
+}
+
+/// Adds or removes the write permissions of everything in a directory tree.
+///
+/// Doesn't follow symlinks, as changing their permissions would change their targets'.
+fn set_writable(root: &Path, writable: bool) -> std::io::Result<()> {
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(path) = pending.pop() {
+        let metadata = std::fs::symlink_metadata(&path)?;
+        if metadata.is_symlink() {
+            continue;
+        }
+        if metadata.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                pending.push(entry?.path());
+            }
+        }
+        let mode = metadata.permissions().mode();
+        let mode = if writable {
+            mode | 0o200
+        } else {
+            mode & !0o222
+        };
+        std::fs::set_permissions(&path, Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_validate_workspace_name() {
+        assert!(validate_workspace_name("global").is_ok());
+        assert!(validate_workspace_name("my-workspace.2").is_ok());
+        for name in ["all", ".", ".."] {
+            let err = validate_workspace_name(name).unwrap_err();
+            assert!(err.to_string().contains("reserved name"));
+        }
+        assert!(validate_workspace_name("a/b").is_err());
+    }
+
+    #[test]
+    fn test_shell_quote() -> Result<()> {
+        let s = r#"it's a "$HOME" `dir` \ here"#;
+        let out = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", ShellKind::Posix.quote(s)))
+            .output()?;
+        assert_eq!(String::from_utf8(out.stdout)?, s);
+        assert_eq!(
+            ShellKind::Fish.quote(s),
+            r#"'it\'s a "$HOME" `dir` \\ here'"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("foo-*", "foo-a"));
+        assert!(glob_matches("foo-*", "foo-"));
+        assert!(glob_matches("*-b", "foo-b"));
+        assert!(glob_matches("f?o", "foo"));
+        assert!(glob_matches("*o*o*", "foo"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("foo-*", "foo"));
+        assert!(!glob_matches("foo-*", "bar-a"));
+        assert!(!glob_matches("f?o", "fo"));
+        assert!(!glob_matches("*-b", "foo-a"));
+        assert!(is_glob("python-*"));
+        assert!(!is_glob("python@3"));
+    }
+
     #[test]
     fn test_is_file_system_safe() {
         assert!(is_file_system_safe("foo"));
@@ -37,4 +421,56 @@ mod tests {
         assert!(!is_file_system_safe(r"foo\bar"));
         assert!(!is_file_system_safe(r#"foo"bar"#));
     }
+
+    #[test]
+    fn test_is_safe_path_component() {
+        assert!(is_safe_path_component("foo"));
+        assert!(is_safe_path_component("1.0.0"));
+        assert!(is_safe_path_component("foo..bar"));
+
+        assert!(!is_safe_path_component(""));
+        assert!(!is_safe_path_component("."));
+        assert!(!is_safe_path_component(".."));
+        assert!(!is_safe_path_component("..."));
+        assert!(!is_safe_path_component(".hidden"));
+        assert!(!is_safe_path_component("foo/bar"));
+    }
+
+    #[test]
+    fn test_normalize_package_name() {
+        assert_eq!(normalize_package_name("Foo-Bar"), "foo-bar");
+        assert!(is_normalized_package_name("foo-bar"));
+        assert!(!is_normalized_package_name("Foo-bar"));
+    }
+
+    #[tokio::test]
+    async fn test_hash_directory() -> Result<()> {
+        let a = tempfile::TempDir::new()?;
+        let b = tempfile::TempDir::new()?;
+        for dir in [&a, &b] {
+            std::fs::create_dir(dir.path().join("bin"))?;
+            std::fs::write(dir.path().join("bin").join("foo"), "foo")?;
+        }
+        assert_eq!(
+            hash_directory(a.path()).await?,
+            hash_directory(b.path()).await?
+        );
+
+        std::fs::write(b.path().join("bin").join("foo"), "bar")?;
+        assert_ne!(
+            hash_directory(a.path()).await?,
+            hash_directory(b.path()).await?
+        );
+
+        std::fs::write(b.path().join("bin").join("foo"), "foo")?;
+        std::fs::set_permissions(
+            b.path().join("bin").join("foo"),
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+        assert_ne!(
+            hash_directory(a.path()).await?,
+            hash_directory(b.path()).await?
+        );
+        Ok(())
+    }
 }
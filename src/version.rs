@@ -0,0 +1,105 @@
+use std::cmp::Ordering;
+
+/// Compares two version strings.
+///
+/// Versions are split into a release and an optional pre-release part at the first `-`, and both
+/// parts into components at each `.`. Numeric components are compared numerically, everything else
+/// lexically, with numeric components ordering before non-numeric ones. A version without a
+/// pre-release part is newer than the same version with one, so `1.0.0-beta < 1.0.0`.
+///
+/// Versions that only differ in ways this doesn't consider, such as `1.01` and `1.1`, fall back
+/// to comparing the strings, so that only identical versions compare as equal.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (a_release, a_pre) = split_pre_release(a);
+    let (b_release, b_pre) = split_pre_release(b);
+
+    compare_components(a_release, b_release)
+        .then_with(|| match (a_pre, b_pre) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(a_pre), Some(b_pre)) => compare_components(a_pre, b_pre),
+        })
+        .then_with(|| a.cmp(b))
+}
+
+/// Splits a version into its release and pre-release parts.
+fn split_pre_release(version: &str) -> (&str, Option<&str>) {
+    match version.split_once('-') {
+        Some((release, pre)) => (release, Some(pre)),
+        None => (version, None),
+    }
+}
+
+/// Compares two `.`-separated lists of version components.
+///
+/// If one list is a prefix of the other, the shorter one orders first.
+fn compare_components(a: &str, b: &str) -> Ordering {
+    let mut a = a.split('.');
+    let mut b = b.split('.');
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a), Some(b)) => match compare_component(a, b) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            },
+        }
+    }
+}
+
+/// Compares a single version component.
+fn compare_component(a: &str, b: &str) -> Ordering {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_versions() {
+        for (a, b) in [
+            ("1.0.0", "1.0.1"),
+            ("1.0.9", "1.0.10"),
+            ("1.9.0", "1.10.0"),
+            ("9.0.0", "10.0.0"),
+            ("1.0", "1.0.0"),
+            ("1.0.0-beta", "1.0.0"),
+            ("1.0.0-alpha", "1.0.0-beta"),
+            ("1.0.0-alpha", "1.0.0-alpha.1"),
+            ("1.0.0-alpha.1", "1.0.0-alpha.beta"),
+            ("1.0.0-beta.2", "1.0.0-beta.11"),
+            ("1.0.0-rc.1", "1.0.1-alpha"),
+            ("1.0.0", "1.0.a"),
+            ("1.01", "1.1"),
+        ] {
+            assert_eq!(compare_versions(a, b), Ordering::Less, "{a} < {b}");
+            assert_eq!(compare_versions(b, a), Ordering::Greater, "{b} > {a}");
+        }
+    }
+
+    #[test]
+    fn test_compare_equal_versions() {
+        for version in ["1.0.0", "1.0.0-beta", "", "latest"] {
+            assert_eq!(compare_versions(version, version), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn test_sort_versions() {
+        let mut versions = vec!["1.10.0", "1.2.0", "1.2.0-rc.1", "0.9", "1.2.0-beta"];
+        versions.sort_by(|a, b| compare_versions(a, b));
+        assert_eq!(
+            versions,
+            vec!["0.9", "1.2.0-beta", "1.2.0-rc.1", "1.2.0", "1.10.0"]
+        );
+    }
+}
@@ -1,13 +1,23 @@
-use std::{fmt::Display, ops::Deref, path::PathBuf};
+use std::{
+    fmt::Display,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
 
-use color_eyre::eyre::{eyre, Result, WrapErr};
+use color_eyre::eyre::{bail, eyre, Result, WrapErr};
 use shellexpand::tilde;
 use sqlx::FromRow;
-use tokio::fs::{create_dir_all, read_dir, read_link, remove_file};
+use tokio::{
+    fs::{create_dir_all, read_dir, read_link, remove_file, symlink_metadata, try_exists},
+    process::Command,
+};
 use tracing::instrument;
 
 use crate::{
     package::{InstalledPackage, WorkspacePackage},
+    state::State,
+    util::files_are_identical,
     WORKSPACE_ROOT,
 };
 
@@ -16,6 +26,8 @@ use crate::{
 pub struct Workspace {
     /// The name of the workplace.
     pub name: String,
+    /// The workspace this one inherits packages from, if any.
+    pub parent: Option<String>,
 }
 
 impl Workspace {
@@ -26,6 +38,7 @@ impl Workspace {
     pub async fn new(name: &str) -> Result<Self> {
         let ws = Self {
             name: String::from(name),
+            parent: None,
         };
         ws.ensure_exists().await?;
         Ok(ws)
@@ -62,31 +75,146 @@ impl Workspace {
     }
 
     /// Removes a package's files from this workspace.
+    ///
+    /// Runs the package's `pre_remove` hook first, if it has one.
     #[instrument]
-    pub async fn remove_package(&self, pkg: &WorkspacePackage) -> Result<()> {
-        let installed_package = InstalledPackage::from(pkg);
-        let pkg_dir = installed_package.directory();
+    pub async fn remove_package(
+        &self,
+        pkg: &InstalledPackage,
+        pre_remove: Option<&str>,
+    ) -> Result<()> {
+        let pkg_dir = pkg.directory();
+
+        if let Some(hook) = pre_remove {
+            self.run_hook(hook, &pkg_dir)
+                .await
+                .wrap_err(format!("pre-remove hook of {pkg} failed"))?;
+        }
 
-        // Remove the package's bin symlinks.
+        self.remove_binaries(&pkg_dir).await
+    }
+
+    /// Removes the binaries of the package in the given package directory from this workspace,
+    /// whether they are symlinks, copies, or hardlinks.
+    #[instrument]
+    pub async fn remove_binaries(&self, pkg_dir: &Path) -> Result<()> {
         let mut bin_dir_reader = read_dir(self.bin_directory()?).await?;
         while let Some(entry) = bin_dir_reader.next_entry().await? {
-            if entry.metadata().await?.file_type().is_symlink()
-                && read_link(entry.path()).await?.starts_with(&pkg_dir)
-            {
+            let owned = if entry.metadata().await?.file_type().is_symlink() {
+                read_link(entry.path()).await?.starts_with(pkg_dir)
+            } else {
+                let pkg_file = pkg_dir.join("bin").join(entry.file_name());
+                try_exists(&pkg_file).await?
+                    && files_are_identical(&pkg_file, &entry.path()).await?
+            };
+            if owned {
                 remove_file(entry.path())
                     .await
-                    .wrap_err("failed to delete package bin symlink")?;
+                    .wrap_err("failed to delete package binary")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the target of the bin symlink for a binary, if this workspace has one.
+    #[instrument]
+    pub async fn bin_link_target(&self, binary: &str) -> Result<Option<PathBuf>> {
+        let link = self.bin_directory()?.join(binary);
+        match symlink_metadata(&link).await {
+            Ok(metadata) if metadata.file_type().is_symlink() => Ok(Some(read_link(&link).await?)),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).wrap_err("failed to inspect workspace bin directory"),
+        }
+    }
+
+    /// Returns if this workspace has a binary of the given name, even if it is a broken link.
+    #[instrument]
+    pub async fn has_binary(&self, binary: &str) -> Result<bool> {
+        Ok(symlink_metadata(self.bin_directory()?.join(binary))
+            .await
+            .is_ok())
+    }
+
+    /// Returns the package in this workspace a binary belongs to, if any.
+    ///
+    /// Symlinks belong to the package they point into, copies and hardlinks to the package that
+    /// has an identical binary.
+    #[instrument(skip(state))]
+    pub async fn bin_owner(&self, state: &State, binary: &str) -> Result<Option<WorkspacePackage>> {
+        if !self.has_binary(binary).await? {
+            return Ok(None);
+        }
+        let link = self.bin_directory()?.join(binary);
+        let target = self.bin_link_target(binary).await?;
+        for pkg in state.workspace_packages(self).await? {
+            let pkg_dir = pkg.installed_package(state).await?.directory();
+            let owned = match &target {
+                Some(target) => target.starts_with(&pkg_dir),
+                None => {
+                    let pkg_file = pkg_dir.join("bin").join(binary);
+                    try_exists(&pkg_file).await? && files_are_identical(&pkg_file, &link).await?
+                }
+            };
+            if owned {
+                return Ok(Some(pkg));
             }
         }
+        Ok(None)
+    }
 
+    /// Runs a package hook script for a package in this workspace.
+    ///
+    /// Hooks run in a scratch directory that is removed afterwards, with `MATCHA_PACKAGE_DIR` and
+    /// `MATCHA_WORKSPACE_BIN` set. The package directory is shared by all workspaces, so hooks
+    /// must not write to it.
+    #[instrument]
+    pub async fn run_hook(&self, hook: &str, pkg_dir: &Path) -> Result<()> {
+        let scratch_dir = tempfile::tempdir().wrap_err("failed to create hook directory")?;
+        let output = Command::new("zsh")
+            .arg("-c")
+            .arg(format!("set -e\n{hook}"))
+            .current_dir(scratch_dir.path())
+            .env("MATCHA_PACKAGE_DIR", pkg_dir)
+            .env("MATCHA_WORKSPACE_BIN", self.bin_directory()?)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .output()
+            .await
+            .wrap_err("failed to spawn hook")?;
+        if !output.status.success() {
+            bail!(
+                "exited with code {}:\n{}",
+                output.status.code().unwrap_or(1),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
         Ok(())
     }
+
+    /// Returns the bin symlinks of this workspace whose targets no longer exist.
+    #[instrument]
+    pub async fn dangling_symlinks(&self) -> Result<Vec<PathBuf>> {
+        let mut dangling = vec![];
+        let mut bin_dir_reader = read_dir(self.bin_directory()?).await?;
+        while let Some(entry) = bin_dir_reader.next_entry().await? {
+            // `try_exists` follows the link, so it reports false if the target is gone.
+            if entry.metadata().await?.file_type().is_symlink() && !try_exists(entry.path()).await?
+            {
+                dangling.push(entry.path());
+            }
+        }
+        dangling.sort();
+        Ok(dangling)
+    }
 }
 
 impl Default for Workspace {
     fn default() -> Self {
         Self {
             name: String::from("default"),
+            parent: None,
         }
     }
 }
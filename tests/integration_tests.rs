@@ -1,5 +1,5 @@
 use std::{
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command as StdCommand, Output, Stdio},
 };
 
@@ -36,6 +36,15 @@ impl Default for TestSetup {
     }
 }
 
+/// Returns the directory a package is installed to, whichever registry it came from.
+fn installed_package_directory(setup: &TestSetup, name: &str, version: &str) -> PathBuf {
+    std::fs::read_dir(setup.package_root.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path().join(name).join(version))
+        .find(|dir| dir.symlink_metadata().is_ok())
+        .unwrap_or_else(|| setup.package_root.path().join(name).join(version))
+}
+
 /// Returns the path to the local test registry.
 fn local_test_registry() -> String {
     PathBuf::from(std::env!("CARGO_MANIFEST_DIR"))
@@ -46,13 +55,40 @@ fn local_test_registry() -> String {
         .to_string()
 }
 
+/// Returns the path to the second local test registry.
+fn other_test_registry() -> String {
+    PathBuf::from(std::env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("other_registry.toml")
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
 /// Runs a command with the provided test setup, returning the result.
 async fn run_test_command(setup: &TestSetup, args: &[&str]) -> Result<Output> {
+    run_test_command_in(setup, Path::new("."), args).await
+}
+
+/// Runs a command with the provided test setup in a directory, returning the result.
+async fn run_test_command_in(setup: &TestSetup, dir: &Path, args: &[&str]) -> Result<Output> {
     let mut cmd: Command = StdCommand::cargo_bin("matcha")?.into();
     cmd.args(args)
+        .current_dir(dir)
         .env("MATCHA_STATE_DB", &setup.state_db)
         .env("MATCHA_PACKAGE_ROOT", setup.package_root.path())
         .env("MATCHA_WORKSPACE_ROOT", setup.workspace_root.path())
+        .env("XDG_CONFIG_HOME", setup.config_dir.path())
+        .env("XDG_CACHE_HOME", setup.config_dir.path())
+        .env("HOME", setup.config_dir.path())
+        .env_remove("MATCHA_CONFIG")
+        .env_remove("MATCHA_CACHE_DIR")
+        .env_remove("MATCHA_USE_CACHE_ONLY")
+        .env_remove("MATCHA_USER_AGENT")
+        .env_remove("MATCHA_WORKSPACE")
+        .env_remove("MATCHA_BUILD_ROOT")
+        .env_remove("MATCHA_LOG")
+        .env_remove("MATCHA_NO_PROGRESS")
         .stdout(Stdio::piped())
         .stderr(Stdio::piped());
     let output = cmd.spawn()?.wait_with_output().await?;
@@ -72,6 +108,23 @@ async fn test_install_a_package() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_install_reports_resolved_version() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "test-package"]).await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr
+        .lines()
+        .any(|line| line == "Resolving test-package -> test-package@0.1.1"));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_install_two_packages() -> Result<()> {
     let setup = TestSetup::default();
@@ -102,7 +155,7 @@ async fn test_install_two_packages() -> Result<()> {
 }
 
 #[tokio::test]
-async fn test_cannot_install_two_different_versions() -> Result<()> {
+async fn test_install_different_version_replaces_pinned_one() -> Result<()> {
     let setup = TestSetup::default();
 
     let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
@@ -112,6 +165,35 @@ async fn test_cannot_install_two_different_versions() -> Result<()> {
     assert!(out.status.success());
 
     let out = run_test_command(&setup, &["package", "install", "test-package@0.1.1"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "test-package@0.1.1 (resolved from 0.1.1)\n"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cannot_install_two_different_versions_at_once() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "test-package@0.1.0",
+            "test-package@0.1.1",
+        ],
+    )
+    .await?;
     assert!(!out.status.success());
 
     let stderr = String::from_utf8(out.stderr)?;
@@ -171,12 +253,110 @@ async fn test_install_stricter_version() -> Result<()> {
     let out = run_test_command(&setup, &["package", "install", "test-package"]).await?;
     assert!(out.status.success());
 
-    let out = run_test_command(&setup, &["package", "install", "test-package@0.1.0"]).await?;
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--allow-downgrade",
+            "test-package@0.1.0",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "test-package"]).await?;
+    assert!(out.status.success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_downgrade_requires_flag() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
     assert!(out.status.success());
 
     let out = run_test_command(&setup, &["package", "install", "test-package"]).await?;
     assert!(out.status.success());
 
+    let out = run_test_command(&setup, &["package", "install", "test-package@0.1.0"]).await?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("downgrading test-package from 0.1.1 to 0.1.0"));
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "test-package@0.1.1 (resolved from *)\n"
+    );
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--allow-downgrade",
+            "test-package@0.1.0",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("Warning: downgrading test-package from 0.1.1 to 0.1.0"));
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "test-package@0.1.0 (resolved from 0.1.0)\n"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_changing_version_keeps_old_version_if_build_failed() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("foo.toml");
+    std::fs::write(
+        &registry,
+        r#"schema_version = 1
+name = "foo"
+
+[[packages]]
+name = "foo"
+version = "1.0.0"
+build = "mkdir $MATCHA_OUTPUT/bin && printf '#!/bin/sh\n' > $MATCHA_OUTPUT/bin/foo && chmod +x $MATCHA_OUTPUT/bin/foo"
+
+[[packages]]
+name = "foo"
+version = "2.0.0"
+build = "exit 1"
+"#,
+    )?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "foo@1.0.0"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "foo@2.0.0"]).await?;
+    assert!(!out.status.success());
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "foo@1.0.0 (resolved from 1.0.0)\n"
+    );
+    assert!(setup
+        .workspace_root
+        .path()
+        .join("global")
+        .join("bin")
+        .join("foo")
+        .exists());
+
     Ok(())
 }
 
@@ -215,6 +395,31 @@ async fn test_list_installed_packages() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_list_installed_packages_long() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "test-package"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "list", "--long"]).await?;
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout)?;
+    assert_eq!(
+        stdout,
+        format!(
+            "test-package@0.1.1 (resolved from *) from {}\n",
+            &local_test_registry()
+        )
+    );
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_list_installed_packages_empty() -> Result<()> {
     let setup = TestSetup::default();
@@ -253,6 +458,35 @@ async fn test_show_package() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_show_package_versions() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "show", "--versions", "test-package"]).await?;
+    assert!(out.status.success());
+    let registry = local_test_registry();
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        format!("test-package@0.1.1 from {registry}\ntest-package@0.1.0 from {registry}\n")
+    );
+
+    let out = run_test_command(
+        &setup,
+        &["package", "show", "--versions", "test-package@~0.1.0"],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        format!("test-package@0.1.0 from {registry}\n")
+    );
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_show_unknown_package() -> Result<()> {
     let setup = TestSetup::default();
@@ -314,6 +548,43 @@ async fn test_list_registries() -> Result<()> {
     let stdout = String::from_utf8(out.stdout)?;
     assert_eq!(stdout, format!("{} (test)\n", &local_test_registry()));
 
+    let out = run_test_command(&setup, &["registry", "list", "--long"]).await?;
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout)?;
+    assert_eq!(
+        stdout,
+        format!("{} (test), fetched just now\n", &local_test_registry())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_registries_as_json() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("foo.toml");
+    let mut manifest = String::from("schema_version = 1\nname = \"foo\"\n");
+    for version in ["1.0.0", "1.1.0"] {
+        manifest.push_str(&format!(
+            "\n[[packages]]\nname = \"foo\"\nversion = \"{version}\"\nbuild = \"true\"\n"
+        ));
+    }
+    std::fs::write(&registry, manifest)?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["registry", "list", "--format", "json"]).await?;
+    assert!(out.status.success());
+    let json: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+    let registries = json.as_array().unwrap();
+    assert_eq!(registries.len(), 1);
+    assert_eq!(registries[0]["uri"], registry.to_str().unwrap());
+    assert_eq!(registries[0]["name"], "foo");
+    assert!(registries[0]["last_fetched"].is_string());
+    assert_eq!(registries[0]["package_count"], 2);
+
     Ok(())
 }
 
@@ -484,6 +755,57 @@ async fn test_install_different_version_in_workspace() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_list_all_workspaces() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["workspace", "add", "test-workspace"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "test-package@0.1.0"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "another-package",
+            "--workspace",
+            "test-workspace",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "list", "--workspace", "all"]).await?;
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout)?;
+    assert_eq!(
+        stdout,
+        "global:\n  test-package@0.1.0 (resolved from 0.1.0)\ntest-workspace:\n  another-package@0.2.0 (resolved from *)\n"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cannot_add_workspace_named_all() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["workspace", "add", "all"]).await?;
+    assert!(!out.status.success());
+
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("all is a reserved name"));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_remove_workspace() -> Result<()> {
     let setup = TestSetup::default();
@@ -551,22 +873,2453 @@ async fn test_garbage_collect_installed_packages() -> Result<()> {
     let out = run_test_command(&setup, &["package", "remove", "package-with-artifact"]).await?;
     assert!(out.status.success());
 
-    assert!(setup
-        .package_root
-        .path()
-        .join("package-with-artifact")
-        .join("0.1.0")
-        .try_exists()?);
+    assert!(installed_package_directory(&setup, "package-with-artifact", "0.1.0").try_exists()?);
 
     let out = run_test_command(&setup, &["package", "garbage-collect"]).await?;
     assert!(out.status.success());
 
-    assert!(!setup
-        .package_root
-        .path()
-        .join("package-with-artifact")
-        .join("0.1.0")
-        .try_exists()?);
+    assert!(!installed_package_directory(&setup, "package-with-artifact", "0.1.0").try_exists()?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_doctor_on_healthy_setup() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "package-with-artifact"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["doctor"]).await?;
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("[pass] package package-with-artifact@0.1.0 is installed"));
+    assert!(stdout.contains(&format!(
+        "[pass] registry {} (test) is reachable",
+        &local_test_registry()
+    )));
+    assert!(!stdout.contains("[fail]"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_doctor_fails_on_missing_package_directory() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "package-with-artifact"]).await?;
+    assert!(out.status.success());
+
+    std::fs::remove_dir_all(installed_package_directory(
+        &setup,
+        "package-with-artifact",
+        "0.1.0",
+    ))?;
+
+    let out = run_test_command(&setup, &["doctor"]).await?;
+    assert!(!out.status.success());
+
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("[fail] package package-with-artifact@0.1.0 is missing"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_doctor_json_format() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "package-with-artifact"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["doctor", "--format", "json"]).await?;
+    assert!(out.status.success());
+    let output: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+    let checks = output["checks"].as_array().unwrap();
+    assert!(checks.iter().all(|check| check["status"] != "fail"));
+    assert!(checks.iter().any(|check| {
+        check["name"] == "package package-with-artifact@0.1.0"
+            && check["status"] == "pass"
+            && check["detail"] == "package package-with-artifact@0.1.0 is installed"
+    }));
+
+    std::fs::remove_dir_all(installed_package_directory(
+        &setup,
+        "package-with-artifact",
+        "0.1.0",
+    ))?;
+
+    // Failures still fail the command, even when the output is JSON.
+    let out = run_test_command(&setup, &["doctor", "--format", "json"]).await?;
+    assert!(!out.status.success());
+    let output: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+    assert!(output["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|check| check["status"] == "fail"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_workspace_env() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["workspace", "add", "test-workspace"]).await?;
+    assert!(out.status.success());
+
+    let bin_dir = setup
+        .workspace_root
+        .path()
+        .join("test-workspace")
+        .join("bin");
+
+    let out = run_test_command(&setup, &["workspace", "env", "test-workspace"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains(&format!("export PATH='{}':\"$PATH\"", bin_dir.display())));
+    assert!(stdout.contains("export MATCHA_WORKSPACE='test-workspace'"));
+
+    let out = run_test_command(
+        &setup,
+        &["workspace", "env", "--shell", "fish", "test-workspace"],
+    )
+    .await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains(&format!("set -x PATH '{}' $PATH", bin_dir.display())));
+    assert!(stdout.contains("set -x MATCHA_WORKSPACE 'test-workspace'"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verify_workspace_finds_and_fixes_broken_links() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "package-with-binary"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["workspace", "verify", "global"]).await?;
+    assert!(out.status.success());
+
+    std::fs::remove_dir_all(installed_package_directory(
+        &setup,
+        "package-with-binary",
+        "0.1.0",
+    ))?;
+    let link = setup
+        .workspace_root
+        .path()
+        .join("global")
+        .join("bin")
+        .join("package-with-binary");
+
+    let out = run_test_command(&setup, &["workspace", "verify", "global"]).await?;
+    assert!(!out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains(&format!("Broken link {}", link.display())));
+
+    let out = run_test_command(&setup, &["workspace", "verify", "--fix", "global"]).await?;
+    assert!(out.status.success());
+    assert!(link.symlink_metadata().is_err());
+
+    let out = run_test_command(&setup, &["workspace", "verify", "global"]).await?;
+    assert!(out.status.success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_garbage_collect_keeps_shared_store_entries() -> Result<()> {
+    let setup = TestSetup::default();
+    let store = setup.package_root.path().join(".store");
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    // Neither package has any build outputs, so they share a store entry.
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "test-package", "another-package"],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert_eq!(std::fs::read_dir(&store)?.count(), 1);
+
+    let out = run_test_command(&setup, &["package", "remove", "test-package"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "gc"]).await?;
+    assert!(out.status.success());
+    assert_eq!(std::fs::read_dir(&store)?.count(), 1);
+    assert!(installed_package_directory(&setup, "another-package", "0.2.0").try_exists()?);
+
+    let out = run_test_command(&setup, &["package", "remove", "another-package"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "gc"]).await?;
+    assert!(out.status.success());
+    assert_eq!(std::fs::read_dir(&store)?.count(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_default_paths_respect_xdg_base_directories() -> Result<()> {
+    let state_home = TempDir::new()?;
+    let data_home = TempDir::new()?;
+
+    let mut cmd: Command = StdCommand::cargo_bin("matcha")?.into();
+    cmd.args(["workspace", "add", "test-workspace"])
+        .env_remove("MATCHA_STATE_DB")
+        .env_remove("MATCHA_PACKAGE_ROOT")
+        .env_remove("MATCHA_WORKSPACE_ROOT")
+        .env("XDG_STATE_HOME", state_home.path())
+        .env("XDG_DATA_HOME", data_home.path())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let out = cmd.spawn()?.wait_with_output().await?;
+    assert!(out.status.success());
+
+    assert!(state_home.path().join("matcha").join("state.db").exists());
+    assert!(data_home
+        .path()
+        .join("matcha")
+        .join("workspaces")
+        .join("test-workspace")
+        .exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reinstall_package() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "package-with-artifact"]).await?;
+    assert!(out.status.success());
+
+    let artifact =
+        installed_package_directory(&setup, "package-with-artifact", "0.1.0").join("artifact.txt");
+    assert!(artifact.exists());
+    std::fs::remove_file(&artifact)?;
+
+    let out = run_test_command(&setup, &["package", "reinstall", "package-with-artifact"]).await?;
+    assert!(out.status.success());
+    assert_eq!(std::fs::read_to_string(&artifact)?, "artifact\n");
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("package-with-artifact@0.1.0"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reinstall_packages_keeps_registry_and_link_mode() -> Result<()> {
+    let setup = TestSetup::default();
+    let first = setup.config_dir.path().join("first.toml");
+    std::fs::write(
+        &first,
+        "schema_version = 1\nname = \"first\"\n\n[[packages]]\nname = \"dup\"\nversion = \"3.0.0\"\nbuild = \"echo first\"\n",
+    )?;
+    let second = setup.config_dir.path().join("second.toml");
+    std::fs::write(
+        &second,
+        "schema_version = 1\nname = \"second\"\n\n[[packages]]\nname = \"dup\"\nversion = \"2.0.0\"\nbuild = \"echo second\"\n\n[[packages]]\nname = \"tool\"\nversion = \"1.0.0\"\nbuild = \"mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/tool\"\n",
+    )?;
+    let (first, second) = (first.to_str().unwrap(), second.to_str().unwrap());
+    for registry in [first, second] {
+        let out = run_test_command(&setup, &["registry", "add", registry]).await?;
+        assert!(out.status.success());
+    }
+
+    let out = run_test_command(&setup, &["package", "install", &format!("{second}::dup")]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "--link-mode", "copy", "tool"],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "reinstall", "dup", "tool"]).await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert_eq!(stderr.matches("Installed 2 package(s)").count(), 1);
+
+    let out = run_test_command(&setup, &["package", "list", "--long"]).await?;
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains(&format!("dup@2.0.0 (resolved from *) from {second}")));
+    let binary = setup
+        .workspace_root
+        .path()
+        .join("global")
+        .join("bin")
+        .join("tool");
+    assert!(std::fs::symlink_metadata(binary)?.is_file());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exit_code_for_unknown_package() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "no-such-package"]).await?;
+    assert_eq!(out.status.code(), Some(2));
+
+    let out = run_test_command(&setup, &["package", "install", "test-package@9.9.9"]).await?;
+    assert_eq!(out.status.code(), Some(2));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exit_code_for_version_conflict() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "test-package@0.1.0",
+            "test-package@0.1.1",
+        ],
+    )
+    .await?;
+    assert_eq!(out.status.code(), Some(3));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_exit_code_for_failed_build() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "failing-build"]).await?;
+    assert_eq!(out.status.code(), Some(4));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_registry_fetches_packages() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("with 7 packages"));
+
+    let out = run_test_command(&setup, &["package", "show", "test-package@0.1.0"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("test-package@0.1.0"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_search_and_install_restricted_to_registry() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["registry", "add", &other_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "search", "package"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("test-package"));
+    assert!(stdout.contains("other-package"));
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "search",
+            "--registry",
+            &other_test_registry(),
+            "package",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(!stdout.contains("test-package"));
+    assert!(stdout.contains("other-package"));
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--registry",
+            &other_test_registry(),
+            "test-package",
+        ],
+    )
+    .await?;
+    assert!(!out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--registry",
+            &other_test_registry(),
+            "other-package",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_serially() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("foo.toml");
+    let mut manifest = String::from("schema_version = 1\nname = \"foo\"\n");
+    for name in ["foo", "bar"] {
+        manifest.push_str(&format!(
+            "\n[[packages]]\nname = \"{name}\"\nversion = \"1.0.0\"\nbuild = \"echo building {name}\"\n"
+        ));
+    }
+    std::fs::write(&registry, manifest)?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "--serial", "foo", "bar"]).await?;
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout)?;
+    let lines = stdout
+        .lines()
+        .filter(|line| line.starts_with("Installed") || line.starts_with("building"))
+        .map(|line| line.split(" in ").next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        [
+            "building bar",
+            "Installed bar@1.0.0",
+            "building foo",
+            "Installed foo@1.0.0"
+        ]
+    );
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "bar@1.0.0 (resolved from *)\nfoo@1.0.0 (resolved from *)\n"
+    );
+
+    // Installing from a manifest file works serially as well.
+    let manifest = setup.config_dir.path().join("bar.toml");
+    std::fs::write(
+        &manifest,
+        std::fs::read_to_string(&registry)?.replace("1.0.0", "2.0.0"),
+    )?;
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "-w",
+            "other",
+            "--serial",
+            "--from",
+            manifest.to_str().unwrap(),
+            "foo",
+            "bar",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.find("building bar").unwrap() < stdout.find("Installed bar").unwrap());
+    assert!(stdout.find("Installed bar").unwrap() < stdout.find("building foo").unwrap());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_build_dependencies_are_not_added_to_workspace() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("foo.toml");
+    std::fs::write(
+        &registry,
+        r#"schema_version = 1
+name = "foo"
+
+[[packages]]
+name = "tool"
+version = "1.0.0"
+build = "mkdir $MATCHA_OUTPUT/bin && printf '#!/bin/sh\necho generated\n' > $MATCHA_OUTPUT/bin/tool && chmod +x $MATCHA_OUTPUT/bin/tool"
+
+[[packages]]
+name = "app"
+version = "1.0.0"
+build_dependencies = ["tool"]
+build = "mkdir $MATCHA_OUTPUT/bin && tool > $MATCHA_OUTPUT/bin/app"
+"#,
+    )?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "app"]).await?;
+    assert!(out.status.success());
+
+    let bin = setup.workspace_root.path().join("global").join("bin");
+    assert_eq!(std::fs::read_to_string(bin.join("app"))?, "generated\n");
+    assert!(!bin.join("tool").exists());
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "app@1.0.0 (resolved from *)\n"
+    );
+
+    // Build dependencies are kept for as long as the packages built with them.
+    let out = run_test_command(&setup, &["package", "gc"]).await?;
+    assert!(out.status.success());
+    assert!(installed_package_directory(&setup, "tool", "1.0.0").exists());
+    let out = run_test_command(&setup, &["package", "remove", "app"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "gc"]).await?;
+    assert!(out.status.success());
+    assert!(!installed_package_directory(&setup, "tool", "1.0.0").exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_search_installed_packages() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("foo.toml");
+    let mut manifest = String::from("schema_version = 1\nname = \"foo\"\n");
+    for name in ["foo", "bar", "foobar"] {
+        manifest.push_str(&format!(
+            "\n[[packages]]\nname = \"{name}\"\nversion = \"1.0.0\"\nbuild = \"true\"\n"
+        ));
+    }
+    std::fs::write(&registry, manifest)?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "foo", "bar"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "search", "foo", "--installed"]).await?;
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "foo@1.0.0 (resolved from *)\n"
+    );
+
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &["package", "search", "foo", "--installed", "-w", "other"],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout)?, "");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_search_unknown_registry_fails() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "search",
+            "--registry",
+            &other_test_registry(),
+            "package",
+        ],
+    )
+    .await?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("is not configured"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_keep_build_directory_of_failed_build() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "--keep-build", "failing-build"],
+    )
+    .await?;
+    assert!(!out.status.success());
+
+    let stdout = String::from_utf8(out.stdout)?;
+    let build_dir = stdout
+        .lines()
+        .find_map(|l| l.strip_prefix("Kept build directory at "))
+        .expect("build directory not reported");
+    let build_dir = PathBuf::from(build_dir);
+    assert!(build_dir.is_dir());
+    std::fs::remove_dir_all(build_dir)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_virtual_package() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &other_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "other-tool"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("other-package@1.0.0"));
+    assert!(!stdout.contains("other-tool"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_summary_is_ordered() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "test-package",
+            "package-with-artifact",
+            "another-package",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout)?;
+    let installed = stdout
+        .lines()
+        .filter_map(|l| l.strip_prefix("Installed "))
+        .map(|l| l.split_once(' ').map_or(l, |(pkg, _)| pkg))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        installed,
+        vec![
+            "another-package@0.2.0",
+            "package-with-artifact@0.1.0",
+            "test-package@0.1.1"
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_workspace_scoped_registry() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["workspace", "add", "project"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "registry",
+            "add",
+            "--workspace",
+            "project",
+            &other_test_registry(),
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["registry", "list"]).await?;
+    let stdout = String::from_utf8(out.stdout)?;
+    assert_eq!(
+        stdout,
+        format!("{} (other) in workspace project\n", &other_test_registry())
+    );
+
+    // The registry is invisible from other workspaces.
+    let out = run_test_command(&setup, &["package", "install", "other-package"]).await?;
+    assert!(!out.status.success());
+    assert_eq!(out.status.code(), Some(2));
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--workspace",
+            "project",
+            "other-package",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    // The workspace can't be removed while the registry is restricted to it.
+    let out = run_test_command(&setup, &["workspace", "remove", "project"]).await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("has registries restricted to it"));
+    let out = run_test_command(&setup, &["registry", "remove", &other_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["workspace", "remove", "project"]).await?;
+    assert!(out.status.success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_registry_to_unknown_workspace_fails() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "registry",
+            "add",
+            "--workspace",
+            "nope",
+            &other_test_registry(),
+        ],
+    )
+    .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("workspace nope does not exist"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_state_backup_and_restore() -> Result<()> {
+    let setup = TestSetup::default();
+    let backup = setup.config_dir.path().join("backup.db");
+    let backup = backup.to_str().unwrap();
+
+    let out = run_test_command(&setup, &["state", "backup", backup]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["workspace", "add", "after-backup"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["state", "restore", backup]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["workspace", "list"]).await?;
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(!stdout.contains("after-backup"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_verbose_flag_shows_debug_output() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    assert!(!String::from_utf8(out.stderr)?.contains("DEBUG"));
+
+    let out = run_test_command(&setup, &["package", "install", "-vv", "test-package"]).await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("DEBUG"));
+    assert!(stderr.contains("install_package"));
+    assert!(!String::from_utf8(out.stdout)?.contains("DEBUG"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_mixed_case_request() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "Test-Package"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    let stdout = String::from_utf8(out.stdout)?;
+    assert_eq!(stdout, "test-package@0.1.1 (resolved from *)\n");
+
+    // Any spelling refers to the same installed package.
+    let out = run_test_command(&setup, &["package", "install", "TEST-package"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "reinstall", "Test-Package"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(String::from_utf8(out.stdout)?, stdout);
+
+    let out = run_test_command(&setup, &["package", "remove", "test-PACKAGE"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert!(String::from_utf8(out.stdout)?.is_empty());
+
+    // Installing it again after removing it is no different from the first time.
+    let out = run_test_command(&setup, &["package", "install", "Test-Package"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(String::from_utf8(out.stdout)?, stdout);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_with_build_override() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "test-package",
+            "another-package",
+            "--build-override",
+            "true",
+        ],
+    )
+    .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("single package"));
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "failing-build",
+            "--build-override",
+            "echo sentinel > $MATCHA_OUTPUT/sentinel.txt",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("won't match the registry"));
+    assert!(
+        installed_package_directory(&setup, "failing-build", "0.1.0")
+            .join("sentinel.txt")
+            .exists()
+    );
+
+    // The overridden build doesn't stand in for the registry's in other workspaces.
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "-w", "other", "failing-build"],
+    )
+    .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("overridden build command"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_package_hooks() -> Result<()> {
+    let setup = TestSetup::default();
+    let marker = setup
+        .workspace_root
+        .path()
+        .join("global")
+        .join("hooks-marker");
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "package-with-hooks"]).await?;
+    assert!(out.status.success());
+    assert!(marker.exists());
+
+    let out = run_test_command(&setup, &["package", "remove", "package-with-hooks"]).await?;
+    assert!(out.status.success());
+    assert!(!marker.exists());
+
+    // Removing a workspace removes its packages, running their pre-remove hooks.
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "-w", "other", "package-with-hooks"],
+    )
+    .await?;
+    assert!(out.status.success());
+    let other_marker = setup
+        .workspace_root
+        .path()
+        .join("other")
+        .join("hooks-marker");
+    assert!(other_marker.exists());
+    let out = run_test_command(&setup, &["workspace", "remove", "other"]).await?;
+    assert!(out.status.success());
+    assert!(!other_marker.exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_post_install_hook_runs_outside_package_directory() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("hooks.toml");
+    std::fs::write(
+        &registry,
+        r#"
+            schema_version = 1
+            name = "hooks"
+
+            [[packages]]
+            name = "scribbling-hook"
+            version = "0.1.0"
+            build = "mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/scribbling-hook"
+            post_install = "touch scribble"
+
+            [[packages]]
+            name = "failing-hook"
+            version = "0.1.0"
+            build = "mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/failing-hook"
+            post_install = "exit 1"
+        "#,
+    )?;
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "scribbling-hook"]).await?;
+    assert!(out.status.success());
+    assert!(
+        !installed_package_directory(&setup, "scribbling-hook", "0.1.0")
+            .join("scribble")
+            .exists()
+    );
+
+    // A failed hook doesn't leave the package's binaries behind.
+    let out = run_test_command(&setup, &["package", "install", "failing-hook"]).await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("post-install hook"));
+    let bin = setup.workspace_root.path().join("global").join("bin");
+    assert!(bin.join("scribbling-hook").exists());
+    assert!(!bin.join("failing-hook").exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_from_manifest_file_changes_versions() -> Result<()> {
+    let setup = TestSetup::default();
+    let manifest = setup.config_dir.path().join("local.toml");
+    let mut contents = String::from("schema_version = 1\nname = \"local\"\n");
+    for version in ["1.0.0", "2.0.0"] {
+        contents.push_str(&format!(
+            "\n[[packages]]\nname = \"foo\"\nversion = \"{version}\"\nbuild = \"mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/foo\"\n"
+        ));
+    }
+    std::fs::write(&manifest, contents)?;
+    let manifest = manifest.to_str().unwrap();
+
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "--from", manifest, "foo@1.0.0"],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    // Re-pinning an installed package replaces it.
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "--from", manifest, "foo@2.0.0"],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("Installed 1 package(s)"));
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "foo@2.0.0 (resolved from 2.0.0)\n"
+    );
+
+    // Going back needs confirmation.
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "--from", manifest, "foo@1.0.0"],
+    )
+    .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("pass --allow-downgrade to proceed"));
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--allow-downgrade",
+            "--from",
+            manifest,
+            "foo@1.0.0",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "foo@1.0.0 (resolved from 1.0.0)\n"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_from_manifest_file() -> Result<()> {
+    let setup = TestSetup::default();
+    let manifest = setup.config_dir.path().join("local.toml");
+    std::fs::write(
+        &manifest,
+        r#"
+            schema_version = 1
+            name = "local"
+
+            [[packages]]
+            name = "local-package"
+            version = "0.1.0"
+            build = "mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/local-package"
+        "#,
+    )?;
+    let manifest = manifest.to_str().unwrap();
+
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "--from", manifest, "missing-package"],
+    )
+    .await?;
+    assert_eq!(out.status.code(), Some(2));
+
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "--from", manifest, "local-package"],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert!(setup
+        .workspace_root
+        .path()
+        .join("global")
+        .join("bin")
+        .join("local-package")
+        .exists());
+
+    let out = run_test_command(&setup, &["package", "list", "--long"]).await?;
+    let stdout = String::from_utf8(out.stdout)?;
+    assert_eq!(
+        stdout,
+        format!("local-package@0.1.0 (resolved from *) from {manifest}\n")
+    );
+
+    // The manifest was not added as a registry.
+    let out = run_test_command(&setup, &["registry", "list"]).await?;
+    assert!(String::from_utf8(out.stdout)?.is_empty());
+
+    // A registry's package of the same name and version doesn't reuse the manifest's build.
+    let registry = setup.config_dir.path().join("registry.toml");
+    std::fs::write(
+        &registry,
+        r#"
+            schema_version = 1
+            name = "registry"
+
+            [[packages]]
+            name = "local-package"
+            version = "0.1.0"
+            build = "mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/registry-package"
+        "#,
+    )?;
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "-w", "other", "local-package"],
+    )
+    .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("collides"));
+    assert!(!setup
+        .workspace_root
+        .path()
+        .join("other")
+        .join("bin")
+        .join("local-package")
+        .exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_which_binary() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "package-with-binary"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "which", "package-with-binary"]).await?;
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "package-with-binary -> package-with-binary@0.1.0 (global)\n"
+    );
+
+    let out = run_test_command(&setup, &["package", "which", "missing-binary"]).await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("missing-binary not found"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_package_directory_is_scoped_by_registry() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "package-with-binary"]).await?;
+    assert!(out.status.success());
+
+    let registry_dirs = std::fs::read_dir(setup.package_root.path())?
+        .map(|entry| entry.unwrap().path())
+        // Skip the content store and the lock file.
+        .filter(|path| !path.file_name().unwrap().to_string_lossy().starts_with('.'))
+        .collect::<Vec<_>>();
+    assert_eq!(registry_dirs.len(), 1);
+    let registry_dir = registry_dirs[0].file_name().unwrap().to_str().unwrap();
+    assert_ne!(registry_dir, "package-with-binary");
+    assert!(registry_dir.chars().all(|c| c.is_ascii_hexdigit()));
+    assert!(registry_dirs[0]
+        .join("package-with-binary")
+        .join("0.1.0")
+        .join("bin")
+        .join("package-with-binary")
+        .exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_installed_packages_move_with_registry() -> Result<()> {
+    let setup = TestSetup::default();
+    let old = setup.config_dir.path().join("old.toml");
+    let new = setup.config_dir.path().join("new.toml");
+    std::fs::copy(local_test_registry(), &old)?;
+    std::fs::copy(local_test_registry(), &new)?;
+
+    let out = run_test_command(&setup, &["registry", "add", old.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "package-with-binary"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "registry",
+            "set-uri",
+            old.to_str().unwrap(),
+            new.to_str().unwrap(),
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let bin = setup.workspace_root.path().join("global").join("bin");
+    assert!(bin.join("package-with-binary").exists());
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "-w", "other", "package-with-binary"],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert!(!String::from_utf8(out.stderr)?.contains("corrupted"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_refuses_same_package_from_another_registry() -> Result<()> {
+    let setup = TestSetup::default();
+    let manifest = setup.config_dir.path().join("manifest.toml");
+    std::fs::copy(local_test_registry(), &manifest)?;
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "package-with-binary"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "-w",
+            "other",
+            "--from",
+            manifest.to_str().unwrap(),
+            "package-with-binary",
+        ],
+    )
+    .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("collides with the one installed from"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_registries_from_config() -> Result<()> {
+    let setup = TestSetup::default();
+    let config = setup.config_dir.path().join("registries.toml");
+    std::fs::write(
+        &config,
+        format!("registries = [{:?}]\n", local_test_registry()),
+    )?;
+    let config = config.to_str().unwrap();
+
+    // Only commands that resolve packages add them.
+    let out = run_test_command(&setup, &["--config", config, "registry", "list"]).await?;
+    assert!(out.status.success());
+    assert!(!String::from_utf8(out.stdout)?.contains(&local_test_registry()));
+
+    let out = run_test_command(
+        &setup,
+        &["--config", config, "package", "search", "test-package"],
+    )
+    .await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["--config", config, "registry", "list"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains(&local_test_registry()));
+
+    // Registries that are already present are left alone.
+    let out = run_test_command(
+        &setup,
+        &["--config", config, "package", "search", "test-package"],
+    )
+    .await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["--config", config, "registry", "list"]).await?;
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout)?, stdout);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unavailable_registry_from_config_warns() -> Result<()> {
+    let setup = TestSetup::default();
+    let missing = setup.config_dir.path().join("missing.toml");
+    let config = setup.config_dir.path().join("registries.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "registries = [{:?}, {:?}]\n",
+            missing.to_str().unwrap(),
+            local_test_registry()
+        ),
+    )?;
+    let config = config.to_str().unwrap();
+
+    let out = run_test_command(
+        &setup,
+        &["--config", config, "package", "install", "test-package"],
+    )
+    .await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("Warning: failed to add registry"));
+    assert!(stderr.contains(missing.to_str().unwrap()));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_missing_explicit_config_fails() -> Result<()> {
+    let setup = TestSetup::default();
+    let config = setup.config_dir.path().join("missing.toml");
+
+    let out = run_test_command(
+        &setup,
+        &["--config", config.to_str().unwrap(), "registry", "list"],
+    )
+    .await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("failed to read config"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_workspaces_with_counts() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["workspace", "add", "empty"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "test-package", "another-package"],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["workspace", "list", "--with-counts"]).await?;
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "empty (0 packages)\nglobal (2 packages)\n"
+    );
+
+    let out = run_test_command(&setup, &["workspace", "list"]).await?;
+    assert_eq!(String::from_utf8(out.stdout)?, "empty\nglobal\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_workspace_inherits_parent_packages() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["workspace", "add", "base"]).await?;
+    assert!(out.status.success());
+    let out =
+        run_test_command(&setup, &["workspace", "add", "project", "--parent", "base"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--workspace",
+            "base",
+            "test-package@0.1.0",
+            "another-package",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--workspace",
+            "project",
+            "test-package",
+            "package-with-binary",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    // The child's own version of a package overrides the inherited one.
+    let out = run_test_command(&setup, &["package", "list", "--workspace", "project"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    let mut lines = stdout.lines().collect::<Vec<_>>();
+    lines.sort();
+    assert_eq!(
+        lines,
+        vec![
+            "another-package@0.2.0 (resolved from *) [inherited from base]",
+            "package-with-binary@0.1.0 (resolved from *)",
+            "test-package@0.1.1 (resolved from *)",
+        ]
+    );
+
+    let out = run_test_command(&setup, &["workspace", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "base\nglobal\nproject (inherits from base)\n"
+    );
+
+    let out = run_test_command(
+        &setup,
+        &["workspace", "add", "orphan", "--parent", "missing"],
+    )
+    .await?;
+    assert!(!out.status.success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prefix_overrides_package_root() -> Result<()> {
+    let setup = TestSetup::default();
+    let prefix = TempDir::new()?;
+    let workspace_root = TempDir::new()?;
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "package-with-binary",
+            "--prefix",
+            prefix.path().to_str().unwrap(),
+            "--workspace-root",
+            workspace_root.path().to_str().unwrap(),
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    assert!(prefix.path().join(".store").exists());
+    assert!(!setup.package_root.path().join(".store").exists());
+    assert!(workspace_root
+        .path()
+        .join("global")
+        .join("bin")
+        .join("package-with-binary")
+        .exists());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conflicting_binaries() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("tools.toml");
+    std::fs::write(
+        &registry,
+        r#"
+            schema_version = 1
+            name = "tools"
+
+            [[packages]]
+            name = "tool-a"
+            version = "1.0.0"
+            build = "mkdir $MATCHA_OUTPUT/bin && echo a > $MATCHA_OUTPUT/bin/tool"
+
+            [[packages]]
+            name = "tool-b"
+            version = "1.0.0"
+            build = "mkdir $MATCHA_OUTPUT/bin && echo b > $MATCHA_OUTPUT/bin/tool"
+        "#,
+    )?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "tool-a"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "install", "tool-b"]).await?;
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("binary 'tool' conflicts with package tool-a@1.0.0"));
+
+    let out = run_test_command(&setup, &["package", "which", "tool"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "tool -> tool-a@1.0.0 (global)\n"
+    );
+
+    let out = run_test_command(&setup, &["package", "install", "--force", "tool-b"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "which", "tool"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "tool -> tool-b@1.0.0 (global)\n"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_update_changes_requested_version() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("foo.toml");
+    let mut manifest = String::from("schema_version = 1\nname = \"foo\"\n");
+    for version in ["0.1.0", "0.1.1", "0.2.0", "0.2.1"] {
+        manifest.push_str(&format!(
+            "\n[[packages]]\nname = \"foo\"\nversion = \"{version}\"\nbuild = \"mkdir $MATCHA_OUTPUT/bin && echo {version} > $MATCHA_OUTPUT/bin/foo\"\n"
+        ));
+    }
+    std::fs::write(&registry, manifest)?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "foo@~0.1"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "foo@0.1.1 (resolved from ~0.1)\n"
+    );
+
+    let out = run_test_command(&setup, &["package", "update", "foo@~0.2"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "foo@0.2.1 (resolved from ~0.2)\n"
+    );
+    let out = run_test_command(&setup, &["package", "which", "foo"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "foo -> foo@0.2.1 (global)\n"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_update_changelog() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("foo.toml");
+    let package = |name: &str, version: &str| {
+        format!("\n[[packages]]\nname = \"{name}\"\nversion = \"{version}\"\nbuild = \"true\"\n")
+    };
+    let manifest = format!(
+        "schema_version = 1\nname = \"foo\"\n{}{}",
+        package("foo", "1.0.0"),
+        package("bar", "1.0.0")
+    );
+    std::fs::write(&registry, &manifest)?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "foo", "bar"]).await?;
+    assert!(out.status.success());
+
+    std::fs::write(&registry, manifest + &package("foo", "1.1.0"))?;
+    let out = run_test_command(&setup, &["package", "update", "--changelog"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("foo: 1.0.0 -> 1.1.0\n"));
+    assert!(!stdout.contains("bar:"));
+
+    let out = run_test_command(&setup, &["package", "update", "--changelog"]).await?;
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout)?.contains("No packages changed\n"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_refresh_registry() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("foo.toml");
+    let package = |version: &str| {
+        format!("\n[[packages]]\nname = \"foo\"\nversion = \"{version}\"\nbuild = \"true\"\n")
+    };
+    let manifest = format!("schema_version = 1\nname = \"foo\"\n{}", package("1.0.0"));
+    std::fs::write(&registry, &manifest)?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+
+    std::fs::write(&registry, manifest + &package("1.1.0"))?;
+    let out =
+        run_test_command(&setup, &["registry", "refresh", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("1 package(s) added, 0 removed"));
+
+    let out = run_test_command(&setup, &["package", "search", "--all-versions", "foo"]).await?;
+    assert!(String::from_utf8(out.stdout)?.contains("foo@1.1.0"));
+
+    let out = run_test_command(
+        &setup,
+        &["registry", "refresh", "https://example.invalid/registry"],
+    )
+    .await?;
+    assert!(!out.status.success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_registry_fetch_dry_run() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("foo.toml");
+    let package = |version: &str| {
+        format!("\n[[packages]]\nname = \"foo\"\nversion = \"{version}\"\nbuild = \"true\"\n")
+    };
+    let manifest = format!("schema_version = 1\nname = \"foo\"\n{}", package("1.0.0"));
+    std::fs::write(&registry, &manifest)?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["registry", "fetch", "--dry-run"]).await?;
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout)?.contains("no changes"));
+
+    std::fs::write(&registry, manifest + &package("1.1.0"))?;
+    let out = run_test_command(&setup, &["registry", "fetch", "--dry-run"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("1 package(s) added, 0 removed, 0 updated"));
+    assert!(stdout.contains("  + foo@1.1.0"));
+
+    // Nothing was persisted, so the same changes are reported again.
+    let out = run_test_command(&setup, &["registry", "fetch", "--dry-run"]).await?;
+    assert!(String::from_utf8(out.stdout)?.contains("  + foo@1.1.0"));
+
+    // Versions that would be pruned right away are not reported as added, but as removed.
+    let out = run_test_command(
+        &setup,
+        &["registry", "fetch", "--dry-run", "--keep-versions", "1"],
+    )
+    .await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains("1 package(s) added, 1 removed, 0 updated"));
+    assert!(stdout.contains("  + foo@1.1.0"));
+    assert!(stdout.contains("  - foo@1.0.0"));
+
+    // Configured registries are not added by a dry run.
+    let other = setup.config_dir.path().join("other.toml");
+    std::fs::write(&other, "schema_version = 1\nname = \"other\"\n")?;
+    let config = setup.config_dir.path().join("registries.toml");
+    std::fs::write(
+        &config,
+        format!("registries = [{:?}]\n", other.to_str().unwrap()),
+    )?;
+    let config = config.to_str().unwrap();
+    let out = run_test_command(
+        &setup,
+        &["--config", config, "registry", "fetch", "--dry-run"],
+    )
+    .await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["registry", "list"]).await?;
+    assert!(!String::from_utf8(out.stdout)?.contains("other"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dangling_binary_of_other_package_needs_force() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("tools.toml");
+    let mut manifest = String::from("schema_version = 1\nname = \"tools\"\n");
+    for name in ["first", "second"] {
+        manifest.push_str(&format!(
+            "\n[[packages]]\nname = \"{name}\"\nversion = \"1.0.0\"\nbuild = \"mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/tool\"\n"
+        ));
+    }
+    std::fs::write(&registry, manifest)?;
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "first"]).await?;
+    assert!(out.status.success());
+
+    // The binary of the first package is dangling, but still belongs to it.
+    std::fs::remove_file(installed_package_directory(&setup, "first", "1.0.0"))?;
+    let out = run_test_command(&setup, &["package", "install", "second"]).await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("conflicts with package first@1.0.0"));
+
+    let out = run_test_command(&setup, &["package", "install", "--force", "second"]).await?;
+    assert!(out.status.success());
+    let link = setup
+        .workspace_root
+        .path()
+        .join("global")
+        .join("bin")
+        .join("tool");
+    assert!(std::fs::read_link(link)?
+        .starts_with(installed_package_directory(&setup, "second", "1.0.0")));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_link_modes() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    for link_mode in ["symlink", "copy", "hardlink"] {
+        let setup = TestSetup::default();
+        let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+        assert!(out.status.success());
+        let out = run_test_command(
+            &setup,
+            &[
+                "package",
+                "install",
+                "--link-mode",
+                link_mode,
+                "package-with-binary",
+            ],
+        )
+        .await?;
+        assert!(out.status.success());
+
+        let binary = setup
+            .workspace_root
+            .path()
+            .join("global")
+            .join("bin")
+            .join("package-with-binary");
+        let metadata = std::fs::symlink_metadata(&binary)?;
+        match link_mode {
+            "symlink" => assert!(metadata.is_symlink()),
+            "copy" => assert!(metadata.is_file() && metadata.nlink() == 1),
+            "hardlink" => assert!(metadata.is_file() && metadata.nlink() > 1),
+            _ => unreachable!(),
+        }
+
+        let out = run_test_command(&setup, &["package", "which", "package-with-binary"]).await?;
+        assert_eq!(
+            String::from_utf8(out.stdout)?,
+            "package-with-binary -> package-with-binary@0.1.0 (global)\n"
+        );
+
+        let out = run_test_command(&setup, &["package", "remove", "package-with-binary"]).await?;
+        assert!(out.status.success());
+        assert!(std::fs::symlink_metadata(&binary).is_err());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_link_mode_is_kept() -> Result<()> {
+    let setup = TestSetup::default();
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--link-mode",
+            "copy",
+            "package-with-binary",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let binary = |workspace: &str| {
+        setup
+            .workspace_root
+            .path()
+            .join(workspace)
+            .join("bin")
+            .join("package-with-binary")
+    };
+
+    // Reinstalling and copying place the binary the same way as the original install.
+    let out = run_test_command(&setup, &["package", "reinstall", "package-with-binary"]).await?;
+    assert!(out.status.success());
+    assert!(std::fs::symlink_metadata(binary("global"))?.is_file());
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "copy",
+            "--from",
+            "global",
+            "--to",
+            "other",
+            "package-with-binary",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert!(std::fs::symlink_metadata(binary("other"))?.is_file());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_summary() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "test-package", "another-package"],
+    )
+    .await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("Installed 2 package(s) into workspace 'global'\n"));
+    assert!(!String::from_utf8(out.stdout)?.contains("into workspace"));
+
+    let out = run_test_command(
+        &setup,
+        &["package", "install", "test-package", "package-with-binary"],
+    )
+    .await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr
+        .contains("Installed 1 package(s) into workspace 'global' (1 skipped, already present)\n"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_package_history() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "test-package"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "remove", "test-package"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "history"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    let events = stdout
+        .lines()
+        .map(|line| line.split_once(' ').unwrap().1)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        events,
+        vec!["remove test-package@0.1.1", "install test-package@0.1.1"]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_remove_glob() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("foo.toml");
+    let mut manifest = String::from("schema_version = 1\nname = \"foo\"\n");
+    for name in ["foo-a", "foo-b", "bar"] {
+        manifest.push_str(&format!(
+            "\n[[packages]]\nname = \"{name}\"\nversion = \"1.0.0\"\nbuild = \"true\"\n"
+        ));
+    }
+    std::fs::write(&registry, manifest)?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "foo-a", "foo-b", "bar"]).await?;
+    assert!(out.status.success());
+
+    // Globs match regardless of case, like package names.
+    let out = run_test_command(&setup, &["package", "remove", "FOO-*"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "bar@1.0.0 (resolved from *)\n"
+    );
+
+    let out = run_test_command(&setup, &["package", "remove", "foo-*"]).await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("foo-* does not match any package"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_install_from_file() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let list = setup.config_dir.path().join("pkgs.txt");
+    std::fs::write(
+        &list,
+        "# Tools for this project\ntest-package\n\nanother-package # pinned later\n",
+    )?;
+    let list = list.to_str().unwrap();
+
+    let out = run_test_command(&setup, &["package", "install", "--from-file", list]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout)?;
+    assert_eq!(stdout.lines().count(), 2);
+    assert!(stdout.contains("test-package@0.1.1"));
+    assert!(stdout.contains("another-package@0.2.0"));
+
+    std::fs::write(
+        setup.config_dir.path().join("pkgs.txt"),
+        "test-package\n::another-package\n",
+    )?;
+    let out = run_test_command(&setup, &["package", "install", "--from-file", list]).await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("invalid package request on line 2"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_freeze_round_trips_through_install() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "test-package@~0.1.0",
+            "another-package",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "freeze"]).await?;
+    assert!(out.status.success());
+    let frozen = String::from_utf8(out.stdout)?;
+    // Packages are pinned to the registry they were installed from.
+    let registry = local_test_registry();
+    assert_eq!(
+        frozen,
+        format!("{registry}::another-package@0.2.0\n{registry}::test-package@0.1.0\n")
+    );
+
+    let list = setup.config_dir.path().join("frozen.txt");
+    std::fs::write(&list, &frozen)?;
+    let out = run_test_command(&setup, &["workspace", "add", "fresh"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--workspace",
+            "fresh",
+            "--from-file",
+            list.to_str().unwrap(),
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "freeze", "--workspace", "fresh"]).await?;
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout)?, frozen);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_package_depends() -> Result<()> {
+    let setup = TestSetup::default();
+    let manifest = setup.config_dir.path().join("local.toml");
+    std::fs::write(
+        &manifest,
+        r#"
+            schema_version = 1
+            name = "local"
+
+            [[packages]]
+            name = "a"
+            version = "1.0.0"
+            dependencies = ["b@~1"]
+            build = "mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/a"
+
+            [[packages]]
+            name = "b"
+            version = "1.0.0"
+            build = "mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/b"
+        "#,
+    )?;
+
+    let out = run_test_command(&setup, &["registry", "add", manifest.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "a", "b"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "depends", "b"]).await?;
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout)?, "a@1.0.0\n");
+
+    let out = run_test_command(&setup, &["package", "depends", "a"]).await?;
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout)?, "");
+
+    let out = run_test_command(&setup, &["package", "remove", "b"]).await?;
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stderr)?
+        .contains("Warning: a@1.0.0 depends on b, which is being removed"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_workspace_from_file() -> Result<()> {
+    let setup = TestSetup::default();
+    let project = setup.config_dir.path().join("project");
+    let subdir = project.join("src");
+    std::fs::create_dir_all(&subdir)?;
+    std::fs::write(project.join(".matcha-workspace"), "project\n")?;
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["workspace", "add", "project"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command_in(&setup, &subdir, &["package", "install", "test-package"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "list", "--workspace", "project"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "test-package@0.1.1 (resolved from *)\n"
+    );
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(String::from_utf8(out.stdout)?, "");
+
+    // An explicit workspace takes precedence over the file.
+    let out = run_test_command_in(
+        &setup,
+        &project,
+        &[
+            "package",
+            "install",
+            "--workspace",
+            "global",
+            "another-package",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "list"]).await?;
+    assert_eq!(
+        String::from_utf8(out.stdout)?,
+        "another-package@0.2.0 (resolved from *)\n"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_registry_expands_tilde() -> Result<()> {
+    let setup = TestSetup::default();
+    let registry = setup.config_dir.path().join("registry.toml");
+    std::fs::copy(local_test_registry(), &registry)?;
+
+    // Quoted, so that the shell would not have expanded it either.
+    let out = run_test_command(&setup, &["registry", "add", "~/registry.toml"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["registry", "list"]).await?;
+    let stdout = String::from_utf8(out.stdout)?;
+    assert!(stdout.contains(registry.to_str().unwrap()));
+    assert!(!stdout.contains('~'));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_registry_validate() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "validate", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let manifest = setup.config_dir.path().join("invalid.toml");
+    std::fs::write(
+        &manifest,
+        r#"
+schema_version = 1
+name = "invalid"
+
+[[packages]]
+name = "unsafe/name"
+version = "1.0"
+
+[[packages]]
+name = "bad-source"
+version = "1.0"
+source = "not a url"
+"#,
+    )?;
+    let out = run_test_command(
+        &setup,
+        &[
+            "registry",
+            "validate",
+            "--format",
+            "json",
+            manifest.to_str().unwrap(),
+        ],
+    )
+    .await?;
+    assert!(!out.status.success());
+    let output: serde_json::Value = serde_json::from_slice(&out.stdout)?;
+    let problems = output["problems"].as_array().unwrap();
+    assert_eq!(problems.len(), 2);
+    assert!(problems
+        .iter()
+        .any(|problem| problem == r#"invalid package name "unsafe/name""#));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_quiet_install() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(
+        &setup,
+        &["--quiet", "registry", "add", &local_test_registry()],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert!(out.stderr.is_empty());
+
+    let out = run_test_command(&setup, &["package", "install", "--quiet", "test-package"]).await?;
+    assert!(out.status.success());
+    assert!(out.stderr.is_empty());
+
+    // Errors are still reported.
+    let out = run_test_command(&setup, &["package", "install", "-q", "failing-build"]).await?;
+    assert!(!out.status.success());
+    assert!(!out.stderr.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_no_ansi_escapes_without_terminal() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    // Output is piped here, so neither spinners nor colors should be emitted, even for errors.
+    for args in [
+        &["package", "install", "test-package"][..],
+        &["package", "install", "failing-build"][..],
+        &["package", "install", "no-such-package"][..],
+    ] {
+        let out = run_test_command(&setup, args).await?;
+        assert!(!out.stdout.contains(&0x1b), "{args:?} printed escapes");
+        assert!(!out.stderr.contains(&0x1b), "{args:?} printed escapes");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_copy_package_between_workspaces() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "package-with-binary"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "copy",
+            "--from",
+            "global",
+            "--to",
+            "other",
+            "package-with-binary",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert!(setup
+        .workspace_root
+        .path()
+        .join("other")
+        .join("bin")
+        .join("package-with-binary")
+        .exists());
+
+    let out = run_test_command(&setup, &["package", "list", "--workspace", "other"]).await?;
+    assert!(String::from_utf8(out.stdout)?.contains("package-with-binary@"));
+
+    // Copying again is a no-op, but a package missing in the source is an error.
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "copy",
+            "--from",
+            "global",
+            "--to",
+            "other",
+            "package-with-binary",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "copy",
+            "--from",
+            "other",
+            "--to",
+            "global",
+            "failing-build",
+        ],
+    )
+    .await?;
+    assert!(!out.status.success());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_corrupted_package_is_rebuilt() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "package-with-binary"]).await?;
+    assert!(out.status.success());
+
+    let binary = installed_package_directory(&setup, "package-with-binary", "0.1.0")
+        .join("bin")
+        .join("package-with-binary");
+    std::fs::write(&binary, "corrupted")?;
+
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--workspace",
+            "other",
+            "package-with-binary",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr)?;
+    assert!(stderr.contains("installed files of package-with-binary@0.1.0 are corrupted"));
+    assert!(std::fs::read(&binary)?.is_empty());
+
+    // The rebuilt package is reused as is.
+    let out = run_test_command(&setup, &["workspace", "add", "third"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--workspace",
+            "third",
+            "package-with-binary",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+    assert!(!String::from_utf8(out.stderr)?.contains("corrupted"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_failed_rebuild_keeps_corrupted_package() -> Result<()> {
+    let setup = TestSetup::default();
+    let marker = setup.config_dir.path().join("fail");
+    let registry = setup.config_dir.path().join("foo.toml");
+    std::fs::write(
+        &registry,
+        format!(
+            r#"schema_version = 1
+name = "foo"
+
+[[packages]]
+name = "foo"
+version = "1.0.0"
+build = "test ! -e {} && mkdir $MATCHA_OUTPUT/bin && echo foo > $MATCHA_OUTPUT/bin/foo"
+"#,
+            marker.display()
+        ),
+    )?;
+
+    let out = run_test_command(&setup, &["registry", "add", registry.to_str().unwrap()]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "foo"]).await?;
+    assert!(out.status.success());
+
+    let binary = installed_package_directory(&setup, "foo", "1.0.0")
+        .join("bin")
+        .join("foo");
+    std::fs::write(&binary, "corrupted")?;
+    std::fs::write(&marker, "")?;
+
+    let out = run_test_command(&setup, &["workspace", "add", "other"]).await?;
+    assert!(out.status.success());
+    let out = run_test_command(&setup, &["package", "install", "-w", "other", "foo"]).await?;
+    assert!(!out.status.success());
+
+    // The installed files are left alone until a rebuild succeeds.
+    assert_eq!(std::fs::read_to_string(&binary)?, "corrupted");
+    let bin = setup.workspace_root.path().join("global").join("bin");
+    assert_eq!(std::fs::read_to_string(bin.join("foo"))?, "corrupted");
+
+    std::fs::remove_file(&marker)?;
+    let out = run_test_command(&setup, &["package", "install", "-w", "other", "foo"]).await?;
+    assert!(out.status.success());
+    assert_eq!(std::fs::read_to_string(bin.join("foo"))?, "foo\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_package_export_bin() -> Result<()> {
+    let setup = TestSetup::default();
+
+    let out = run_test_command(&setup, &["registry", "add", &local_test_registry()]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "export-bin", "package-with-binary"]).await?;
+    assert!(!out.status.success());
+    assert!(String::from_utf8(out.stderr)?.contains("package package-with-binary is not installed"));
+
+    let out = run_test_command(&setup, &["package", "install", "package-with-binary"]).await?;
+    assert!(out.status.success());
+
+    let out = run_test_command(&setup, &["package", "export-bin", "package-with-binary"]).await?;
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout)?, "package-with-binary\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_build_installs_to_prefix() -> Result<()> {
+    let setup = TestSetup::default();
+    let manifest = setup.config_dir.path().join("prefix.toml");
+    std::fs::write(
+        &manifest,
+        r#"
+            schema_version = 1
+            name = "prefix"
+
+            [[packages]]
+            name = "prefix-package"
+            version = "0.1.0"
+            build = """
+            mkdir $MATCHA_PREFIX/bin
+            echo -n $MATCHA_PREFIX > $MATCHA_PREFIX/prefix.txt
+            printf '#!/bin/sh\ncat %s/prefix.txt\n' $MATCHA_PREFIX > $MATCHA_PREFIX/bin/prefix-package
+            chmod +x $MATCHA_PREFIX/bin/prefix-package
+            """
+        "#,
+    )?;
+
+    let out = run_test_command(
+        &setup,
+        &[
+            "package",
+            "install",
+            "--from",
+            manifest.to_str().unwrap(),
+            "prefix-package",
+        ],
+    )
+    .await?;
+    assert!(out.status.success());
+
+    let pkg_dir = installed_package_directory(&setup, "prefix-package", "0.1.0");
+    assert_eq!(
+        std::fs::read_to_string(pkg_dir.join("prefix.txt"))?,
+        pkg_dir.to_str().unwrap()
+    );
+    // Paths baked in during the build still work after the package is installed.
+    let out = std::process::Command::new(pkg_dir.join("bin").join("prefix-package")).output()?;
+    assert_eq!(String::from_utf8(out.stdout)?, pkg_dir.to_str().unwrap());
 
     Ok(())
 }
@@ -0,0 +1,53 @@
+use color_eyre::Result;
+use matcha::{
+    manifest::InstallOptions,
+    registry::{DefaultFetcher, Registry},
+    PackageRequest, State, PACKAGE_ROOT, WORKSPACE_ROOT,
+};
+use tempfile::TempDir;
+
+#[tokio::test]
+async fn test_install_through_library() -> Result<()> {
+    let root = TempDir::new()?;
+    WORKSPACE_ROOT.set(root.path().join("workspaces")).unwrap();
+    PACKAGE_ROOT.set(root.path().join("packages")).unwrap();
+
+    let manifest = root.path().join("registry.toml");
+    std::fs::write(
+        &manifest,
+        r#"
+            schema_version = 1
+            name = "embedded"
+
+            [[packages]]
+            name = "embedded-package"
+            version = "1.0.0"
+            build = "mkdir $MATCHA_OUTPUT/bin && touch $MATCHA_OUTPUT/bin/embedded-package"
+        "#,
+    )?;
+
+    let state = State::load(":memory:").await?;
+    let fetcher = DefaultFetcher;
+    let mut registry = Registry::new(manifest.to_str().unwrap());
+    registry.initialize(&state, &fetcher).await?;
+    registry.fetch(&state, &fetcher).await?;
+
+    let request: PackageRequest = "embedded-package".parse()?;
+    matcha::install(
+        &state,
+        &[request.to_string()],
+        "global",
+        None,
+        &InstallOptions::default(),
+    )
+    .await?;
+
+    let workspace = state.get_workspace("global").await?.unwrap();
+    let packages = state.workspace_packages(&workspace).await?;
+    assert_eq!(packages.len(), 1);
+    assert_eq!(packages[0].name, "embedded-package");
+    assert_eq!(packages[0].version, "1.0.0");
+    assert!(workspace.bin_directory()?.join("embedded-package").exists());
+
+    Ok(())
+}